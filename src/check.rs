@@ -0,0 +1,124 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::tls::{self, TlsCryptoProvider};
+
+/// Outcome of one of the checks of --check-config
+#[derive(Debug, Serialize)]
+struct Check {
+    check: &'static str,
+    target: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Report of --check-config, printed as json so orchestration can act on it
+#[derive(Debug, Serialize)]
+pub struct Report {
+    ok: bool,
+    checks: Vec<Check>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            ok: true,
+            checks: Vec::new(),
+        }
+    }
+}
+
+impl Report {
+    pub fn add(&mut self, check: &'static str, target: impl Display, ret: anyhow::Result<()>) {
+        self.ok &= ret.is_ok();
+        self.checks.push(Check {
+            check,
+            target: target.to_string(),
+            ok: ret.is_ok(),
+            error: ret.err().map(|err| format!("{:#}", err)),
+        });
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.ok
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+/// The certificate chain and the private key can be loaded, and go together
+pub fn tls_certificate(cert_path: &Path, key_path: &Path, kind: TlsCryptoProvider) -> anyhow::Result<()> {
+    let chain = tls::load_certificates_from_pem(cert_path)
+        .with_context(|| format!("cannot load tls certificate {:?}", cert_path))?;
+    if chain.is_empty() {
+        return Err(anyhow!("no certificate found in {:?}", cert_path));
+    }
+    let key = tls::load_private_key_from_file(key_path)
+        .with_context(|| format!("cannot load tls private key {:?}", key_path))?;
+    tls::check_key_pair(&chain, &key, kind)
+}
+
+/// Certificates of the CA of the clients or the servers
+pub fn tls_ca_certificates(path: &Path) -> anyhow::Result<()> {
+    let certificates =
+        tls::load_certificates_from_pem(path).with_context(|| format!("cannot load ca certificates {:?}", path))?;
+    if certificates.is_empty() {
+        return Err(anyhow!("no certificate found in {:?}", path));
+    }
+    Ok(())
+}
+
+/// With the resolver of the system, the one of --dns-resolver is only used once running
+pub async fn dns(host: &str, port: u16) -> anyhow::Result<()> {
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("cannot resolve {}", host))?;
+    if addrs.count() == 0 {
+        return Err(anyhow!("no address for {}", host));
+    }
+    Ok(())
+}
+
+/// The address is not in use, and the process is allowed to bind it. The socket is closed right away
+pub async fn bind(addr: SocketAddr, tcp: bool, udp: bool) -> anyhow::Result<()> {
+    if tcp {
+        TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("cannot bind tcp {}", addr))?;
+    }
+    if udp {
+        UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("cannot bind udp {}", addr))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        report.add("dns", "example.com", Ok(()));
+        assert!(report.is_ok());
+        report.add("bind", "0.0.0.0:443", Err(anyhow!("Permission denied")));
+        assert!(!report.is_ok());
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["checks"][0]["target"], "example.com");
+        assert!(json["checks"][0].get("error").is_none());
+        assert_eq!(json["checks"][1]["error"], "Permission denied");
+    }
+}
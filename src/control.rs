@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use futures_util::future::BoxFuture;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// Request sent by `wstunnel ctl` to the control socket of the client, as a json line
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Add {
+        local_to_remote: Vec<String>,
+        remote_to_local: Vec<String>,
+    },
+    List,
+    Remove {
+        ids: Vec<u64>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Forwardings(Vec<Forwarding>),
+    Error(String),
+}
+
+/// A -L or -R of the client, from the command line or added at runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Forwarding {
+    pub id: u64,
+    pub reverse: bool,
+    pub tunnel: String,
+    /// False once its tasks have stopped by themselves, i.e: a listener that failed
+    pub running: bool,
+}
+
+impl Forwarding {
+    pub fn to_line(&self) -> String {
+        format!(
+            "{:>4}  {}  {}{}",
+            self.id,
+            if self.reverse { "-R" } else { "-L" },
+            self.tunnel,
+            if self.running { "" } else { "  (stopped)" }
+        )
+    }
+}
+
+/// Of `wstunnel ctl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+// Bumped when fields of the json output change meaning or are removed, adding fields keeps it
+const JSON_OUTPUT_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forwardings: Option<&'a [Forwarding]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Result of a request, as printed by `wstunnel ctl --output json`
+pub fn to_json(ret: &anyhow::Result<Vec<Forwarding>>) -> String {
+    let output = JsonOutput {
+        version: JSON_OUTPUT_VERSION,
+        forwardings: ret.as_ref().ok().map(|forwardings| forwardings.as_slice()),
+        error: ret.as_ref().err().map(|err| format!("{:#}", err)),
+    };
+    serde_json::to_string(&output).unwrap_or_default()
+}
+
+/// Parse the argument of a -L (false) or -R (true), and start it
+pub type AddTunnel = Arc<dyn Fn(bool, String) -> BoxFuture<'static, anyhow::Result<Forwarding>> + Send + Sync>;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+// The listeners of the forwarding, or its registration on the server when reverse
+type Tasks = Vec<JoinHandle<()>>;
+static FORWARDINGS: Lazy<Mutex<BTreeMap<u64, (Forwarding, Tasks)>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Keep the tasks of the forwarding, to stop them when it is removed
+pub fn register(reverse: bool, tunnel: String, tasks: Tasks) -> Forwarding {
+    let forwarding = Forwarding {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        reverse,
+        tunnel,
+        running: true,
+    };
+    FORWARDINGS.lock().insert(forwarding.id, (forwarding.clone(), tasks));
+    forwarding
+}
+
+fn list() -> Vec<Forwarding> {
+    FORWARDINGS
+        .lock()
+        .values()
+        .map(|(forwarding, tasks)| Forwarding {
+            running: tasks.iter().any(|task| !task.is_finished()),
+            ..forwarding.clone()
+        })
+        .collect()
+}
+
+/// Stop listening for the forwardings, the connections they have already accepted stay open
+fn remove(ids: &[u64]) -> anyhow::Result<Vec<Forwarding>> {
+    let mut forwardings = FORWARDINGS.lock();
+    if let Some(id) = ids.iter().find(|id| !forwardings.contains_key(id)) {
+        return Err(anyhow!("no forwarding with id {}", id));
+    }
+
+    let mut removed = vec![];
+    for id in ids {
+        if let Some((forwarding, tasks)) = forwardings.remove(id) {
+            tasks.iter().for_each(|task| task.abort());
+            info!("Removed forwarding {}: {}", forwarding.id, forwarding.tunnel);
+            removed.push(forwarding);
+        }
+    }
+    Ok(removed)
+}
+
+async fn add(add_tunnel: &AddTunnel, local_to_remote: Vec<String>, remote_to_local: Vec<String>) -> Response {
+    let mut added: Vec<Forwarding> = vec![];
+    let args = local_to_remote
+        .into_iter()
+        .map(|arg| (false, arg))
+        .chain(remote_to_local.into_iter().map(|arg| (true, arg)));
+    for (reverse, arg) in args {
+        match add_tunnel(reverse, arg.clone()).await {
+            Ok(forwarding) => {
+                info!("Added forwarding {}: {}", forwarding.id, forwarding.tunnel);
+                added.push(forwarding);
+            }
+            // All or nothing, so the command can be fixed and run again
+            Err(err) => {
+                let _ = remove(&added.iter().map(|forwarding| forwarding.id).collect::<Vec<_>>());
+                return Response::Error(format!("cannot add {}: {:#}", arg, err));
+            }
+        }
+    }
+    Response::Forwardings(added)
+}
+
+async fn serve(stream: impl AsyncRead + AsyncWrite + Unpin, add_tunnel: AddTunnel) -> anyhow::Result<()> {
+    let (rx, mut tx) = tokio::io::split(stream);
+    let mut line = String::new();
+    BufReader::new(rx).read_line(&mut line).await?;
+
+    let response = match serde_json::from_str(&line) {
+        Ok(Request::Add {
+            local_to_remote,
+            remote_to_local,
+        }) => add(&add_tunnel, local_to_remote, remote_to_local).await,
+        Ok(Request::List) => Response::Forwardings(list()),
+        Ok(Request::Remove { ids }) => match remove(&ids) {
+            Ok(removed) => Response::Forwardings(removed),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Err(err) => Response::Error(format!("invalid request: {}", err)),
+    };
+
+    let mut response = serde_json::to_vec(&response)?;
+    response.push(b'\n');
+    tx.write_all(&response).await?;
+    tx.shutdown().await?;
+    Ok(())
+}
+
+/// Accept the requests of `wstunnel ctl` on the unix socket, only the same user can connect
+#[cfg(unix)]
+pub async fn run_server(path: &str, add_tunnel: AddTunnel) -> anyhow::Result<()> {
+    let listener = crate::unix_socket::bind(std::path::Path::new(path), Some(0o600), None)?;
+    info!("Starting control socket listening on {}", path);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let add_tunnel = add_tunnel.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(stream, add_tunnel).await {
+                            error!("Error on the control socket: {:?}", err);
+                        }
+                    });
+                }
+                Err(err) => error!("Cannot accept on the control socket: {:?}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Accept the requests of `wstunnel ctl` on the named pipe, i.e: \\.\pipe\wstunnel
+#[cfg(windows)]
+pub async fn run_server(path: &str, add_tunnel: AddTunnel) -> anyhow::Result<()> {
+    use futures_util::StreamExt;
+
+    let mut server = Box::pin(crate::named_pipe::run_server(path).await?);
+    tokio::spawn(async move {
+        while let Some(stream) = server.next().await {
+            match stream {
+                Ok(stream) => {
+                    let add_tunnel = add_tunnel.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(stream, add_tunnel).await {
+                            error!("Error on the control socket: {:?}", err);
+                        }
+                    });
+                }
+                Err(err) => error!("{:?}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Send the request to the control socket of the client, as `wstunnel ctl` does
+pub async fn send(path: &str, request: &Request) -> anyhow::Result<Vec<Forwarding>> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .with_context(|| format!("cannot connect to the control socket {}", path))?;
+    #[cfg(windows)]
+    let stream = crate::named_pipe::connect(path, std::time::Duration::from_secs(5)).await?;
+
+    let (rx, mut tx) = tokio::io::split(stream);
+    let mut request = serde_json::to_vec(request)?;
+    request.push(b'\n');
+    tx.write_all(&request).await?;
+
+    let mut line = String::new();
+    BufReader::new(rx).read_line(&mut line).await?;
+    match serde_json::from_str(&line).context("invalid response from the control socket")? {
+        Response::Forwardings(forwardings) => Ok(forwardings),
+        Response::Error(err) => Err(anyhow!(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_remove() {
+        let listener = tokio::spawn(std::future::pending::<()>());
+        let forwarding = register(false, "tcp://127.0.0.1:8080 -> localhost:80".to_string(), vec![listener]);
+        assert!(list().iter().any(|f| f.id == forwarding.id && f.running));
+
+        // Nothing is removed if one of the ids is unknown
+        assert!(remove(&[forwarding.id, u64::MAX]).is_err());
+        assert!(list().iter().any(|f| f.id == forwarding.id));
+        assert_eq!(remove(&[forwarding.id]).unwrap()[0].id, forwarding.id);
+        assert!(list().iter().all(|f| f.id != forwarding.id));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let forwardings = vec![Forwarding {
+            id: 1,
+            reverse: true,
+            tunnel: "tcp://0.0.0.0:2222 -> localhost:22".to_string(),
+            running: false,
+        }];
+        assert_eq!(
+            to_json(&Ok(forwardings)),
+            r#"{"version":1,"forwardings":[{"id":1,"reverse":true,"tunnel":"tcp://0.0.0.0:2222 -> localhost:22","running":false}]}"#
+        );
+        assert_eq!(
+            to_json(&Err(anyhow!("no forwarding with id 3"))),
+            r#"{"version":1,"error":"no forwarding with id 3"}"#
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use tracing::warn;
+
+/// File with the pid of the client, removed when it stops
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write our pid to the file. The one of a process still running is not replaced, the one left by a process
+    /// that did not stop cleanly is
+    pub fn create(path: PathBuf) -> anyhow::Result<Self> {
+        match fs::read_to_string(&path) {
+            Ok(content) => match content.trim().parse::<u32>() {
+                Ok(pid) if is_running(pid) => {
+                    return Err(anyhow!(
+                        "{:?} is the pid file of wstunnel already running with pid {}",
+                        path,
+                        pid
+                    ));
+                }
+                _ => warn!("Replacing the stale pid file {:?}", path),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(anyhow!("cannot read the pid file {:?}: {}", path, err)),
+        }
+
+        let pid_file = Self { path };
+        pid_file.write(std::process::id())?;
+        Ok(pid_file)
+    }
+
+    fn write(&self, pid: u32) -> anyhow::Result<()> {
+        fs::write(&self.path, format!("{}\n", pid))
+            .with_context(|| format!("cannot write the pid file {:?}", self.path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("Cannot remove the pid file {:?}: {}", self.path, err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+
+    // Signal 0 only checks that the process exists, EPERM means it exists under another user
+    let Ok(pid) = i32::try_from(pid) else {
+        return false;
+    };
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+/// Continue in the background, in a new session without the terminal. The parent writes the pid of the child to the
+/// pid file before exiting, so it can be read as soon as the command returns.
+/// Must be called before any thread is started, i.e: the tokio runtime. The working directory is kept, so the relative
+/// paths of the arguments still work
+#[cfg(unix)]
+pub fn detach(pid_file: Option<&PidFile>) -> anyhow::Result<()> {
+    use nix::unistd::{dup2, fork, setsid, ForkResult};
+    use std::os::fd::AsRawFd;
+
+    // Safety: there is only the main thread yet
+    match unsafe { fork() }.context("cannot fork")? {
+        ForkResult::Parent { child } => {
+            if let Some(pid_file) = pid_file {
+                if let Err(err) = pid_file.write(child.as_raw() as u32) {
+                    eprintln!("{:?}", err);
+                    std::process::exit(1);
+                }
+            }
+            std::process::exit(0);
+        }
+        ForkResult::Child => {}
+    }
+
+    setsid().context("cannot start a new session")?;
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("cannot open /dev/null")?;
+    for fd in [
+        io::stdin().as_raw_fd(),
+        io::stdout().as_raw_fd(),
+        io::stderr().as_raw_fd(),
+    ] {
+        dup2(null.as_raw_fd(), fd).context("cannot redirect the standard streams to /dev/null")?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn detach(_pid_file: Option<&PidFile>) -> anyhow::Result<()> {
+    Err(anyhow!("--daemon is only available on unix"))
+}
+
+/// Resolves when the client is asked to stop, with ctrl-c or SIGTERM. Once detached there is no terminal to hang up,
+/// SIGHUP reopens the log file instead, after it has been moved away by logrotate
+#[cfg(unix)]
+pub async fn stop_requested(detached: bool) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("cannot listen for SIGTERM");
+    let mut sighup = match detached {
+        true => Some(signal(SignalKind::hangup()).expect("cannot listen for SIGHUP")),
+        false => None,
+    };
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return,
+            _ = sigterm.recv() => return,
+            Some(_) = async { sighup.as_mut()?.recv().await } => {
+                crate::log_file::reopen();
+                tracing::info!("Received SIGHUP, the log file has been reopened");
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn stop_requested(_detached: bool) {
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("wstunnel-test-{}.pid", std::process::id()));
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        // We are still running
+        assert!(PidFile::create(path.clone()).is_err());
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // Left by a process that has not stopped cleanly
+        fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+    }
+}
@@ -1,6 +1,16 @@
+use crate::health::{self, Check};
+use hickory_resolver::config::{ResolverOpts, ServerOrderingStrategy};
+use hickory_resolver::error::ResolveErrorKind;
 use hickory_resolver::TokioAsyncResolver;
-use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use parking_lot::Mutex;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use url::Host;
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 pub enum DnsResolver {
     System,
@@ -9,19 +19,247 @@ pub enum DnsResolver {
 
 impl DnsResolver {
     pub async fn lookup_host(&self, domain: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        if split_scoped_ipv6(domain).is_some() {
+            return Ok(vec![scoped_socket_addr(domain, port)?]);
+        }
         let addrs: Vec<SocketAddr> = match self {
             DnsResolver::System => tokio::net::lookup_host(format!("{}:{}", domain, port)).await?.collect(),
-            DnsResolver::TrustDns(dns_resolver) => dns_resolver
-                .lookup_ip(domain)
-                .await?
-                .into_iter()
-                .map(|ip| match ip {
-                    IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
-                    IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
-                })
-                .collect(),
+            DnsResolver::TrustDns(dns_resolver) => {
+                let lookup = dns_resolver.lookup_ip(domain).await;
+                match &lookup {
+                    Ok(_) => health::recover(Check::DnsResolver),
+                    // Unknown domains are not the fault of the resolver
+                    Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {}
+                    Err(err) => health::fail(Check::DnsResolver, err),
+                }
+                lookup?
+                    .into_iter()
+                    .map(|ip| match ip {
+                        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+                        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+                    })
+                    .collect()
+            }
         };
 
         Ok(addrs)
     }
 }
+
+/// Ipv6 address with the zone it is scoped to, like fe80::1%eth0 for a link-local one. The zone is the name or the
+/// index of an interface, it is only checked to be there: it is opaque to the client, only the host where the
+/// address is used knows its interfaces. Url knows nothing of zones, so these addresses are carried as `Host::Domain`
+pub fn split_scoped_ipv6(host: &str) -> Option<(Ipv6Addr, &str)> {
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    let (ip, zone) = host.split_once('%')?;
+    if zone.is_empty() || zone.contains(|c: char| c.is_whitespace() || matches!(c, '[' | ']' | '%' | '/')) {
+        return None;
+    }
+    Some((Ipv6Addr::from_str(ip).ok()?, zone))
+}
+
+/// Like `split_scoped_ipv6`, with the zone resolved to the index of an interface of this host
+pub fn parse_scoped_ipv6(host: &str) -> Option<(Ipv6Addr, u32)> {
+    let (ip, zone) = split_scoped_ipv6(host)?;
+    let scope_id = match zone.parse() {
+        Ok(index) => index,
+        Err(_) => interface_index(zone)?,
+    };
+    Some((ip, scope_id))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    nix::net::if_::if_nametoindex(name).ok()
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Like `Host::parse`, with the ipv6 addresses scoped to a zone too
+pub fn parse_host(host: &str) -> Result<Host, url::ParseError> {
+    match split_scoped_ipv6(host) {
+        Some(_) => Ok(Host::Domain(host.trim_start_matches('[').trim_end_matches(']').to_string())),
+        None => Host::parse(host),
+    }
+}
+
+/// Address of an ip, to bind to, domains are not resolved
+pub fn socket_addr(host: &Host, port: u16) -> anyhow::Result<SocketAddr> {
+    match host {
+        Host::Ipv4(ip) => Ok(SocketAddr::V4(SocketAddrV4::new(*ip, port))),
+        Host::Ipv6(ip) => Ok(SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, 0))),
+        Host::Domain(domain) if split_scoped_ipv6(domain).is_some() => scoped_socket_addr(domain, port),
+        Host::Domain(domain) => Err(anyhow::anyhow!("{} is not an ip address", domain)),
+    }
+}
+
+fn scoped_socket_addr(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    match parse_scoped_ipv6(host) {
+        Some((ip, scope_id)) => Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))),
+        None => Err(anyhow::anyhow!("{} is scoped to an unknown interface", host)),
+    }
+}
+
+/// Order in which the name servers of the resolver are queried
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NameServerStrategy {
+    /// The fastest and most reliable ones first, from the statistics of the previous queries
+    #[default]
+    Fastest,
+    /// In the given order, the next one only once the previous one failed or timed out
+    Ordered,
+    /// All of them at once, the first answer is used
+    Parallel,
+}
+
+/// Options of the resolver of the destinations, so a slow name server only stalls the tunnels for `timeout`
+pub fn resolver_opts(
+    mut opts: ResolverOpts,
+    timeout: Option<Duration>,
+    attempts: Option<usize>,
+    strategy: NameServerStrategy,
+    nb_name_servers: usize,
+) -> ResolverOpts {
+    if let Some(timeout) = timeout {
+        opts.timeout = timeout;
+    }
+    if let Some(attempts) = attempts {
+        opts.attempts = attempts;
+    }
+    match strategy {
+        NameServerStrategy::Fastest => {}
+        NameServerStrategy::Ordered => {
+            opts.server_ordering_strategy = ServerOrderingStrategy::UserProvidedOrder;
+            opts.num_concurrent_reqs = 1;
+        }
+        NameServerStrategy::Parallel => opts.num_concurrent_reqs = nb_name_servers.max(1),
+    }
+    opts
+}
+
+// Addresses resolved by the system have no TTL, they are kept this long
+const SYSTEM_TTL: Duration = Duration::from_secs(30);
+
+/// Addresses of a server the client connects to. They are resolved again once their TTL is over or after a failed
+/// connection, and new connections go to each of them in turn, to spread them across round-robin DNS
+#[derive(Debug)]
+pub struct ServerAddrs {
+    host: Host<String>,
+    port: u16,
+    // The system resolver if the system config cannot be read
+    resolver: Option<TokioAsyncResolver>,
+    resolved: Mutex<Option<(Vec<SocketAddr>, Instant)>>,
+    next: AtomicUsize,
+}
+
+impl ServerAddrs {
+    pub fn new(host: Host<String>, port: u16) -> Self {
+        Self {
+            host,
+            port,
+            resolver: TokioAsyncResolver::tokio_from_system_conf().ok(),
+            resolved: Mutex::new(None),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// All the addresses, starting from the one the next connection should go to
+    pub async fn lookup(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let domain = match &self.host {
+            Host::Domain(domain) => domain,
+            Host::Ipv4(ip) => return Ok(vec![SocketAddr::V4(SocketAddrV4::new(*ip, self.port))]),
+            Host::Ipv6(ip) => return Ok(vec![SocketAddr::V6(SocketAddrV6::new(*ip, self.port, 0, 0))]),
+        };
+
+        let cached = self.resolved.lock().clone();
+        let mut addrs = match cached {
+            Some((addrs, valid_until)) if Instant::now() < valid_until => addrs,
+            _ => {
+                let (addrs, valid_until) = match &self.resolver {
+                    Some(resolver) => {
+                        let lookup = resolver.lookup_ip(domain.as_str()).await?;
+                        let addrs = lookup.iter().map(|ip| SocketAddr::new(ip, self.port)).collect();
+                        (addrs, lookup.valid_until())
+                    }
+                    None => (
+                        DnsResolver::System.lookup_host(domain, self.port).await?,
+                        Instant::now() + SYSTEM_TTL,
+                    ),
+                };
+                debug!("Resolved server {} to {:?}", domain, addrs);
+                *self.resolved.lock() = Some((addrs.clone(), valid_until));
+                addrs
+            }
+        };
+
+        if !addrs.is_empty() {
+            let next = self.next.fetch_add(1, Ordering::Relaxed) % addrs.len();
+            addrs.rotate_left(next);
+        }
+        Ok(addrs)
+    }
+
+    /// Resolve the addresses again on the next connection
+    pub fn invalidate(&self) {
+        *self.resolved.lock() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scoped_ipv6() {
+        let ip = Ipv6Addr::from_str("fe80::1").unwrap();
+        assert_eq!(parse_scoped_ipv6("fe80::1%2"), Some((ip, 2)));
+        assert_eq!(parse_scoped_ipv6("[fe80::1%2]"), Some((ip, 2)));
+        assert_eq!(parse_scoped_ipv6("fe80::1"), None);
+        assert_eq!(parse_scoped_ipv6("fe80::1%no-such-interface"), None);
+        #[cfg(target_os = "linux")]
+        assert_eq!(parse_scoped_ipv6("fe80::1%lo"), Some((ip, 1)));
+
+        assert_eq!(
+            split_scoped_ipv6("[fe80::1%no-such-interface]"),
+            Some((ip, "no-such-interface"))
+        );
+        assert_eq!(split_scoped_ipv6("fe80::1%"), None);
+
+        assert_eq!(parse_host("[fe80::1%2]").unwrap(), Host::Domain("fe80::1%2".to_string()));
+        // The zone is an interface of the server, not of the client parsing it
+        assert_eq!(
+            parse_host("[fe80::1%no-such-interface]").unwrap(),
+            Host::Domain("fe80::1%no-such-interface".to_string())
+        );
+        assert!(parse_host("fe80::1").is_err());
+        assert_eq!(
+            socket_addr(&parse_host("fe80::1%2").unwrap(), 80).unwrap(),
+            SocketAddr::V6(SocketAddrV6::new(ip, 80, 0, 2))
+        );
+        assert!(socket_addr(&parse_host("fe80::1%no-such-interface").unwrap(), 80).is_err());
+        assert!(socket_addr(&Host::Domain("example.org".to_string()), 80).is_err());
+    }
+
+    #[test]
+    fn test_resolver_opts() {
+        let opts = resolver_opts(ResolverOpts::default(), None, None, NameServerStrategy::Fastest, 3);
+        assert_eq!(opts, ResolverOpts::default());
+
+        let opts = resolver_opts(
+            ResolverOpts::default(),
+            Some(Duration::from_secs(1)),
+            Some(0),
+            NameServerStrategy::Ordered,
+            3,
+        );
+        assert_eq!((opts.timeout, opts.attempts), (Duration::from_secs(1), 0));
+        assert_eq!(opts.server_ordering_strategy, ServerOrderingStrategy::UserProvidedOrder);
+        assert_eq!(opts.num_concurrent_reqs, 1);
+
+        let opts = resolver_opts(ResolverOpts::default(), None, None, NameServerStrategy::Parallel, 3);
+        assert_eq!(opts.num_concurrent_reqs, 3);
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::anyhow;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Header carrying the random salt of each peer, from which the keys of the websocket are derived
+pub static ENCRYPTION_HEADER: &str = "x-wstunnel-encryption";
+pub const SALT_LEN: usize = 32;
+const MIN_KEY_LEN: usize = 16;
+
+/// Pre-shared secret configured on both the client and the server
+#[derive(Clone)]
+pub struct PayloadKey(Arc<[u8]>);
+
+impl PayloadKey {
+    pub fn new(secret: &str) -> anyhow::Result<Self> {
+        if secret.len() < MIN_KEY_LEN {
+            return Err(anyhow!(
+                "payload encryption key must be at least {} characters long",
+                MIN_KEY_LEN
+            ));
+        }
+
+        Ok(Self(secret.as_bytes().into()))
+    }
+}
+
+// Never leak the secret in the logs
+impl Debug for PayloadKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PayloadKey(****)")
+    }
+}
+
+pub fn new_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+pub fn encode_salt(salt: &[u8; SALT_LEN]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(salt)
+}
+
+pub fn decode_salt(salt: &[u8]) -> anyhow::Result<[u8; SALT_LEN]> {
+    base64::engine::general_purpose::STANDARD
+        .decode(salt)
+        .ok()
+        .and_then(|salt| salt.try_into().ok())
+        .ok_or_else(|| anyhow!("invalid payload encryption salt"))
+}
+
+/// Ciphers of one side of a websocket, to encrypt what is sent and decrypt what is received
+pub type PayloadCiphers = (PayloadCipher, PayloadCipher);
+
+/// Encrypt or decrypt every frame exchanged in one direction of a websocket.
+/// Frames are never lost nor re-ordered over a websocket, so nonces are a counter known by both sides
+pub struct PayloadCipher {
+    cipher: XChaCha20Poly1305,
+    counter: u64,
+}
+
+impl PayloadCipher {
+    fn new(key: &PayloadKey, salt: &[u8], direction: &[u8]) -> Self {
+        let mut okm = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), &key.0)
+            .expand(direction, &mut okm)
+            .expect("32 bytes is a valid hkdf output length");
+
+        Self {
+            cipher: XChaCha20Poly1305::new(&okm.into()),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .encrypt(&nonce, data)
+            .expect("encryption cannot fail for in memory buffers")
+    }
+
+    pub fn open(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.cipher.decrypt(&nonce, data).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "cannot decrypt tunnel payload, payload encryption keys do not match",
+            )
+        })
+    }
+}
+
+/// Both peers contribute a salt, so keys are never reused
+/// even if one of them replays an old handshake
+pub fn ciphers(
+    key: &PayloadKey,
+    client_salt: &[u8; SALT_LEN],
+    server_salt: &[u8; SALT_LEN],
+    is_client: bool,
+) -> PayloadCiphers {
+    let salt = [client_salt.as_slice(), server_salt.as_slice()].concat();
+    let client_to_server = PayloadCipher::new(key, &salt, b"wstunnel client to server");
+    let server_to_client = PayloadCipher::new(key, &salt, b"wstunnel server to client");
+
+    if is_client {
+        (client_to_server, server_to_client)
+    } else {
+        (server_to_client, client_to_server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_cipher() {
+        let key = PayloadKey::new("correct horse battery staple").unwrap();
+        let (client_salt, server_salt) = (new_salt(), new_salt());
+        let (mut client_tx, mut client_rx) = ciphers(&key, &client_salt, &server_salt, true);
+        let (mut server_tx, mut server_rx) = ciphers(&key, &client_salt, &server_salt, false);
+
+        let first = client_tx.seal(b"hello");
+        let second = client_tx.seal(b"hello");
+        assert_ne!(first, second);
+        assert_eq!(server_rx.open(&first).unwrap(), b"hello");
+        assert_eq!(server_rx.open(&second).unwrap(), b"hello");
+        assert_eq!(client_rx.open(&server_tx.seal(b"world")).unwrap(), b"world");
+
+        // Replayed, tampered or encrypted with another key
+        assert!(server_rx.open(&second).is_err());
+        let (mut client_tx, _) = ciphers(&key, &client_salt, &server_salt, true);
+        let (_, mut server_rx) = ciphers(&key, &client_salt, &server_salt, false);
+        let mut tampered = client_tx.seal(b"hello");
+        tampered[0] ^= 1;
+        assert!(server_rx.open(&tampered).is_err());
+        let other_key = PayloadKey::new("another secret key!").unwrap();
+        let (mut other_tx, _) = ciphers(&other_key, &client_salt, &server_salt, true);
+        let (_, mut server_rx) = ciphers(&key, &client_salt, &server_salt, false);
+        assert!(server_rx.open(&other_tx.seal(b"hello")).is_err());
+
+        assert!(PayloadKey::new("short").is_err());
+        assert_eq!(decode_salt(encode_salt(&client_salt).as_bytes()).unwrap(), client_salt);
+    }
+}
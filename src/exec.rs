@@ -0,0 +1,114 @@
+use anyhow::anyhow;
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::info;
+
+use crate::tcp::LocalStream;
+
+/// Stdin and stdout of a spawned command. The command is killed when the stream is dropped
+pub struct ExecStream {
+    _child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+/// Program run by the command, its first word
+pub fn program(cmd: &str) -> &str {
+    cmd.split_whitespace().next().unwrap_or_default()
+}
+
+/// Arguments are split on whitespaces, no shell is involved
+pub fn spawn(cmd: &str) -> anyhow::Result<ExecStream> {
+    let mut args = cmd.split_whitespace();
+    let Some(program) = args.next() else {
+        return Err(anyhow!("cannot spawn an empty command"));
+    };
+
+    info!("Spawning command {}", cmd);
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| anyhow!("cannot spawn command {}: {}", cmd, err))?;
+
+    let stdin = child.stdin.take();
+    let Some(stdout) = child.stdout.take() else {
+        return Err(anyhow!("cannot get stdout of command {}", cmd));
+    };
+
+    Ok(ExecStream {
+        _child: child,
+        stdin,
+        stdout,
+    })
+}
+
+impl AsyncRead for ExecStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ExecStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+        match self.stdin.as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.stdin.as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    // The command only sees the end of its input once the pipe is closed
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        if let Some(stdin) = self.stdin.as_mut() {
+            std::task::ready!(Pin::new(stdin).poll_flush(cx))?;
+        }
+        self.stdin = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl LocalStream for ExecStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_exec_relay() {
+        let (mut visitor, mut tunnel) = tokio::io::duplex(1024);
+        let mut command = spawn("tr a-z A-Z").unwrap();
+        let relay = tokio::spawn(async move { tokio::io::copy_bidirectional(&mut tunnel, &mut command).await });
+
+        visitor.write_all(b"hello from the visitor").await.unwrap();
+        visitor.shutdown().await.unwrap();
+        let mut output = String::new();
+        visitor.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, "HELLO FROM THE VISITOR");
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exec_spawn_failure() {
+        let err = spawn("/nonexistent/wstunnel-exec --arg").err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("cannot spawn command /nonexistent/wstunnel-exec"));
+        assert!(spawn("   ").is_err());
+        assert_eq!(program("rsync --server ."), "rsync");
+    }
+}
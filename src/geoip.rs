@@ -0,0 +1,144 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use maxminddb::Reader;
+use serde::Deserialize;
+
+/// Destinations matched by the geoip rules of the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeoIpRule {
+    Country(String),
+    Asn(u32),
+}
+
+/// Country and autonomous system of the destinations, looked up in MaxMind databases (i.e: GeoLite2-Country and
+/// GeoLite2-ASN). Denied destinations are refused, and if there are allowed ones, destinations must be one of them
+pub struct GeoIp {
+    dbs: Vec<Reader<Vec<u8>>>,
+    allow: Vec<GeoIpRule>,
+    deny: Vec<GeoIpRule>,
+}
+
+/// Fields of the records used by the rules, a country database has no asn and an asn one no country
+#[derive(Debug, Default, Deserialize)]
+struct Record {
+    country: Option<Country>,
+    autonomous_system_number: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Country {
+    iso_code: Option<String>,
+}
+
+impl GeoIp {
+    pub fn open(paths: &[impl AsRef<Path>], allow: Vec<GeoIpRule>, deny: Vec<GeoIpRule>) -> anyhow::Result<Self> {
+        let dbs = paths
+            .iter()
+            .map(|path| {
+                Reader::open_readfile(path.as_ref())
+                    .with_context(|| format!("cannot load geoip database {:?}", path.as_ref()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { dbs, allow, deny })
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let mut country = None;
+        let mut asn = None;
+        for db in &self.dbs {
+            // A corrupted database is as if it did not know the ip
+            let Ok(Some(record)) = db.lookup(ip.to_canonical()).and_then(|found| found.decode::<Record>()) else {
+                continue;
+            };
+            country = country.or_else(|| record.country.and_then(|country| country.iso_code));
+            asn = asn.or(record.autonomous_system_number);
+        }
+
+        let matches = |rule: &GeoIpRule| match rule {
+            GeoIpRule::Country(code) => country.as_ref().is_some_and(|c| c.eq_ignore_ascii_case(code)),
+            GeoIpRule::Asn(number) => asn == Some(*number),
+        };
+        !self.deny.iter().any(matches) && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+    fn string(db: &mut Vec<u8>, s: &str) {
+        db.push(0x40 | s.len() as u8);
+        db.extend_from_slice(s.as_bytes());
+    }
+
+    // Ipv4 database of one node: 0.0.0.0/1 is FR in AS64500, 128.0.0.0/1 points to the right record
+    fn test_db(right: u8) -> Vec<u8> {
+        let mut db = vec![0, 0, 17, 0, 0, right];
+        db.extend_from_slice(&[0; 16]);
+        db.push(0xE2);
+        string(&mut db, "country");
+        db.push(0xE1);
+        string(&mut db, "iso_code");
+        string(&mut db, "FR");
+        string(&mut db, "autonomous_system_number");
+        db.extend_from_slice(&[0xC2, 0xFB, 0xF4]);
+
+        db.extend_from_slice(METADATA_MARKER);
+        db.push(0xE9);
+        string(&mut db, "binary_format_major_version");
+        db.extend_from_slice(&[0xA1, 2]);
+        string(&mut db, "binary_format_minor_version");
+        db.push(0xA0);
+        string(&mut db, "build_epoch");
+        db.extend_from_slice(&[0x01, 0x02, 1]);
+        string(&mut db, "database_type");
+        string(&mut db, "Test");
+        string(&mut db, "description");
+        db.push(0xE0);
+        string(&mut db, "languages");
+        db.extend_from_slice(&[0x00, 0x04]);
+        string(&mut db, "node_count");
+        db.extend_from_slice(&[0xC1, 1]);
+        string(&mut db, "record_size");
+        db.extend_from_slice(&[0xA1, 24]);
+        string(&mut db, "ip_version");
+        db.extend_from_slice(&[0xA1, 4]);
+        db
+    }
+
+    fn geoip(db: Vec<u8>, allow: Vec<GeoIpRule>, deny: Vec<GeoIpRule>) -> GeoIp {
+        GeoIp {
+            dbs: vec![Reader::from_source(db).unwrap()],
+            allow,
+            deny,
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let fr = "10.0.0.1".parse().unwrap();
+        let unknown = "200.0.0.1".parse().unwrap();
+
+        let allow_asn = geoip(test_db(1), vec![GeoIpRule::Asn(64500)], vec![]);
+        assert!(allow_asn.is_allowed(fr));
+        assert!(allow_asn.is_allowed("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!allow_asn.is_allowed(unknown));
+        assert!(!allow_asn.is_allowed("2001:db8::1".parse().unwrap()));
+
+        let deny_fr = geoip(test_db(1), vec![], vec![GeoIpRule::Country("fr".to_string())]);
+        assert!(!deny_fr.is_allowed(fr));
+        assert!(deny_fr.is_allowed(unknown));
+    }
+
+    #[test]
+    fn test_corrupted_db() {
+        // The right record points before the data section
+        let allow_asn = geoip(test_db(5), vec![GeoIpRule::Asn(64500)], vec![]);
+        assert!(!allow_asn.is_allowed("200.0.0.1".parse().unwrap()));
+        assert!(allow_asn.is_allowed("10.0.0.1".parse().unwrap()));
+    }
+}
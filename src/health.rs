@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tracing::{info, warn};
+
+/// Dependencies of the server whose failures make it not ready, i.e: the tls certificate or the dns resolver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Check {
+    TlsCertificate,
+    DnsResolver,
+}
+
+impl Check {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Check::TlsCertificate => "tls certificate",
+            Check::DnsResolver => "dns resolver",
+        }
+    }
+
+    /// Failures of the resolver are only seen by lookups, that a server not ready gets few of. So they are forgotten
+    /// after a while, instead of waiting for a lookup to succeed
+    fn forgotten_after(&self) -> Option<Duration> {
+        match self {
+            Check::TlsCertificate => None,
+            Check::DnsResolver => Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+static LISTENING: AtomicBool = AtomicBool::new(false);
+// 0 when there is no limit
+static MAX_TUNNELS: AtomicUsize = AtomicUsize::new(0);
+static FAILURES: Lazy<Mutex<BTreeMap<Check, (String, Instant)>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The server accepts connections, until it is drained
+pub fn set_listening(listening: bool) {
+    LISTENING.store(listening, Ordering::Relaxed);
+}
+
+pub fn set_max_tunnels(max_tunnels: Option<usize>) {
+    MAX_TUNNELS.store(max_tunnels.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The check is failing, the server is not ready until it recovers
+pub fn fail(check: Check, failure: impl Display) {
+    if FAILURES
+        .lock()
+        .insert(check, (failure.to_string(), Instant::now()))
+        .is_none()
+    {
+        warn!("The {} is failing, the server is not ready: {}", check.as_str(), failure);
+    }
+}
+
+pub fn recover(check: Check) {
+    if FAILURES.lock().remove(&check).is_some() {
+        info!("The {} has recovered, the server is ready again", check.as_str());
+    }
+}
+
+/// Why the server cannot open new tunnels, empty if it is ready
+pub fn not_ready_reasons(open_tunnels: usize) -> Vec<String> {
+    reasons_at(open_tunnels, Instant::now())
+}
+
+fn reasons_at(open_tunnels: usize, now: Instant) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if !LISTENING.load(Ordering::Relaxed) {
+        reasons.push("not listening".to_string());
+    }
+    let max_tunnels = MAX_TUNNELS.load(Ordering::Relaxed);
+    if max_tunnels > 0 && open_tunnels >= max_tunnels {
+        reasons.push(format!("{} tunnels open, the max is {}", open_tunnels, max_tunnels));
+    }
+    let mut failures = FAILURES.lock();
+    failures.retain(|check, (_, failed_at)| {
+        check
+            .forgotten_after()
+            .is_none_or(|after| now.duration_since(*failed_at) < after)
+    });
+    for (check, (failure, _)) in failures.iter() {
+        reasons.push(format!("{}: {}", check.as_str(), failure));
+    }
+    reasons
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_ready_reasons() {
+        assert_eq!(not_ready_reasons(0), ["not listening"]);
+        set_listening(true);
+        set_max_tunnels(Some(2));
+        assert!(not_ready_reasons(1).is_empty());
+        assert_eq!(not_ready_reasons(2), ["2 tunnels open, the max is 2"]);
+
+        fail(Check::DnsResolver, "request timed out");
+        fail(Check::TlsCertificate, "no private key");
+        assert_eq!(
+            not_ready_reasons(0),
+            ["tls certificate: no private key", "dns resolver: request timed out"]
+        );
+        assert_eq!(
+            reasons_at(0, Instant::now() + Duration::from_secs(31)),
+            ["tls certificate: no private key"]
+        );
+        recover(Check::TlsCertificate);
+        assert!(not_ready_reasons(0).is_empty());
+    }
+}
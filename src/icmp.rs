@@ -0,0 +1,197 @@
+use std::future::pending;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use anyhow::Context;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+const PAYLOAD_LEN: usize = 56;
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(0);
+
+struct Pinger {
+    socket: UdpSocket,
+    dest: IpAddr,
+    // Raw sockets receive all the icmp packets of the host, with the ip header for ipv4
+    raw: bool,
+    identifier: u16,
+}
+
+impl Pinger {
+    fn new(dest: IpAddr) -> anyhow::Result<Self> {
+        let (domain, protocol) = match dest {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        // Unprivileged icmp sockets are allowed by net.ipv4.ping_group_range on linux, raw ones need CAP_NET_RAW
+        let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(socket) => (socket, false),
+            Err(err) => {
+                debug!("Cannot open an unprivileged icmp socket, trying a raw one: {}", err);
+                let socket = Socket::new(domain, Type::RAW, Some(protocol))
+                    .context("cannot open an icmp socket, the server needs CAP_NET_RAW or net.ipv4.ping_group_range")?;
+                (socket, true)
+            }
+        };
+        socket.set_nonblocking(true)?;
+        socket.connect(&SocketAddr::new(dest, 0).into())?;
+
+        Ok(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            dest,
+            raw,
+            identifier: (std::process::id() as u16).wrapping_add(NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed)),
+        })
+    }
+
+    /// Send an echo request every second, and write a line for its reply or its timeout
+    async fn run(&self, host: &str, mut out: impl AsyncWrite + Unpin) -> io::Result<()> {
+        out.write_all(
+            format!(
+                "PING {} ({}) {} bytes of data, from the wstunnel server\n",
+                host, self.dest, PAYLOAD_LEN
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+        let mut buf = vec![0; 1500];
+        let mut seq: u16 = 1;
+        loop {
+            let sent_at = Instant::now();
+            let deadline = sent_at + INTERVAL;
+            let line = match self
+                .socket
+                .send(&echo_request(self.dest.is_ipv6(), self.identifier, seq))
+                .await
+            {
+                Ok(_) => loop {
+                    match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                        Err(_) => break format!("Request timeout for icmp_seq={}\n", seq),
+                        Ok(Err(err)) => break format!("From {} icmp_seq={} {}\n", self.dest, seq, err),
+                        Ok(Ok(len)) => {
+                            let reply = parse_echo_reply(&buf[..len], self.dest.is_ipv6(), self.raw, self.identifier);
+                            if let Some((reply_seq, len)) = reply.filter(|(reply_seq, _)| *reply_seq == seq) {
+                                break format!(
+                                    "{} bytes from {}: icmp_seq={} time={:.1} ms\n",
+                                    len,
+                                    self.dest,
+                                    reply_seq,
+                                    sent_at.elapsed().as_secs_f64() * 1000.0
+                                );
+                            }
+                        }
+                    }
+                },
+                Err(err) => format!("Cannot send icmp_seq={}: {}\n", seq, err),
+            };
+
+            out.write_all(line.as_bytes()).await?;
+            seq = seq.wrapping_add(1);
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+fn echo_request(ipv6: bool, identifier: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![if ipv6 { 128 } else { 8 }, 0, 0, 0];
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend((0..PAYLOAD_LEN).map(|i| i as u8));
+    // The checksum of icmpv6 covers the ip addresses, the kernel computes it
+    if !ipv6 {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+fn checksum(packet: &[u8]) -> u16 {
+    let mut sum = packet
+        .chunks(2)
+        .map(|word| u32::from(word[0]) << 8 | u32::from(*word.get(1).unwrap_or(&0)))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Sequence and length of the echo reply, None if the packet is something else
+fn parse_echo_reply(packet: &[u8], ipv6: bool, raw: bool, identifier: u16) -> Option<(u16, usize)> {
+    let packet = match (raw, ipv6) {
+        (true, false) => packet.get((*packet.first()? & 0x0f) as usize * 4..)?,
+        _ => packet,
+    };
+    if packet.len() < 8 || packet[0] != if ipv6 { 129 } else { 0 } {
+        return None;
+    }
+    // The kernel sets the identifier of unprivileged sockets, and only gives them their replies
+    if raw && packet[4..6] != identifier.to_be_bytes() {
+        return None;
+    }
+    Some((u16::from_be_bytes([packet[6], packet[7]]), packet.len()))
+}
+
+/// Ping the destination every second, until the returned stream is dropped. A line is written to it for each reply,
+/// what is written to it is discarded
+pub fn ping(host: String, dest: IpAddr) -> anyhow::Result<DuplexStream> {
+    let pinger = Pinger::new(dest)?;
+    let (stream, pinger_stream) = tokio::io::duplex(4096);
+
+    tokio::spawn(async move {
+        let (mut rx, tx) = tokio::io::split(pinger_stream);
+        let discard = async {
+            let mut buf = [0; 512];
+            while rx.read(&mut buf).await.is_ok_and(|len| len > 0) {}
+            pending::<()>().await
+        };
+
+        info!("Pinging {} ({})", host, dest);
+        select! {
+            _ = discard => {},
+            ret = pinger.run(&host, tx) => debug!("Stopped pinging {}: {:?}", dest, ret),
+        }
+    });
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_packets() {
+        let request = echo_request(false, 0x1234, 7);
+        assert_eq!(&request[..8], &[8, 0, 0xee, 0xb1, 0x12, 0x34, 0, 7]);
+        assert_eq!(request.len(), 8 + PAYLOAD_LEN);
+        assert_eq!(checksum(&request), 0);
+
+        let mut reply = request.clone();
+        reply[0] = 0;
+        assert_eq!(parse_echo_reply(&reply, false, false, 0), Some((7, 64)));
+        assert_eq!(parse_echo_reply(&request, false, false, 0x1234), None);
+
+        // Raw ipv4 sockets receive the ip header, and the replies of the other pings
+        let mut raw_reply = vec![0x45];
+        raw_reply.extend_from_slice(&[0; 19]);
+        raw_reply.extend_from_slice(&reply);
+        assert_eq!(parse_echo_reply(&raw_reply, false, true, 0x1234), Some((7, 64)));
+        assert_eq!(parse_echo_reply(&raw_reply, false, true, 0x4321), None);
+
+        let request = echo_request(true, 0x1234, 7);
+        assert_eq!(&request[..8], &[128, 0, 0, 0, 0x12, 0x34, 0, 7]);
+        assert_eq!(
+            parse_echo_reply(&[129, 0, 0, 0, 0x12, 0x34, 0, 7], true, true, 0x1234),
+            Some((7, 8))
+        );
+    }
+}
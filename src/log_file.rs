@@ -0,0 +1,176 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Set by SIGHUP, the file is reopened on the next write
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+/// Reopen the log file, once it has been moved away by an external tool like logrotate
+#[cfg(unix)]
+pub fn reopen() {
+    REOPEN.store(true, Ordering::Relaxed);
+}
+
+/// When the log file is rotated, and how many of the previous ones are kept
+#[derive(Debug, Clone)]
+pub struct LogRotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub max_files: usize,
+    pub compress: bool,
+}
+
+/// Log file renamed to FILE.1, FILE.2, ... once it gets too big or too old, the oldest ones being removed
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    // Compression of the last rotated file, done in the background not to hold the logs for it
+    compressing: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, rotation: LogRotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size,
+            opened_at: Instant::now(),
+            compressing: None,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        if self.rotation.compress {
+            path.push(".zst");
+        }
+        path.into()
+    }
+
+    fn should_rotate(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        let too_big = self.rotation.max_size.is_some_and(|max| self.size + len as u64 > max);
+        let too_old = self.rotation.max_age.is_some_and(|max| self.opened_at.elapsed() >= max);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        // The previous file must be compressed before it is renamed, it is long done unless the logs are huge
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+        let max_files = self.rotation.max_files;
+        if max_files > 0 {
+            let _ = fs::remove_file(self.rotated_path(max_files));
+            for index in (1..max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+
+            if self.rotation.compress {
+                let mut uncompressed = self.path.clone().into_os_string();
+                uncompressed.push(".1");
+                let uncompressed = PathBuf::from(uncompressed);
+                fs::rename(&self.path, &uncompressed)?;
+                let compressed = self.rotated_path(1);
+                self.compressing = Some(thread::spawn(move || {
+                    if let Err(err) = compress(&uncompressed, &compressed) {
+                        eprintln!("Cannot compress rotated log file {:?}: {}", uncompressed, err);
+                    }
+                }));
+            } else {
+                fs::rename(&self.path, self.rotated_path(1))?;
+            }
+        }
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    zstd::stream::copy_encode(File::open(from)?, File::create(to)?, 0)?;
+    fs::remove_file(from)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Cannot go through the logger, we are the logger
+        if REOPEN.swap(false, Ordering::Relaxed) {
+            match OpenOptions::new().create(true).append(true).open(&self.path) {
+                Ok(file) => {
+                    self.size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                    self.file = file;
+                }
+                Err(err) => eprintln!("Cannot reopen log file {:?}: {}", self.path, err),
+            }
+        }
+        if self.should_rotate(buf.len()) {
+            if let Err(err) = self.rotate() {
+                eprintln!("Cannot rotate log file {:?}: {}", self.path, err);
+            }
+        }
+
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-log-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wstunnel.log");
+        let rotation = LogRotation {
+            max_size: Some(10),
+            max_age: None,
+            max_files: 2,
+            compress: false,
+        };
+
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        for line in ["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 4\n");
+        assert_eq!(fs::read_to_string(dir.join("wstunnel.log.1")).unwrap(), "line 3\n");
+        assert_eq!(fs::read_to_string(dir.join("wstunnel.log.2")).unwrap(), "line 2\n");
+        assert!(!dir.join("wstunnel.log.3").exists());
+
+        file.rotation.compress = true;
+        file.rotate().unwrap();
+        file.compressing.take().unwrap().join().unwrap();
+        assert!(!dir.join("wstunnel.log.1").exists());
+        let compressed = fs::read(dir.join("wstunnel.log.1.zst")).unwrap();
+        assert_eq!(zstd::stream::decode_all(compressed.as_slice()).unwrap(), b"line 4\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,635 @@
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Record};
+use tracing::{Event, Id, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+use url::Url;
+
+const APP_NAME: &str = "wstunnel";
+// daemon
+const SYSLOG_FACILITY: u8 = 3;
+// A stuck collector must not stall the whole process
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// The messages sent while the collector is down are dropped, until the next attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Messages waiting for the syslog writer, the next ones are dropped once it is full
+const SYSLOG_QUEUE_SIZE: usize = 1024;
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Where syslog messages are sent, i.e: udp://127.0.0.1:514, tcp://127.0.0.1:601, unix:///dev/log
+#[derive(Debug, Clone)]
+pub enum SyslogTarget {
+    Udp(String),
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl SyslogTarget {
+    pub fn parse(arg: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(arg)?;
+        let addr = || match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => Ok(format!("{}:{}", host, port)),
+            _ => Err(anyhow!("syslog target {} must have a host and a port", arg)),
+        };
+
+        match url.scheme() {
+            "udp" => Ok(Self::Udp(addr()?)),
+            "tcp" => Ok(Self::Tcp(addr()?)),
+            #[cfg(unix)]
+            "unix" => Ok(Self::Unix(url.path().into())),
+            scheme => Err(anyhow!("unsupported syslog scheme {}", scheme)),
+        }
+    }
+}
+
+/// Ids of the significant events, set as the event_id field of the logs.
+/// Only them and the errors go to the Windows Event Log, as events with this id
+pub mod event_id {
+    pub const STARTED: u32 = 1;
+    pub const STOPPED: u32 = 2;
+    pub const AUTH_FAILURE: u32 = 100;
+    pub const TLS_ERROR: u32 = 200;
+    /// Errors without an id of their own
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub const ERROR: u32 = 1000;
+}
+
+enum Transport {
+    // Sent by a thread of its own, with whether messages are being dropped because it lags behind
+    Syslog(Mutex<(SyncSender<String>, bool)>),
+    // Non blocking and not behind a lock, a stalled journald must not hold the threads logging. With whether
+    // messages are being dropped because it lags behind
+    #[cfg(unix)]
+    Journald(std::os::unix::net::UnixDatagram, AtomicBool),
+    #[cfg(windows)]
+    EventLog(Mutex<event_log::EventSource>),
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Connection {
+    fn connect(target: &SyslogTarget) -> io::Result<Self> {
+        match target {
+            SyslogTarget::Udp(addr) => {
+                let addr = addr
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cannot resolve syslog address"))?;
+                let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                socket.connect(addr)?;
+                Ok(Self::Udp(socket))
+            }
+            SyslogTarget::Tcp(addr) => {
+                let mut last_err = io::Error::new(io::ErrorKind::NotFound, "cannot resolve syslog address");
+                let mut stream = None;
+                for addr in addr.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                        Ok(cnx) => {
+                            stream = Some(cnx);
+                            break;
+                        }
+                        Err(err) => last_err = err,
+                    }
+                }
+                let stream = stream.ok_or(last_err)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                Ok(Self::Tcp(stream))
+            }
+            #[cfg(unix)]
+            SyslogTarget::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                Ok(Self::Unix(socket))
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Connection::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            // Octet counting framing of RFC 6587, messages may contain new lines
+            Connection::Tcp(stream) => stream.write_all(format!("{} {}", message.len(), message).as_bytes()),
+            #[cfg(unix)]
+            Connection::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+        }
+    }
+}
+
+/// Writer of the syslog messages, the connection is made and remade here so the logging never waits for it
+fn run_syslog(target: SyslogTarget, messages: Receiver<String>) {
+    let mut connection: Option<Connection> = None;
+    let mut retry_at = Instant::now();
+    for message in messages {
+        if connection.is_none() {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            match Connection::connect(&target) {
+                Ok(cnx) => connection = Some(cnx),
+                Err(err) => {
+                    // Cannot go through the logger, we are the logger
+                    eprintln!("Cannot connect to syslog {:?}: {}", target, err);
+                    retry_at = Instant::now() + RECONNECT_DELAY;
+                    continue;
+                }
+            }
+        }
+
+        if let Some(cnx) = &mut connection {
+            if let Err(err) = cnx.send(&message) {
+                eprintln!("Cannot send log message: {}", err);
+                // Reconnect on the next message
+                connection = None;
+            }
+        }
+    }
+}
+
+/// Fields of an event, or of a span
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.fields.push((name, value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => self.fields.push((name, format!("{:?}", value))),
+        }
+    }
+}
+
+struct LogLine<'a> {
+    level: Level,
+    target: &'a str,
+    message: &'a str,
+    fields: Vec<(&'static str, String)>,
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+fn hostname() -> String {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_default();
+    match hostname.trim() {
+        "" => "-".to_string(),
+        hostname => hostname.to_string(),
+    }
+}
+
+/// The fields of the event and of its spans are appended to the message like on stdout
+fn text(line: &LogLine) -> String {
+    let mut text = format!("{}: {}", line.target, line.message);
+    for (name, value) in &line.fields {
+        let _ = write!(text, " {}={}", name, value);
+    }
+    text
+}
+
+/// RFC 5424 message
+fn syslog_message(line: &LogLine, timestamp: &str, hostname: &str) -> String {
+    format!(
+        "<{}>1 {} {} {} {} - - {}",
+        SYSLOG_FACILITY * 8 + severity(line.level),
+        timestamp,
+        hostname,
+        APP_NAME,
+        std::process::id(),
+        text(line)
+    )
+}
+
+/// Native protocol of journald, each field of the event becomes a field of the journal entry
+#[cfg(unix)]
+fn journald_message(line: &LogLine) -> Vec<u8> {
+    let mut message = vec![];
+    let mut push = |name: &str, value: &str| {
+        // Values with new lines must be length prefixed
+        if value.contains('\n') {
+            message.extend_from_slice(name.as_bytes());
+            message.push(b'\n');
+            message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            message.extend_from_slice(value.as_bytes());
+            message.push(b'\n');
+        } else {
+            message.extend_from_slice(format!("{}={}\n", name, value).as_bytes());
+        }
+    };
+
+    push("MESSAGE", line.message);
+    push("PRIORITY", &severity(line.level).to_string());
+    push("SYSLOG_IDENTIFIER", APP_NAME);
+    push("TARGET", line.target);
+    for (name, value) in &line.fields {
+        push(&journald_field_name(name), value);
+    }
+    message
+}
+
+/// Journal field names are uppercase letters, digits and underscores, and cannot start with an underscore or a digit
+#[cfg(unix)]
+fn journald_field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_uppercase() => name,
+        _ => format!("F{}", name),
+    }
+}
+
+#[cfg(windows)]
+fn event_id(line: &LogLine) -> Option<u32> {
+    line.fields
+        .iter()
+        .find(|(name, _)| *name == "event_id")
+        .and_then(|(_, id)| id.parse().ok())
+}
+
+/// Tracing layer sending the logs to syslog, journald or the Windows Event Log
+pub struct LogSinkLayer {
+    transport: Transport,
+    hostname: String,
+}
+
+impl LogSinkLayer {
+    pub fn syslog(target: SyslogTarget) -> anyhow::Result<Self> {
+        // Connected lazily, so the collector does not have to be up before us
+        let (tx, rx) = mpsc::sync_channel(SYSLOG_QUEUE_SIZE);
+        thread::Builder::new()
+            .name("syslog".to_string())
+            .spawn(move || run_syslog(target, rx))
+            .map_err(|err| anyhow!("cannot start the syslog writer: {}", err))?;
+        Ok(Self {
+            transport: Transport::Syslog(Mutex::new((tx, false))),
+            hostname: hostname(),
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn journald() -> anyhow::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket
+            .connect(JOURNALD_SOCKET)
+            .map_err(|err| anyhow!("cannot connect to journald at {}: {}", JOURNALD_SOCKET, err))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            transport: Transport::Journald(socket, AtomicBool::new(false)),
+            hostname: hostname(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn journald() -> anyhow::Result<Self> {
+        Err(anyhow!("journald is only available on linux"))
+    }
+
+    #[cfg(windows)]
+    pub fn event_log() -> anyhow::Result<Self> {
+        let source = event_log::EventSource::register(APP_NAME)
+            .map_err(|err| anyhow!("cannot register to the Windows Event Log: {}", err))?;
+        Ok(Self {
+            transport: Transport::EventLog(Mutex::new(source)),
+            hostname: hostname(),
+        })
+    }
+
+    #[cfg(not(windows))]
+    pub fn event_log() -> anyhow::Result<Self> {
+        Err(anyhow!("the Windows Event Log is only available on windows"))
+    }
+
+    fn send(&self, line: &LogLine) {
+        let ret = match &self.transport {
+            Transport::Syslog(writer) => {
+                let (writer, dropping) = &mut *writer.lock();
+                let mut timestamp = String::new();
+                let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+                let message = syslog_message(line, &timestamp, &self.hostname);
+                match writer.try_send(message) {
+                    Ok(()) => {
+                        *dropping = false;
+                        Ok(())
+                    }
+                    // Reported once, until the writer catches up
+                    Err(TrySendError::Full(_)) if *dropping => Ok(()),
+                    Err(TrySendError::Full(_)) => {
+                        *dropping = true;
+                        Err(io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            "syslog is too slow, dropping the messages",
+                        ))
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        Err(io::Error::new(io::ErrorKind::BrokenPipe, "the syslog writer is gone"))
+                    }
+                }
+            }
+            #[cfg(unix)]
+            Transport::Journald(socket, dropping) => match socket.send(&journald_message(line)) {
+                Ok(_) => {
+                    dropping.store(false, Ordering::Relaxed);
+                    Ok(())
+                }
+                // Reported once, until journald catches up
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => match dropping.swap(true, Ordering::Relaxed) {
+                    true => Ok(()),
+                    false => Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "journald is too slow, dropping the messages",
+                    )),
+                },
+                Err(err) => Err(err),
+            },
+            #[cfg(windows)]
+            Transport::EventLog(source) => {
+                let source = source.lock();
+                match (event_id(line), line.level) {
+                    (Some(id), level) => source.report(level, id, &text(line)),
+                    (None, Level::ERROR) => source.report(Level::ERROR, event_id::ERROR, &text(line)),
+                    _ => Ok(()),
+                }
+            }
+        };
+
+        // Cannot go through the logger, we are the logger
+        if let Err(err) = ret {
+            eprintln!("Cannot send log message: {}", err);
+        }
+    }
+}
+
+impl<S> Layer<S> for LogSinkLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        // Shared with the other sink, if both are enabled
+        if extensions.get_mut::<Fields>().is_none() {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            extensions.insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<Fields>() {
+            let mut recorded = Fields::default();
+            values.record(&mut recorded);
+            for (name, value) in recorded.fields {
+                fields.fields.retain(|(n, _)| *n != name);
+                fields.fields.push((name, value));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
+        // Fields of the outermost span first, like on stdout
+        let mut all_fields = vec![];
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<Fields>() {
+                    all_fields.extend(span_fields.fields.iter().cloned());
+                }
+            }
+        }
+        // Records of the log crate carry their real target as a field
+        let mut target = event.metadata().target().to_string();
+        for (name, value) in fields.fields {
+            match name {
+                "log.target" => target = value,
+                name if name.starts_with("log.") => {}
+                name => all_fields.push((name, value)),
+            }
+        }
+
+        self.send(&LogLine {
+            level: *event.metadata().level(),
+            target: &target,
+            message: &fields.message,
+            fields: all_fields,
+        });
+    }
+}
+
+#[cfg(windows)]
+mod event_log {
+    use std::ffi::{c_void, OsStr};
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use tracing::Level;
+
+    const EVENTLOG_ERROR_TYPE: u16 = 0x1;
+    const EVENTLOG_WARNING_TYPE: u16 = 0x2;
+    const EVENTLOG_INFORMATION_TYPE: u16 = 0x4;
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn RegisterEventSourceW(server_name: *const u16, source_name: *const u16) -> *mut c_void;
+        fn ReportEventW(
+            event_log: *mut c_void,
+            event_type: u16,
+            category: u16,
+            event_id: u32,
+            user_sid: *mut c_void,
+            num_strings: u16,
+            data_size: u32,
+            strings: *const *const u16,
+            raw_data: *mut c_void,
+        ) -> i32;
+        fn DeregisterEventSource(event_log: *mut c_void) -> i32;
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        OsStr::new(s).encode_wide().chain(Some(0)).collect()
+    }
+
+    /// Source of the events, in the Application log
+    pub struct EventSource(*mut c_void);
+
+    // The handle is not tied to the thread that registered it
+    unsafe impl Send for EventSource {}
+
+    impl EventSource {
+        pub fn register(name: &str) -> io::Result<Self> {
+            let name = wide(name);
+            let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(handle))
+        }
+
+        pub fn report(&self, level: Level, event_id: u32, message: &str) -> io::Result<()> {
+            let event_type = match level {
+                Level::ERROR => EVENTLOG_ERROR_TYPE,
+                Level::WARN => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            let message = wide(message);
+            let strings = [message.as_ptr()];
+            let ret = unsafe {
+                ReportEventW(
+                    self.0,
+                    event_type,
+                    0,
+                    event_id,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null_mut(),
+                )
+            };
+            if ret == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for EventSource {
+        fn drop(&mut self) {
+            unsafe { DeregisterEventSource(self.0) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line() -> LogLine<'static> {
+        LogLine {
+            level: Level::WARN,
+            target: "wstunnel::tunnel::server",
+            message: "Rejecting connection",
+            fields: vec![("id", "0123".to_string()), ("remote", "127.0.0.1:80".to_string())],
+        }
+    }
+
+    #[test]
+    fn test_syslog_message() {
+        let message = syslog_message(&line(), "2024-01-01T00:00:00.000000Z", "host");
+        let expected = format!(
+            "<28>1 2024-01-01T00:00:00.000000Z host wstunnel {} - - wstunnel::tunnel::server: Rejecting connection \
+             id=0123 remote=127.0.0.1:80",
+            std::process::id()
+        );
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn test_syslog_writer() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = SyslogTarget::Tcp(listener.local_addr().unwrap().to_string());
+        let layer = LogSinkLayer::syslog(target).unwrap();
+        layer.send(&line());
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = vec![0; 256];
+        let len = stream.read(&mut received).unwrap();
+        let received = String::from_utf8_lossy(&received[..len]);
+        let (len, message) = received.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), message.len());
+        assert!(message.starts_with("<28>1 "));
+        assert!(message.ends_with("Rejecting connection id=0123 remote=127.0.0.1:80"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_journald_message() {
+        let mut line = line();
+        line.message = "multi\nline";
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&10u64.to_le_bytes());
+        expected.extend_from_slice(b"multi\nline\n");
+        expected.extend_from_slice(
+            b"PRIORITY=4\nSYSLOG_IDENTIFIER=wstunnel\nTARGET=wstunnel::tunnel::server\nID=0123\nREMOTE=127.0.0.1:80\n",
+        );
+        assert_eq!(journald_message(&line), expected);
+        assert_eq!(journald_field_name("_private"), "F_PRIVATE");
+        assert_eq!(journald_field_name("forwarded-for"), "FORWARDED_FOR");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_journald_stalled() {
+        // Nobody reads the other end, once its buffer is full the messages are dropped instead of blocking
+        let (socket, _journald) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let sink = LogSinkLayer {
+            transport: Transport::Journald(socket, AtomicBool::new(false)),
+            hostname: hostname(),
+        };
+        for _ in 0..10_000 {
+            sink.send(&line());
+        }
+        let Transport::Journald(_, dropping) = &sink.transport else {
+            unreachable!()
+        };
+        assert!(dropping.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,316 @@
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use url::Host;
+
+// Counters are aggregated in between, so the byte counters do not cost a packet per read
+const STATSD_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+const STATSD_PREFIX: &str = "wstunnel";
+// Fits in a datagram without fragmentation on usual networks
+const STATSD_MAX_PACKET_LEN: usize = 1432;
+// Samples beyond this, in between two flushes, are dropped
+const MAX_PENDING_LATENCIES: usize = 10_000;
+
+/// Counters shared by the client and the server, for the whole process
+pub struct Metrics {
+    pub tunnels_opened: AtomicU64,
+    pub tunnels_closed: AtomicU64,
+    /// Read from the local side and sent into the websocket
+    pub bytes_sent: AtomicU64,
+    /// Received from the websocket and written to the local side
+    pub bytes_received: AtomicU64,
+    /// Cannot reach the server, or the destination of the tunnel
+    pub connect_errors: AtomicU64,
+    /// Upgrade requests refused by the server
+    pub upgrade_rejections: AtomicU64,
+    /// Connections dropped by the server for not completing their handshake in time
+    pub handshake_timeouts: AtomicU64,
+    /// Peers of the udp servers, each one is a tunnel
+    pub udp_flows_opened: AtomicU64,
+    pub udp_flows_closed: AtomicU64,
+    /// Flows closed to make room for new ones, past --udp-max-flows
+    pub udp_flow_evictions: AtomicU64,
+    /// Datagrams of a flow that does not read them as fast as its peer sends them
+    pub udp_datagram_drops: AtomicU64,
+    /// Accepted by a reverse listener, but not taken by a tunnel in time
+    pub reverse_connection_drops: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    tunnels_opened: AtomicU64::new(0),
+    tunnels_closed: AtomicU64::new(0),
+    bytes_sent: AtomicU64::new(0),
+    bytes_received: AtomicU64::new(0),
+    connect_errors: AtomicU64::new(0),
+    upgrade_rejections: AtomicU64::new(0),
+    handshake_timeouts: AtomicU64::new(0),
+    udp_flows_opened: AtomicU64::new(0),
+    udp_flows_closed: AtomicU64::new(0),
+    udp_flow_evictions: AtomicU64::new(0),
+    udp_datagram_drops: AtomicU64::new(0),
+    reverse_connection_drops: AtomicU64::new(0),
+};
+
+impl Metrics {
+    pub fn counters(&self) -> [(&'static str, u64); 12] {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        [
+            ("tunnels.opened", get(&self.tunnels_opened)),
+            ("tunnels.closed", get(&self.tunnels_closed)),
+            ("bytes.sent", get(&self.bytes_sent)),
+            ("bytes.received", get(&self.bytes_received)),
+            ("errors.connect", get(&self.connect_errors)),
+            ("errors.upgrade_rejected", get(&self.upgrade_rejections)),
+            ("errors.handshake_timeout", get(&self.handshake_timeouts)),
+            ("udp.flows.opened", get(&self.udp_flows_opened)),
+            ("udp.flows.closed", get(&self.udp_flows_closed)),
+            ("udp.flows.evicted", get(&self.udp_flow_evictions)),
+            ("udp.datagrams.dropped", get(&self.udp_datagram_drops)),
+            ("reverse.connections.dropped", get(&self.reverse_connection_drops)),
+        ]
+    }
+}
+
+/// Increment the counter, returns its new value
+#[inline]
+pub fn incr(counter: &AtomicU64, value: u64) -> u64 {
+    counter.fetch_add(value, Ordering::Relaxed) + value
+}
+
+/// Tunnel counted as opened, and as closed once dropped
+pub struct OpenTunnel(());
+
+impl OpenTunnel {
+    pub fn new() -> Self {
+        incr(&METRICS.tunnels_opened, 1);
+        Self(())
+    }
+}
+
+impl Drop for OpenTunnel {
+    fn drop(&mut self) {
+        incr(&METRICS.tunnels_closed, 1);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Latency {
+    /// Until the connection to the destination is established
+    Connect,
+    /// Until the destination sends its first byte, once connected
+    FirstByte,
+    /// Of the websocket between the client and the server, measured with pings
+    RoundTrip,
+}
+
+impl Latency {
+    fn name(self) -> &'static str {
+        match self {
+            Latency::Connect => "destination.connect_time",
+            Latency::FirstByte => "destination.first_byte_time",
+            Latency::RoundTrip => "websocket.rtt",
+        }
+    }
+}
+
+struct LatencySample {
+    latency: Latency,
+    destination: Option<String>,
+    elapsed: Duration,
+}
+
+// Only recorded while a sink is there to consume them
+static LATENCIES_ENABLED: AtomicBool = AtomicBool::new(false);
+static LATENCIES: Lazy<Mutex<Vec<LatencySample>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn record_latency(latency: Latency, destination: Option<&str>, elapsed: Duration) {
+    if !LATENCIES_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut latencies = LATENCIES.lock();
+    if latencies.len() < MAX_PENDING_LATENCIES {
+        latencies.push(LatencySample {
+            latency,
+            destination: destination.map(str::to_string),
+            elapsed,
+        });
+    }
+}
+
+/// Reader from the destination, recording the latency of its first byte
+#[pin_project]
+pub struct FirstByte<R> {
+    #[pin]
+    inner: R,
+    pending: Option<(String, Instant)>,
+}
+
+impl<R> FirstByte<R> {
+    pub fn new(inner: R, destination: String) -> Self {
+        Self {
+            inner,
+            pending: Some((destination, Instant::now())),
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for FirstByte<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let ret = this.inner.poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            if let Some((destination, started_at)) = this.pending.take() {
+                record_latency(Latency::FirstByte, Some(&destination), started_at.elapsed());
+            }
+        }
+        ret
+    }
+}
+
+/// Counters are sent as what changed since the last flush, plus gauges of the tunnels and udp flows currently open.
+/// Latencies are sent as timers, that agents aggregate as histograms, tagged with their destination if any
+fn statsd_lines(
+    counters: &[(&'static str, u64)],
+    last: &[(&'static str, u64)],
+    latencies: &[LatencySample],
+) -> Vec<String> {
+    let mut lines: Vec<String> = counters
+        .iter()
+        .zip(last)
+        .map(|((name, value), (_, last))| format!("{}.{}:{}|c", STATSD_PREFIX, name, value.saturating_sub(*last)))
+        .collect();
+
+    let value = |name| counters.iter().find(|(n, _)| *n == name).map_or(0, |(_, v)| *v);
+    let active = value("tunnels.opened").saturating_sub(value("tunnels.closed"));
+    lines.push(format!("{}.tunnels.active:{}|g", STATSD_PREFIX, active));
+    let active = value("udp.flows.opened").saturating_sub(value("udp.flows.closed"));
+    lines.push(format!("{}.udp.flows.active:{}|g", STATSD_PREFIX, active));
+
+    lines.extend(latencies.iter().map(|sample| {
+        let line = format!(
+            "{}.{}:{:.3}|ms",
+            STATSD_PREFIX,
+            sample.latency.name(),
+            sample.elapsed.as_secs_f64() * 1000.0
+        );
+        match &sample.destination {
+            None => line,
+            Some(destination) => format!("{}|#destination:{}", line, destination),
+        }
+    }));
+    lines
+}
+
+/// Lines are packed in as few datagrams as possible
+fn statsd_packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = vec![];
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= STATSD_MAX_PACKET_LEN => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+async fn resolve(host: &Host<String>, port: u16) -> anyhow::Result<SocketAddr> {
+    match host {
+        Host::Domain(domain) => tokio::net::lookup_host((domain.as_str(), port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("cannot resolve {}", domain)),
+        Host::Ipv4(ip) => Ok(SocketAddr::new(IpAddr::V4(*ip), port)),
+        Host::Ipv6(ip) => Ok(SocketAddr::new(IpAddr::V6(*ip), port)),
+    }
+}
+
+async fn send_statsd(addr: &(Host<String>, u16), lines: &[String]) -> anyhow::Result<()> {
+    // Resolved on every flush, the agent may move
+    let addr = resolve(&addr.0, addr.1).await?;
+    let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+    let socket = UdpSocket::bind(bind).await?;
+    for packet in statsd_packets(lines) {
+        socket.send_to(packet.as_bytes(), addr).await?;
+    }
+    Ok(())
+}
+
+/// Push the metrics to a StatsD agent, Datadog agents understand it too
+pub async fn run_statsd(addr: (Host<String>, u16)) {
+    info!("Sending metrics to StatsD at {}:{}", addr.0, addr.1);
+    LATENCIES_ENABLED.store(true, Ordering::Relaxed);
+    let mut last = METRICS.counters().map(|(name, _)| (name, 0));
+    let mut flush = tokio::time::interval(STATSD_FLUSH_INTERVAL);
+    flush.tick().await;
+
+    loop {
+        flush.tick().await;
+        let counters = METRICS.counters();
+        let latencies = std::mem::take(&mut *LATENCIES.lock());
+        if let Err(err) = send_statsd(&addr, &statsd_lines(&counters, &last, &latencies)).await {
+            warn!("Cannot send metrics to StatsD at {}:{}: {:?}", addr.0, addr.1, err);
+            continue;
+        }
+        last = counters;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statsd_lines() {
+        let last = [("tunnels.opened", 2), ("tunnels.closed", 1), ("bytes.sent", 100)];
+        let counters = [("tunnels.opened", 5), ("tunnels.closed", 2), ("bytes.sent", 100)];
+        let latencies = [
+            LatencySample {
+                latency: Latency::Connect,
+                destination: Some("127.0.0.1:80".to_string()),
+                elapsed: Duration::from_micros(1500),
+            },
+            LatencySample {
+                latency: Latency::RoundTrip,
+                destination: None,
+                elapsed: Duration::from_millis(20),
+            },
+        ];
+        assert_eq!(
+            statsd_lines(&counters, &last, &latencies),
+            vec![
+                "wstunnel.tunnels.opened:3|c",
+                "wstunnel.tunnels.closed:1|c",
+                "wstunnel.bytes.sent:0|c",
+                "wstunnel.tunnels.active:3|g",
+                "wstunnel.udp.flows.active:0|g",
+                "wstunnel.destination.connect_time:1.500|ms|#destination:127.0.0.1:80",
+                "wstunnel.websocket.rtt:20.000|ms",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_statsd_packets() {
+        let lines = vec!["a".repeat(1000), "b".repeat(500), "c".repeat(100)];
+        let packets = statsd_packets(&lines);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0], lines[0]);
+        assert_eq!(packets[1], format!("{}\n{}", lines[1], lines[2]));
+    }
+}
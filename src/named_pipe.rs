@@ -0,0 +1,67 @@
+pub static NAMED_PIPE_PREFIX: &str = r"\\.\pipe\";
+
+#[inline]
+pub fn is_named_pipe(path: &str) -> bool {
+    path.starts_with(NAMED_PIPE_PREFIX)
+}
+
+#[cfg(windows)]
+mod windows {
+    use anyhow::{anyhow, Context};
+    use futures_util::{stream, Stream};
+    use std::time::Duration;
+    use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions};
+    use tokio::time::Instant;
+    use tracing::{debug, info};
+
+    // https://learn.microsoft.com/en-us/windows/win32/debug/system-error-codes--0-499-
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    pub async fn run_server(pipe_name: &str) -> anyhow::Result<impl Stream<Item = anyhow::Result<NamedPipeServer>>> {
+        info!("Starting named pipe server listening cnx on {}", pipe_name);
+
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(pipe_name)
+            .with_context(|| format!("Cannot create named pipe server {}", pipe_name))?;
+
+        let pipe_name = pipe_name.to_string();
+        let stream = stream::unfold(Some(server), move |server| {
+            let pipe_name = pipe_name.clone();
+            async move {
+                let server = server?;
+                if let Err(err) = server.connect().await {
+                    return Some((Err(anyhow!("Cannot accept named pipe client on {}: {}", pipe_name, err)), None));
+                }
+
+                // The connected instance is handed over to the tunnel, so we need a new one for the next client
+                match ServerOptions::new().create(&pipe_name) {
+                    Ok(next_server) => Some((Ok(server), Some(next_server))),
+                    Err(err) => Some((Err(anyhow!("Cannot create named pipe server {}: {}", pipe_name, err)), None)),
+                }
+            }
+        });
+
+        Ok(stream)
+    }
+
+    pub async fn connect(pipe_name: &str, connect_timeout: Duration) -> anyhow::Result<NamedPipeClient> {
+        info!("Opening named pipe connection to {}", pipe_name);
+
+        let deadline = Instant::now() + connect_timeout;
+        loop {
+            match ClientOptions::new().open(pipe_name) {
+                Ok(client) => return Ok(client),
+                Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) && Instant::now() < deadline => {
+                    debug!("Named pipe {} is busy, retrying", pipe_name);
+                }
+                Err(err) => return Err(anyhow!("Cannot connect to named pipe {}: {}", pipe_name, err)),
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+#[cfg(windows)]
+pub use windows::{connect, run_server};
@@ -0,0 +1,66 @@
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+const VERSION_2_PROXY: u8 = 0x21;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+const TLV_UNIQUE_ID: u8 = 0x05;
+const UNIQUE_ID_MAX_LEN: usize = 128;
+
+/// PROXY protocol v2 header of a tcp connection from src to dst, with the unique id TLV (truncated to 128 bytes).
+/// If only one of the addresses is ipv6, the other is sent as ipv4 mapped
+pub fn v2_header(src: SocketAddr, dst: SocketAddr, unique_id: &[u8]) -> Vec<u8> {
+    let unique_id = &unique_id[..unique_id.len().min(UNIQUE_ID_MAX_LEN)];
+    let mut addrs = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => [src_ip.octets(), dst_ip.octets()].concat(),
+        (src_ip, dst_ip) => [to_ipv6(src_ip), to_ipv6(dst_ip)].concat(),
+    };
+    let family = match addrs.len() {
+        8 => TCP_OVER_IPV4,
+        _ => TCP_OVER_IPV6,
+    };
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst.port().to_be_bytes());
+
+    let mut header = Vec::with_capacity(16 + addrs.len() + 3 + unique_id.len());
+    header.extend_from_slice(&SIGNATURE);
+    header.push(VERSION_2_PROXY);
+    header.push(family);
+    header.extend_from_slice(&((addrs.len() + 3 + unique_id.len()) as u16).to_be_bytes());
+    header.extend_from_slice(&addrs);
+    header.push(TLV_UNIQUE_ID);
+    header.extend_from_slice(&(unique_id.len() as u16).to_be_bytes());
+    header.extend_from_slice(unique_id);
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v2_header() {
+        let header = v2_header("10.0.0.1:4242".parse().unwrap(), "192.168.1.2:22".parse().unwrap(), b"id");
+        assert_eq!(&header[..12], &SIGNATURE);
+        assert_eq!(
+            &header[12..],
+            &[0x21, 0x11, 0, 17, 10, 0, 0, 1, 192, 168, 1, 2, 0x10, 0x92, 0, 22, 0x05, 0, 2, b'i', b'd']
+        );
+
+        let header = v2_header("10.0.0.1:4242".parse().unwrap(), "[::1]:22".parse().unwrap(), &[b'a'; 200]);
+        assert_eq!(header[13], TCP_OVER_IPV6);
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]) as usize, 36 + 3 + 128);
+        assert_eq!(
+            &header[16..32],
+            &"::ffff:10.0.0.1".parse::<std::net::Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(header.len(), 16 + 36 + 3 + 128);
+    }
+}
@@ -0,0 +1,83 @@
+use once_cell::sync::OnceCell;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+// Set when the accept and TLS work has a runtime of its own, the tunnels are relayed on this one
+static RELAY_RUNTIME: OnceCell<Handle> = OnceCell::new();
+
+/// Threads of the runtimes are pinned in turn to each of the cores
+#[derive(Debug)]
+pub struct CpuAffinity {
+    cores: Vec<usize>,
+    next: AtomicUsize,
+}
+
+impl CpuAffinity {
+    pub fn new(cores: Vec<usize>) -> Self {
+        Self {
+            cores,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn pin_current_thread(&self) {
+        let core = self.cores[self.next.fetch_add(1, Ordering::Relaxed) % self.cores.len()];
+        if let Err(err) = pin_current_thread(core) {
+            warn!("Cannot pin thread to cpu {}: {}", core, err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> io::Result<()> {
+    use nix::sched::{sched_setaffinity, CpuSet};
+    use nix::unistd::Pid;
+
+    let mut cpus = CpuSet::new();
+    cpus.set(core)?;
+    sched_setaffinity(Pid::from_raw(0), &cpus)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_core: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cpu affinity is only available on linux",
+    ))
+}
+
+pub fn build(name: &str, worker_threads: Option<usize>, affinity: Option<Arc<CpuAffinity>>) -> io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name(name);
+    // Otherwise the TOKIO_WORKER_THREADS env variable, or the number of cpus
+    if let Some(worker_threads) = worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    if let Some(affinity) = affinity {
+        builder.on_thread_start(move || affinity.pin_current_thread());
+    }
+    builder.build()
+}
+
+/// Relay the tunnels on this runtime, instead of the one accepting the connections
+pub fn set_relay_runtime(handle: Handle) {
+    let _ = RELAY_RUNTIME.set(handle);
+}
+
+/// Spawn the relay of a tunnel, on the runtime dedicated to them if any
+pub fn spawn_relay<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match RELAY_RUNTIME.get() {
+        Some(handle) => handle.spawn(fut),
+        None => tokio::spawn(fut),
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Context};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::SignatureScheme;
+
+use crate::tls::{self, TlsCryptoProvider};
+use crate::x509::{der, sequence};
+
+// Ed25519 keys are only 32 random bytes, so they can be made without an asn.1 or x509 library
+const ED25519_OID: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+const COMMON_NAME_OID: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const COMMON_NAME: &str = "wstunnel";
+// Fixed, so the certificate only depends on the key
+const NOT_BEFORE: &str = "240101000000Z";
+const NOT_AFTER: &str = "99991231235959Z";
+
+/// Certificate and key generated at startup, when the server has no --tls-certificate. The same seed gives the same
+/// certificate, so its fingerprint can be pinned by the clients across restarts. Without one it is random
+pub fn generate(
+    crypto_provider: TlsCryptoProvider,
+    seed: Option<&str>,
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let provider = tls::crypto_provider(crypto_provider, false)?;
+    let secret: [u8; 32] = match seed {
+        Some(seed) => Sha256::digest(seed.as_bytes()).into(),
+        None => {
+            let mut secret = [0; 32];
+            provider
+                .secure_random
+                .fill(&mut secret)
+                .map_err(|_| anyhow!("cannot generate a tls private key"))?;
+            secret
+        }
+    };
+
+    // Pkcs8 v1, without the public key
+    let key = sequence(&[
+        &der(0x02, &[0]),
+        &sequence(&[ED25519_OID]),
+        &der(0x04, &der(0x04, &secret)),
+    ]);
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key.clone_key())
+        .context("cannot load the generated tls private key")?;
+    let public_key = signing_key
+        .public_key()
+        .ok_or_else(|| anyhow!("cannot get the public key of the generated tls private key"))?;
+
+    let mut serial = Sha256::digest(public_key.as_ref())[..16].to_vec();
+    serial[0] = (serial[0] & 0x7f) | 0x01;
+    let name = sequence(&[&der(
+        0x31,
+        &sequence(&[COMMON_NAME_OID, &der(0x0c, COMMON_NAME.as_bytes())]),
+    )]);
+    let validity = sequence(&[&der(0x17, NOT_BEFORE.as_bytes()), &der(0x18, NOT_AFTER.as_bytes())]);
+    let tbs = sequence(&[
+        &der(0xa0, &der(0x02, &[2])),
+        &der(0x02, &serial),
+        &sequence(&[ED25519_OID]),
+        &name,
+        &validity,
+        &name,
+        public_key.as_ref(),
+    ]);
+
+    let signature = signing_key
+        .choose_scheme(&[SignatureScheme::ED25519])
+        .ok_or_else(|| anyhow!("the {:?} crypto provider cannot sign with ed25519", crypto_provider))?
+        .sign(&tbs)
+        .context("cannot sign the generated tls certificate")?;
+    let certificate = sequence(&[
+        &tbs,
+        &sequence(&[ED25519_OID]),
+        &der(0x03, &[&[0], signature.as_slice()].concat()),
+    ]);
+
+    Ok((vec![CertificateDer::from(certificate)], key))
+}
+
+/// Sha256 of the certificate, as printed by `openssl x509 -fingerprint -sha256`
+pub fn fingerprint(certificate: &CertificateDer<'_>) -> String {
+    Sha256::digest(certificate.as_ref())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let (certificate, _) = generate(TlsCryptoProvider::default(), Some("secret")).unwrap();
+        let (same, _) = generate(TlsCryptoProvider::default(), Some("secret")).unwrap();
+        let (random, _) = generate(TlsCryptoProvider::default(), None).unwrap();
+        assert_eq!(fingerprint(&certificate[0]), fingerprint(&same[0]));
+        assert_ne!(fingerprint(&certificate[0]), fingerprint(&random[0]));
+        assert_eq!(fingerprint(&certificate[0]).len(), 32 * 3 - 1);
+
+        // Accepted by rustls
+        let provider = tls::crypto_provider(TlsCryptoProvider::default(), false).unwrap();
+        let (certificate, key) = generate(TlsCryptoProvider::default(), None).unwrap();
+        tokio_rustls::rustls::ServerConfig::builder_with_provider(std::sync::Arc::new(provider))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certificate, key)
+            .unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use anyhow::{anyhow, Context};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+const TLS_HANDSHAKE_CONTENT_TYPE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+const TLS_SERVER_NAME_TYPE_HOSTNAME: u8 = 0x00;
+const MAX_CLIENT_HELLO_LEN: usize = 16 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// Not enough data has been received to parse the ClientHello, need at least this many bytes
+    Incomplete(usize),
+    /// A valid ClientHello, with the server name if the client provided one
+    Complete(Option<String>),
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let (val, rest) = self.buf.split_first()?;
+        self.buf = rest;
+        Some(*val)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let val = self.take(2)?;
+        Some(u16::from_be_bytes([val[0], val[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let val = self.take(3)?;
+        Some(((val[0] as usize) << 16) | ((val[1] as usize) << 8) | val[2] as usize)
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (val, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(val)
+    }
+}
+
+/// Extract the SNI of a TLS ClientHello, as sent by the client in its first tls record
+pub fn parse_client_hello(buf: &[u8]) -> anyhow::Result<ClientHello> {
+    if buf.len() < 5 {
+        return Ok(ClientHello::Incomplete(5));
+    }
+
+    let mut record = Reader { buf };
+    if record.u8() != Some(TLS_HANDSHAKE_CONTENT_TYPE) {
+        return Err(anyhow!("not a TLS handshake record"));
+    }
+    let _version = record.u16();
+    let record_len = record.u16().unwrap_or_default() as usize;
+    if record_len > MAX_CLIENT_HELLO_LEN {
+        return Err(anyhow!("TLS record too big for a ClientHello: {} bytes", record_len));
+    }
+    let Some(handshake) = record.take(record_len) else {
+        return Ok(ClientHello::Incomplete(5 + record_len));
+    };
+
+    let mut handshake = Reader { buf: handshake };
+    if handshake.u8() != Some(TLS_CLIENT_HELLO) {
+        return Err(anyhow!("TLS handshake is not a ClientHello"));
+    }
+    let malformed = || anyhow!("malformed TLS ClientHello");
+    let hello_len = handshake.u24().ok_or_else(malformed)?;
+    let hello = handshake.take(hello_len).ok_or_else(malformed)?;
+
+    let mut hello = Reader { buf: hello };
+    let _version = hello.u16().ok_or_else(malformed)?;
+    let _random = hello.take(32).ok_or_else(malformed)?;
+    let session_id_len = hello.u8().ok_or_else(malformed)? as usize;
+    hello.take(session_id_len).ok_or_else(malformed)?;
+    let cipher_suites_len = hello.u16().ok_or_else(malformed)? as usize;
+    hello.take(cipher_suites_len).ok_or_else(malformed)?;
+    let compression_len = hello.u8().ok_or_else(malformed)? as usize;
+    hello.take(compression_len).ok_or_else(malformed)?;
+
+    // No extensions at all, so no SNI
+    let Some(extensions_len) = hello.u16() else {
+        return Ok(ClientHello::Complete(None));
+    };
+    let extensions = hello.take(extensions_len as usize).ok_or_else(malformed)?;
+    let mut extensions = Reader { buf: extensions };
+    while let Some(ext_type) = extensions.u16() {
+        let ext_len = extensions.u16().ok_or_else(malformed)? as usize;
+        let ext = extensions.take(ext_len).ok_or_else(malformed)?;
+        if ext_type != TLS_EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut ext = Reader { buf: ext };
+        let list_len = ext.u16().ok_or_else(malformed)? as usize;
+        let mut names = Reader {
+            buf: ext.take(list_len).ok_or_else(malformed)?,
+        };
+        while let Some(name_type) = names.u8() {
+            let name_len = names.u16().ok_or_else(malformed)? as usize;
+            let name = names.take(name_len).ok_or_else(malformed)?;
+            if name_type == TLS_SERVER_NAME_TYPE_HOSTNAME {
+                let name = std::str::from_utf8(name).with_context(|| "invalid SNI hostname")?;
+                return Ok(ClientHello::Complete(Some(name.to_ascii_lowercase())));
+            }
+        }
+    }
+
+    Ok(ClientHello::Complete(None))
+}
+
+/// A hostname pattern is either a plain hostname or a wildcard on its leftmost label covering
+/// all its subdomains, i.e: *.alice.tunnel.example.com
+pub fn is_valid_hostname_pattern(pattern: &str) -> bool {
+    let hostname = pattern.strip_prefix("*.").unwrap_or(pattern);
+    let is_hostname = !hostname.is_empty()
+        && hostname
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+
+    // Avoid a single client being able to claim a whole tld with *.com
+    is_hostname && (hostname.len() == pattern.len() || hostname.contains('.'))
+}
+
+/// Route lookup keys for a hostname, from the most specific (the hostname itself) to the least specific wildcard
+pub fn hostname_route_candidates(hostname: &str) -> impl Iterator<Item = String> + '_ {
+    let wildcards = hostname
+        .match_indices('.')
+        .map(|(ix, _)| &hostname[ix + 1..])
+        .filter(|parent| parent.contains('.'))
+        .map(|parent| format!("*.{}", parent));
+
+    std::iter::once(hostname.to_string()).chain(wildcards)
+}
+
+/// Peek at the beginning of the stream, without consuming it, until we get the SNI of the TLS ClientHello
+pub async fn peek_sni(stream: &TcpStream, peek_timeout: Duration) -> anyhow::Result<String> {
+    let deadline = Instant::now() + peek_timeout;
+    let mut buf = vec![0u8; 1024];
+
+    loop {
+        let nb_bytes = tokio::time::timeout_at(deadline, stream.peek(&mut buf))
+            .await
+            .map_err(|_| anyhow!("timeout while waiting for TLS ClientHello"))??;
+        if nb_bytes == 0 {
+            return Err(anyhow!("connection closed before receiving TLS ClientHello"));
+        }
+
+        match parse_client_hello(&buf[..nb_bytes])? {
+            ClientHello::Complete(Some(sni)) => return Ok(sni),
+            ClientHello::Complete(None) => return Err(anyhow!("TLS ClientHello does not contain a SNI")),
+            ClientHello::Incomplete(needed) => {
+                if needed > buf.len() {
+                    buf.resize(needed, 0);
+                } else if Instant::now() < deadline {
+                    // Peek returns as soon as data is available, so give some time for the rest to arrive
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![];
+        // supported_versions extension, to check we skip unknown extensions
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(sni) = sni {
+            let name_len = sni.len() as u16;
+            extensions.extend_from_slice(&TLS_EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(name_len + 5).to_be_bytes());
+            extensions.extend_from_slice(&(name_len + 3).to_be_bytes());
+            extensions.push(TLS_SERVER_NAME_TYPE_HOSTNAME);
+            extensions.extend_from_slice(&name_len.to_be_bytes());
+            extensions.extend_from_slice(sni.as_bytes());
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.extend_from_slice(&[0x00]); // session id
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher suites
+        hello.extend_from_slice(&[0x01, 0x00]); // compression
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![TLS_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![TLS_HANDSHAKE_CONTENT_TYPE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let hello = client_hello(Some("Tunnel.Example.com"));
+        assert_eq!(
+            parse_client_hello(&hello).unwrap(),
+            ClientHello::Complete(Some("tunnel.example.com".to_string()))
+        );
+
+        let hello = client_hello(None);
+        assert_eq!(parse_client_hello(&hello).unwrap(), ClientHello::Complete(None));
+    }
+
+    #[test]
+    fn test_parse_incomplete_client_hello() {
+        let hello = client_hello(Some("tunnel.example.com"));
+        assert_eq!(parse_client_hello(&hello[..3]).unwrap(), ClientHello::Incomplete(5));
+        assert_eq!(parse_client_hello(&hello[..20]).unwrap(), ClientHello::Incomplete(hello.len()));
+    }
+
+    #[test]
+    fn test_hostname_pattern() {
+        assert!(is_valid_hostname_pattern("app.example.com"));
+        assert!(is_valid_hostname_pattern("*.alice.tunnel.example.com"));
+        assert!(is_valid_hostname_pattern("*.example.com"));
+        assert!(!is_valid_hostname_pattern("*.com"));
+        assert!(!is_valid_hostname_pattern("*"));
+        assert!(!is_valid_hostname_pattern("app.*.example.com"));
+        assert!(!is_valid_hostname_pattern("**.example.com"));
+        assert!(!is_valid_hostname_pattern("app..example.com"));
+
+        let candidates: Vec<String> = hostname_route_candidates("a.b.alice.example.com").collect();
+        assert_eq!(
+            candidates,
+            vec![
+                "a.b.alice.example.com",
+                "*.b.alice.example.com",
+                "*.alice.example.com",
+                "*.example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_not_tls() {
+        assert!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+}
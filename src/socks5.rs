@@ -1,90 +1,117 @@
-use anyhow::Context;
-use fast_socks5::server::{Config, DenyAuthentication, Socks5Server};
-use fast_socks5::util::target_addr::TargetAddr;
+use anyhow::{anyhow, Context};
+use fast_socks5::util::target_addr::{read_address, TargetAddr};
 use fast_socks5::{consts, ReplyError};
-use futures_util::{stream, Stream, StreamExt};
+use futures_util::{stream, Stream};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Poll;
-use tokio::io::AsyncWriteExt;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
 use tracing::{info, warn};
 use url::Host;
 
+use crate::tcp;
+use crate::tunnel::constant_time_eq;
+use crate::tunnel::split_tunnel::AllowFrom;
+
+/// Past this, a client that has not sent its request is disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request of a socks5 client, the CONNECT is already answered
+#[derive(Debug)]
+pub enum Socks5Request {
+    Connect((Host, u16)),
+    /// Wait for a connection from this peer (i.e: ftp active mode), the replies are left to the caller
+    Bind((Host, u16)),
+}
+
 #[allow(clippy::type_complexity)]
 pub struct Socks5Listener {
-    stream: Pin<Box<dyn Stream<Item = anyhow::Result<(TcpStream, (Host, u16))>> + Send>>,
+    stream: Pin<Box<dyn Stream<Item = anyhow::Result<(TcpStream, Socks5Request)>> + Send>>,
 }
 
 impl Stream for Socks5Listener {
-    type Item = anyhow::Result<(TcpStream, (Host, u16))>;
+    type Item = anyhow::Result<(TcpStream, Socks5Request)>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         unsafe { self.map_unchecked_mut(|x| &mut x.stream) }.poll_next(cx)
     }
 }
 
-pub async fn run_server(bind: SocketAddr) -> Result<Socks5Listener, anyhow::Error> {
+/// Clients must authenticate with one of the credentials (username, password), or without authentication if there
+/// are none. BIND requests are refused unless allow_bind is set
+pub async fn run_server(
+    bind: SocketAddr,
+    allow_bind: bool,
+    allow_from: AllowFrom,
+    credentials: &[(String, String)],
+) -> Result<Socks5Listener, anyhow::Error> {
     info!("Starting SOCKS5 server listening cnx on {}", bind);
 
-    let server = Socks5Server::<DenyAuthentication>::bind(bind)
+    let listener = tcp::bind_listener(bind)
         .await
         .with_context(|| format!("Cannot create socks5 server {:?}", bind))?;
 
-    let mut cfg = Config::<DenyAuthentication>::default();
-    cfg.set_allow_no_auth(true);
-    cfg.set_dns_resolve(false);
-    cfg.set_execute_command(false);
-
-    let server = server.with_config(cfg);
-    let stream = stream::unfold(server, move |server| async {
-        let mut acceptor = server.incoming();
+    // Each handshake runs in its own task, a client slow to send its request must not hold the accept loop
+    let (tx, rx) = mpsc::channel(128);
+    let credentials: Arc<[(String, String)]> = credentials.into();
+    tokio::spawn(async move {
         loop {
-            let cnx = match acceptor.next().await {
-                None => return None,
-                Some(Err(err)) => {
-                    drop(acceptor);
-                    return Some((Err(anyhow::Error::new(err)), server));
-                }
-                Some(Ok(cnx)) => cnx,
+            let accepted = select! {
+                accepted = listener.accept() => accepted,
+                // The listener is dropped, stop accepting
+                _ = tx.closed() => return,
             };
-
-            let cnx = match cnx.upgrade_to_socks5().await {
-                Ok(cnx) => cnx,
+            let mut cnx = match accepted {
+                Ok((cnx, peer)) if allow_from.allows(peer.ip()) => cnx,
+                Ok((_, peer)) => {
+                    warn!("Rejecting socks5 cnx from {}, not in allow_from", peer);
+                    continue;
+                }
                 Err(err) => {
-                    warn!("Rejecting socks5 cnx: {}", err);
+                    let _ = tx.send(Err(anyhow::Error::new(err))).await;
                     continue;
                 }
             };
 
-            let Some(target) = cnx.target_addr() else {
-                warn!("Rejecting socks5 cnx: no target addr");
-                continue;
-            };
-
-            let (host, port) = match target {
-                TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
-                TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
-                TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
-            };
+            let tx = tx.clone();
+            let credentials = credentials.clone();
+            tokio::spawn(async move {
+                let request = match timeout(HANDSHAKE_TIMEOUT, handshake(&mut cnx, allow_bind, &credentials)).await {
+                    Ok(Ok(request)) => request,
+                    Ok(Err(err)) => {
+                        warn!("Rejecting socks5 cnx: {}", err);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Rejecting socks5 cnx: no request after {:?}", HANDSHAKE_TIMEOUT);
+                        return;
+                    }
+                };
+
+                if let Socks5Request::Connect(_) = request {
+                    let ret = reply(
+                        &mut cnx,
+                        &ReplyError::Succeeded,
+                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+                    )
+                    .await;
+                    if let Err(err) = ret {
+                        warn!("Cannot reply to socks5 client: {}", err);
+                        return;
+                    }
+                }
 
-            let mut cnx = cnx.into_inner();
-            let ret = cnx
-                .write_all(&new_reply(
-                    &ReplyError::Succeeded,
-                    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
-                ))
-                .await;
-
-            if let Err(err) = ret {
-                warn!("Cannot reply to socks5 client: {}", err);
-                continue;
-            }
-
-            drop(acceptor);
-            return Some((Ok((cnx, (host, port))), server));
+                let _ = tx.send(Ok((cnx, request))).await;
+            });
         }
     });
+    let stream = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|cnx| (cnx, rx)) });
 
     let listener = Socks5Listener {
         stream: Box::pin(stream),
@@ -93,6 +120,89 @@ pub async fn run_server(bind: SocketAddr) -> Result<Socks5Listener, anyhow::Erro
     Ok(listener)
 }
 
+async fn handshake(
+    cnx: &mut TcpStream,
+    allow_bind: bool,
+    credentials: &[(String, String)],
+) -> anyhow::Result<Socks5Request> {
+    let [version, nb_methods] = [cnx.read_u8().await?, cnx.read_u8().await?];
+    if version != consts::SOCKS5_VERSION {
+        return Err(anyhow!("unsupported socks version {}", version));
+    }
+    let mut methods = vec![0; nb_methods as usize];
+    cnx.read_exact(&mut methods).await?;
+    let method = match credentials.is_empty() {
+        true => consts::SOCKS5_AUTH_METHOD_NONE,
+        false => consts::SOCKS5_AUTH_METHOD_PASSWORD,
+    };
+    if !methods.contains(&method) {
+        cnx.write_all(&[consts::SOCKS5_VERSION, consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE])
+            .await?;
+        return Err(anyhow!("no supported authentication method in {:?}", methods));
+    }
+    cnx.write_all(&[consts::SOCKS5_VERSION, method]).await?;
+    if !credentials.is_empty() {
+        authenticate(cnx, credentials).await?;
+    }
+
+    let mut request = [0; 4];
+    cnx.read_exact(&mut request).await?;
+    let [version, cmd, _, addr_type] = request;
+    if version != consts::SOCKS5_VERSION {
+        return Err(anyhow!("unsupported socks version {}", version));
+    }
+    let target = match read_address(cnx, addr_type).await {
+        Ok(target) => target,
+        Err(err) => {
+            reply(cnx, &ReplyError::AddressTypeNotSupported, unspecified_addr()).await?;
+            return Err(err);
+        }
+    };
+    let target = match target {
+        TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
+        TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
+        TargetAddr::Domain(host, port) => (Host::Domain(host), port),
+    };
+
+    match cmd {
+        consts::SOCKS5_CMD_TCP_CONNECT => Ok(Socks5Request::Connect(target)),
+        consts::SOCKS5_CMD_TCP_BIND if allow_bind => Ok(Socks5Request::Bind(target)),
+        _ => {
+            reply(cnx, &ReplyError::CommandNotSupported, unspecified_addr()).await?;
+            Err(anyhow!("unsupported command {}", cmd))
+        }
+    }
+}
+
+/// Username/password authentication, RFC 1929
+async fn authenticate(cnx: &mut TcpStream, credentials: &[(String, String)]) -> anyhow::Result<()> {
+    let version = cnx.read_u8().await?;
+    if version != 1 {
+        return Err(anyhow!("unsupported username/password authentication version {}", version));
+    }
+    let mut username = vec![0; cnx.read_u8().await? as usize];
+    cnx.read_exact(&mut username).await?;
+    let mut password = vec![0; cnx.read_u8().await? as usize];
+    cnx.read_exact(&mut password).await?;
+
+    let valid = credentials.iter().any(|(user, pass)| {
+        constant_time_eq(user.as_bytes(), &username) & constant_time_eq(pass.as_bytes(), &password)
+    });
+    cnx.write_all(&[1, if valid { 0 } else { 1 }]).await?;
+    if !valid {
+        return Err(anyhow!("bad credentials for user {:?}", String::from_utf8_lossy(&username)));
+    }
+    Ok(())
+}
+
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+}
+
+pub async fn reply(cnx: &mut TcpStream, error: &ReplyError, sock_addr: SocketAddr) -> std::io::Result<()> {
+    cnx.write_all(&new_reply(error, sock_addr)).await
+}
+
 fn new_reply(error: &ReplyError, sock_addr: SocketAddr) -> Vec<u8> {
     let (addr_type, mut ip_oct, mut port) = match sock_addr {
         SocketAddr::V4(sock) => (
@@ -2,6 +2,11 @@
 pub mod server {
 
     use tokio_fd::AsyncFd;
+
+    use crate::tcp::LocalWrite;
+
+    impl LocalWrite for AsyncFd {}
+
     pub async fn run_server() -> Result<(AsyncFd, AsyncFd), anyhow::Error> {
         eprintln!("Starting STDIO server");
 
@@ -17,12 +22,16 @@ pub mod server {
     use bytes::BytesMut;
     use std::io::{Read, Write};
     use std::{io, thread};
-    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+    use tokio::io::{AsyncRead, AsyncReadExt, DuplexStream};
     use tokio::task::LocalSet;
     use tokio_stream::wrappers::UnboundedReceiverStream;
     use tokio_util::io::StreamReader;
 
-    pub async fn run_server() -> Result<(impl AsyncRead, impl AsyncWrite), anyhow::Error> {
+    use crate::tcp::LocalWrite;
+
+    impl LocalWrite for DuplexStream {}
+
+    pub async fn run_server() -> Result<(impl AsyncRead, impl LocalWrite), anyhow::Error> {
         eprintln!("Starting STDIO server");
 
         crossterm::terminal::enable_raw_mode()?;
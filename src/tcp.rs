@@ -1,13 +1,20 @@
 use anyhow::{anyhow, Context};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 use std::{io, vec};
 
 use crate::dns::DnsResolver;
 use base64::Engine;
 use bytes::BytesMut;
 use log::warn;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use once_cell::sync::OnceCell;
+use socket2::{SockRef, TcpKeepalive};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::time::timeout;
 use tokio_stream::wrappers::TcpListenerStream;
@@ -15,57 +22,235 @@ use tracing::debug;
 use tracing::log::info;
 use url::{Host, Url};
 
-fn configure_socket(socket: &mut TcpSocket, so_mark: &Option<u32>) -> Result<(), anyhow::Error> {
+const DEFAULT_BACKLOG: u32 = 1024;
+
+/// Tuning of tcp sockets, the defaults of the system are kept for the unset ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOpts {
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<Duration>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    /// Only for listeners
+    pub backlog: Option<u32>,
+}
+
+impl SocketOpts {
+    /// Comma separated list of nodelay=BOOL, keepalive=SECONDS, recv_buffer=BYTES, send_buffer=BYTES, backlog=INT
+    pub fn parse(opts: &str) -> anyhow::Result<Self> {
+        let mut ret = Self::default();
+        for opt in opts.split(',').map(str::trim).filter(|opt| !opt.is_empty()) {
+            let (name, value) = opt
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid socket option {}, expected NAME=VALUE", opt))?;
+            let invalid = || anyhow!("invalid value for socket option {}: {}", name, value);
+            match name {
+                "nodelay" => ret.nodelay = Some(value.parse().map_err(|_| invalid())?),
+                "keepalive" => ret.keepalive = Some(Duration::from_secs(value.parse().map_err(|_| invalid())?)),
+                "recv_buffer" => ret.recv_buffer_size = Some(value.parse().map_err(|_| invalid())?),
+                "send_buffer" => ret.send_buffer_size = Some(value.parse().map_err(|_| invalid())?),
+                "backlog" => ret.backlog = Some(value.parse().ok().filter(|b| *b > 0).ok_or_else(invalid)?),
+                _ => return Err(anyhow!("unknown socket option {}", name)),
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn apply(&self, socket: SockRef) -> anyhow::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay).context("cannot set TCP_NODELAY")?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            socket
+                .set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))
+                .context("cannot set SO_KEEPALIVE")?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size).context("cannot set SO_RCVBUF")?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size).context("cannot set SO_SNDBUF")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the connections leave the host from, for policy routing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Egress {
+    /// (linux only) SO_MARK of the packets
+    pub so_mark: Option<u32>,
+    /// (linux only) Bound to this interface, i.e: the one of a vpn
+    pub interface: Option<String>,
+    /// Bound to this local ip, only the destinations of the same family can be reached
+    pub source: Option<IpAddr>,
+}
+
+impl Egress {
+    pub fn with_so_mark(so_mark: Option<u32>) -> Self {
+        Self {
+            so_mark,
+            ..Default::default()
+        }
+    }
+
+    /// Comma separated list of interface=NAME, so_mark=INT, source=IP
+    pub fn parse(opts: &str) -> anyhow::Result<Self> {
+        let mut ret = Self::default();
+        for opt in opts.split(',').map(str::trim).filter(|opt| !opt.is_empty()) {
+            let (name, value) = opt
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid egress option {}, expected NAME=VALUE", opt))?;
+            let invalid = || anyhow!("invalid value for egress option {}: {}", name, value);
+            match name {
+                "interface" if !value.is_empty() => ret.interface = Some(value.to_string()),
+                "so_mark" => ret.so_mark = Some(value.parse().map_err(|_| invalid())?),
+                "source" => ret.source = Some(value.parse().map_err(|_| invalid())?),
+                "interface" => return Err(invalid()),
+                _ => return Err(anyhow!("unknown egress option {}", name)),
+            }
+        }
+        if ret == Self::default() {
+            return Err(anyhow!("empty egress options {}", opts));
+        }
+        Ok(ret)
+    }
+
+    /// If the destination can be reached from the source ip
+    pub fn reaches(&self, addr: &SocketAddr) -> bool {
+        self.source.is_none_or(|source| source.is_ipv4() == addr.is_ipv4())
+    }
+
+    /// Before the socket is connected
+    pub fn apply(&self, socket: SockRef) -> anyhow::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(so_mark) = self.so_mark {
+            socket.set_mark(so_mark).context("cannot set SO_MARK")?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = &self.interface {
+            socket
+                .bind_device(Some(interface.as_bytes()))
+                .with_context(|| format!("cannot bind to interface {}", interface))?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.interface.is_some() {
+            return Err(anyhow!("Binding to an interface is only available on linux"));
+        }
+        if let Some(source) = self.source {
+            socket
+                .bind(&SocketAddr::new(source, 0).into())
+                .with_context(|| format!("cannot bind to source ip {}", source))?;
+        }
+        Ok(())
+    }
+}
+
+static LISTENER_OPTS: OnceCell<SocketOpts> = OnceCell::new();
+
+/// Options of the sockets of the local listeners, accepted connections inherit them
+pub fn set_listener_opts(opts: SocketOpts) {
+    let _ = LISTENER_OPTS.set(opts);
+}
+
+/// A listener with the options of `set_listener_opts`
+pub async fn bind_listener(bind: SocketAddr) -> Result<TcpListener, anyhow::Error> {
+    let opts = match LISTENER_OPTS.get() {
+        Some(opts) if *opts != SocketOpts::default() => opts,
+        _ => return Ok(TcpListener::bind(bind).await?),
+    };
+
+    let socket = if bind.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Like the listeners of TcpListener::bind
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    opts.apply(SockRef::from(&socket))?;
+    socket.bind(bind)?;
+    Ok(socket.listen(opts.backlog.unwrap_or(DEFAULT_BACKLOG))?)
+}
+
+fn configure_socket(socket: &mut TcpSocket, egress: &Egress) -> Result<(), anyhow::Error> {
     socket
         .set_nodelay(true)
         .with_context(|| format!("cannot set no_delay on socket: {}", io::Error::last_os_error()))?;
 
+    egress.apply(SockRef::from(&*socket))
+}
+
+/// A multipath tcp socket if asked and the kernel supports it, plain tcp otherwise. Linux only, the peer falls
+/// back to plain tcp by itself if it does not support it
+fn new_socket(addr: &SocketAddr, mptcp: bool) -> io::Result<TcpSocket> {
     #[cfg(target_os = "linux")]
-    if let Some(so_mark) = so_mark {
-        use std::os::fd::AsFd;
+    if mptcp {
+        use socket2::{Domain, Protocol, Socket, Type};
 
-        let ret = nix::sys::socket::setsockopt(&socket.as_fd(), nix::sys::socket::sockopt::Mark, so_mark);
-        if let Err(err) = ret {
-            return Err(anyhow!(
-                "Cannot set SO_MARK on the connection {:?} {:?}",
-                err,
-                io::Error::last_os_error()
-            ));
+        match Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::MPTCP)) {
+            Ok(socket) => {
+                socket.set_nonblocking(true)?;
+                return Ok(TcpSocket::from_std_stream(socket.into()));
+            }
+            Err(err) => warn!("Cannot create a multipath tcp socket, using plain tcp. reason {err}"),
         }
     }
+    #[cfg(not(target_os = "linux"))]
+    if mptcp {
+        warn!("Multipath tcp is only available on linux, using plain tcp");
+    }
 
-    Ok(())
+    match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
 }
 
-pub async fn connect(
+pub async fn resolve(
     host: &Host<String>,
     port: u16,
-    so_mark: Option<u32>,
-    connect_timeout: Duration,
     dns_resolver: &DnsResolver,
-) -> Result<TcpStream, anyhow::Error> {
-    info!("Opening TCP connection to {}:{}", host, port);
-
-    let socket_addrs: Vec<SocketAddr> = match host {
+) -> Result<Vec<SocketAddr>, anyhow::Error> {
+    Ok(match host {
         Host::Domain(domain) => dns_resolver
             .lookup_host(domain.as_str(), port)
             .await
             .with_context(|| format!("cannot resolve domain: {}", domain))?,
         Host::Ipv4(ip) => vec![SocketAddr::V4(SocketAddrV4::new(*ip, port))],
         Host::Ipv6(ip) => vec![SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, 0))],
-    };
+    })
+}
+
+pub async fn connect(
+    host: &Host<String>,
+    port: u16,
+    so_mark: Option<u32>,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+) -> Result<TcpStream, anyhow::Error> {
+    let socket_addrs = resolve(host, port, dns_resolver).await?;
+    connect_addrs(host, port, socket_addrs, &Egress::with_so_mark(so_mark), connect_timeout, false).await
+}
+
+/// Connect to the first of the addresses of the host that accepts the connection
+pub async fn connect_addrs(
+    host: &Host<String>,
+    port: u16,
+    socket_addrs: Vec<SocketAddr>,
+    egress: &Egress,
+    connect_timeout: Duration,
+    mptcp: bool,
+) -> Result<TcpStream, anyhow::Error> {
+    info!("Opening TCP connection to {}:{}", host, port);
 
     let mut cnx = None;
     let mut last_err = None;
-    for addr in socket_addrs {
+    for addr in socket_addrs.into_iter().filter(|addr| egress.reaches(addr)) {
         debug!("connecting to {}", addr);
 
-        let mut socket = match &addr {
-            SocketAddr::V4(_) => TcpSocket::new_v4()?,
-            SocketAddr::V6(_) => TcpSocket::new_v6()?,
-        };
-
-        configure_socket(&mut socket, &so_mark)?;
+        let mut socket = new_socket(&addr, mptcp)?;
+        configure_socket(&mut socket, egress)?;
         match timeout(connect_timeout, socket.connect(addr)).await {
             Ok(Ok(stream)) => {
                 cnx = Some(stream);
@@ -102,11 +287,21 @@ pub async fn connect_with_http_proxy(
     port: u16,
     so_mark: Option<u32>,
     connect_timeout: Duration,
+    mptcp: bool,
 ) -> Result<TcpStream, anyhow::Error> {
     let proxy_host = proxy.host().context("Cannot parse proxy host")?.to_owned();
     let proxy_port = proxy.port_or_known_default().unwrap_or(80);
 
-    let mut socket = connect(&proxy_host, proxy_port, so_mark, connect_timeout, &DnsResolver::System).await?;
+    let proxy_addrs = resolve(&proxy_host, proxy_port, &DnsResolver::System).await?;
+    let mut socket = connect_addrs(
+        &proxy_host,
+        proxy_port,
+        proxy_addrs,
+        &Egress::with_so_mark(so_mark),
+        connect_timeout,
+        mptcp,
+    )
+    .await?;
     info!("Connected to http proxy {}:{}", proxy_host, proxy_port);
 
     let authorization = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
@@ -169,19 +364,146 @@ pub async fn connect_with_http_proxy(
 pub async fn run_server(bind: SocketAddr, ip_transparent: bool) -> Result<TcpListenerStream, anyhow::Error> {
     info!("Starting TCP server listening cnx on {}", bind);
 
-    let listener = TcpListener::bind(bind)
+    let listener = bind_listener(bind)
         .await
         .with_context(|| format!("Cannot create TCP server {:?}", bind))?;
 
     #[cfg(target_os = "linux")]
     if ip_transparent {
         info!("TCP server listening in TProxy mode");
-        socket2::SockRef::from(&listener).set_ip_transparent(ip_transparent)?;
+        SockRef::from(&listener).set_ip_transparent(ip_transparent)?;
     }
 
     Ok(TcpListenerStream::new(listener))
 }
 
+/// Listener of the server. With reuse_port, another process can listen on the same address, so a new server can take
+/// over without downtime
+pub fn bind_server(bind: SocketAddr, reuse_port: bool, mptcp: bool) -> Result<TcpListener, anyhow::Error> {
+    if reuse_port && cfg!(not(unix)) {
+        return Err(anyhow!("Reusing the listening port is only available on unix"));
+    }
+
+    let socket = new_socket(&bind, mptcp)?;
+    // Like the listeners of TcpListener::bind
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(reuse_port)?;
+    socket
+        .bind(bind)
+        .with_context(|| format!("Cannot bind TCP server {:?}", bind))?;
+
+    Ok(socket.listen(DEFAULT_BACKLOG)?)
+}
+
+/// Resets the connection of an `AbortableWriteHalf` when it is dropped, instead of closing it gracefully
+#[derive(Debug, Clone, Default)]
+pub struct Abort(Arc<AtomicBool>);
+
+impl Abort {
+    pub fn abort(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Write half of a tcp connection, that can be reset through its `Abort` handle
+pub struct AbortableWriteHalf {
+    // Only taken when dropped
+    inner: Option<OwnedWriteHalf>,
+    abort: Abort,
+}
+
+pub fn abortable_split(stream: TcpStream) -> (OwnedReadHalf, AbortableWriteHalf) {
+    let (rx, tx) = stream.into_split();
+    (
+        rx,
+        AbortableWriteHalf {
+            inner: Some(tx),
+            abort: Abort::default(),
+        },
+    )
+}
+
+/// Write half of the local connection of a tunnel
+pub trait LocalWrite: AsyncWrite {
+    /// Handle to reset the connection, for the transports that can be reset instead of only closed
+    fn abort_handle(&self) -> Option<Abort> {
+        None
+    }
+}
+
+impl LocalWrite for AbortableWriteHalf {
+    fn abort_handle(&self) -> Option<Abort> {
+        Some(self.abort.clone())
+    }
+}
+
+impl<T: AsyncWrite> LocalWrite for tokio::io::WriteHalf<T> {}
+
+/// Local connection of a tunnel, split into its halves to be relayed
+pub trait LocalStream: AsyncRead + AsyncWrite + Send + Sized + 'static {
+    /// Tcp connections with `abortable_split`, so they come with their `Abort` handle
+    #[allow(clippy::type_complexity)]
+    fn split_local(self) -> (Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>, Option<Abort>) {
+        let (rx, tx) = tokio::io::split(self);
+        (Box::pin(rx), Box::pin(tx), None)
+    }
+}
+
+impl LocalStream for TcpStream {
+    fn split_local(self) -> (Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>, Option<Abort>) {
+        let (rx, tx) = abortable_split(self);
+        let abort = tx.abort_handle();
+        (Box::pin(rx), Box::pin(tx), abort)
+    }
+}
+
+impl AbortableWriteHalf {
+    fn inner(&mut self) -> Pin<&mut OwnedWriteHalf> {
+        Pin::new(self.inner.as_mut().expect("write half used after drop"))
+    }
+}
+
+impl Drop for AbortableWriteHalf {
+    fn drop(&mut self) {
+        let Some(inner) = self.inner.take() else {
+            return;
+        };
+        if self.abort.0.load(Ordering::Relaxed) {
+            // Without a FIN first, the reset is sent once the read half is dropped too and the socket closed
+            let _ = SockRef::from(inner.as_ref()).set_linger(Some(Duration::ZERO));
+            inner.forget();
+        }
+    }
+}
+
+impl AsyncWrite for AbortableWriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().inner().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner().poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().inner().poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -232,6 +554,7 @@ mod tests {
             1236,
             None,
             Duration::from_secs(1),
+            false,
         )
         .await
         .unwrap();
@@ -252,4 +575,39 @@ mod tests {
         let _ = client.read(&mut buf).await.unwrap();
         assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n\r\n"));
     }
+
+    #[test]
+    fn test_socket_opts() {
+        let opts = SocketOpts::parse("nodelay=false, keepalive=30,recv_buffer=262144,backlog=4096").unwrap();
+        assert_eq!(opts.nodelay, Some(false));
+        assert_eq!(opts.keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(opts.recv_buffer_size, Some(262144));
+        assert_eq!(opts.send_buffer_size, None);
+        assert_eq!(opts.backlog, Some(4096));
+        assert_eq!(SocketOpts::parse("").unwrap(), SocketOpts::default());
+
+        assert!(SocketOpts::parse("nodelay").is_err());
+        assert!(SocketOpts::parse("nodelay=yes").is_err());
+        assert!(SocketOpts::parse("backlog=0").is_err());
+        assert!(SocketOpts::parse("linger=1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_abortable_split() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut buf = [0u8; 1];
+
+        let (rx, tx) = abortable_split(TcpStream::connect(addr).await.unwrap());
+        let mut peer = server.accept().await.unwrap().0;
+        drop((rx, tx));
+        assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+
+        let (rx, tx) = abortable_split(TcpStream::connect(addr).await.unwrap());
+        let mut peer = server.accept().await.unwrap().0;
+        tx.abort_handle().unwrap().abort();
+        drop((rx, tx));
+        let err = peer.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
 }
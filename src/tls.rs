@@ -1,36 +1,204 @@
-use crate::{TlsClientConfig, TlsServerConfig, WsClientConfig};
+use crate::{x509, TlsClientConfig, TlsServerConfig, WsClientConfig};
 use anyhow::{anyhow, Context};
+use std::collections::HashMap;
 use std::fs::File;
 
 use log::warn;
+use once_cell::sync::{Lazy, OnceCell};
+use sha2::{Digest, Sha256};
 use std::io::BufReader;
 use std::path::Path;
 use std::sync::Arc;
-use std::time::SystemTime;
 use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
-use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::client::{ClientSessionMemoryCache, Resumption};
 
-use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, ServerName};
-use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::crypto::{CryptoProvider, SupportedKxGroup};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::{ProducesTickets, WebPkiClientVerifier};
+use tokio_rustls::rustls::{CertificateError, ClientConfig, DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::{rustls, LazyConfigAcceptor, TlsAcceptor, TlsConnector};
 use tracing::info;
 
-struct NullVerifier;
+// The connector is re-created for every new connection to the server, so sessions must be stored outside of it
+// in order to be resumed by the next connections
+static CLIENT_SESSION_CACHE: Lazy<Arc<ClientSessionMemoryCache>> =
+    Lazy::new(|| Arc::new(ClientSessionMemoryCache::new(256)));
+
+// Same for the server, the acceptor is re-created when the certificate is reloaded, and we want tickets emitted
+// before that to still be valid
+static SERVER_TICKETER: OnceCell<Arc<dyn ProducesTickets>> = OnceCell::new();
+
+#[derive(Debug)]
+struct NullVerifier(Arc<CryptoProvider>);
 impl ServerCertVerifier for NullVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &Certificate,
-        _intermediates: &[Certificate],
-        _server_name: &ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Accepts the certificate with this sha256, whoever signed it, i.e: the self-signed one of the server
+#[derive(Debug)]
+struct PinnedVerifier {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
         _ocsp_response: &[u8],
-        _now: SystemTime,
+        _now: UnixTime,
     ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() != self.fingerprint {
+            return Err(rustls::Error::General(
+                "the certificate of the server does not match --tls-certificate-fingerprint".to_string(),
+            ));
+        }
         Ok(ServerCertVerified::assertion())
     }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Certificate chain with its private key
+pub type CertifiedKeyDer = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// Crypto library used by rustls, which ones are available depends on the features wstunnel is built with
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlsCryptoProvider {
+    AwsLcRs,
+    /// aws-lc-rs restricted to FIPS approved algorithms, requires the `fips` feature
+    AwsLcRsFips,
+    Ring,
+}
+
+impl Default for TlsCryptoProvider {
+    fn default() -> Self {
+        if cfg!(feature = "aws-lc-rs") {
+            TlsCryptoProvider::AwsLcRs
+        } else {
+            TlsCryptoProvider::Ring
+        }
+    }
+}
+
+impl TlsCryptoProvider {
+    pub fn is_fips(&self) -> bool {
+        *self == TlsCryptoProvider::AwsLcRsFips
+    }
+
+    fn ticketer(&self) -> Result<Arc<dyn ProducesTickets>, rustls::Error> {
+        match self {
+            #[cfg(feature = "aws-lc-rs")]
+            TlsCryptoProvider::AwsLcRs | TlsCryptoProvider::AwsLcRsFips => rustls::crypto::aws_lc_rs::Ticketer::new(),
+            #[cfg(feature = "ring")]
+            TlsCryptoProvider::Ring => rustls::crypto::ring::Ticketer::new(),
+            #[allow(unreachable_patterns)]
+            _ => Err(rustls::Error::General(format!(
+                "{:?} crypto provider is not available in this build",
+                self
+            ))),
+        }
+    }
+}
+
+pub fn crypto_provider(kind: TlsCryptoProvider, post_quantum: bool) -> anyhow::Result<CryptoProvider> {
+    let (mut provider, post_quantum_kx): (CryptoProvider, Option<&'static dyn SupportedKxGroup>) = match kind {
+        // With the fips feature, aws-lc-rs is backed by the FIPS validated module
+        #[cfg(feature = "aws-lc-rs")]
+        TlsCryptoProvider::AwsLcRs | TlsCryptoProvider::AwsLcRsFips => (
+            rustls::crypto::aws_lc_rs::default_provider(),
+            Some(rustls::crypto::aws_lc_rs::kx_group::X25519MLKEM768),
+        ),
+        #[cfg(feature = "ring")]
+        TlsCryptoProvider::Ring => (rustls::crypto::ring::default_provider(), None),
+        #[allow(unreachable_patterns)]
+        _ => return Err(anyhow!("{:?} crypto provider is not available in this build of wstunnel", kind)),
+    };
+
+    // Hybrid X25519 + ML-KEM (the standardized Kyber) key exchange goes first, so its key share is sent directly
+    // in the ClientHello. Peers not supporting it fallback to classic key exchange.
+    provider
+        .kx_groups
+        .retain(|kx| kx.name() != rustls::NamedGroup::X25519MLKEM768);
+    if post_quantum {
+        let Some(post_quantum_kx) = post_quantum_kx else {
+            return Err(anyhow!(
+                "post-quantum key exchange is not supported by the {:?} crypto provider",
+                kind
+            ));
+        };
+        provider.kx_groups.insert(0, post_quantum_kx);
+    }
+
+    if kind.is_fips() {
+        provider.kx_groups.retain(|kx| kx.fips());
+        provider.cipher_suites.retain(|suite| suite.fips());
+        if !provider.fips() {
+            return Err(anyhow!(
+                "crypto provider is not running in FIPS mode, wstunnel must be built with the fips feature"
+            ));
+        }
+    }
+
+    Ok(provider)
 }
 
-pub fn load_certificates_from_pem(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+pub fn load_certificates_from_pem(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
     info!("Loading tls certificate from {:?}", path);
 
     let file = File::open(path)?;
@@ -40,7 +208,7 @@ pub fn load_certificates_from_pem(path: &Path) -> anyhow::Result<Vec<Certificate
     Ok(certs
         .into_iter()
         .filter_map(|cert| match cert {
-            Ok(cert) => Some(Certificate(cert.to_vec())),
+            Ok(cert) => Some(cert),
             Err(err) => {
                 warn!("Error while parsing tls certificate: {:?}", err);
                 None
@@ -49,7 +217,7 @@ pub fn load_certificates_from_pem(path: &Path) -> anyhow::Result<Vec<Certificate
         .collect())
 }
 
-pub fn load_private_key_from_file(path: &Path) -> anyhow::Result<PrivateKey> {
+pub fn load_private_key_from_file(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
     info!("Loading tls private key from {:?}", path);
 
     let file = File::open(path)?;
@@ -59,7 +227,7 @@ pub fn load_private_key_from_file(path: &Path) -> anyhow::Result<PrivateKey> {
         return Err(anyhow!("No private key found in {path:?}"));
     };
 
-    Ok(PrivateKey(private_key.secret_der().to_vec()))
+    Ok(private_key)
 }
 
 fn tls_connector(tls_cfg: &TlsClientConfig, alpn_protocols: Option<Vec<Vec<u8>>>) -> anyhow::Result<TlsConnector> {
@@ -68,37 +236,249 @@ fn tls_connector(tls_cfg: &TlsClientConfig, alpn_protocols: Option<Vec<Vec<u8>>>
     // Load system certificates and add them to the root store
     let certs = rustls_native_certs::load_native_certs().with_context(|| "Cannot load system certificates")?;
     for cert in certs {
-        root_store.add(&Certificate(cert.as_ref().to_vec()))?;
+        root_store.add(cert)?;
     }
 
-    let mut config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    let provider = Arc::new(crypto_provider(tls_cfg.tls_crypto_provider, tls_cfg.tls_post_quantum)?);
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store);
+    // To authenticate to servers requiring a client certificate (mTLS)
+    let mut config = match &tls_cfg.tls_certificate {
+        Some(certificate) => builder
+            .with_client_auth_cert(certificate.0.clone(), certificate.1.clone_key())
+            .with_context(|| "invalid tls client certificate or private key")?,
+        None => builder.with_no_client_auth(),
+    };
 
     // To bypass certificate verification
-    if !tls_cfg.tls_verify_certificate {
-        config.dangerous().set_certificate_verifier(Arc::new(NullVerifier));
+    if let Some(fingerprint) = tls_cfg.tls_certificate_fingerprint {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedVerifier { fingerprint, provider }));
+    } else if !tls_cfg.tls_verify_certificate {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NullVerifier(provider)));
+    }
+
+    if tls_cfg.tls_crypto_provider.is_fips() && !config.fips() {
+        return Err(anyhow!("tls client configuration is not FIPS compliant"));
     }
 
     if let Some(alpn_protocols) = alpn_protocols {
         config.alpn_protocols = alpn_protocols;
     }
-    let tls_connector = TlsConnector::from(Arc::new(config));
+
+    config.resumption = Resumption::store(CLIENT_SESSION_CACHE.clone());
+    config.enable_early_data = tls_cfg.tls_early_data;
+    let tls_connector = TlsConnector::from(Arc::new(config)).early_data(tls_cfg.tls_early_data);
     Ok(tls_connector)
 }
 
+/// Connector to any https server (i.e: webhooks), verified against the system certificates
+pub fn https_connector(tls_crypto_provider: TlsCryptoProvider) -> anyhow::Result<TlsConnector> {
+    let tls_cfg = TlsClientConfig {
+        tls_sni_override: None,
+        tls_verify_certificate: true,
+        tls_certificate_fingerprint: None,
+        tls_certificate: None,
+        tls_early_data: false,
+        tls_post_quantum: false,
+        tls_crypto_provider,
+    };
+    tls_connector(&tls_cfg, Some(vec![b"http/1.1".to_vec()]))
+}
+
 pub fn tls_acceptor(tls_cfg: &TlsServerConfig, alpn_protocols: Option<Vec<Vec<u8>>>) -> anyhow::Result<TlsAcceptor> {
-    let mut config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(tls_cfg.tls_certificate.lock().clone(), tls_cfg.tls_key.lock().clone())
+    let config = server_config(
+        tls_cfg.tls_crypto_provider,
+        tls_cfg.tls_post_quantum,
+        tls_cfg.tls_certificate.lock().clone(),
+        tls_cfg.tls_key.lock().clone_key(),
+        tls_cfg.tls_ocsp_response.lock().clone(),
+        &tls_cfg.tls_client_ca_certificates,
+        alpn_protocols,
+    )?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn server_config(
+    kind: TlsCryptoProvider,
+    post_quantum: bool,
+    certificate: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    ocsp_response: Vec<u8>,
+    client_ca_certificates: &[CertificateDer<'static>],
+    alpn_protocols: Option<Vec<Vec<u8>>>,
+) -> anyhow::Result<rustls::ServerConfig> {
+    let provider = Arc::new(crypto_provider(kind, post_quantum)?);
+    let builder =
+        rustls::ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+    // mTLS, only the clients with a certificate issued by one of these are accepted
+    let builder = match client_ca_certificates {
+        [] => builder.with_no_client_auth(),
+        certificates => {
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in certificates {
+                roots.add(certificate.clone())?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .with_context(|| "invalid tls client ca certificates")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+    let mut config = builder
+        .with_single_cert_with_ocsp(certificate, key, ocsp_response)
         .with_context(|| "invalid tls certificate or private key")?;
 
+    if kind.is_fips() && !config.fips() {
+        return Err(anyhow!("tls server configuration is not FIPS compliant"));
+    }
+
     if let Some(alpn_protocols) = alpn_protocols {
         config.alpn_protocols = alpn_protocols;
     }
-    Ok(TlsAcceptor::from(Arc::new(config)))
+
+    // Stateless session tickets, to allow clients to resume their sessions
+    // Early data is not accepted, as the http upgrade request of a tunnel could be replayed
+    config.ticketer = SERVER_TICKETER
+        .get_or_try_init(|| kind.ticketer())
+        .with_context(|| "cannot create tls session ticketer")?
+        .clone();
+    Ok(config)
+}
+
+/// The private key must be the one of the first certificate of the chain
+pub fn check_key_pair(
+    chain: &[CertificateDer<'static>],
+    key: &PrivateKeyDer<'static>,
+    kind: TlsCryptoProvider,
+) -> anyhow::Result<()> {
+    let provider = crypto_provider(kind, false)?;
+    let signer = provider.key_provider.load_private_key(key.clone_key())?;
+    match rustls::sign::CertifiedKey::new(chain.to_vec(), signer).keys_match() {
+        // The public key of the private key cannot always be known
+        Ok(()) | Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::Unknown)) => Ok(()),
+        Err(err) => Err(anyhow!("the private key does not match the certificate: {}", err)),
+    }
+}
+
+/// Warn about the mistakes in the certificate chain that browsers do not forgive, i.e: a missing intermediate
+/// certificate. wstunnel clients do not verify the certificate by default, so they would not notice
+pub fn check_certificate_chain(chain: &[CertificateDer<'static>], kind: TlsCryptoProvider) {
+    let certificates = match chain
+        .iter()
+        .map(|cert| x509::Certificate::parse(cert))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(certificates) => certificates,
+        Err(err) => return warn!("Cannot parse the tls certificate chain: {:?}", err),
+    };
+    let Some(leaf) = certificates.first() else {
+        return warn!("The tls certificate file holds no certificate");
+    };
+    for (i, pair) in certificates.windows(2).enumerate() {
+        if pair[0].issuer != pair[1].subject {
+            warn!(
+                "Certificate {} of the tls certificate chain is not the issuer of certificate {}, the chain must go from the certificate to its root",
+                i + 1,
+                i
+            );
+        }
+    }
+    // Self-signed, nothing to verify it against
+    if certificates.len() == 1 && leaf.issuer == leaf.subject {
+        return;
+    }
+
+    let verify = || -> anyhow::Result<Result<(), rustls::Error>> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs()? {
+            let _ = roots.add(cert);
+        }
+        let provider = Arc::new(crypto_provider(kind, false)?);
+        let verifier =
+            rustls::client::WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+        // The hostnames of the certificate are not known, only the chain is checked
+        let ret = verifier.verify_server_cert(
+            &chain[0],
+            &chain[1..],
+            &ServerName::try_from("wstunnel.invalid")?,
+            &[],
+            UnixTime::now(),
+        );
+        Ok(ret.map(|_| ()))
+    };
+    match verify() {
+        Ok(Ok(())) => {}
+        Ok(Err(rustls::Error::InvalidCertificate(err))) => match err {
+            CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => {}
+            CertificateError::UnknownIssuer => match certificates.last() {
+                Some(root) if root.issuer == root.subject => {
+                    warn!("The root of the tls certificate chain is not trusted by this system")
+                }
+                _ => warn!(
+                    "The tls certificate chain does not lead to a trusted root, its intermediate certificates are probably missing from the file"
+                ),
+            },
+            CertificateError::Expired | CertificateError::ExpiredContext { .. } => {
+                warn!("The tls certificate chain holds an expired certificate")
+            }
+            CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => {
+                warn!("The tls certificate chain holds a certificate not valid yet")
+            }
+            err => warn!("The tls certificate chain is invalid: {:?}", err),
+        },
+        Ok(Err(err)) => warn!("The tls certificate chain is invalid: {:?}", err),
+        Err(err) => warn!("Cannot verify the tls certificate chain: {:?}", err),
+    }
+}
+
+/// Certificates the server terminates the tls of the visitors of reverse tcp tunnels with, picked by the SNI of the
+/// visitor. The certificate of the server is used for the other hostnames
+#[derive(Default)]
+pub struct ReverseTls {
+    pub crypto_provider: TlsCryptoProvider,
+    pub certificates: HashMap<String, CertifiedKeyDer>,
+}
+
+impl ReverseTls {
+    /// Of the hostname, or of its wildcard, i.e: *.example.com for www.example.com
+    fn certificate(&self, hostname: &str) -> Option<&CertifiedKeyDer> {
+        let hostname = hostname.to_ascii_lowercase();
+        self.certificates.get(&hostname).or_else(|| {
+            let (_, parent) = hostname.split_once('.')?;
+            self.certificates.get(&format!("*.{}", parent))
+        })
+    }
+
+    /// Terminate the tls of a visitor, the plain stream is relayed through the tunnel
+    pub async fn accept(
+        &self,
+        server_tls: Option<&TlsServerConfig>,
+        stream: TcpStream,
+    ) -> anyhow::Result<tokio_rustls::server::TlsStream<TcpStream>> {
+        let handshake = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream).await?;
+        let sni = handshake.client_hello().server_name().map(|sni| sni.to_string());
+        let (certificate, key, ocsp_response, post_quantum) =
+            match (sni.as_deref().and_then(|sni| self.certificate(sni)), server_tls) {
+                (Some((certificate, key)), _) => (certificate.clone(), key.clone_key(), vec![], false),
+                (None, Some(tls)) => (
+                    tls.tls_certificate.lock().clone(),
+                    tls.tls_key.lock().clone_key(),
+                    tls.tls_ocsp_response.lock().clone(),
+                    tls.tls_post_quantum,
+                ),
+                (None, None) => {
+                    return Err(anyhow!("no tls certificate for the hostname {:?}", sni.unwrap_or_default()))
+                }
+            };
+        let crypto_provider = server_tls.map_or(self.crypto_provider, |tls| tls.tls_crypto_provider);
+        let config = server_config(crypto_provider, post_quantum, certificate, key, ocsp_response, &[], None)?;
+        Ok(handshake.into_stream(Arc::new(config)).await?)
+    }
 }
 
 pub async fn connect(
@@ -114,7 +494,7 @@ pub async fn connect(
 
     let tls_connector = tls_connector(tls_cfg, Some(vec![b"http/1.1".to_vec()]))?;
     let tls_stream = tls_connector
-        .connect(sni, tcp_stream)
+        .connect(sni.to_owned(), tcp_stream)
         .await
         .with_context(|| format!("failed to do TLS handshake with the server {:?}", client_cfg.remote_addr))?;
 
@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::{cursor, execute, queue, terminal};
+use http_body_util::{BodyExt, Empty};
+use hyper::header::HOST;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use url::Url;
+
+use crate::tunnel::Stats;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+// Samples kept for the sparklines, the oldest ones are dropped once they no longer fit
+const HISTORY_LEN: usize = 240;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+async fn fetch_stats(url: &Url) -> anyhow::Result<Stats> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("no host in {}", url))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let req = Request::get(url.join("stats")?.path())
+        .header(HOST, format!("{}:{}", url.host_str().unwrap_or_default(), port))
+        .body(Empty::<bytes::Bytes>::new())?;
+    let response = sender.send_request(req).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("the admin api answered with status {}", response.status()));
+    }
+    let body = response.into_body().collect().await?.to_bytes();
+    serde_json::from_slice(&body).context("invalid stats")
+}
+
+/// Counters of the successive fetches, to show their rates
+#[derive(Default)]
+struct History {
+    last: Option<(Instant, BTreeMap<String, u64>)>,
+    rates: BTreeMap<String, VecDeque<u64>>,
+}
+
+impl History {
+    fn push(&mut self, now: Instant, counters: BTreeMap<String, u64>) {
+        if let Some((last_at, last)) = &self.last {
+            let elapsed = now.duration_since(*last_at).as_secs_f64().max(0.001);
+            for (name, value) in &counters {
+                let delta = value.saturating_sub(last.get(name).copied().unwrap_or(0));
+                let rates = self.rates.entry(name.clone()).or_default();
+                rates.push_back((delta as f64 / elapsed) as u64);
+                if rates.len() > HISTORY_LEN {
+                    rates.pop_front();
+                }
+            }
+        }
+        self.last = Some((now, counters));
+    }
+
+    fn rates(&self, name: &str) -> Vec<u64> {
+        self.rates
+            .get(name)
+            .map(|rates| rates.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn counter(&self, name: &str) -> u64 {
+        self.last
+            .as_ref()
+            .and_then(|(_, counters)| counters.get(name).copied())
+            .unwrap_or(0)
+    }
+}
+
+/// The last width values, scaled to the biggest one
+fn sparkline(values: &[u64], width: usize) -> String {
+    let values = &values[values.len().saturating_sub(width)..];
+    let max = values.iter().copied().max().unwrap_or(0);
+    values
+        .iter()
+        .map(|value| match max {
+            0 => SPARKS[0],
+            max => SPARKS[((value * (SPARKS.len() as u64 - 1)) / max) as usize],
+        })
+        .collect()
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn render(url: &Url, stats: &Result<Stats, String>, history: &History, (cols, rows): (u16, u16)) -> Vec<String> {
+    let spark_width = (cols as usize).saturating_sub(44).max(10);
+    let mut lines = vec![format!("wstunnel top - {}  (q to quit)", url), String::new()];
+
+    for (label, name) in [("sent", "bytes.sent"), ("received", "bytes.received")] {
+        let rates = history.rates(name);
+        lines.push(format!(
+            "{:<9} {:>10}/s  {:<width$}  total {}",
+            label,
+            format_bytes(rates.last().copied().unwrap_or(0)),
+            sparkline(&rates, spark_width),
+            format_bytes(history.counter(name)),
+            width = spark_width
+        ));
+    }
+    let opened = history.rates("tunnels.opened");
+    lines.push(format!(
+        "{:<9} {:>10}/s  {:<width$}  total {}",
+        "tunnels",
+        opened.last().copied().unwrap_or(0),
+        sparkline(&opened, spark_width),
+        history.counter("tunnels.opened"),
+        width = spark_width
+    ));
+    lines.push(String::new());
+
+    lines.push(format!(
+        "errors    connect {}  upgrade rejected {}  handshake timeout {}",
+        history.counter("errors.connect"),
+        history.counter("errors.upgrade_rejected"),
+        history.counter("errors.handshake_timeout"),
+    ));
+    lines.push(format!(
+        "udp       flows open {}  evicted {}",
+        history
+            .counter("udp.flows.opened")
+            .saturating_sub(history.counter("udp.flows.closed")),
+        history.counter("udp.flows.evicted"),
+    ));
+    lines.push(format!(
+        "reverse   connections dropped {}",
+        history.counter("reverse.connections.dropped"),
+    ));
+    lines.push(String::new());
+
+    match stats {
+        Err(err) => lines.push(format!("Cannot fetch the stats: {}", err)),
+        Ok(stats) => {
+            lines.push(format!("{} tunnels open", stats.tunnels.len()));
+            lines.push(format!("{:>6}  {:>8}  DESTINATION", "ID", "OPEN FOR"));
+            let mut tunnels: Vec<_> = stats.tunnels.iter().collect();
+            tunnels.sort_by_key(|tunnel| tunnel.id);
+            let room = (rows as usize).saturating_sub(lines.len() + 1);
+            for tunnel in tunnels.iter().take(room) {
+                lines.push(format!(
+                    "{:>6}  {:>8}  {}",
+                    tunnel.id,
+                    format_duration(tunnel.open_for_sec),
+                    tunnel.destination
+                ));
+            }
+            if tunnels.len() > room {
+                lines.push(format!("... and {} more", tunnels.len() - room));
+            }
+        }
+    }
+
+    lines
+        .into_iter()
+        .take(rows as usize)
+        .map(|line| line.chars().take(cols as usize).collect())
+        .collect()
+}
+
+fn quit_requested() -> io::Result<bool> {
+    while event::poll(Duration::ZERO)? {
+        if let Event::Key(KeyEvent { code, modifiers, .. }) = event::read()? {
+            match code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return Ok(true),
+                _ => {}
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Poll the stats of the admin api of a client or a server, and show them until q is pressed
+pub async fn run(url: Url, interval: Duration) -> anyhow::Result<()> {
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+    let _guard = scopeguard::guard((), |_| {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    });
+
+    let mut history = History::default();
+    let mut next_fetch = Instant::now();
+    loop {
+        if Instant::now() >= next_fetch {
+            next_fetch += interval;
+            let stats = match tokio::time::timeout(FETCH_TIMEOUT, fetch_stats(&url)).await {
+                Ok(Ok(stats)) => Ok(stats),
+                Ok(Err(err)) => Err(format!("{:#}", err)),
+                Err(_) => Err("timeout".to_string()),
+            };
+            if let Ok(stats) = &stats {
+                history.push(Instant::now(), stats.counters.clone());
+            }
+
+            queue!(stdout, cursor::MoveTo(0, 0), terminal::Clear(terminal::ClearType::All))?;
+            for line in render(&url, &stats, &history, terminal::size()?) {
+                write!(stdout, "{}\r\n", line)?;
+            }
+            stdout.flush()?;
+        }
+
+        if quit_requested()? {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0, 1, 2, 4, 8], 10), "▁▁▂▄█");
+        assert_eq!(sparkline(&[0, 0], 10), "▁▁");
+        // Only the most recent values fit
+        assert_eq!(sparkline(&[8, 8, 0, 4], 2), "▁█");
+
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_duration(65), "1m05s");
+        assert_eq!(format_duration(7260), "2h01m");
+    }
+
+    #[test]
+    fn test_history() {
+        let now = Instant::now();
+        let mut history = History::default();
+        history.push(now, BTreeMap::from([("bytes.sent".to_string(), 100)]));
+        assert!(history.rates("bytes.sent").is_empty());
+        history.push(now + Duration::from_secs(2), BTreeMap::from([("bytes.sent".to_string(), 300)]));
+        assert_eq!(history.rates("bytes.sent"), [100]);
+        assert_eq!(history.counter("bytes.sent"), 300);
+    }
+}
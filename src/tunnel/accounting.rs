@@ -0,0 +1,313 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use ahash::{HashMap, HashMapExt};
+use anyhow::anyhow;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes each client can relay during the lifetime of the process, sent and received together. By the identity of
+/// its certificate, or else the subject of its token
+#[derive(Debug, Default)]
+pub struct ByteQuotas {
+    /// Quota of the clients without their own one
+    pub default: Option<u64>,
+    pub subjects: HashMap<String, u64>,
+    /// The tokens are signed with a secret of the server (--jwt-secret), their subject can account the clients
+    /// without a certificate. With the default secret, anyone can sign a token with any subject
+    pub verified_tokens: bool,
+}
+
+// Accounts without a quota, nor a tunnel open for that long, are dropped with their records
+const IDLE_TIMEOUT_SECS: u64 = 24 * 3600;
+const EVICTION_INTERVAL_SECS: u64 = 60;
+
+static QUOTAS: OnceCell<ByteQuotas> = OnceCell::new();
+static ACCOUNTS: Lazy<Mutex<HashMap<String, Arc<Account>>>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+static LAST_EVICTION: AtomicU64 = AtomicU64::new(0);
+
+/// Count the bytes relayed for each client, and enforce the quotas
+pub fn enable_accounting(quotas: ByteQuotas) {
+    let _ = QUOTAS.set(quotas);
+}
+
+pub(super) struct Account {
+    sent: AtomicU64,
+    received: AtomicU64,
+    tunnels: AtomicU64,
+    quota: Option<u64>,
+    first_seen: u64,
+    last_opened: AtomicU64,
+}
+
+impl Account {
+    fn is_exhausted(&self) -> bool {
+        self.quota
+            .is_some_and(|quota| self.sent.load(Ordering::Relaxed) + self.received.load(Ordering::Relaxed) >= quota)
+    }
+}
+
+/// Account of the client for a new tunnel, refused once its quota is exhausted. By the identity of its certificate,
+/// or else the subject of its token, the id of the tunnel without one. None if accounting is not enabled, or for a
+/// client without a certificate whose token is not signed with --jwt-secret
+pub(super) fn open(identity: Option<&str>, token_subject: &str) -> anyhow::Result<Option<Arc<Account>>> {
+    let Some(quotas) = QUOTAS.get() else {
+        return Ok(None);
+    };
+    let Some(subject) = account_subject(quotas, identity, token_subject) else {
+        return Ok(None);
+    };
+
+    let now = jsonwebtoken::get_current_timestamp();
+    let mut accounts = ACCOUNTS.lock();
+    if LAST_EVICTION.load(Ordering::Relaxed) + EVICTION_INTERVAL_SECS <= now {
+        LAST_EVICTION.store(now, Ordering::Relaxed);
+        evict_idle(&mut accounts, now);
+    }
+    let account = accounts
+        .entry(subject.to_string())
+        .or_insert_with(|| {
+            Arc::new(Account {
+                sent: AtomicU64::new(0),
+                received: AtomicU64::new(0),
+                tunnels: AtomicU64::new(0),
+                quota: quotas.subjects.get(subject).copied().or(quotas.default),
+                first_seen: now,
+                last_opened: AtomicU64::new(now),
+            })
+        })
+        .clone();
+    drop(accounts);
+
+    account.last_opened.store(now, Ordering::Relaxed);
+    if account.is_exhausted() {
+        return Err(anyhow!("byte quota of {} is exhausted", subject));
+    }
+    account.tunnels.fetch_add(1, Ordering::Relaxed);
+    Ok(Some(account))
+}
+
+fn account_subject<'a>(quotas: &ByteQuotas, identity: Option<&'a str>, token_subject: &'a str) -> Option<&'a str> {
+    identity.or(Some(token_subject).filter(|_| quotas.verified_tokens))
+}
+
+/// The map holds the only reference to the accounts without an open tunnel. Those with a quota are kept, not to
+/// reset it
+fn evict_idle(accounts: &mut HashMap<String, Arc<Account>>, now: u64) {
+    accounts.retain(|_, account| {
+        account.quota.is_some()
+            || Arc::strong_count(account) > 1
+            || account.last_opened.load(Ordering::Relaxed) + IDLE_TIMEOUT_SECS > now
+    });
+}
+
+/// Count what is read from and written to the local streams of the tunnel. Once the quota is exhausted,
+/// they fail on their next read or write, which closes the tunnel
+pub(super) fn meter<R, W>(account: Option<Arc<Account>>, local_rx: R, local_tx: W) -> (Metered<R>, Metered<W>) {
+    (
+        Metered {
+            inner: local_rx,
+            account: account.clone(),
+        },
+        Metered {
+            inner: local_tx,
+            account,
+        },
+    )
+}
+
+#[pin_project]
+pub(super) struct Metered<S> {
+    #[pin]
+    inner: S,
+    account: Option<Arc<Account>>,
+}
+
+fn quota_exhausted() -> io::Error {
+    io::Error::other("byte quota exhausted")
+}
+
+impl<R: AsyncRead> AsyncRead for Metered<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let Some(account) = this.account else {
+            return this.inner.poll_read(cx, buf);
+        };
+        if account.is_exhausted() {
+            return Poll::Ready(Err(quota_exhausted()));
+        }
+
+        let filled = buf.filled().len();
+        let ret = this.inner.poll_read(cx, buf);
+        account
+            .sent
+            .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        ret
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Metered<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let Some(account) = this.account else {
+            return this.inner.poll_write(cx, buf);
+        };
+        if account.is_exhausted() {
+            return Poll::Ready(Err(quota_exhausted()));
+        }
+
+        let ret = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &ret {
+            account.received.fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        ret
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Accounting of a client, with the timestamps of its first and last tunnels. Bytes are counted as the events, see
+/// `Event`
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(super) struct Record {
+    subject: String,
+    bytes_sent: u64,
+    bytes_received: u64,
+    tunnels: u64,
+    quota_bytes: Option<u64>,
+    first_seen: u64,
+    last_opened: u64,
+}
+
+pub(super) fn records() -> Vec<Record> {
+    let mut records: Vec<_> = ACCOUNTS
+        .lock()
+        .iter()
+        .map(|(subject, account)| Record {
+            subject: subject.clone(),
+            bytes_sent: account.sent.load(Ordering::Relaxed),
+            bytes_received: account.received.load(Ordering::Relaxed),
+            tunnels: account.tunnels.load(Ordering::Relaxed),
+            quota_bytes: account.quota,
+            first_seen: account.first_seen,
+            last_opened: account.last_opened.load(Ordering::Relaxed),
+        })
+        .collect();
+    records.sort_by(|a, b| a.subject.cmp(&b.subject));
+    records
+}
+
+pub(super) fn to_csv(records: &[Record]) -> String {
+    let mut csv = "subject,bytes_sent,bytes_received,tunnels,quota_bytes,first_seen,last_opened\n".to_string();
+    for record in records {
+        let subject = match record.subject.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", record.subject.replace('"', "\"\"")),
+            false => record.subject.clone(),
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            subject,
+            record.bytes_sent,
+            record.bytes_received,
+            record.tunnels,
+            record.quota_bytes.map(|quota| quota.to_string()).unwrap_or_default(),
+            record.first_seen,
+            record.last_opened
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(quota: Option<u64>, last_opened: u64) -> Arc<Account> {
+        Arc::new(Account {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            tunnels: AtomicU64::new(1),
+            quota,
+            first_seen: last_opened,
+            last_opened: AtomicU64::new(last_opened),
+        })
+    }
+
+    #[test]
+    fn test_account_subject() {
+        let mut quotas = ByteQuotas::default();
+        assert_eq!(
+            account_subject(&quotas, Some("alice@example.com"), "bob"),
+            Some("alice@example.com")
+        );
+        assert_eq!(account_subject(&quotas, None, "bob"), None);
+        quotas.verified_tokens = true;
+        assert_eq!(
+            account_subject(&quotas, Some("alice@example.com"), "bob"),
+            Some("alice@example.com")
+        );
+        assert_eq!(account_subject(&quotas, None, "bob"), Some("bob"));
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let now = 1700000000 + IDLE_TIMEOUT_SECS;
+        let mut accounts = HashMap::new();
+        accounts.insert("idle".to_string(), account(None, 1700000000));
+        accounts.insert("recent".to_string(), account(None, now - 60));
+        accounts.insert("quota".to_string(), account(Some(1024), 1700000000));
+        let open = account(None, 1700000000);
+        accounts.insert("open".to_string(), open.clone());
+
+        evict_idle(&mut accounts, now);
+        let mut kept: Vec<_> = accounts.keys().map(String::as_str).collect();
+        kept.sort();
+        assert_eq!(kept, ["open", "quota", "recent"]);
+
+        drop(open);
+        evict_idle(&mut accounts, now);
+        assert!(!accounts.contains_key("open"));
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let records = [
+            Record {
+                subject: "alice".to_string(),
+                bytes_sent: 10,
+                bytes_received: 20,
+                tunnels: 2,
+                quota_bytes: Some(1024),
+                first_seen: 1700000000,
+                last_opened: 1700000060,
+            },
+            Record {
+                subject: "bob, \"the builder\"".to_string(),
+                bytes_sent: 0,
+                bytes_received: 0,
+                tunnels: 1,
+                quota_bytes: None,
+                first_seen: 1700000000,
+                last_opened: 1700000000,
+            },
+        ];
+        assert_eq!(
+            to_csv(&records),
+            "subject,bytes_sent,bytes_received,tunnels,quota_bytes,first_seen,last_opened\n\
+             alice,10,20,2,1024,1700000000,1700000060\n\
+             \"bob, \"\"the builder\"\"\",0,0,1,,1700000000,1700000000\n"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use futures_util::{pin_mut, StreamExt};
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{http, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tracing::{span, warn, Instrument, Level};
+
+use super::{accounting, listeners, registry};
+use crate::metrics::METRICS;
+use crate::{health, tcp, udp};
+
+/// Counters of the process and its open tunnels, polled by `wstunnel top`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub counters: BTreeMap<String, u64>,
+    pub tunnels: Vec<TunnelStats>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TunnelStats {
+    pub id: usize,
+    pub destination: String,
+    pub open_for_sec: u64,
+}
+
+fn stats() -> Stats {
+    Stats {
+        counters: METRICS
+            .counters()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+        tunnels: registry::snapshot()
+            .into_iter()
+            .map(|(id, tunnel)| TunnelStats {
+                id,
+                destination: tunnel.destination,
+                open_for_sec: tunnel.opened_at.elapsed().as_secs(),
+            })
+            .collect(),
+    }
+}
+
+/// Read only http api to inspect the state of the server, or of the client.
+/// It is not authenticated, bind it to a private address
+pub async fn run_admin_server(bind: SocketAddr) -> anyhow::Result<()> {
+    let listener = tcp::run_server(bind, false).await?;
+    pin_mut!(listener);
+
+    while let Some(cnx) = listener.next().await {
+        let stream = match cnx {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Error while accepting admin connection {:?}", err);
+                continue;
+            }
+        };
+
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        let conn_fut = http1::Builder::new().serve_connection(
+            TokioIo::new(stream),
+            service_fn(|req| async move { Ok::<_, hyper::Error>(handle_request(req)) }),
+        );
+        let fut = async move {
+            if let Err(err) = conn_fut.await {
+                warn!("Error while serving admin connection: {:?}", err);
+            }
+        }
+        .instrument(span!(Level::INFO, "admin", peer = peer));
+
+        tokio::spawn(fut);
+    }
+
+    Ok(())
+}
+
+fn handle_request(req: Request<Incoming>) -> Response<String> {
+    if req.method() != Method::GET {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let (content_type, body) = match req.uri().path() {
+        "/live" => ("text/plain", Ok("OK".to_string())),
+        "/ready" => return ready_response(),
+        "/stats" => ("application/json", serde_json::to_string_pretty(&stats())),
+        "/reverse-listeners" => ("application/json", serde_json::to_string_pretty(&listeners::snapshot())),
+        "/udp-flows" => ("application/json", serde_json::to_string_pretty(&udp::flows_snapshot())),
+        "/accounting" => ("application/json", serde_json::to_string_pretty(&accounting::records())),
+        "/accounting.csv" => ("text/csv", Ok(accounting::to_csv(&accounting::records()))),
+        _ => return status_response(StatusCode::NOT_FOUND),
+    };
+    match body {
+        Ok(body) => http::Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap(),
+        Err(_) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// 503 with the reasons, one per line, while the server cannot open new tunnels
+fn ready_response() -> Response<String> {
+    let reasons = health::not_ready_reasons(registry::count());
+    if reasons.is_empty() {
+        return status_response(StatusCode::OK);
+    }
+    http::Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(reasons.join("\n") + "\n")
+        .unwrap()
+}
+
+fn status_response(status: StatusCode) -> Response<String> {
+    http::Response::builder()
+        .status(status)
+        .body(status.canonical_reason().unwrap_or_default().to_string())
+        .unwrap()
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use once_cell::sync::OnceCell;
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+use super::rate_limit::RateLimiter;
+
+/// Bytes a destination can send before being held to the ratio, for the first answers of a handshake
+const GRACE: u64 = 4 * 1024;
+
+pub(super) struct Limits {
+    max_ratio: Option<f64>,
+    destinations: Option<RateLimiter<SocketAddr>>,
+}
+
+impl Limits {
+    fn new(max_ratio: Option<f64>, max_packets_per_sec: Option<u32>) -> Self {
+        Self {
+            max_ratio,
+            destinations: max_packets_per_sec.map(|rate| RateLimiter::new(rate, rate)),
+        }
+    }
+}
+
+static LIMITS: OnceCell<Arc<Limits>> = OnceCell::new();
+
+/// Limits of the udp tunnels of the server, so it cannot be used to reflect or amplify traffic. A destination
+/// cannot send back more than max_ratio times the bytes it received, plus a few KB, and cannot be sent more than
+/// max_packets_per_sec datagrams by all the tunnels together. Datagrams past the limits are dropped
+pub fn set_udp_anti_amplification(max_ratio: Option<f64>, max_packets_per_sec: Option<u32>) {
+    let _ = LIMITS.set(Arc::new(Limits::new(max_ratio, max_packets_per_sec)));
+}
+
+/// The limits given to `set_udp_anti_amplification`, shared by all the tunnels
+pub(super) fn limits() -> Option<Arc<Limits>> {
+    LIMITS.get().cloned()
+}
+
+/// Udp socket connected to the destination of a tunnel, held to the limits if any
+#[pin_project]
+pub(super) struct Guarded<S> {
+    #[pin]
+    inner: S,
+    dest: SocketAddr,
+    limits: Option<Arc<Limits>>,
+    sent: u64,
+    received: u64,
+    // The datagram being written already took its token
+    acquired: bool,
+    // Warned once per tunnel only
+    ratio_exceeded: bool,
+    rate_exceeded: bool,
+}
+
+impl<S> Guarded<S> {
+    pub fn new(inner: S, dest: SocketAddr, limits: Option<Arc<Limits>>) -> Self {
+        Self {
+            inner,
+            dest,
+            limits,
+            sent: 0,
+            received: 0,
+            acquired: false,
+            ratio_exceeded: false,
+            rate_exceeded: false,
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Guarded<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        let Some(max_ratio) = this.limits.as_ref().and_then(|limits| limits.max_ratio) else {
+            return this.inner.poll_read(cx, buf);
+        };
+
+        loop {
+            let filled = buf.filled().len();
+            ready!(this.inner.as_mut().poll_read(cx, buf))?;
+            let len = (buf.filled().len() - filled) as u64;
+            if len == 0 || *this.received + len <= GRACE + (*this.sent as f64 * max_ratio) as u64 {
+                *this.received += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            if !*this.ratio_exceeded {
+                warn!(
+                    "Dropping the datagrams of {} above {} times the bytes sent to it",
+                    this.dest, max_ratio
+                );
+                *this.ratio_exceeded = true;
+            }
+            buf.set_filled(filled);
+        }
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Guarded<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let limiter = this.limits.as_ref().and_then(|limits| limits.destinations.as_ref());
+        if !*this.acquired && limiter.is_some_and(|limiter| !limiter.try_acquire(*this.dest)) {
+            if !*this.rate_exceeded {
+                warn!("Dropping the datagrams to {}, too many are sent to it", this.dest);
+                *this.rate_exceeded = true;
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        *this.acquired = true;
+        let written = ready!(this.inner.poll_write(cx, buf));
+        *this.acquired = false;
+        let written = written?;
+        *this.sent += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_guarded() {
+        let limits = Arc::new(Limits::new(Some(2.0), Some(2)));
+        let (guarded, mut dest) = tokio::io::duplex(64 * 1024);
+        let mut guarded = Guarded::new(guarded, "10.0.0.1:53".parse().unwrap(), Some(limits));
+        let mut buf = vec![0; 64 * 1024];
+
+        // Past its rate, the datagrams to the destination are dropped
+        guarded.write_all(&[1; 100]).await.unwrap();
+        guarded.write_all(&[2; 10]).await.unwrap();
+        guarded.write_all(&[3; 10]).await.unwrap();
+        assert_eq!(dest.read(&mut buf).await.unwrap(), 110);
+        assert!(tokio::time::timeout(Duration::from_millis(10), dest.read(&mut buf))
+            .await
+            .is_err());
+
+        // And its answers past the ratio
+        dest.write_all(&vec![4; GRACE as usize + 220]).await.unwrap();
+        assert_eq!(guarded.read(&mut buf).await.unwrap(), GRACE as usize + 220);
+        dest.write_all(&[5]).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(10), guarded.read(&mut buf))
+            .await
+            .is_err());
+    }
+}
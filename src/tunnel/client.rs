@@ -1,79 +1,366 @@
-use super::{to_host_port, JwtTunnelConfig, JWT_HEADER_PREFIX, JWT_KEY};
-use crate::{LocalToRemote, WsClientConfig};
+use super::control_frame::ControlMessage;
+use super::error_code::{ErrorCode, ERROR_CODE_HEADER};
+use super::events;
+use super::failover::ServerTunnel;
+use super::io::{StreamEnd, COMPRESSION_HEADER, HALF_CLOSE_HEADER, RESET_HEADER};
+use super::protocol_version;
+use super::ready::{self, READY_ACK_HEADER};
+use super::rtt;
+use super::session::{JwtSessionResume, PumpEnd, ResumableSession, SESSION_RX_HEADER};
+use super::split_tunnel::{self, Route};
+use super::{registry, reliable_udp, to_host_port, JwtTunnelConfig, JWT_HEADER_PREFIX, REQUEST_ID_HEADER};
+use crate::dns::DnsResolver;
+use crate::encryption::{self, PayloadCiphers, ENCRYPTION_HEADER};
+use crate::metrics::{self, FirstByte, Latency, METRICS};
+use crate::tcp::{Abort, LocalStream, LocalWrite};
+use crate::{socks5, tcp};
+use crate::{Compression, LocalProtocol, LocalToRemote, Socks5Bind, WsClientConfig};
 use anyhow::{anyhow, Context};
 
 use base64::Engine;
 use bytes::Bytes;
-use fastwebsockets::WebSocket;
+use fast_socks5::ReplyError;
+use fastwebsockets::{Role, WebSocket, WebSocketError};
 use futures_util::pin_mut;
 use http_body_util::Empty;
 use hyper::body::Incoming;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, HOST, SEC_WEBSOCKET_KEY};
 use hyper::header::{AUTHORIZATION, COOKIE, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
-use hyper::header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY};
 use hyper::upgrade::Upgraded;
-use hyper::{Request, Response};
-use hyper_util::rt::{TokioExecutor, TokioIo};
-use std::future::Future;
-use std::ops::{Deref, DerefMut};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::future::{pending, Future};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::DerefMut;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::select;
 use tokio::sync::oneshot;
+use tokio::time::Instant;
 use tokio_stream::{Stream, StreamExt};
 use tracing::log::debug;
-use tracing::{error, span, Instrument, Level, Span};
+use tracing::{error, info, span, warn, Instrument, Level, Span};
 use url::{Host, Url};
 use uuid::Uuid;
 
-fn tunnel_to_jwt_token(request_id: Uuid, tunnel: &LocalToRemote) -> String {
-    let cfg = JwtTunnelConfig::new(request_id, tunnel);
-    let (alg, secret) = JWT_KEY.deref();
-    jsonwebtoken::encode(alg, &cfg, secret).unwrap_or_default()
+const RESUME_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REVERSE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Local destination that reverse tunnels are allowed to connect to, None matches any host or port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedTarget {
+    pub host: Option<Host<String>>,
+    pub port: Option<u16>,
+}
+
+impl AllowedTarget {
+    fn matches(&self, (host, port): &(Host, u16)) -> bool {
+        // Hosts of tunnels are parsed as opaque, an ip can be a Host::Domain, so compare them as written
+        self.host
+            .as_ref()
+            .is_none_or(|h| h.to_string().eq_ignore_ascii_case(&host.to_string()))
+            && self.port.is_none_or(|p| p == *port)
+    }
+}
+
+fn is_allowed_target(client_cfg: &WsClientConfig, target: &(Host, u16)) -> bool {
+    client_cfg
+        .remote_to_local_allow
+        .as_ref()
+        .is_none_or(|allowed| allowed.iter().any(|a| a.matches(target)))
+}
+
+fn tunnel_to_jwt_token(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    tunnel: &LocalToRemote,
+    resume: Option<JwtSessionResume>,
+) -> String {
+    let mut cfg = JwtTunnelConfig::new(request_id, client_cfg, tunnel);
+    cfg.s = resume;
+    let secret = &client_cfg.jwt_secret;
+    jsonwebtoken::encode(&secret.header(), &cfg, &secret.encoding_key).unwrap_or_default()
+}
+
+fn upgrade_path_suffix(client_cfg: &WsClientConfig) -> String {
+    match &client_cfg.http_upgrade_path_secret {
+        None => "events".to_string(),
+        Some(secret) => secret.generate_segment(jsonwebtoken::get_current_timestamp()),
+    }
+}
+
+/// Invalid lines are skipped, so a bad edit of the file does not prevent the client from reconnecting
+async fn headers_from_file(path: &Path) -> Vec<(HeaderName, HeaderValue)> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(err) => {
+            warn!("Cannot read http headers file {:?}: {}", path, err);
+            return vec![];
+        }
+    };
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match crate::parse_http_headers(line) {
+            Ok(header) => Some(header),
+            Err(err) => {
+                warn!("Ignoring invalid line in http headers file {:?}: {}", path, err);
+                None
+            }
+        })
+        // The host header is set from the -H options or the server address
+        .filter(|(k, _)| k != HOST)
+        .collect()
 }
 
 pub async fn connect(
     request_id: Uuid,
     client_cfg: &WsClientConfig,
     tunnel_cfg: &LocalToRemote,
-) -> anyhow::Result<(WebSocket<TokioIo<Upgraded>>, Response<Incoming>)> {
-    let mut pooled_cnx = match client_cfg.cnx_pool().get().await {
-        Ok(tcp_stream) => tcp_stream,
-        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}"))?,
+    resume: Option<JwtSessionResume>,
+) -> anyhow::Result<(
+    WebSocket<TokioIo<Upgraded>>,
+    Response<Incoming>,
+    Option<PayloadCiphers>,
+    ServerTunnel,
+)> {
+    let jwt = tunnel_to_jwt_token(request_id, client_cfg, tunnel_cfg, resume);
+    let file_headers = match &client_cfg.http_headers_file {
+        Some(path) => headers_from_file(path).await,
+        None => vec![],
     };
+    let client_salt = client_cfg
+        .payload_encryption_key
+        .as_ref()
+        .map(|_| encryption::new_salt());
 
-    let mut req = Request::builder()
-        .method("GET")
-        .uri(format!("/{}/events", &client_cfg.http_upgrade_path_prefix,))
-        .header(HOST, &client_cfg.http_header_host)
-        .header(UPGRADE, "websocket")
-        .header(CONNECTION, "upgrade")
-        .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
-        .header(SEC_WEBSOCKET_VERSION, "13")
-        .header(
-            SEC_WEBSOCKET_PROTOCOL,
-            format!("v1, {}{}", JWT_HEADER_PREFIX, tunnel_to_jwt_token(request_id, tunnel_cfg)),
-        )
-        .version(hyper::Version::HTTP_11);
+    // Fail over to the next server only if this one cannot be reached, not if it refuses the tunnel
+    let servers = client_cfg.servers();
+    let mut last_err = None;
+    let mut upgraded = None;
+    for server in servers.candidates(tunnel_cfg.group.as_deref()) {
+        let mut pooled_cnx = match server.pool.get().await {
+            Ok(tcp_stream) => tcp_stream,
+            Err(err) => {
+                servers.set_healthy(server, false);
+                last_err = Some(anyhow!("failed to get a connection to the server from the pool: {err:?}"));
+                continue;
+            }
+        };
+
+        let mut req = Request::builder()
+            .method("GET")
+            .uri(format!(
+                "/{}/{}",
+                &client_cfg.http_upgrade_path_prefix,
+                upgrade_path_suffix(client_cfg)
+            ))
+            .header(HOST, &server.config.http_header_host)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "upgrade")
+            .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(
+                SEC_WEBSOCKET_PROTOCOL,
+                format!("{}{}{}", protocol_version::client_header(), JWT_HEADER_PREFIX, jwt),
+            )
+            .header(REQUEST_ID_HEADER, request_id.to_string())
+            .version(hyper::Version::HTTP_11);
 
-    for (k, v) in &client_cfg.http_headers {
-        req = req.header(k, v);
+        for (k, v) in client_cfg
+            .http_headers
+            .iter()
+            .chain(file_headers.iter().map(|(k, v)| (k, v)))
+        {
+            req = req.header(k, v);
+        }
+        if let Some(auth) = &client_cfg.http_upgrade_credentials {
+            req = req.header(AUTHORIZATION, auth);
+        }
+        if let Some(salt) = &client_salt {
+            req = req.header(ENCRYPTION_HEADER, encryption::encode_salt(salt));
+        }
+
+        let req = req.body(Empty::<Bytes>::new()).with_context(|| {
+            format!(
+                "failed to build HTTP request to contact the server {:?}",
+                server.config.remote_addr
+            )
+        })?;
+        debug!("with HTTP upgrade request {:?}", req);
+        let transport = pooled_cnx.deref_mut().take().unwrap();
+        let err = match handshake(req, transport).await {
+            Ok(Ok((ws, response))) => {
+                upgraded = Some((ws, response, server.open_tunnel()));
+                break;
+            }
+            Ok(Err(refused)) => {
+                return Err(refused_error(&refused).context(format!(
+                    "failed to do websocket handshake with the server {:?}",
+                    server.config.remote_addr
+                )));
+            }
+            Err(err) => err.context(format!(
+                "failed to do websocket handshake with the server {:?}",
+                server.config.remote_addr
+            )),
+        };
+        servers.set_healthy(server, false);
+        last_err = Some(err);
     }
-    if let Some(auth) = &client_cfg.http_upgrade_credentials {
-        req = req.header(AUTHORIZATION, auth);
+    let Some((mut ws, response, server_tunnel)) = upgraded else {
+        return Err(last_err.unwrap());
+    };
+    let version = protocol_version::accepted(response.headers().get(SEC_WEBSOCKET_PROTOCOL))?;
+    debug!("Using protocol {} with the server", version);
+    // Replaced by a reverse proxy in front of the server
+    if let Some(correlation_id) = response
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| *id != request_id.to_string())
+    {
+        Span::current().record("correlation_id", correlation_id);
     }
 
-    let req = req.body(Empty::<Bytes>::new()).with_context(|| {
-        format!(
-            "failed to build HTTP request to contact the server {:?}",
-            client_cfg.remote_addr
-        )
-    })?;
-    debug!("with HTTP upgrade request {:?}", req);
-    let transport = pooled_cnx.deref_mut().take().unwrap();
-    let (ws, response) = fastwebsockets::handshake::client(&TokioExecutor::new(), req, transport)
-        .await
-        .with_context(|| format!("failed to do websocket handshake with the server {:?}", client_cfg.remote_addr))?;
+    let ciphers = match (&client_cfg.payload_encryption_key, client_salt) {
+        (Some(key), Some(client_salt)) => {
+            let server_salt = response
+                .headers()
+                .get(ENCRYPTION_HEADER)
+                .ok_or_else(|| anyhow!("server does not support payload encryption"))
+                .and_then(|salt| encryption::decode_salt(salt.as_bytes()))?;
+            Some(encryption::ciphers(key, &client_salt, &server_salt, true))
+        }
+        _ => None,
+    };
+
+    // The server waits for it before relaying the visitor of a reverse listener
+    if response.headers().contains_key(READY_ACK_HEADER) {
+        ready::send_ready(&mut ws)
+            .await
+            .context("failed to send the ready frame to the server")?;
+    }
 
-    Ok((ws, response))
+    Ok((ws, response, ciphers, server_tunnel))
+}
+
+/// An upgrade refused by the server is given back, for its error code
+async fn handshake<S>(
+    req: Request<Empty<Bytes>>,
+    transport: S,
+) -> anyhow::Result<Result<(WebSocket<TokioIo<Upgraded>>, Response<Incoming>), Response<Incoming>>>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(transport)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.with_upgrades().await {
+            debug!("Error polling the connection to the server: {}", err);
+        }
+    });
+
+    let mut response = sender.send_request(req).await?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(Err(response));
+    }
+    let is_websocket = response
+        .headers()
+        .get(UPGRADE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.eq_ignore_ascii_case("websocket"));
+    if !is_websocket {
+        return Err(anyhow::Error::new(WebSocketError::InvalidUpgradeHeader));
+    }
+
+    let upgraded = hyper::upgrade::on(&mut response).await?;
+    Ok(Ok((WebSocket::after_handshake(TokioIo::new(upgraded), Role::Client), response)))
+}
+
+/// Servers run with --upgrade-rejection-error-codes tell why
+fn refused_error(response: &Response<Incoming>) -> anyhow::Error {
+    let code = response
+        .headers()
+        .get(ERROR_CODE_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(ErrorCode::from_header);
+    match code {
+        Some(code) => anyhow!(
+            "upgrade refused by the server with status {} ({}): {}",
+            response.status(),
+            code,
+            code.hint()
+        ),
+        None => anyhow::Error::new(WebSocketError::InvalidStatusCode(response.status().as_u16())),
+    }
+}
+
+fn count_connect_error() {
+    metrics::incr(&METRICS.connect_errors, 1);
+}
+
+fn has_control_frames(response: &Response<Incoming>) -> bool {
+    protocol_version::accepted(response.headers().get(SEC_WEBSOCKET_PROTOCOL))
+        .is_ok_and(|version| version.has_control_frames())
+}
+
+/// Older servers close the tunnel at the first end of stream, relay resets as graceful closes, and never tell why
+pub(super) fn negotiated_stream_end(response: &Response<Incoming>, abort: Option<Abort>) -> Arc<StreamEnd> {
+    StreamEnd::new(
+        response.headers().contains_key(HALF_CLOSE_HEADER),
+        response.headers().contains_key(RESET_HEADER),
+        has_control_frames(response),
+        abort,
+    )
+}
+
+/// Destination asked by the visitor of a reverse socks5 tunnel, in the first frame, or the cookie of older servers
+async fn reverse_socks5_destination(
+    ws: &mut WebSocket<TokioIo<Upgraded>>,
+    response: &Response<Incoming>,
+) -> anyhow::Result<(Host, u16)> {
+    if has_control_frames(response) {
+        return match ControlMessage::read_first(ws).await? {
+            ControlMessage::Destination { host, port } => Ok((Host::parse(&host)?, port)),
+            message => Err(anyhow!("expected the destination from the server, got {:?}", message)),
+        };
+    }
+
+    response
+        .headers()
+        .get(COOKIE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| base64::engine::general_purpose::STANDARD.decode(h).ok())
+        .and_then(|h| Url::parse(&String::from_utf8_lossy(&h)).ok())
+        .and_then(|url| match (url.host(), url.port_or_known_default()) {
+            (Some(h), Some(p)) => Some((h.to_owned(), p)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no destination in the cookie of the server"))
+}
+
+/// Compression is only applied if the server acknowledged it, older servers ignore it
+fn negotiated_compression(tunnel_cfg: &LocalToRemote, response: &Response<Incoming>) -> Option<Compression> {
+    let compression = tunnel_cfg.compression?;
+    let acked = response
+        .headers()
+        .get(COMPRESSION_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h == compression.as_str());
+    if !acked {
+        warn!(
+            "Server does not support {} compression, tunnel will not be compressed",
+            compression.as_str()
+        );
+        return None;
+    }
+
+    Some(compression)
 }
 
 async fn connect_to_server<R, W>(
@@ -84,25 +371,264 @@ async fn connect_to_server<R, W>(
 ) -> anyhow::Result<()>
 where
     R: AsyncRead + Send + 'static,
-    W: AsyncWrite + Send + 'static,
+    W: LocalWrite + Send + 'static,
 {
-    let (mut ws, _) = connect(request_id, client_cfg, remote_cfg).await?;
+    let resume = session_resume(client_cfg, remote_cfg, None);
+    let (mut ws, response, ciphers, server_tunnel) = connect(request_id, client_cfg, remote_cfg, resume.clone())
+        .await
+        .inspect_err(|_| count_connect_error())?;
+    let _registered = registry::register(format!("{}:{}", remote_cfg.remote.0, remote_cfg.remote.1), None);
     ws.set_auto_apply_mask(client_cfg.websocket_mask_frame);
+    let compression = negotiated_compression(remote_cfg, &response);
 
-    let (ws_rx, ws_tx) = ws.split(tokio::io::split);
     let (local_rx, local_tx) = duplex_stream;
+    let abort = local_tx.abort_handle();
+    let destination = format!("{}:{}", remote_cfg.remote.0, remote_cfg.remote.1);
+    let (local_rx, local_tx) = events::watch_tunnel(None, &request_id.to_string(), destination, local_rx, local_tx);
+    if resume.is_some() {
+        let session = ResumableSession::new(Box::pin(local_rx), Box::pin(local_tx), compression, remote_cfg.qos);
+        run_resumable_session(request_id, client_cfg, remote_cfg, ws, ciphers, server_tunnel, session).await;
+        return Ok(());
+    }
+
+    let _server_tunnel = server_tunnel;
+    let stream_end = negotiated_stream_end(&response, abort);
+    relay(
+        client_cfg,
+        remote_cfg,
+        ws,
+        ciphers,
+        compression,
+        stream_end,
+        (local_rx, local_tx),
+    )
+    .await;
+    Ok(())
+}
+
+// The server gives up on the peer of a socks5 BIND before
+const BIND_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// Socks5 BIND, the server listens on a port of the range through a reverse tcp tunnel. The socks5 client is given
+/// this port once the server listens, and answered again with the peer that connected to it. Only the peer of the
+/// request is accepted by the server
+pub async fn socks5_bind(
+    client_cfg: Arc<WsClientConfig>,
+    mut tunnel_cfg: LocalToRemote,
+    bind: Socks5Bind,
+    mut stream: TcpStream,
+    peer: (Host, u16),
+) {
+    let request_id = Uuid::now_v7();
+    let nb_ports = (bind.last_port - bind.first_port) as u128 + 1;
+    let port = bind.first_port + (Uuid::new_v4().as_u128() % nb_ports) as u16;
+    let listen = match bind.addr {
+        IpAddr::V4(_) => Host::Ipv4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => Host::Ipv6(Ipv6Addr::UNSPECIFIED),
+    };
+    let span = span!(
+        Level::INFO,
+        "tunnel",
+        id = request_id.to_string(),
+        correlation_id = tracing::field::Empty,
+        tunnel = tracing::field::Empty,
+        remote = format!("{}:{}", listen, port)
+    );
+    tunnel_cfg.local_protocol = LocalProtocol::ReverseTcp;
+    tunnel_cfg.remote = (listen, port);
+    tunnel_cfg.bind_peer = Some(peer);
+
+    let tunnel = async move {
+        let public_addr = SocketAddr::new(bind.addr, port);
+        let mut buf = [0; 1];
+        // The server upgrades once it listens for the peer
+        let connected = select! {
+            ret = connect(request_id, &client_cfg, &tunnel_cfg, None) => ret,
+            // Nothing is expected from the socks5 client before the replies, it gave up
+            _ = stream.peek(&mut buf) => return Ok(()),
+        };
+        let (mut ws, response, ciphers, server_tunnel) = match connected {
+            Ok(ret) => ret,
+            Err(err) => {
+                count_connect_error();
+                let _ = socks5::reply(&mut stream, &ReplyError::GeneralFailure, public_addr).await;
+                return Err(err);
+            }
+        };
+        socks5::reply(&mut stream, &ReplyError::Succeeded, public_addr).await?;
+
+        let first_frame = select! {
+            ret = ControlMessage::read_first_within(&mut ws, BIND_TIMEOUT) => ret,
+            _ = stream.peek(&mut buf) => return Ok(()),
+        };
+        let peer_addr = match first_frame {
+            Ok(ControlMessage::Destination { host, port }) => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, port))
+                .map_err(|err| anyhow!("bad peer {} from the server: {}", host, err)),
+            Ok(msg) => Err(anyhow!("expected the peer of the socks5 BIND from the server, got {:?}", msg)),
+            Err(err) => Err(err),
+        };
+        let peer_addr = match peer_addr {
+            Ok(peer_addr) => peer_addr,
+            Err(err) => {
+                let _ = socks5::reply(&mut stream, &ReplyError::GeneralFailure, public_addr).await;
+                return Err(err);
+            }
+        };
+        socks5::reply(&mut stream, &ReplyError::Succeeded, peer_addr).await?;
+
+        let destination = format!("{}:{}", tunnel_cfg.remote.0, tunnel_cfg.remote.1);
+        let _registered = registry::register(destination.clone(), None);
+        ws.set_auto_apply_mask(client_cfg.websocket_mask_frame);
+        let compression = negotiated_compression(&tunnel_cfg, &response);
+        let (local_rx, local_tx) = tcp::abortable_split(stream);
+        let abort = local_tx.abort_handle();
+        let (local_rx, local_tx) = events::watch_tunnel(None, &request_id.to_string(), destination, local_rx, local_tx);
+        let _server_tunnel = server_tunnel;
+        let stream_end = negotiated_stream_end(&response, abort);
+        relay(
+            &client_cfg,
+            &tunnel_cfg,
+            ws,
+            ciphers,
+            compression,
+            stream_end,
+            (local_rx, local_tx),
+        )
+        .await;
+        anyhow::Ok(())
+    };
+
+    if let Err(err) = tunnel.instrument(span).await {
+        error!("{:?}", err);
+    }
+}
+
+/// Relay the local stream over the websocket until either side closes
+pub(super) async fn relay<R, W>(
+    client_cfg: &WsClientConfig,
+    remote_cfg: &LocalToRemote,
+    ws: WebSocket<TokioIo<Upgraded>>,
+    ciphers: Option<PayloadCiphers>,
+    compression: Option<Compression>,
+    stream_end: Arc<StreamEnd>,
+    duplex_stream: (R, W),
+) where
+    R: AsyncRead + Send + 'static,
+    W: AsyncWrite + Send + 'static,
+{
+    let (local_rx, local_tx) = duplex_stream;
+    let (ws_rx, ws_tx) = ws.split(tokio::io::split);
+    let (tx_cipher, rx_cipher) = ciphers.unzip();
     let (close_tx, close_rx) = oneshot::channel::<()>();
+    let (pong_tx, pong_rx) = rtt::pong_channel();
+    let (reliable_tx, reliable_rx) = reliable_udp(&remote_cfg.local_protocol);
 
     // Forward local tx to websocket tx
     let ping_frequency = client_cfg.websocket_ping_frequency;
     tokio::spawn(
-        super::io::propagate_read(local_rx, ws_tx, close_tx, Some(ping_frequency)).instrument(Span::current()),
+        super::io::propagate_read(
+            local_rx,
+            ws_tx,
+            close_tx,
+            pong_rx,
+            Some(ping_frequency),
+            reliable_tx,
+            compression,
+            tx_cipher,
+            remote_cfg.qos,
+            stream_end.clone(),
+        )
+        .instrument(Span::current()),
     );
 
     // Forward websocket rx to local rx
-    let _ = super::io::propagate_write(local_tx, ws_rx, close_rx).await;
+    let _ = super::io::propagate_write(
+        local_tx,
+        ws_rx,
+        close_rx,
+        pong_tx,
+        reliable_rx,
+        compression,
+        rx_cipher,
+        remote_cfg.qos,
+        stream_end,
+    )
+    .await;
+}
 
-    Ok(())
+fn session_resume(
+    client_cfg: &WsClientConfig,
+    tunnel_cfg: &LocalToRemote,
+    rx: Option<u64>,
+) -> Option<JwtSessionResume> {
+    let timeout = client_cfg.tunnel_resume_timeout?;
+
+    // Udp datagrams cannot be replayed from the middle
+    match tunnel_cfg.local_protocol {
+        LocalProtocol::Udp { .. } | LocalProtocol::ReverseUdp { .. } | LocalProtocol::TProxyUdp { .. } => None,
+        _ => Some(JwtSessionResume {
+            timeout: timeout.as_secs(),
+            rx,
+        }),
+    }
+}
+
+/// Carry the tunnel over new websockets each time the current one is lost, until the session is over or
+/// the server cannot be reached anymore
+async fn run_resumable_session(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    tunnel_cfg: &LocalToRemote,
+    mut ws: WebSocket<TokioIo<Upgraded>>,
+    mut ciphers: Option<PayloadCiphers>,
+    mut _server_tunnel: ServerTunnel,
+    mut session: ResumableSession,
+) {
+    let timeout = client_cfg.tunnel_resume_timeout.unwrap_or_default();
+    let ping_frequency = Some(client_cfg.websocket_ping_frequency);
+    let mut server_rx = 0;
+
+    loop {
+        match session
+            .pump(ws, ciphers, server_rx, ping_frequency, pending::<()>())
+            .await
+        {
+            PumpEnd::Closed | PumpEnd::Interrupted(_) => return,
+            PumpEnd::Disconnected => {}
+        }
+
+        info!("Websocket lost, trying to resume the session for {:?}", timeout);
+        let deadline = Instant::now() + timeout;
+        (ws, ciphers, _server_tunnel, server_rx) = loop {
+            let resume = session_resume(client_cfg, tunnel_cfg, Some(session.rx_offset()));
+            let err = match connect(request_id, client_cfg, tunnel_cfg, resume).await {
+                Ok((ws, response, ciphers, server_tunnel)) => {
+                    let server_rx = response
+                        .headers()
+                        .get(SESSION_RX_HEADER)
+                        .and_then(|h| h.to_str().ok())
+                        .and_then(|h| h.parse::<u64>().ok());
+                    match server_rx {
+                        Some(server_rx) => break (ws, ciphers, server_tunnel, server_rx),
+                        None => anyhow!("server does not support session resumption"),
+                    }
+                }
+                Err(err) => err,
+            };
+
+            if Instant::now() + RESUME_RETRY_DELAY > deadline {
+                error!("Cannot resume session: {:?}", err);
+                return;
+            }
+            warn!("Cannot resume session, retrying: {:?}", err);
+            tokio::time::sleep(RESUME_RETRY_DELAY).await;
+        };
+        ws.set_auto_apply_mask(client_cfg.websocket_mask_frame);
+    }
 }
 
 pub async fn run_tunnel<T, R, W>(
@@ -113,7 +639,7 @@ pub async fn run_tunnel<T, R, W>(
 where
     T: Stream<Item = anyhow::Result<((R, W), (Host, u16))>>,
     R: AsyncRead + Send + 'static,
-    W: AsyncWrite + Send + 'static,
+    W: LocalWrite + Send + 'static,
 {
     pin_mut!(incoming_cnx);
     while let Some(Ok((cnx_stream, remote_dest))) = incoming_cnx.next().await {
@@ -122,6 +648,8 @@ where
             Level::INFO,
             "tunnel",
             id = request_id.to_string(),
+            correlation_id = tracing::field::Empty,
+            tunnel = tracing::field::Empty,
             remote = format!("{}:{}", remote_dest.0, remote_dest.1)
         );
         let mut tunnel_cfg = tunnel_cfg.clone();
@@ -129,9 +657,15 @@ where
         let client_config = client_config.clone();
 
         let tunnel = async move {
-            let _ = connect_to_server(request_id, &client_config, &tunnel_cfg, cnx_stream)
-                .await
-                .map_err(|err| error!("{:?}", err));
+            let _ = async {
+                if is_direct(&client_config, &tunnel_cfg) {
+                    return connect_directly(&client_config, &tunnel_cfg, cnx_stream).await;
+                }
+                resolve_locally(&mut tunnel_cfg).await?;
+                connect_to_server(request_id, &client_config, &tunnel_cfg, cnx_stream).await
+            }
+            .await
+            .map_err(|err| error!("{:?}", err));
         }
         .instrument(span);
 
@@ -141,6 +675,73 @@ where
     Ok(())
 }
 
+/// Udp tunnels always go through the server
+fn is_direct(client_cfg: &WsClientConfig, tunnel_cfg: &LocalToRemote) -> bool {
+    let is_tcp = matches!(
+        tunnel_cfg.local_protocol,
+        LocalProtocol::Tcp | LocalProtocol::Socks5 { .. } | LocalProtocol::TProxyTcp
+    );
+    is_tcp && split_tunnel::route(&client_cfg.routes, &tunnel_cfg.remote) == Route::Direct
+}
+
+async fn connect_directly<R, W>(
+    client_cfg: &WsClientConfig,
+    tunnel_cfg: &LocalToRemote,
+    duplex_stream: (R, W),
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Send + 'static,
+    W: LocalWrite + Send + 'static,
+{
+    let (host, port) = &tunnel_cfg.remote;
+    info!("Connecting directly to {}:{}, without the tunnel", host, port);
+    let (mut rx, mut tx) = tcp::connect(
+        host,
+        *port,
+        client_cfg.socket_so_mark,
+        client_cfg.timeout_connect,
+        &DnsResolver::System,
+    )
+    .await?
+    .into_split();
+
+    let (local_rx, local_tx) = duplex_stream;
+    pin_mut!(local_rx);
+    pin_mut!(local_tx);
+    let _ = tokio::join!(
+        async {
+            let _ = tokio::io::copy(&mut local_rx, &mut tx).await;
+            tx.shutdown().await
+        },
+        async {
+            let _ = tokio::io::copy(&mut rx, &mut local_tx).await;
+            local_tx.shutdown().await
+        }
+    );
+    Ok(())
+}
+
+/// Hostnames asked by socks5 clients are resolved by the server, unless the tunnel resolves them locally
+async fn resolve_locally(tunnel_cfg: &mut LocalToRemote) -> anyhow::Result<()> {
+    let (LocalProtocol::Socks5 { local_dns: true, .. }, Host::Domain(domain)) =
+        (&tunnel_cfg.local_protocol, &tunnel_cfg.remote.0)
+    else {
+        return Ok(());
+    };
+
+    let addr = DnsResolver::System
+        .lookup_host(domain, tunnel_cfg.remote.1)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("cannot resolve {}", domain))?;
+    tunnel_cfg.remote.0 = match addr.ip() {
+        IpAddr::V4(ip) => Host::Ipv4(ip),
+        IpAddr::V6(ip) => Host::Ipv6(ip),
+    };
+    Ok(())
+}
+
 pub async fn run_reverse_tunnel<F, Fut, T>(
     client_config: Arc<WsClientConfig>,
     mut tunnel_cfg: LocalToRemote,
@@ -149,12 +750,18 @@ pub async fn run_reverse_tunnel<F, Fut, T>(
 where
     F: Fn((Host, u16)) -> Fut,
     Fut: Future<Output = anyhow::Result<T>>,
-    T: AsyncRead + AsyncWrite + Send + 'static,
+    T: LocalStream,
 {
     // Invert local with remote
     let remote_ori = tunnel_cfg.remote;
-    tunnel_cfg.remote = to_host_port(tunnel_cfg.local);
+    tunnel_cfg.remote = match &tunnel_cfg.local_protocol {
+        LocalProtocol::ReverseSni { hostname } | LocalProtocol::ReverseHttp { hostname, .. } => {
+            (Host::Domain(hostname.clone()), 0)
+        }
+        _ => to_host_port(tunnel_cfg.local),
+    };
 
+    let mut active_server = client_config.servers().subscribe();
     loop {
         let client_config = client_config.clone();
         let request_id = Uuid::now_v7();
@@ -162,49 +769,128 @@ where
             Level::INFO,
             "tunnel",
             id = request_id.to_string(),
+            correlation_id = tracing::field::Empty,
+            tunnel = tracing::field::Empty,
             remote = format!("{}:{}", tunnel_cfg.remote.0, tunnel_cfg.remote.1)
         );
         let _span = span.enter();
 
         // Correctly configure tunnel cfg
-        let (mut ws, response) = connect(request_id, &client_config, &tunnel_cfg)
-            .instrument(span.clone())
-            .await?;
+        let resume = session_resume(&client_config, &tunnel_cfg, None);
+        active_server.mark_unchanged();
+        let connected = select! {
+            ret = connect(request_id, &client_config, &tunnel_cfg, resume.clone()).instrument(span.clone()) => ret,
+            // Registered again on the server new tunnels now go to
+            _ = active_server.changed() => continue,
+        };
+        let (mut ws, response, ciphers, server_tunnel) = match connected {
+            Ok(ret) => ret,
+            // Another server may still be reached
+            Err(err) if client_config.servers().len() > 1 => {
+                count_connect_error();
+                warn!("Cannot register reverse tunnel, retrying: {:?}", err);
+                tokio::time::sleep(REVERSE_RETRY_DELAY).await;
+                continue;
+            }
+            Err(err) => {
+                count_connect_error();
+                return Err(err);
+            }
+        };
         ws.set_auto_apply_mask(client_config.websocket_mask_frame);
+        let compression = negotiated_compression(&tunnel_cfg, &response);
 
-        // Connect to endpoint
-        let remote = response
-            .headers()
-            .get(COOKIE)
-            .and_then(|h| h.to_str().ok())
-            .and_then(|h| base64::engine::general_purpose::STANDARD.decode(h).ok())
-            .and_then(|h| Url::parse(&String::from_utf8_lossy(&h)).ok())
-            .and_then(|url| match (url.host(), url.port_or_known_default()) {
-                (Some(h), Some(p)) => Some((h.to_owned(), p)),
-                _ => None,
-            })
-            .unwrap_or(remote_ori.clone());
+        // Connect to endpoint, only socks5 lets the server choose it
+        let remote = match tunnel_cfg.local_protocol {
+            LocalProtocol::ReverseSocks5 => match reverse_socks5_destination(&mut ws, &response).await {
+                Ok(remote) => remote,
+                Err(err) => {
+                    warn!("Cannot get the destination of the reverse socks5 tunnel: {:#}", err);
+                    continue;
+                }
+            },
+            _ => remote_ori.clone(),
+        };
+        // Commands are checked against --exec-allow instead
+        let is_exec = matches!(tunnel_cfg.local_protocol, LocalProtocol::Exec { .. });
+        if !is_exec && !is_allowed_target(&client_config, &remote) {
+            warn!(
+                "Refusing reverse tunnel to {}:{}, not in the allowed targets",
+                remote.0, remote.1
+            );
+            continue;
+        }
 
+        let connect_started_at = Instant::now();
         let stream = match connect_to_dest(remote.clone()).instrument(span.clone()).await {
             Ok(s) => s,
             Err(err) => {
                 error!("Cannot connect to {remote:?}: {err:?}");
+                count_connect_error();
                 continue;
             }
         };
 
-        let (local_rx, local_tx) = tokio::io::split(stream);
+        let (local_rx, local_tx, abort) = stream.split_local();
+        let destination = format!("{}:{}", remote.0, remote.1);
+        metrics::record_latency(Latency::Connect, Some(&destination), connect_started_at.elapsed());
+        let local_rx = FirstByte::new(local_rx, destination.clone());
+        let (local_rx, local_tx) =
+            events::watch_tunnel(None, &request_id.to_string(), destination.clone(), local_rx, local_tx);
+        if resume.is_some() {
+            let tunnel_cfg = tunnel_cfg.clone();
+            let session = ResumableSession::new(Box::pin(local_rx), Box::pin(local_tx), compression, tunnel_cfg.qos);
+            let tunnel = async move {
+                let _registered = registry::register(destination, None);
+                run_resumable_session(request_id, &client_config, &tunnel_cfg, ws, ciphers, server_tunnel, session)
+                    .await;
+            }
+            .instrument(span.clone());
+            tokio::spawn(tunnel);
+            continue;
+        }
+
         let (ws_rx, ws_tx) = ws.split(tokio::io::split);
         let (close_tx, close_rx) = oneshot::channel::<()>();
+        let (pong_tx, pong_rx) = rtt::pong_channel();
+        let (reliable_tx, reliable_rx) = reliable_udp(&tunnel_cfg.local_protocol);
+        let (tx_cipher, rx_cipher) = ciphers.unzip();
+        let qos = tunnel_cfg.qos;
+        let stream_end = negotiated_stream_end(&response, abort);
 
         let tunnel = async move {
+            let _registered = registry::register(destination, None);
+            let _server_tunnel = server_tunnel;
             let ping_frequency = client_config.websocket_ping_frequency;
             tokio::spawn(
-                super::io::propagate_read(local_rx, ws_tx, close_tx, Some(ping_frequency)).instrument(Span::current()),
+                super::io::propagate_read(
+                    local_rx,
+                    ws_tx,
+                    close_tx,
+                    pong_rx,
+                    Some(ping_frequency),
+                    reliable_tx,
+                    compression,
+                    tx_cipher,
+                    qos,
+                    stream_end.clone(),
+                )
+                .instrument(Span::current()),
             );
 
             // Forward websocket rx to local rx
-            let _ = super::io::propagate_write(local_tx, ws_rx, close_rx).await;
+            let _ = super::io::propagate_write(
+                local_tx,
+                ws_rx,
+                close_rx,
+                pong_tx,
+                reliable_rx,
+                compression,
+                rx_cipher,
+                qos,
+                stream_end,
+            )
+            .await;
         }
         .instrument(span.clone());
         tokio::spawn(tunnel);
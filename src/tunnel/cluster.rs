@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use jsonwebtoken::{Algorithm, Validation};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn, Instrument, Span};
+use url::Url;
+use uuid::Uuid;
+
+use super::{decode_jwt, jti, listeners, JwtSecret};
+
+const KEY_PREFIX: &str = "wstunnel:reverse:";
+const REFRESH: Duration = Duration::from_secs(10);
+// Registrations of a server that stopped refreshing them disappear after that
+const REGISTRATION_TTL: Duration = Duration::from_secs(30);
+const TIMEOUT: Duration = Duration::from_secs(5);
+// Lifetime of the tokens authenticating the relayed connections
+const RELAY_TOKEN_LIFETIME: u64 = 60;
+const MAX_RELAY_TOKEN_LEN: u64 = 4096;
+
+/// Servers sharing their reverse tunnels through a redis, behind the same load balancer
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    pub redis: Url,
+    /// Host the other servers connect to, to reach the reverse listeners of this one
+    pub advertise: String,
+    /// Where the other servers relay the connections of the reverse tunnels of this one. Without it, they connect
+    /// directly to the reverse listeners
+    pub relay_bind: Option<SocketAddr>,
+}
+
+/// Value of the key of a reverse listener in redis, where to forward its connections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Registration {
+    host: String,
+    relay_port: Option<u16>,
+}
+
+/// Claims of the token sent by the server relaying a connection, signed with the jwt secret they share
+#[derive(Debug, Serialize, Deserialize)]
+struct RelayClaims {
+    bind: String,
+    // Nonce, so a captured token cannot be replayed to the relay
+    jti: String,
+    exp: u64,
+}
+
+/// Forwarders of the reverse listeners of the other servers, by key of their registration
+#[derive(Default)]
+struct Forwarders {
+    running: HashMap<String, (Registration, JoinHandle<()>)>,
+    // Reverse tunnels opened on this server for the binds of forwarders, i.e: their client failed over to it. Not
+    // forwarded again until this server registers them
+    handed_over: HashMap<String, Instant>,
+}
+
+static FORWARDERS: Lazy<Mutex<Forwarders>> = Lazy::new(|| Mutex::new(Forwarders::default()));
+
+async fn connect(url: &Url) -> anyhow::Result<MultiplexedConnection> {
+    let client = redis::Client::open(url.as_str()).with_context(|| format!("invalid redis url {}", url))?;
+    Ok(client.get_multiplexed_async_connection().await?)
+}
+
+/// Keys and values of the registrations of all the servers
+async fn registrations(redis: &mut MultiplexedConnection) -> anyhow::Result<HashMap<String, Registration>> {
+    let mut keys = Vec::new();
+    let mut scan = redis.scan_match::<_, String>(format!("{}*", KEY_PREFIX)).await?;
+    while let Some(key) = scan.next_item().await {
+        keys.push(key?);
+    }
+    drop(scan);
+    if keys.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let values: Vec<Option<String>> = redis.mget(&keys).await?;
+    let mut registrations = HashMap::new();
+    // Expired meanwhile without a value
+    for (key, registration) in keys.into_iter().zip(values) {
+        let Some(registration) = registration else {
+            continue;
+        };
+        match serde_json::from_str(&registration) {
+            Ok(registration) => _ = registrations.insert(key, registration),
+            Err(err) => warn!("Invalid registration {} in the cluster redis: {}", key, err),
+        }
+    }
+    Ok(registrations)
+}
+
+/// Stop forwarding a bind to another server, before a reverse tunnel of this one listens on it
+pub(super) async fn hand_over(bind: &str) {
+    let key = format!("{}{}", KEY_PREFIX, bind);
+    let forwarder = {
+        let mut forwarders = FORWARDERS.lock();
+        forwarders.handed_over.insert(key.clone(), Instant::now());
+        forwarders.running.remove(&key)
+    };
+    if let Some((server, forwarder)) = forwarder {
+        info!(
+            "Not forwarding {} to the server {} anymore, its reverse tunnel is on this one",
+            bind, server.host
+        );
+        forwarder.abort();
+        // The listener closed once the task is dropped
+        let _ = forwarder.await;
+    }
+}
+
+/// Register the reverse listeners of this server, for the other servers to forward the connections they get on the
+/// same ports to it. And forward to the other servers the ones of their reverse listeners
+pub(super) async fn run(cluster: ClusterConfig, secrets: Vec<JwtSecret>) {
+    if let Some(relay_bind) = cluster.relay_bind {
+        let secrets = secrets.clone();
+        tokio::spawn(async move {
+            if let Err(err) = run_relay(relay_bind, secrets).await {
+                error!("Cluster relay stopped: {:?}", err);
+            }
+        });
+    }
+
+    let registration = Registration {
+        host: cluster.advertise.clone(),
+        relay_port: cluster.relay_bind.map(|relay_bind| relay_bind.port()),
+    };
+    let Ok(registration) = serde_json::to_string(&registration) else {
+        return;
+    };
+    let mut redis: Option<MultiplexedConnection> = None;
+    loop {
+        let ret = match redis.as_mut() {
+            Some(redis) => {
+                let sync = sync(&cluster, &registration, &secrets[0], redis);
+                tokio::time::timeout(TIMEOUT, sync)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("timeout")))
+            }
+            None => match tokio::time::timeout(TIMEOUT, connect(&cluster.redis)).await {
+                Ok(Ok(connected)) => {
+                    info!(
+                        "Connected to the cluster redis {}",
+                        cluster.redis.host_str().unwrap_or_default()
+                    );
+                    redis = Some(connected);
+                    continue;
+                }
+                Ok(Err(err)) => Err(err),
+                Err(_) => Err(anyhow!("timeout while connecting to redis")),
+            },
+        };
+        if let Err(err) = ret {
+            // Forwarders are kept as they are, the other servers are likely still there
+            warn!("Cannot share the reverse tunnels with the cluster: {:#}", err);
+            redis = None;
+        }
+        tokio::time::sleep(REFRESH).await;
+    }
+}
+
+async fn sync(
+    cluster: &ClusterConfig,
+    registration: &str,
+    secret: &JwtSecret,
+    redis: &mut MultiplexedConnection,
+) -> anyhow::Result<()> {
+    // Udp cannot be forwarded as a stream, socks5 and tls listeners are forwarded before their handshake
+    let local = listeners::snapshot()
+        .into_iter()
+        .filter(|listener| listener.protocol != "udp")
+        .map(|listener| format!("{}{}", KEY_PREFIX, listener.bind))
+        .collect::<Vec<_>>();
+    for key in &local {
+        redis
+            .set_ex::<_, _, ()>(key, registration, REGISTRATION_TTL.as_secs())
+            .await?;
+    }
+
+    let remote = registrations(redis)
+        .await?
+        .into_iter()
+        .filter(|(key, server)| server.host != cluster.advertise && !local.contains(key))
+        .collect::<HashMap<_, _>>();
+    let mut forwarders = FORWARDERS.lock();
+    let forwarders = &mut *forwarders;
+    forwarders
+        .handed_over
+        .retain(|key, since| !local.contains(key) && since.elapsed() < REGISTRATION_TTL);
+    forwarders.running.retain(|key, (server, forwarder)| {
+        let keep = remote.get(key) == Some(server) && !forwarder.is_finished();
+        if !keep {
+            forwarder.abort();
+        }
+        keep
+    });
+    for (key, server) in remote {
+        if forwarders.running.contains_key(&key) || forwarders.handed_over.contains_key(&key) {
+            continue;
+        }
+        let bind = key.trim_start_matches(KEY_PREFIX).to_string();
+        let forwarder = tokio::spawn(forward(bind, server.clone(), secret.clone()).instrument(Span::none()));
+        forwarders.running.insert(key, (server, forwarder));
+    }
+    Ok(())
+}
+
+/// Listen on the same address as the reverse listener of the other server, and relay the connections to it
+async fn forward(bind: String, server: Registration, secret: JwtSecret) {
+    let Some(port) = bind.rsplit_once(':').map(|(_, port)| port.to_string()) else {
+        return;
+    };
+    let listener = match TcpListener::bind(&bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            // i.e: an address of the other server, or a reverse tunnel of this one being opened
+            debug!("Cannot listen on {} to forward it to {}: {}", bind, server.host, err);
+            return;
+        }
+    };
+    info!("Forwarding {} to the reverse tunnel of the server {}", bind, server.host);
+    let peer = format!("{}:{}", server.host, server.relay_port.map_or(port, |port| port.to_string()));
+    loop {
+        let (mut visitor, visitor_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Error while accepting connections to forward to {}: {:?}", server.host, err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let (peer, bind, secret) = (peer.clone(), bind.clone(), secret.clone());
+        let relayed = server.relay_port.is_some();
+        tokio::spawn(async move {
+            let mut stream = match tokio::time::timeout(TIMEOUT, TcpStream::connect(&peer)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => return warn!("Cannot forward {} to {}: {}", visitor_addr, peer, err),
+                Err(_) => return warn!("Timeout while forwarding {} to {}", visitor_addr, peer),
+            };
+            if relayed {
+                if let Err(err) = stream.write_all(relay_token(&secret, bind).as_bytes()).await {
+                    return warn!("Cannot relay {} to {}: {}", visitor_addr, peer, err);
+                }
+            }
+            let _ = tokio::io::copy_bidirectional(&mut visitor, &mut stream).await;
+        });
+    }
+}
+
+/// First line of a relayed connection, telling the reverse listener to hand it to
+fn relay_token(secret: &JwtSecret, bind: String) -> String {
+    let claims = RelayClaims {
+        bind,
+        jti: Uuid::new_v4().to_string(),
+        exp: jsonwebtoken::get_current_timestamp() + RELAY_TOKEN_LIFETIME,
+    };
+    let token = jsonwebtoken::encode(&secret.header(), &claims, &secret.encoding_key).unwrap_or_default();
+    format!("{}\n", token)
+}
+
+/// Accept the connections relayed by the other servers, and hand them to the reverse listener of this one they are for
+async fn run_relay(bind: SocketAddr, secrets: Vec<JwtSecret>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("cannot listen on {} for the cluster relay", bind))?;
+    info!("Starting cluster relay listening on {}", bind);
+    let secrets = Arc::new(secrets);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Error while accepting relayed connections: {:?}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let secrets = secrets.clone();
+        tokio::spawn(async move {
+            if let Err(err) = relay(stream, &secrets).await {
+                warn!("Rejecting the connection relayed by {}: {:#}", peer, err);
+            }
+        });
+    }
+}
+
+async fn relay(stream: TcpStream, secrets: &[JwtSecret]) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut token = String::new();
+    tokio::time::timeout(TIMEOUT, (&mut stream).take(MAX_RELAY_TOKEN_LEN).read_line(&mut token))
+        .await
+        .map_err(|_| anyhow!("timeout while reading the relay token"))??;
+    let claims: RelayClaims = decode_jwt(token.trim_end(), secrets, &Validation::new(Algorithm::HS256))
+        .context("invalid relay token")?
+        .claims;
+    jti::check_not_replayed(&claims.jti, Some(claims.exp)).context("invalid relay token")?;
+
+    // Only to the reverse listeners, the relay must not be a way to reach anything else
+    if !listeners::snapshot()
+        .iter()
+        .any(|listener| listener.protocol != "udp" && listener.bind == claims.bind)
+    {
+        return Err(anyhow!("no reverse listener on {}", claims.bind));
+    }
+    let local = match claims.bind.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => {
+            let loopback = match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            SocketAddr::new(loopback, addr.port()).to_string()
+        }
+        _ => claims.bind,
+    };
+    let mut listener = TcpStream::connect(&local)
+        .await
+        .with_context(|| format!("cannot connect to the reverse listener {}", local))?;
+    tokio::io::copy_bidirectional(&mut stream, &mut listener).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_token() {
+        let secrets = [JwtSecret::parse("cluster-secret").unwrap()];
+        let token = relay_token(&secrets[0], "0.0.0.0:8443".to_string());
+        let validation = Validation::new(Algorithm::HS256);
+        let claims: RelayClaims = decode_jwt(token.trim_end(), &secrets, &validation).unwrap().claims;
+        assert_eq!(claims.bind, "0.0.0.0:8443");
+        assert!(jti::check_not_replayed(&claims.jti, Some(claims.exp)).is_ok());
+        assert!(jti::check_not_replayed(&claims.jti, Some(claims.exp)).is_err());
+        let other_token = relay_token(&secrets[0], "0.0.0.0:8443".to_string());
+        let other: RelayClaims = decode_jwt(other_token.trim_end(), &secrets, &validation)
+            .unwrap()
+            .claims;
+        assert_ne!(other.jti, claims.jti);
+
+        let other = JwtSecret::parse("other-secret").unwrap();
+        assert!(decode_jwt::<RelayClaims>(token.trim_end(), &[other], &validation).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hand_over() {
+        let bind = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let server = Registration {
+            host: "127.0.0.1".to_string(),
+            relay_port: Some(1),
+        };
+        let secret = JwtSecret::parse("cluster-secret").unwrap();
+        let forwarder = tokio::spawn(forward(bind.clone(), server.clone(), secret));
+        while TcpStream::connect(&bind).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let key = format!("{}{}", KEY_PREFIX, bind);
+        FORWARDERS.lock().running.insert(key.clone(), (server, forwarder));
+
+        hand_over(&bind).await;
+        assert!(TcpListener::bind(&bind).await.is_ok());
+        assert!(FORWARDERS.lock().handed_over.contains_key(&key));
+        assert!(!FORWARDERS.lock().running.contains_key(&key));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use fastwebsockets::{Frame, OpCode, Payload, WebSocket};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The first frame is sent right after the upgrade response, past this the tunnel is given up
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// In-band message of a tunnel, to signal something to the peer without an http header. Sent as a text frame
+/// holding a json object, only once both peers speak v2 of the protocol, the data frames being all binary.
+/// They are not encrypted by --payload-encryption-key, they carry none of the data of the tunnel.
+/// Messages unknown to the peer are ignored by it, new ones can be added without a new version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum ControlMessage {
+    /// Bytes of the tunnel sent so far, along with the keepalive pings
+    Stats { sent: u64 },
+    /// The sender is closing the tunnel, just before its close frame
+    Closing { reason: String },
+    /// First frame of the server on a reverse socks5 tunnel, the destination asked by its visitor.
+    /// With v1 it is given in the cookie header of the upgrade response instead, as https://HOST:PORT in base64.
+    /// Also the first frame of a socks5 BIND, the peer that connected to its listener
+    Destination { host: String, port: u16 },
+    /// The local side has shut down its writes, the `eof` text frame with v1
+    Eof,
+    /// The local connection is reset, just before the close frame. The `reset` text frame with v1
+    Reset,
+    /// Sent by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+impl ControlMessage {
+    pub fn frame(&self) -> Frame<'static> {
+        Frame::text(Payload::Owned(serde_json::to_vec(self).unwrap_or_default()))
+    }
+
+    pub fn is_control(payload: &[u8]) -> bool {
+        payload.first() == Some(&b'{')
+    }
+
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+
+    /// The first frame of the tunnel, before any data
+    pub async fn read_first<S>(ws: &mut WebSocket<S>) -> anyhow::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Self::read_first_within(ws, FIRST_FRAME_TIMEOUT).await
+    }
+
+    /// When the peer has something to wait for before sending it
+    pub async fn read_first_within<S>(ws: &mut WebSocket<S>, timeout: Duration) -> anyhow::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let frame = tokio::time::timeout(timeout, ws.read_frame())
+            .await
+            .map_err(|_| anyhow!("no control frame from the peer after {:?}", timeout))??;
+        if frame.opcode != OpCode::Text || !Self::is_control(&frame.payload) {
+            return Err(anyhow!(
+                "expected a control frame from the peer, got a {:?} frame",
+                frame.opcode
+            ));
+        }
+        Self::decode(&frame.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_message() {
+        let closing = ControlMessage::Closing {
+            reason: "destination closed the connection".to_string(),
+        };
+        let frame = closing.frame();
+        assert_eq!(
+            &frame.payload[..],
+            br#"{"type":"closing","reason":"destination closed the connection"}"#
+        );
+        assert!(ControlMessage::is_control(&frame.payload));
+        assert_eq!(ControlMessage::decode(&frame.payload).unwrap(), closing);
+
+        assert_eq!(
+            ControlMessage::decode(br#"{"type":"stats","sent":42,"received":7}"#).unwrap(),
+            ControlMessage::Stats { sent: 42 }
+        );
+        assert_eq!(
+            ControlMessage::decode(br#"{"type":"window","bytes":1024}"#).unwrap(),
+            ControlMessage::Unknown
+        );
+        assert!(ControlMessage::decode(br#"{"sent":42}"#).is_err());
+        assert_eq!(&ControlMessage::Eof.frame().payload[..], br#"{"type":"eof"}"#);
+        assert_eq!(ControlMessage::decode(br#"{"type":"reset"}"#).unwrap(), ControlMessage::Reset);
+        assert!(!ControlMessage::is_control(b"eof"));
+    }
+
+    #[tokio::test]
+    async fn test_read_first() {
+        use fastwebsockets::Role;
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WebSocket::after_handshake(client, Role::Client);
+        let mut server = WebSocket::after_handshake(server, Role::Server);
+        let destination = ControlMessage::Destination {
+            host: "[::1]".to_string(),
+            port: 22,
+        };
+        server.write_frame(destination.frame()).await.unwrap();
+        assert_eq!(ControlMessage::read_first(&mut client).await.unwrap(), destination);
+
+        server
+            .write_frame(Frame::binary(Payload::Borrowed(b"{}")))
+            .await
+            .unwrap();
+        assert!(ControlMessage::read_first(&mut client).await.is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use bytes::Bytes;
+use http_body_util::{Either, Full};
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, CONNECTION, CONTENT_TYPE, HOST};
+use hyper::{http, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{warn, Instrument, Span};
+use url::Url;
+
+/// What is served to requests that are not websocket upgrades, so the server looks like an ordinary website
+#[derive(Debug, Clone)]
+pub enum Decoy {
+    StaticDir(PathBuf),
+    Upstream(Url),
+}
+
+pub(super) type DecoyBody = Either<Incoming, Full<Bytes>>;
+pub(super) type DecoyResponse = Response<DecoyBody>;
+
+fn response(status: StatusCode, content_type: &'static str, body: impl Into<Bytes>) -> DecoyResponse {
+    http::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Either::Right(Full::new(body.into())))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode) -> DecoyResponse {
+    response(status, "text/plain", status.canonical_reason().unwrap_or_default().to_string())
+}
+
+pub(super) async fn serve(decoy: &Decoy, req: Request<Incoming>) -> DecoyResponse {
+    match decoy {
+        Decoy::StaticDir(root) => serve_file(root, &req).await,
+        Decoy::Upstream(upstream) => proxy(upstream, req).await,
+    }
+}
+
+/// File of the static directory requested by the url path, None if the path tries to escape the directory
+fn resolve_path(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let url_path = urlencoding::decode(url_path).ok()?;
+    let mut path = root.to_path_buf();
+    for segment in url_path.split('/').filter(|s| !s.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains('\\') || segment.contains('\0') {
+            return None;
+        }
+        path.push(segment);
+    }
+
+    if url_path.ends_with('/') {
+        path.push("index.html");
+    }
+    Some(path)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()).unwrap_or_default() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn serve_file(root: &Path, req: &Request<Incoming>) -> DecoyResponse {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let mut path = resolve_path(root, req.uri().path()).unwrap_or_default();
+    if tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_dir()) {
+        path.push("index.html");
+    }
+
+    // An empty path, when trying to escape the directory, is never found
+    let content = if path.as_os_str().is_empty() {
+        Err(std::io::ErrorKind::NotFound.into())
+    } else {
+        tokio::fs::read(&path).await
+    };
+    let (status, path, content) = match content {
+        Ok(content) => (StatusCode::OK, path, content),
+        // Like most web servers, allow the site to have its own not found page
+        Err(_) => {
+            let not_found = root.join("404.html");
+            match tokio::fs::read(&not_found).await {
+                Ok(content) => (StatusCode::NOT_FOUND, not_found, content),
+                Err(_) => return error_response(StatusCode::NOT_FOUND),
+            }
+        }
+    };
+
+    let content = if req.method() == Method::HEAD { vec![] } else { content };
+    response(status, content_type(&path), content)
+}
+
+async fn proxy(upstream: &Url, mut req: Request<Incoming>) -> DecoyResponse {
+    let host = upstream.host_str().unwrap_or_default();
+    let port = upstream.port_or_known_default().unwrap_or(80);
+    let stream = match tokio::time::timeout(Duration::from_secs(10), TcpStream::connect((host, port))).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            warn!("Cannot connect to decoy upstream {}: {:?}", upstream, err);
+            return error_response(StatusCode::BAD_GATEWAY);
+        }
+        Err(_) => {
+            warn!("Timeout while connecting to decoy upstream {}", upstream);
+            return error_response(StatusCode::GATEWAY_TIMEOUT);
+        }
+    };
+
+    let (mut sender, conn) = match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!("Cannot start http connection with decoy upstream {}: {:?}", upstream, err);
+            return error_response(StatusCode::BAD_GATEWAY);
+        }
+    };
+    tokio::spawn(
+        async move {
+            if let Err(err) = conn.await {
+                warn!("Error while proxying request to decoy upstream: {:?}", err);
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    // The upstream site is reached by its own name, the request is otherwise relayed as is
+    let headers = req.headers_mut();
+    headers.remove(CONNECTION);
+    headers.remove("keep-alive");
+    headers.remove("proxy-connection");
+    if let Some(host) = upstream.host_str() {
+        let host = match upstream.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        if let Ok(host) = HeaderValue::from_str(&host) {
+            headers.insert(HOST, host);
+        }
+    }
+
+    match sender.send_request(req).await {
+        Ok(response) => response.map(Either::Left),
+        Err(err) => {
+            warn!("Error while proxying request to decoy upstream: {:?}", err);
+            error_response(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path() {
+        let root = Path::new("/srv/www");
+        assert_eq!(resolve_path(root, "/"), Some(PathBuf::from("/srv/www/index.html")));
+        assert_eq!(
+            resolve_path(root, "/blog/post%201.html"),
+            Some(PathBuf::from("/srv/www/blog/post 1.html"))
+        );
+        assert_eq!(resolve_path(root, "/blog/"), Some(PathBuf::from("/srv/www/blog/index.html")));
+        assert_eq!(resolve_path(root, "/../etc/passwd"), None);
+        assert_eq!(resolve_path(root, "/%2e%2e/etc/passwd"), None);
+        assert_eq!(resolve_path(root, "/a/..%5C..%5Cetc"), None);
+    }
+}
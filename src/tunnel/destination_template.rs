@@ -0,0 +1,217 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+
+const VARIABLES: [&str; 4] = ["%USER%", "%HOST%", "%PORT%", "%MATCH%"];
+
+/// Destination resolved when the tunnel is opened, from the one asked by the client, i.e:
+/// `jump:22=%USER%.jump.internal:22` or `*.corp:22=%MATCH%.internal.example.com:%PORT%`.
+/// The pattern is a host, `*` or `*.domain`, and a port or `*`. In the template
+///  %USER% is the identity of the client certificate, the tunnel is refused without one. Never the subject of the
+///  token, it is chosen by the client even with --jwt-secret
+///  %HOST% and %PORT% are the destination asked by the client, the host in lowercase
+///  %MATCH% is the part of the host matched by the `*` of the pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestinationTemplate {
+    pattern: DestinationPattern,
+    template: String,
+}
+
+/// Destinations of a host, `*` or `*.domain`, and a port or `*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct DestinationPattern {
+    host: String,
+    port: Option<u16>,
+}
+
+/// Values of the variables of a template
+pub(super) struct TemplateVars<'a> {
+    pub user: Option<&'a str>,
+    pub host: &'a str,
+    pub port: u16,
+}
+
+impl DestinationPattern {
+    /// The part of the host matched by the `*` of the pattern, empty without one. The host must be in lowercase
+    pub fn matches<'a>(&self, host: &'a str, port: u16) -> Option<&'a str> {
+        if self.port.is_some_and(|p| p != port) {
+            return None;
+        }
+        match self.host.as_str() {
+            "*" => Some(host),
+            pattern => match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .and_then(|sub| sub.strip_suffix('.'))
+                    .filter(|sub| !sub.is_empty()),
+                None => Some("").filter(|_| pattern == host),
+            },
+        }
+    }
+}
+
+impl FromStr for DestinationPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("invalid destination pattern {}, expected HOST:PORT", s);
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| invalid())?),
+        };
+        let domain = match host {
+            "*" => "",
+            host => host.strip_prefix("*.").unwrap_or(host),
+        };
+        if host.is_empty() || domain.contains('*') {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl Display for DestinationPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}:*", self.host),
+        }
+    }
+}
+
+impl DestinationTemplate {
+    /// The destination of the first template matching the one asked by the client. An error if a variable has no
+    /// value, or one that is not made of letters, digits, '-', '_' and '.'
+    pub(super) fn expand(
+        templates: &[DestinationTemplate],
+        vars: &TemplateVars,
+    ) -> Option<anyhow::Result<(String, u16)>> {
+        // Hostnames are case insensitive, DB.eu.CORP must not escape the template of *.corp
+        let host = vars.host.to_ascii_lowercase();
+        let vars = TemplateVars { host: &host, ..*vars };
+        templates.iter().find_map(|template| {
+            let matched = template.pattern.matches(vars.host, vars.port)?;
+            Some(template.render(&vars, matched))
+        })
+    }
+
+    fn render(&self, vars: &TemplateVars, matched: &str) -> anyhow::Result<(String, u16)> {
+        let port = vars.port.to_string();
+        let mut dest = self.template.clone();
+        for (name, value) in VARIABLES
+            .iter()
+            .zip([vars.user, Some(vars.host), Some(&port), Some(matched)])
+        {
+            if !dest.contains(name) {
+                continue;
+            }
+            let value = value.ok_or_else(|| anyhow!("no value for {} of destination template {}", name, self))?;
+            if value.is_empty()
+                || value.starts_with('.')
+                || value.contains("..")
+                || !value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            {
+                return Err(anyhow!(
+                    "invalid value {:?} for {} of destination template {}",
+                    value,
+                    name,
+                    self
+                ));
+            }
+            dest = dest.replace(name, value);
+        }
+
+        let (host, port) = dest
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("invalid destination {} of template {}", dest, self))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port of destination {} of template {}", dest, self))?;
+        Ok((host.to_string(), port))
+    }
+}
+
+impl FromStr for DestinationTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!(
+                "invalid destination template {}, expected HOST:PORT=TEMPLATE_HOST:TEMPLATE_PORT",
+                s
+            )
+        };
+        let (pattern, template) = s.split_once('=').ok_or_else(invalid)?;
+        let pattern = pattern.parse()?;
+        let (template_host, template_port) = template.rsplit_once(':').ok_or_else(invalid)?;
+        if template_host.is_empty() || (template_port != "%PORT%" && template_port.parse::<u16>().is_err()) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            pattern,
+            template: template.to_string(),
+        })
+    }
+}
+
+impl Display for DestinationTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.pattern, self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination_template() {
+        let templates: Vec<DestinationTemplate> = [
+            "jump:22=%USER%.jump.internal:22",
+            "*.corp:*=%MATCH%.internal.example.com:%PORT%",
+            "*:8080=%HOST%.proxy:80",
+        ]
+        .iter()
+        .map(|t| t.parse().unwrap())
+        .collect();
+        let expand = |user, host, port| {
+            DestinationTemplate::expand(&templates, &TemplateVars { user, host, port }).map(|dest| dest.ok())
+        };
+
+        assert_eq!(
+            expand(Some("alice"), "jump", 22),
+            Some(Some(("alice.jump.internal".to_string(), 22)))
+        );
+        assert_eq!(expand(None, "jump", 22), Some(None));
+        assert_eq!(expand(Some("../etc"), "jump", 22), Some(None));
+        assert_eq!(expand(Some("a:1"), "jump", 22), Some(None));
+        assert_eq!(expand(Some("alice"), "jump", 23), None);
+        assert_eq!(
+            expand(None, "db.eu.corp", 5432),
+            Some(Some(("db.eu.internal.example.com".to_string(), 5432)))
+        );
+        assert_eq!(
+            expand(None, "DB.eu.CORP", 5432),
+            Some(Some(("db.eu.internal.example.com".to_string(), 5432)))
+        );
+        assert_eq!(expand(Some("alice"), "Jump", 22).flatten().unwrap().0, "alice.jump.internal");
+        assert_eq!(expand(None, "corp", 5432), None);
+        assert_eq!(expand(None, "web", 8080), Some(Some(("web.proxy".to_string(), 80))));
+        assert_eq!(expand(None, "web", 8081), None);
+
+        assert!("jump:22".parse::<DestinationTemplate>().is_err());
+        assert!("jump=host:22".parse::<DestinationTemplate>().is_err());
+        assert!("ju*mp:22=host:22".parse::<DestinationTemplate>().is_err());
+        assert!("jump:22=host:%USER%".parse::<DestinationTemplate>().is_err());
+        assert_eq!(templates[1].to_string(), "*.corp:*=%MATCH%.internal.example.com:%PORT%");
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+use super::registry;
+
+// Check this often if the last tunnels are gone
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Resolves when the server is asked to hand over to a new process, with SIGUSR2
+#[cfg(unix)]
+pub(super) async fn drain_requested() -> anyhow::Result<()> {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())?;
+    signal.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(super) async fn drain_requested() -> anyhow::Result<()> {
+    std::future::pending().await
+}
+
+/// Let the established tunnels finish, up to the timeout
+pub(super) async fn wait_tunnels_closed(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut last_active = 0;
+    loop {
+        let active = registry::count();
+        if active == 0 {
+            info!("All tunnels are closed");
+            return;
+        }
+        if Instant::now() >= deadline {
+            info!("Drain timeout reached, closing the {} remaining tunnels", active);
+            for (id, tunnel) in registry::snapshot() {
+                info!(
+                    "Closing tunnel {} to {}, open for {:?}",
+                    id,
+                    tunnel.destination,
+                    tunnel.opened_at.elapsed()
+                );
+            }
+            return;
+        }
+
+        if active != last_active {
+            info!("Waiting for {} tunnels to close", active);
+            last_active = active;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
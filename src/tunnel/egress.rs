@@ -0,0 +1,66 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+
+use super::destination_template::DestinationPattern;
+use crate::tcp::Egress;
+
+/// Egress of the tunnels to the destinations of a pattern, i.e: `*.partner.com:*=interface=wg0,source=10.8.0.2`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EgressRule {
+    pattern: DestinationPattern,
+    egress: Egress,
+}
+
+impl EgressRule {
+    /// Egress of the first rule matching the destination
+    pub(super) fn find<'a>(rules: &'a [EgressRule], host: &str, port: u16) -> Option<&'a Egress> {
+        rules
+            .iter()
+            .find(|rule| rule.pattern.matches(host, port).is_some())
+            .map(|rule| &rule.egress)
+    }
+}
+
+impl FromStr for EgressRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, egress) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid egress rule {}, expected HOST:PORT=OPTS", s))?;
+        Ok(Self {
+            pattern: pattern.parse()?,
+            egress: Egress::parse(egress).with_context(|| format!("invalid egress rule {}", s))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_egress_rule() {
+        let rules: Vec<EgressRule> = ["*.partner.com:*=interface=wg0,so_mark=42", "db:5432=source=10.8.0.2"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+
+        let egress = EgressRule::find(&rules, "api.partner.com", 443).unwrap();
+        assert_eq!(egress.interface.as_deref(), Some("wg0"));
+        assert_eq!(egress.so_mark, Some(42));
+        assert_eq!(
+            EgressRule::find(&rules, "db", 5432).unwrap().source,
+            Some("10.8.0.2".parse().unwrap())
+        );
+        assert!(EgressRule::find(&rules, "partner.com", 443).is_none());
+        assert!(EgressRule::find(&rules, "db", 5433).is_none());
+
+        assert!("*.partner.com:*".parse::<EgressRule>().is_err());
+        assert!("*.partner.com:*=".parse::<EgressRule>().is_err());
+        assert!("*.partner.com:*=interface=".parse::<EgressRule>().is_err());
+        assert!("*.partner.com:*=source=wg0".parse::<EgressRule>().is_err());
+        assert!("*.partner.com:*=vrf=blue".parse::<EgressRule>().is_err());
+    }
+}
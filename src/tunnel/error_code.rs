@@ -0,0 +1,76 @@
+use std::fmt::{Display, Formatter};
+
+/// Response header of a rejected upgrade request telling the client why, when the server is run with
+/// --upgrade-rejection-error-codes. Absent otherwise, probes cannot tell the server from any other website
+pub(super) static ERROR_CODE_HEADER: &str = "x-wstunnel-error";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ErrorCode {
+    AuthFailed,
+    DestForbidden,
+    DestUnreachable,
+    LimitExceeded,
+    VersionUnsupported,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::AuthFailed => "auth_failed",
+            ErrorCode::DestForbidden => "dest_forbidden",
+            ErrorCode::DestUnreachable => "dest_unreachable",
+            ErrorCode::LimitExceeded => "limit_exceeded",
+            ErrorCode::VersionUnsupported => "version_unsupported",
+        }
+    }
+
+    pub fn from_header(code: &str) -> Option<Self> {
+        match code {
+            "auth_failed" => Some(ErrorCode::AuthFailed),
+            "dest_forbidden" => Some(ErrorCode::DestForbidden),
+            "dest_unreachable" => Some(ErrorCode::DestUnreachable),
+            "limit_exceeded" => Some(ErrorCode::LimitExceeded),
+            "version_unsupported" => Some(ErrorCode::VersionUnsupported),
+            _ => None,
+        }
+    }
+
+    /// What the user of the client can do about it
+    pub fn hint(&self) -> &'static str {
+        match self {
+            ErrorCode::AuthFailed => {
+                "check the credentials, the path prefix, the secrets and the payload encryption key shared with the server"
+            }
+            ErrorCode::DestForbidden => "the destination is not in the --restrict-to rules of the server",
+            ErrorCode::DestUnreachable => "the server cannot connect to the destination, or bind the reverse listener",
+            ErrorCode::LimitExceeded => "the server is at its limit of tunnels, handshakes or bytes, retry later",
+            ErrorCode::VersionUnsupported => "the client and the server have no protocol version in common, upgrade the oldest",
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        for code in [
+            ErrorCode::AuthFailed,
+            ErrorCode::DestForbidden,
+            ErrorCode::DestUnreachable,
+            ErrorCode::LimitExceeded,
+            ErrorCode::VersionUnsupported,
+        ] {
+            assert_eq!(ErrorCode::from_header(code.as_str()), Some(code));
+        }
+        assert_eq!(ErrorCode::from_header("AUTH_FAILED"), None);
+        assert_eq!(ErrorCode::from_header(""), None);
+    }
+}
@@ -0,0 +1,232 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::body::Incoming;
+use hyper::Request;
+use pin_project::pin_project;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+use super::{hooks, webhook};
+
+/// Client of the server, as seen by it
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct Peer {
+    peer: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forwarded_for: Option<String>,
+}
+
+impl Peer {
+    pub fn new(peer: SocketAddr, req: &Request<Incoming>) -> Self {
+        let forwarded_for = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string());
+        Self { peer, forwarded_for }
+    }
+}
+
+/// Bytes are counted as `Metrics::bytes_sent` and `Metrics::bytes_received`
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(super) enum Event {
+    TunnelOpen {
+        id: String,
+        destination: String,
+    },
+    TunnelClose {
+        id: String,
+        destination: String,
+        bytes_sent: u64,
+        bytes_received: u64,
+        duration_ms: u64,
+    },
+    AuthFailure {
+        reason: &'static str,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct Notification {
+    pub timestamp: u64,
+    /// Only known by the server
+    #[serde(flatten)]
+    pub peer: Option<Peer>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+fn is_enabled() -> bool {
+    webhook::is_enabled() || hooks::is_enabled()
+}
+
+/// Give the event to the webhooks and exec hooks, if any
+pub(super) fn notify(peer: Option<&Peer>, event: Event) {
+    if !is_enabled() {
+        return;
+    }
+
+    let notification = Notification {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        peer: peer.cloned(),
+        event,
+    };
+    hooks::run(&notification);
+    webhook::send(notification);
+}
+
+/// Notify the opening of the tunnel, and its closing with the bytes counted on its local streams once both are dropped
+pub(super) fn watch_tunnel<R, W>(
+    peer: Option<&Peer>,
+    id: &str,
+    destination: String,
+    local_rx: R,
+    local_tx: W,
+) -> (Counted<R>, Counted<W>) {
+    if !is_enabled() {
+        return (
+            Counted {
+                inner: local_rx,
+                stats: None,
+            },
+            Counted {
+                inner: local_tx,
+                stats: None,
+            },
+        );
+    }
+
+    notify(
+        peer,
+        Event::TunnelOpen {
+            id: id.to_string(),
+            destination: destination.clone(),
+        },
+    );
+    let stats = Arc::new(TunnelStats {
+        peer: peer.cloned(),
+        id: id.to_string(),
+        destination,
+        opened_at: Instant::now(),
+        sent: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+    });
+    (
+        Counted {
+            inner: local_rx,
+            stats: Some(stats.clone()),
+        },
+        Counted {
+            inner: local_tx,
+            stats: Some(stats),
+        },
+    )
+}
+
+struct TunnelStats {
+    peer: Option<Peer>,
+    id: String,
+    destination: String,
+    opened_at: Instant,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Drop for TunnelStats {
+    fn drop(&mut self) {
+        let event = Event::TunnelClose {
+            id: std::mem::take(&mut self.id),
+            destination: std::mem::take(&mut self.destination),
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            bytes_received: self.received.load(Ordering::Relaxed),
+            duration_ms: self.opened_at.elapsed().as_millis() as u64,
+        };
+        notify(self.peer.as_ref(), event);
+    }
+}
+
+/// Local stream of a tunnel, counting what is read from it and written to it
+#[pin_project]
+pub(super) struct Counted<S> {
+    #[pin]
+    inner: S,
+    stats: Option<Arc<TunnelStats>>,
+}
+
+impl<R: AsyncRead> AsyncRead for Counted<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let ret = this.inner.poll_read(cx, buf);
+        if let Some(stats) = this.stats {
+            stats
+                .sent
+                .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        }
+        ret
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Counted<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let ret = this.inner.poll_write(cx, buf);
+        if let (Some(stats), Poll::Ready(Ok(written))) = (this.stats, &ret) {
+            stats.received.fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        ret
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_json() {
+        let mut notification = Notification {
+            timestamp: 1700000000,
+            peer: Some(Peer {
+                peer: "10.0.0.1:4242".parse().unwrap(),
+                forwarded_for: None,
+            }),
+            event: Event::TunnelClose {
+                id: "id".to_string(),
+                destination: "localhost:22".to_string(),
+                bytes_sent: 10,
+                bytes_received: 20,
+                duration_ms: 1500,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&notification).unwrap(),
+            r#"{"timestamp":1700000000,"peer":"10.0.0.1:4242","event":"tunnel_close","id":"id","destination":"localhost:22","bytes_sent":10,"bytes_received":20,"duration_ms":1500}"#
+        );
+
+        notification.peer = None;
+        notification.event = Event::AuthFailure { reason: "bad token" };
+        assert_eq!(
+            serde_json::to_string(&notification).unwrap(),
+            r#"{"timestamp":1700000000,"event":"auth_failure","reason":"bad token"}"#
+        );
+    }
+}
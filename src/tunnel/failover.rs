@@ -0,0 +1,288 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bb8::ManageConnection;
+use futures_util::future::join_all;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::WsClientConfig;
+
+/// How new tunnels are spread across the healthy servers
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Balancing {
+    /// To the first one, the next ones are only used when it cannot be reached
+    #[default]
+    Failover,
+    /// To each of them in turn, in proportion to their weights
+    Weighted,
+    /// To the one with the fewest open tunnels relative to its weight
+    LeastConnections,
+}
+
+/// A server the client can connect to, with its own pool of connections
+#[derive(Debug)]
+pub struct Server {
+    pub config: WsClientConfig,
+    pub pool: bb8::Pool<WsClientConfig>,
+    // Servers with a weight of 0 only get new tunnels if the others cannot be reached
+    weight: u32,
+    healthy: AtomicBool,
+    open_tunnels: Arc<AtomicUsize>,
+}
+
+impl Server {
+    fn name(&self) -> String {
+        format!("{}:{}", self.config.remote_addr.0, self.config.remote_addr.1)
+    }
+
+    /// Count a tunnel opened on the server, until the guard is dropped
+    pub fn open_tunnel(&self) -> ServerTunnel {
+        self.open_tunnels.fetch_add(1, Ordering::Relaxed);
+        ServerTunnel(self.open_tunnels.clone())
+    }
+
+    // Compare the open tunnels of the servers, relative to their weights
+    fn cmp_load(&self, other: &Server) -> std::cmp::Ordering {
+        let (open, other_open) = (
+            self.open_tunnels.load(Ordering::Relaxed) as u64,
+            other.open_tunnels.load(Ordering::Relaxed) as u64,
+        );
+        match (self.weight, other.weight) {
+            (0, 0) => open.cmp(&other_open),
+            (0, _) => std::cmp::Ordering::Greater,
+            (_, 0) => std::cmp::Ordering::Less,
+            (weight, other_weight) => (open * other_weight as u64).cmp(&(other_open * weight as u64)),
+        }
+    }
+}
+
+pub struct ServerTunnel(Arc<AtomicUsize>);
+
+impl Drop for ServerTunnel {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Servers of the client by order of preference. New tunnels are spread across the healthy ones,
+/// so they fail over when one cannot be reached and fail back once it can again
+#[derive(Debug)]
+pub struct Servers {
+    servers: Vec<Server>,
+    balancing: Balancing,
+    // Current weights of the smooth weighted round robin, by server
+    current_weights: Mutex<Vec<i64>>,
+    // Index of the server new tunnels go to with failover
+    active: watch::Sender<usize>,
+}
+
+impl Servers {
+    /// With the weight of each server
+    pub fn new(servers: Vec<(WsClientConfig, bb8::Pool<WsClientConfig>, u32)>, balancing: Balancing) -> Self {
+        assert!(!servers.is_empty());
+        let servers = servers
+            .into_iter()
+            .map(|(config, pool, weight)| Server {
+                config,
+                pool,
+                weight,
+                healthy: AtomicBool::new(true),
+                open_tunnels: Arc::new(AtomicUsize::new(0)),
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            current_weights: Mutex::new(vec![0; servers.len()]),
+            servers,
+            balancing,
+            active: watch::Sender::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Servers to try in turn, the one chosen by the balancing first, then the other healthy ones.
+    /// Tunnels of the same group all go to the same server, as long as it can be reached
+    pub fn candidates(&self, group: Option<&str>) -> Vec<&Server> {
+        let mut healthy = self
+            .servers
+            .iter()
+            .filter(|s| s.healthy.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        match (self.balancing, group) {
+            // All the tunnels already go to the same server
+            (Balancing::Failover, _) => {}
+            (_, Some(group)) => {
+                healthy.sort_by(|a, b| rendezvous_score(group, b).total_cmp(&rendezvous_score(group, a)));
+            }
+            (Balancing::Weighted, None) => {
+                if let Some(chosen) = self.weighted_choice(&healthy) {
+                    healthy[..=chosen].rotate_right(1);
+                }
+            }
+            (Balancing::LeastConnections, None) => healthy.sort_by(|a, b| a.cmp_load(b)),
+        }
+
+        let unhealthy = self.servers.iter().filter(|s| !s.healthy.load(Ordering::Relaxed));
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    // Position of the chosen server in the list
+    fn weighted_choice(&self, servers: &[&Server]) -> Option<usize> {
+        let indexes = servers
+            .iter()
+            .map(|server| self.servers.iter().position(|s| std::ptr::eq(s, *server)).unwrap())
+            .collect::<Vec<_>>();
+        let weights = servers.iter().map(|server| server.weight).collect::<Vec<_>>();
+
+        let mut current_weights = self.current_weights.lock();
+        let mut servers_current_weights = indexes.iter().map(|i| current_weights[*i]).collect::<Vec<_>>();
+        let chosen = smooth_weighted_choice(&mut servers_current_weights, &weights);
+        for (i, current_weight) in indexes.into_iter().zip(servers_current_weights) {
+            current_weights[i] = current_weight;
+        }
+        chosen
+    }
+
+    /// Changes each time new tunnels go to another server
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.active.subscribe()
+    }
+
+    pub fn set_healthy(&self, server: &Server, healthy: bool) {
+        if server.healthy.swap(healthy, Ordering::Relaxed) == healthy {
+            return;
+        }
+        if healthy {
+            info!("Server {} is reachable again", server.name());
+        } else {
+            warn!("Server {} is unreachable", server.name());
+        }
+
+        if self.balancing != Balancing::Failover {
+            return;
+        }
+
+        // When none is healthy, the first one is still tried first
+        let active = self
+            .servers
+            .iter()
+            .position(|s| s.healthy.load(Ordering::Relaxed))
+            .unwrap_or(0);
+        self.active.send_if_modified(|current| {
+            if *current == active {
+                return false;
+            }
+            info!("New tunnels now go to server {}", self.servers[active].name());
+            *current = active;
+            true
+        });
+    }
+}
+
+/// Connect to each server at this interval, to know when they can be reached again
+pub async fn run_health_checks(servers: Arc<Servers>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let checks = servers.servers.iter().map(|server| async {
+            let timeout = server.config.timeout_connect;
+            let healthy = matches!(tokio::time::timeout(timeout, server.config.connect()).await, Ok(Ok(Some(_))));
+            servers.set_healthy(server, healthy);
+        });
+        join_all(checks).await;
+    }
+}
+
+/// Weighted rendezvous hashing, the server with the highest score gets the tunnels of the group.
+/// When a server cannot be reached, only its groups move to another one
+fn rendezvous_score(group: &str, server: &Server) -> f64 {
+    weighted_score(group, &server.name(), server.weight)
+}
+
+fn weighted_score(group: &str, server: &str, weight: u32) -> f64 {
+    if weight == 0 {
+        return 0.0;
+    }
+    let hash = Sha256::new()
+        .chain_update(group)
+        .chain_update([0])
+        .chain_update(server)
+        .finalize();
+    // In ]0, 1[, so that its log is negative
+    let hash = (u64::from_be_bytes(hash[..8].try_into().unwrap()) >> 11) as f64;
+    let unit = (hash + 0.5) / (1u64 << 53) as f64;
+    weight as f64 / -unit.ln()
+}
+
+/// Smooth weighted round robin, the choices of each server are spread evenly over time.
+/// Servers with a weight of 0 are never chosen
+fn smooth_weighted_choice(current_weights: &mut [i64], weights: &[u32]) -> Option<usize> {
+    let mut total = 0;
+    let mut chosen: Option<usize> = None;
+    for (i, weight) in weights.iter().enumerate() {
+        if *weight == 0 {
+            continue;
+        }
+        current_weights[i] += *weight as i64;
+        total += *weight as i64;
+        if !matches!(chosen, Some(best) if current_weights[best] >= current_weights[i]) {
+            chosen = Some(i);
+        }
+    }
+
+    current_weights[chosen?] -= total;
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smooth_weighted_choice() {
+        let mut current_weights = vec![0; 3];
+        let choices = (0..7)
+            .map(|_| smooth_weighted_choice(&mut current_weights, &[5, 1, 1]).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(choices, vec![0, 0, 1, 0, 2, 0, 0]);
+
+        let mut current_weights = vec![0; 2];
+        assert_eq!(smooth_weighted_choice(&mut current_weights, &[0, 2]), Some(1));
+        assert_eq!(smooth_weighted_choice(&mut current_weights, &[0, 0]), None);
+    }
+
+    #[test]
+    fn test_weighted_score() {
+        let servers = ["a:443", "b:443", "c:443"];
+        fn chosen<'a>(group: &str, servers: &[&'a str], weights: &[u32]) -> &'a str {
+            let scores = servers
+                .iter()
+                .zip(weights)
+                .map(|(server, weight)| weighted_score(group, server, *weight))
+                .collect::<Vec<_>>();
+            servers[(0..servers.len())
+                .max_by(|a, b| scores[*a].total_cmp(&scores[*b]))
+                .unwrap()]
+        }
+
+        // Groups of a server that is removed are the only ones to move
+        let groups = (0..300).map(|i| format!("group-{}", i)).collect::<Vec<_>>();
+        let mut counts = [0; 3];
+        for group in &groups {
+            let server = chosen(group, &servers, &[1, 1, 2]);
+            counts[servers.iter().position(|s| *s == server).unwrap()] += 1;
+            if server != "b:443" {
+                assert_eq!(chosen(group, &["a:443", "c:443"], &[1, 2]), server);
+            }
+            assert_ne!(chosen(group, &servers, &[1, 0, 1]), "b:443");
+        }
+        assert!(counts[2] > counts[0] && counts[2] > counts[1], "{:?}", counts);
+    }
+}
@@ -0,0 +1,125 @@
+use std::process::Stdio;
+
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use tokio::process::Command;
+use tokio::runtime::Handle;
+use tracing::{debug, warn, Instrument};
+
+use super::events::{Event, Notification};
+
+/// Commands run when a tunnel is opened or closed
+#[derive(Debug, Default)]
+pub struct ExecHooks {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+}
+
+static HOOKS: OnceCell<ExecHooks> = OnceCell::new();
+
+pub fn set_exec_hooks(hooks: ExecHooks) {
+    let _ = HOOKS.set(hooks);
+}
+
+pub(super) fn is_enabled() -> bool {
+    HOOKS
+        .get()
+        .is_some_and(|hooks| hooks.on_connect.is_some() || hooks.on_disconnect.is_some())
+}
+
+/// Spawn the command of the event in the background, arguments are split on whitespaces, no shell is involved
+pub(super) fn run(notification: &Notification) {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    let cmd = match notification.event {
+        Event::TunnelOpen { .. } => &hooks.on_connect,
+        Event::TunnelClose { .. } => &hooks.on_disconnect,
+        Event::AuthFailure { .. } => return,
+    };
+    let Some(cmd) = cmd else {
+        return;
+    };
+    // Tunnels can still be closed while the runtime shuts down
+    let Ok(runtime) = Handle::try_current() else {
+        return;
+    };
+
+    let mut args = cmd.split_whitespace();
+    let Some(program) = args.next() else {
+        return;
+    };
+    debug!("Running hook {}", cmd);
+    let _guard = runtime.enter();
+    let mut child = match Command::new(program)
+        .args(args)
+        .envs(env_vars(notification))
+        .stdin(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            warn!("Cannot run hook {}: {}", cmd, err);
+            return;
+        }
+    };
+
+    let cmd = cmd.clone();
+    runtime.spawn(
+        async move {
+            match child.wait().await {
+                Ok(status) if !status.success() => warn!("Hook {} failed with {}", cmd, status),
+                Ok(_) => {}
+                Err(err) => warn!("Error while waiting for hook {}: {}", cmd, err),
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Fields of the event as WSTUNNEL_ variables, i.e: WSTUNNEL_DESTINATION
+fn env_vars(notification: &Notification) -> Vec<(String, String)> {
+    let Ok(Value::Object(fields)) = serde_json::to_value(notification) else {
+        return vec![];
+    };
+
+    fields
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(value) => value,
+                value => value.to_string(),
+            };
+            (format!("WSTUNNEL_{}", name.to_ascii_uppercase()), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_vars() {
+        let notification = Notification {
+            timestamp: 1700000000,
+            peer: None,
+            event: Event::TunnelOpen {
+                id: "id".to_string(),
+                destination: "localhost:22".to_string(),
+            },
+        };
+        let mut vars = env_vars(&notification);
+        vars.sort();
+        assert_eq!(
+            vars,
+            [
+                ("WSTUNNEL_DESTINATION", "localhost:22"),
+                ("WSTUNNEL_EVENT", "tunnel_open"),
+                ("WSTUNNEL_ID", "id"),
+                ("WSTUNNEL_TIMESTAMP", "1700000000"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+    }
+}
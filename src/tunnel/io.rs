@@ -1,21 +1,191 @@
 use fastwebsockets::{Frame, OpCode, Payload, WebSocketError, WebSocketRead, WebSocketWrite};
 use futures_util::{pin_mut, FutureExt};
-use hyper::upgrade::Upgraded;
-
-use hyper_util::rt::TokioIo;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::select;
 use tokio::sync::oneshot;
 use tokio::time::Instant;
 use tracing::log::debug;
 use tracing::{error, info, trace, warn};
 
+use super::control_frame::ControlMessage;
+use super::memory::{self, BufferUsage};
+use super::qos;
+use super::reliable::{self, ReliableRx, ReliableTx};
+use super::rtt::{self, PongRx, PongTx, RoundTrip};
+use crate::encryption::PayloadCipher;
+use crate::metrics::{self, Latency, METRICS};
+use crate::tcp::Abort;
+use crate::{Compression, Qos};
+
+/// Response header used by the server to acknowledge the compression requested by the client
+pub(super) static COMPRESSION_HEADER: &str = "x-wstunnel-compression";
+// Protect against decompression bombs, the read buffer never grow that much
+const MAX_DECOMPRESSED_LEN: usize = 32 * 1024 * 1024;
+/// Response header used by the server to acknowledge that it relays the half close of the tcp connections
+pub(super) static HALF_CLOSE_HEADER: &str = "x-wstunnel-half-close";
+/// Response header used by the server to acknowledge that it relays the resets of the tcp connections
+pub(super) static RESET_HEADER: &str = "x-wstunnel-reset";
+// Text frames ending the stream with v1 of the protocol, the data frames are all binary. With v2 they are
+// ControlMessage::Eof and ControlMessage::Reset.
+// Sent instead of closing the websocket when the local side shuts down its writes
+const EOF: &[u8] = b"eof";
+// Sent before closing the websocket when the local connection is reset
+const RESET: &[u8] = b"reset";
+
+/// How the end of the local connection is relayed to the peer, as negotiated with it. Shared by both directions of
+/// the tunnel
+#[derive(Debug, Default)]
+pub(super) struct StreamEnd {
+    // The tunnel is closed once both sides have shut down their writes, instead of at the first end of stream
+    half_close: bool,
+    reset: bool,
+    // The peer understands control frames, to be told why the tunnel is closed and how the stream ends
+    control: bool,
+    // Without it, the local connection is closed gracefully when the peer's one is reset
+    abort: Option<Abort>,
+    local_eof: AtomicBool,
+    peer_eof: AtomicBool,
+}
+
+impl StreamEnd {
+    pub fn new(half_close: bool, reset: bool, control: bool, abort: Option<Abort>) -> Arc<Self> {
+        Arc::new(Self {
+            half_close,
+            reset,
+            control,
+            abort,
+            ..Default::default()
+        })
+    }
+
+    fn eof_frame(&self) -> Frame<'static> {
+        match self.control {
+            true => ControlMessage::Eof.frame(),
+            false => Frame::text(Payload::Borrowed(EOF)),
+        }
+    }
+
+    fn reset_frame(&self) -> Frame<'static> {
+        match self.control {
+            true => ControlMessage::Reset.frame(),
+            false => Frame::text(Payload::Borrowed(RESET)),
+        }
+    }
+
+    /// Returns whether the peer is done too
+    fn on_local_eof(&self) -> bool {
+        self.local_eof.store(true, Ordering::SeqCst);
+        self.peer_eof.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the local side is done too
+    fn on_peer_eof(&self) -> bool {
+        self.peer_eof.store(true, Ordering::SeqCst);
+        self.local_eof.load(Ordering::SeqCst)
+    }
+}
+
+/// Each websocket frame is compressed on its own, so it works the same for udp datagrams and tcp streams
+pub(super) fn compress(compression: Compression, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match compression {
+        Compression::Zstd => zstd::bulk::compress(data, 0),
+        Compression::Lz4 => Ok(lz4_flex::block::compress_prepend_size(data)),
+    }
+}
+
+pub(super) fn decompress(compression: Compression, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match compression {
+        Compression::Zstd => zstd::bulk::decompress(data, MAX_DECOMPRESSED_LEN),
+        Compression::Lz4 => {
+            let invalid_data = |err| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+            let (len, data) = lz4_flex::block::uncompressed_size(data).map_err(invalid_data)?;
+            if len > MAX_DECOMPRESSED_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("lz4 frame too big once decompressed: {} bytes", len),
+                ));
+            }
+            lz4_flex::block::decompress(data, len).map_err(invalid_data)
+        }
+    }
+}
+
+// Bytes relayed by a tunnel, in each direction, before it yields to the other tunnels of its worker thread.
+// So a bulk transfer does not starve the interactive tunnels, 0 leaves it to the runtime
+static RELAY_BUDGET: AtomicUsize = AtomicUsize::new(256 * 1024);
+
+pub fn set_relay_budget(bytes: usize) {
+    RELAY_BUDGET.store(bytes, Ordering::Relaxed);
+}
+
+pub(super) struct RelayBudget {
+    remaining: usize,
+    qos: Qos,
+}
+
+impl RelayBudget {
+    pub fn new(qos: Qos) -> Self {
+        Self {
+            remaining: Self::budget(qos),
+            qos,
+        }
+    }
+
+    // Bulk tunnels yield more often, for the interactive ones of their thread to run sooner
+    fn budget(qos: Qos) -> usize {
+        match qos {
+            Qos::Interactive => RELAY_BUDGET.load(Ordering::Relaxed),
+            Qos::Bulk => RELAY_BUDGET.load(Ordering::Relaxed) / 4,
+        }
+    }
+
+    /// Wait for the bandwidth limit, and yield once the budget is spent, the next turn of the tunnel has a full budget again
+    pub async fn consume(&mut self, len: usize) {
+        qos::throttle(self.qos, len).await;
+        let budget = Self::budget(self.qos);
+        if budget == 0 {
+            return;
+        }
+
+        self.remaining = self.remaining.saturating_sub(len);
+        if self.remaining == 0 {
+            self.remaining = budget;
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+async fn next_ack(reliable: &mut Option<ReliableTx>) -> Option<u64> {
+    match reliable {
+        Some(reliable) => reliable.next_ack().await,
+        None => futures_util::future::pending().await,
+    }
+}
+
+/// Payload encryption wraps everything else, so acks and retransmissions are protected too
+fn encrypt<'a>(cipher: &mut Option<PayloadCipher>, payload: Payload<'a>) -> Payload<'a> {
+    match cipher {
+        None => payload,
+        Some(cipher) => Payload::Owned(cipher.seal(&payload)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn propagate_read(
     local_rx: impl AsyncRead,
-    mut ws_tx: WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>,
+    mut ws_tx: WebSocketWrite<impl AsyncWrite + Unpin>,
     mut close_tx: oneshot::Sender<()>,
+    mut pong_rx: PongRx,
     ping_frequency: Option<Duration>,
+    mut reliable: Option<ReliableTx>,
+    compression: Option<Compression>,
+    mut cipher: Option<PayloadCipher>,
+    qos: Qos,
+    stream_end: Arc<StreamEnd>,
 ) -> Result<(), WebSocketError> {
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local tx ==> websocket tx tunnel");
@@ -30,48 +200,131 @@ pub(super) async fn propagate_read(
     let start_at = Instant::now().checked_add(frequency).unwrap_or(Instant::now());
     let timeout = tokio::time::interval_at(start_at, frequency);
     let should_close = close_tx.closed().fuse();
+    let mut retransmit = tokio::time::interval(reliable::RETRANSMIT_TIMEOUT);
+    let header_len = if reliable.is_some() { reliable::HEADER_LEN } else { 0 };
+    let mut budget = RelayBudget::new(qos);
+    let mut usage = BufferUsage::new();
+    usage.set(buffer.capacity());
 
     pin_mut!(timeout);
     pin_mut!(should_close);
     pin_mut!(local_rx);
+    let mut eof = false;
+    let mut sent = 0u64;
+    let mut closing = None;
     loop {
+        let paused = usage.should_pause();
+        if paused && buffer.capacity() > MAX_PACKET_LENGTH {
+            debug!("Too much memory buffered by the tunnels, shrinking the buffer of this one");
+            buffer = vec![0u8; MAX_PACKET_LENGTH];
+            usage.set(buffer.capacity());
+        }
+
         let read_len = select! {
             biased;
 
-            read_len = local_rx.read(&mut buffer) => read_len,
+            read_len = local_rx.read(&mut buffer[header_len..]), if !paused && !eof => read_len,
 
             _ = &mut should_close => break,
 
+            Some(payload) = pong_rx.recv() => {
+                ws_tx.write_frame(Frame::pong(Payload::Owned(payload))).await?;
+                continue;
+            }
+
+            Some(seq) = next_ack(&mut reliable) => {
+                let ack = encrypt(&mut cipher, Payload::Owned(reliable::ack_frame(seq)));
+                ws_tx.write_frame(Frame::binary(ack)).await?;
+                continue;
+            }
+
+            _ = retransmit.tick(), if reliable.is_some() => {
+                for datagram in reliable.as_mut().map(|r| r.retransmissions()).unwrap_or_default() {
+                    ws_tx.write_frame(Frame::binary(encrypt(&mut cipher, Payload::Owned(datagram)))).await?;
+                }
+                continue;
+            }
+
             _ = timeout.tick(), if ping_frequency.is_some() => {
                 debug!("sending ping to keep websocket connection alive");
-                ws_tx.write_frame(Frame::new(true, OpCode::Ping, None, Payload::BorrowedMut(&mut []))).await?;
+                ws_tx.write_frame(Frame::new(true, OpCode::Ping, None, Payload::Owned(rtt::ping_payload()))).await?;
+                if stream_end.control {
+                    ws_tx.write_frame(ControlMessage::Stats { sent }.frame()).await?;
+                }
 
                 continue;
             }
+
+            _ = tokio::time::sleep(memory::RECHECK_INTERVAL), if paused => continue,
         };
 
         let read_len = match read_len {
-            Ok(0) => break,
+            // The other direction keeps going until the peer shuts down its writes too
+            Ok(0) if stream_end.half_close => {
+                debug!("Local side has shut down its writes, relaying the half close");
+                if let Err(err) = ws_tx.write_frame(stream_end.eof_frame()).await {
+                    warn!("error while writing to websocket tx tunnel {}", err);
+                    break;
+                }
+                if stream_end.on_local_eof() {
+                    break;
+                }
+                eof = true;
+                continue;
+            }
+            Ok(0) => {
+                closing = Some("connection closed".to_string());
+                break;
+            }
             Ok(read_len) => read_len,
+            Err(err) if stream_end.reset && err.kind() == std::io::ErrorKind::ConnectionReset => {
+                info!("Local connection reset, resetting the peer's one");
+                let _ = ws_tx.write_frame(stream_end.reset_frame()).await;
+                break;
+            }
             Err(err) => {
                 warn!("error while reading incoming bytes from local tx tunnel: {}", err);
+                closing = Some(format!("connection error: {}", err));
                 break;
             }
         };
+        metrics::incr(&METRICS.bytes_sent, read_len as u64);
+        sent += read_len as u64;
 
         //debug!("read {} wasted {}% usable {} capa {}", read_len, 100 - (read_len * 100 / buffer.capacity()), buffer.as_slice().len(), buffer.capacity());
-        if let Err(err) = ws_tx
-            .write_frame(Frame::binary(Payload::BorrowedMut(&mut buffer[..read_len])))
-            .await
-        {
+        let frame_len = header_len + read_len;
+        let payload = match compression {
+            None => {
+                if let Some(reliable) = reliable.as_mut() {
+                    reliable.seal(&mut buffer[..frame_len]);
+                }
+                Payload::BorrowedMut(&mut buffer[..frame_len])
+            }
+            Some(compression) => {
+                let mut frame = vec![0u8; header_len];
+                match compress(compression, &buffer[header_len..frame_len]) {
+                    Ok(compressed) => frame.extend_from_slice(&compressed),
+                    Err(err) => {
+                        warn!("error while compressing tunnel payload {}", err);
+                        break;
+                    }
+                }
+                if let Some(reliable) = reliable.as_mut() {
+                    reliable.seal(&mut frame);
+                }
+                Payload::Owned(frame)
+            }
+        };
+        if let Err(err) = ws_tx.write_frame(Frame::binary(encrypt(&mut cipher, payload))).await {
             warn!("error while writing to websocket tx tunnel {}", err);
             break;
         }
+        budget.consume(read_len).await;
 
         // If the buffer has been completely filled with previous read, Double it !
         // For the buffer to not be a bottleneck when the TCP window scale
         // For udp, the buffer will never grows.
-        if buffer.capacity() == read_len {
+        if buffer.capacity() == frame_len && usage.can_grow(buffer.capacity() / 4) {
             buffer.clear();
             let new_size = buffer.capacity() + (buffer.capacity() / 4); // grow buffer by 1.25 %
             buffer.reserve_exact(new_size);
@@ -82,29 +335,49 @@ pub(super) async fn propagate_read(
                 new_size,
                 buffer.as_slice().len(),
                 buffer.capacity()
-            )
+            );
+            usage.set(buffer.capacity());
         }
     }
 
+    if let (true, Some(reason)) = (stream_end.control, closing) {
+        let _ = ws_tx.write_frame(ControlMessage::Closing { reason }.frame()).await;
+    }
     // Send normal close
     let _ = ws_tx.write_frame(Frame::close(1000, &[])).await;
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn propagate_write(
     local_tx: impl AsyncWrite,
-    mut ws_rx: WebSocketRead<ReadHalf<TokioIo<Upgraded>>>,
+    mut ws_rx: WebSocketRead<impl AsyncRead + Unpin>,
     mut close_rx: oneshot::Receiver<()>,
+    pong_tx: PongTx,
+    mut reliable: Option<ReliableRx>,
+    compression: Option<Compression>,
+    mut cipher: Option<PayloadCipher>,
+    qos: Qos,
+    stream_end: Arc<StreamEnd>,
 ) -> Result<(), WebSocketError> {
-    let _guard = scopeguard::guard((), |_| {
+    let mut round_trip = scopeguard::guard(RoundTrip::default(), |round_trip| {
         info!("Closing local rx <== websocket rx tunnel");
+        if let Some(smoothed) = round_trip.smoothed() {
+            info!("Websocket round trip time was {:?}", smoothed);
+        }
     });
+    // Pings are answered by the tx side, as it owns the write half of the websocket
     let mut x = |x: Frame<'_>| {
         debug!("frame {:?} {:?}", x.opcode, x.payload);
+        if x.opcode == OpCode::Pong {
+            let _ = pong_tx.try_send(x.payload.to_vec());
+        }
         futures_util::future::ready(anyhow::Ok(()))
     };
 
+    let mut budget = RelayBudget::new(qos);
+    let mut received = 0u64;
     pin_mut!(local_tx);
     loop {
         let msg = select! {
@@ -123,11 +396,92 @@ pub(super) async fn propagate_write(
         };
 
         trace!("receive ws frame {:?} {:?}", msg.opcode, msg.payload);
-        let ret = match msg.opcode {
-            OpCode::Continuation | OpCode::Text | OpCode::Binary => local_tx.write_all(msg.payload.as_ref()).await,
-            OpCode::Close => break,
-            OpCode::Ping => Ok(()),
-            OpCode::Pong => Ok(()),
+        let control = match msg.opcode {
+            OpCode::Text if stream_end.control && ControlMessage::is_control(&msg.payload) => {
+                Some(ControlMessage::decode(&msg.payload))
+            }
+            _ => None,
+        };
+        let v1_end = |end: &[u8]| !stream_end.control && msg.opcode == OpCode::Text && msg.payload.as_ref() == end;
+        let peer_eof = matches!(control, Some(Ok(ControlMessage::Eof))) || v1_end(EOF);
+        let peer_reset = matches!(control, Some(Ok(ControlMessage::Reset))) || v1_end(RESET);
+        let ret = match (msg.opcode, control) {
+            _ if stream_end.half_close && peer_eof => {
+                debug!("Peer has shut down its writes, shutting down the local ones");
+                let ret = local_tx.shutdown().await;
+                if stream_end.on_peer_eof() {
+                    break;
+                }
+                ret
+            }
+            _ if stream_end.reset && peer_reset => {
+                info!("Peer connection reset, resetting the local one");
+                if let Some(abort) = &stream_end.abort {
+                    abort.abort();
+                }
+                break;
+            }
+            (_, Some(control)) => {
+                match control {
+                    Ok(ControlMessage::Stats { sent }) => {
+                        debug!("Peer sent {} bytes so far, {} received", sent, received)
+                    }
+                    Ok(ControlMessage::Closing { reason }) => info!("Peer is closing the tunnel: {}", reason),
+                    // Only expected first, not negotiated, or sent by a newer peer
+                    Ok(
+                        ControlMessage::Destination { .. }
+                        | ControlMessage::Eof
+                        | ControlMessage::Reset
+                        | ControlMessage::Unknown,
+                    ) => {
+                        trace!("Ignoring control frame {:?}", msg.payload)
+                    }
+                    Err(err) => warn!("Ignoring invalid control frame: {}", err),
+                }
+                Ok(())
+            }
+            (OpCode::Continuation | OpCode::Text | OpCode::Binary, None) => {
+                let decrypted = match cipher.as_mut() {
+                    None => None,
+                    Some(cipher) => match cipher.open(msg.payload.as_ref()) {
+                        Ok(data) => Some(data),
+                        Err(err) => {
+                            error!("{}", err);
+                            break;
+                        }
+                    },
+                };
+                let frame = decrypted.as_deref().unwrap_or(msg.payload.as_ref());
+                let data = match reliable.as_mut() {
+                    None => Some(frame),
+                    Some(reliable) => reliable.open(frame),
+                };
+                let data = match (data, compression) {
+                    (None, _) => None,
+                    (Some(data), None) => Some(Ok(Cow::Borrowed(data))),
+                    (Some(data), Some(compression)) => Some(decompress(compression, data).map(Cow::Owned)),
+                };
+                match data {
+                    None => Ok(()),
+                    Some(Ok(data)) => {
+                        metrics::incr(&METRICS.bytes_received, data.len() as u64);
+                        received += data.len() as u64;
+                        let ret = local_tx.write_all(&data).await;
+                        budget.consume(data.len()).await;
+                        ret
+                    }
+                    Some(Err(err)) => Err(err),
+                }
+            }
+            (OpCode::Close, _) => break,
+            (OpCode::Ping, _) => Ok(()),
+            (OpCode::Pong, _) => {
+                if let Some(sample) = round_trip.on_pong(&msg.payload) {
+                    debug!("Websocket round trip time {:?}, smoothed {:?}", sample, round_trip.smoothed());
+                    metrics::record_latency(Latency::RoundTrip, None, sample);
+                }
+                Ok(())
+            }
         };
 
         if let Err(err) = ret {
@@ -138,3 +492,110 @@ pub(super) async fn propagate_write(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastwebsockets::{Role, WebSocket};
+
+    #[test]
+    fn test_compression() {
+        let data = b"hello hello hello hello hello hello hello hello".repeat(100);
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let compressed = compress(compression, &data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(decompress(compression, &compressed).unwrap(), data);
+            assert!(decompress(compression, b"garbage").is_err());
+        }
+
+        let bomb = compress(Compression::Lz4, &vec![0u8; MAX_DECOMPRESSED_LEN + 1]).unwrap();
+        assert!(decompress(Compression::Lz4, &bomb).is_err());
+    }
+
+    #[test]
+    fn test_half_close() {
+        let stream_end = StreamEnd::new(true, false, false, None);
+        assert!(!stream_end.on_local_eof());
+        assert!(stream_end.on_peer_eof());
+
+        let stream_end = StreamEnd::new(true, false, false, None);
+        assert!(!stream_end.on_peer_eof());
+        assert!(stream_end.on_local_eof());
+    }
+
+    #[tokio::test]
+    async fn test_relay_half_close() {
+        // The end of stream is a text frame with v1, a control frame with v2
+        for control in [false, true] {
+            let (ws_a, ws_b) = tokio::io::duplex(64 * 1024);
+            let relay = |ws, role, control| {
+                let (local, visitor) = tokio::io::duplex(64 * 1024);
+                let (ws_rx, ws_tx) = WebSocket::after_handshake(ws, role).split(tokio::io::split);
+                let (local_rx, local_tx) = tokio::io::split(local);
+                let (close_tx, close_rx) = oneshot::channel();
+                let (pong_tx, pong_rx) = rtt::pong_channel();
+                let stream_end = StreamEnd::new(true, false, control, None);
+                let read = propagate_read(
+                    local_rx,
+                    ws_tx,
+                    close_tx,
+                    pong_rx,
+                    None,
+                    None,
+                    None,
+                    None,
+                    Qos::Interactive,
+                    stream_end.clone(),
+                );
+                let write = propagate_write(
+                    local_tx,
+                    ws_rx,
+                    close_rx,
+                    pong_tx,
+                    None,
+                    None,
+                    None,
+                    Qos::Interactive,
+                    stream_end,
+                );
+                (visitor, async move { futures_util::join!(read, write) })
+            };
+            let (mut visitor_a, relay_a) = relay(ws_a, Role::Client, control);
+            let (mut visitor_b, relay_b) = relay(ws_b, Role::Server, control);
+            let relays = tokio::spawn(async move { futures_util::join!(relay_a, relay_b) });
+
+            // A is done sending, B keeps going and A still receives it
+            visitor_a.write_all(b"request").await.unwrap();
+            visitor_a.shutdown().await.unwrap();
+            let mut request = [0; 7];
+            visitor_b.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"request");
+            for _ in 0..3 {
+                visitor_b.write_all(b"response").await.unwrap();
+                let mut response = [0; 8];
+                visitor_a.read_exact(&mut response).await.unwrap();
+                assert_eq!(&response, b"response");
+            }
+            assert_eq!(visitor_b.read(&mut request).await.unwrap(), 0);
+
+            // Closed once both sides are done
+            visitor_b.shutdown().await.unwrap();
+            assert_eq!(visitor_a.read(&mut request).await.unwrap(), 0);
+            let ((read_a, write_a), (read_b, write_b)) = tokio::time::timeout(Duration::from_secs(5), relays)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(read_a.is_ok() && write_a.is_ok() && read_b.is_ok() && write_b.is_ok());
+        }
+    }
+
+    #[test]
+    fn test_relay_budget() {
+        let mut budget = RelayBudget::new(Qos::Interactive);
+        assert!(budget.consume(128 * 1024).now_or_never().is_some());
+        assert!(budget.consume(127 * 1024).now_or_never().is_some());
+        // Spent, the tunnel yields
+        assert!(budget.consume(64 * 1024).now_or_never().is_none());
+        assert!(budget.consume(64 * 1024).now_or_never().is_some());
+    }
+}
@@ -0,0 +1,78 @@
+use ahash::{HashMap, HashMapExt};
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::time::Duration;
+
+/// How long the tokens minted by the client are valid. Each upgrade request uses a new token
+pub(super) const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+// Tokens are remembered until they expire, so do not accept tokens living too long to bound memory usage
+const MAX_TOKEN_LIFETIME: u64 = 15 * 60;
+// Same as the default leeway of jsonwebtoken, expired tokens are still accepted for that long
+const EXPIRY_LEEWAY: u64 = 60;
+
+struct SeenTokens {
+    expire_at: HashMap<String, u64>,
+    pruned_at: u64,
+}
+
+static SEEN_TOKENS: Lazy<Mutex<SeenTokens>> = Lazy::new(|| {
+    Mutex::new(SeenTokens {
+        expire_at: HashMap::with_capacity(0),
+        pruned_at: 0,
+    })
+});
+
+impl SeenTokens {
+    fn insert(&mut self, jti: &str, exp: Option<u64>, now: u64) -> anyhow::Result<()> {
+        let Some(exp) = exp else {
+            return Err(anyhow!("token with a jti must have an expiration"));
+        };
+        if exp > now + MAX_TOKEN_LIFETIME {
+            return Err(anyhow!("token expires too far in the future, check the clock of the client"));
+        }
+
+        // Avoid scanning the whole map on every request
+        if now > self.pruned_at {
+            self.expire_at.retain(|_, expire_at| *expire_at >= now);
+            self.pruned_at = now;
+        }
+
+        if self.expire_at.contains_key(jti) {
+            return Err(anyhow!("token {} has already been used", jti));
+        }
+        self.expire_at.insert(jti.to_string(), exp + EXPIRY_LEEWAY);
+
+        Ok(())
+    }
+}
+
+/// Reject tokens that have already been used, so an upgrade request cannot be replayed to open new tunnels
+pub(super) fn check_not_replayed(jti: &str, exp: Option<u64>) -> anyhow::Result<()> {
+    SEEN_TOKENS
+        .lock()
+        .insert(jti, exp, jsonwebtoken::get_current_timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replayed_token() {
+        let mut seen = SeenTokens {
+            expire_at: HashMap::with_capacity(0),
+            pruned_at: 0,
+        };
+        let now = 1_000_000;
+        assert!(seen.insert("a", Some(now + 300), now).is_ok());
+        assert!(seen.insert("b", Some(now + 300), now).is_ok());
+        assert!(seen.insert("a", Some(now + 300), now + 1).is_err());
+        assert!(seen.insert("c", None, now).is_err());
+        assert!(seen.insert("c", Some(now + MAX_TOKEN_LIFETIME + 1), now).is_err());
+
+        // Expired tokens are forgotten, jsonwebtoken rejects them anyway
+        assert!(seen.insert("d", Some(now + 600), now + 300 + EXPIRY_LEEWAY + 1).is_ok());
+        assert_eq!(seen.expire_at.len(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use slab::Slab;
+use tokio::time::Instant;
+
+/// Listener bound by the server for reverse tunnels. It outlives the tunnels, each new one waits on it
+/// for an incoming connection
+#[derive(Debug)]
+pub(super) struct Listener {
+    protocol: &'static str,
+    bind: String,
+    // Only this client can wait on it, with a reverse tunnel state file
+    owner: Option<String>,
+    // Id of the last tunnel that waited on the listener
+    client: Mutex<String>,
+    waiting: AtomicBool,
+    // Connections accepted but not taken by a tunnel yet
+    queued: AtomicUsize,
+    last_active: Mutex<Instant>,
+}
+
+impl Listener {
+    pub fn is_owned_by(&self, owner: Option<&str>) -> bool {
+        self.owner.is_none() || self.owner.as_deref() == owner
+    }
+
+    /// A tunnel has waited on it, at least once
+    pub fn has_waited(&self) -> bool {
+        !self.client.lock().is_empty()
+    }
+
+    pub fn wait(&self, client: &str) {
+        *self.client.lock() = client.to_string();
+        self.waiting.store(true, Ordering::Relaxed);
+    }
+
+    pub fn queue(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unqueue(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A connection is handed to the waiting tunnel
+    pub fn take(&self) {
+        self.unqueue();
+        self.waiting.store(false, Ordering::Relaxed);
+        *self.last_active.lock() = Instant::now();
+    }
+}
+
+static LISTENERS: Lazy<Mutex<Slab<Arc<Listener>>>> = Lazy::new(|| Mutex::new(Slab::new()));
+
+/// Listener registered until dropped
+pub(super) struct RegisteredListener {
+    id: usize,
+    pub listener: Arc<Listener>,
+}
+
+impl Drop for RegisteredListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(self.id);
+    }
+}
+
+pub(super) fn register(protocol: &'static str, bind: String, owner: Option<String>) -> RegisteredListener {
+    let listener = Arc::new(Listener {
+        protocol,
+        bind,
+        owner,
+        client: Mutex::new(String::new()),
+        waiting: AtomicBool::new(false),
+        queued: AtomicUsize::new(0),
+        last_active: Mutex::new(Instant::now()),
+    });
+    let id = LISTENERS.lock().insert(listener.clone());
+    RegisteredListener { id, listener }
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct ListenerInfo {
+    pub protocol: &'static str,
+    pub bind: String,
+    pub client: String,
+    pub waiting: bool,
+    pub queued: usize,
+    pub idle_sec: u64,
+}
+
+/// Listeners currently bound, by protocol and address
+pub(super) fn snapshot() -> Vec<ListenerInfo> {
+    let mut listeners = LISTENERS
+        .lock()
+        .iter()
+        .map(|(_, listener)| ListenerInfo {
+            protocol: listener.protocol,
+            bind: listener.bind.clone(),
+            client: listener.client.lock().clone(),
+            waiting: listener.waiting.load(Ordering::Relaxed),
+            queued: listener.queued.load(Ordering::Relaxed),
+            idle_sec: listener.last_active.lock().elapsed().as_secs(),
+        })
+        .collect::<Vec<_>>();
+    listeners.sort_by(|a, b| (a.protocol, &a.bind).cmp(&(b.protocol, &b.bind)));
+    listeners
+}
+
+/// All of them on one line, for the logs
+pub(super) fn summary() -> String {
+    let listeners = snapshot();
+    if listeners.is_empty() {
+        return "none".to_string();
+    }
+    listeners
+        .iter()
+        .map(|l| {
+            format!(
+                "{}://{} (client {}, {}, {} queued, idle {}s)",
+                l.protocol,
+                l.bind,
+                l.client,
+                if l.waiting { "waiting" } else { "not waiting" },
+                l.queued,
+                l.idle_sec
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let registered = register("tcp", "127.0.0.1:1212".to_string(), Some("alice".to_string()));
+        assert!(!registered.listener.has_waited());
+        assert!(registered.listener.is_owned_by(Some("alice")));
+        assert!(!registered.listener.is_owned_by(Some("bob")));
+        assert!(!registered.listener.is_owned_by(None));
+        registered.listener.wait("tunnel-1");
+        registered.listener.queue();
+        registered.listener.queue();
+        registered.listener.take();
+
+        let find = || snapshot().into_iter().find(|l| l.bind == "127.0.0.1:1212");
+        let listener = find().unwrap();
+        assert_eq!(
+            (listener.client.as_str(), listener.waiting, listener.queued),
+            ("tunnel-1", false, 1)
+        );
+        assert!(summary().contains("tcp://127.0.0.1:1212 (client tunnel-1, not waiting, 1 queued"));
+
+        drop(registered);
+        assert!(find().is_none());
+    }
+}
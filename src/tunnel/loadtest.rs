@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{debug, info, span, warn, Instrument, Level};
+use url::Host;
+use uuid::Uuid;
+
+use super::client;
+use super::split_tunnel::AllowFrom;
+use crate::{LocalProtocol, LocalToRemote, Qos, WsClientConfig};
+
+// Size of the writes of a tunnel to the echo server
+const CHUNK_LEN: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub struct LoadTestConfig {
+    pub tunnels: usize,
+    /// Tunnels opened per second
+    pub ramp_rate: u32,
+    /// How long the tunnels stay open once they are all open
+    pub duration: Duration,
+    /// Where the server connects the tunnels to, the echo server by default
+    pub destination: Option<(Host<String>, u16)>,
+    /// Traffic is only sent through the tunnels if they go to the echo server
+    pub echo_bind: Option<SocketAddr>,
+}
+
+#[derive(Default)]
+struct OpenTunnels {
+    open: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl OpenTunnels {
+    fn open(&self) {
+        let open = self.open.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(open, Ordering::Relaxed);
+    }
+
+    fn close(&self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Open the tunnels at the ramp rate, keep them open until the end of the test and report how the server coped
+pub async fn run(client_cfg: Arc<WsClientConfig>, cfg: LoadTestConfig) -> anyhow::Result<()> {
+    let echo_bind = match (&cfg.echo_bind, &cfg.destination) {
+        (Some(echo_bind), _) => Some(*echo_bind),
+        (None, Some(_)) => None,
+        (None, None) => Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+    };
+    let echo_addr = match echo_bind {
+        Some(bind) => Some(run_echo_server(bind).await?),
+        None => None,
+    };
+    let destination = match (cfg.destination, echo_addr) {
+        (Some(destination), _) => destination,
+        (None, Some(echo_addr)) => match echo_addr.ip() {
+            IpAddr::V4(ip) => (Host::Ipv4(ip), echo_addr.port()),
+            IpAddr::V6(ip) => (Host::Ipv6(ip), echo_addr.port()),
+        },
+        (None, None) => unreachable!(),
+    };
+    let tunnel_cfg = LocalToRemote {
+        local_protocol: LocalProtocol::Tcp,
+        local: SocketAddr::from(([127, 0, 0, 1], 0)),
+        remote: destination,
+        compression: None,
+        qos: Qos::default(),
+        group: None,
+        http_rewrite: None,
+        tls_termination: false,
+        bind_peer: None,
+        allow_from: AllowFrom::default(),
+    };
+
+    let ramp = Duration::from_secs_f64(cfg.tunnels as f64 / cfg.ramp_rate as f64);
+    info!(
+        "Opening {} tunnels to {}:{} over {:?}, then keeping them open for {:?}",
+        cfg.tunnels, tunnel_cfg.remote.0, tunnel_cfg.remote.1, ramp, cfg.duration
+    );
+
+    let started_at = Instant::now();
+    let deadline = started_at + ramp + cfg.duration;
+    let open_tunnels = Arc::new(OpenTunnels::default());
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / cfg.ramp_rate as f64));
+    let mut tasks = JoinSet::new();
+    for _ in 0..cfg.tunnels {
+        ticker.tick().await;
+        let request_id = Uuid::now_v7();
+        let span = span!(Level::INFO, "loadtest", id = request_id.to_string());
+        let tunnel = run_tunnel(
+            request_id,
+            client_cfg.clone(),
+            tunnel_cfg.clone(),
+            deadline,
+            echo_addr.is_some(),
+            open_tunnels.clone(),
+        );
+        tasks.spawn(tunnel.instrument(span));
+    }
+
+    let mut handshakes = Vec::with_capacity(cfg.tunnels);
+    let mut received = 0;
+    let mut errors = HashMap::<String, usize>::new();
+    while let Some(ret) = tasks.join_next().await {
+        match ret? {
+            Ok((handshake, bytes)) => {
+                handshakes.push(handshake);
+                received += bytes;
+            }
+            Err(err) => {
+                debug!("Tunnel failed: {:?}", err);
+                *errors.entry(format!("{:#}", err)).or_default() += 1;
+            }
+        }
+    }
+    let elapsed = started_at.elapsed();
+
+    handshakes.sort();
+    info!(
+        "{}/{} tunnels opened ({:.1}%), at most {} at the same time",
+        handshakes.len(),
+        cfg.tunnels,
+        handshakes.len() as f64 * 100.0 / cfg.tunnels.max(1) as f64,
+        open_tunnels.peak.load(Ordering::Relaxed)
+    );
+    if !handshakes.is_empty() {
+        info!(
+            "Handshake latency p50={:?} p90={:?} p99={:?} max={:?}",
+            percentile(&handshakes, 50.0),
+            percentile(&handshakes, 90.0),
+            percentile(&handshakes, 99.0),
+            handshakes[handshakes.len() - 1]
+        );
+    }
+    if echo_addr.is_some() {
+        info!(
+            "Echoed {} bytes in {:?}, {:.2} MB/s",
+            received,
+            elapsed,
+            received as f64 / elapsed.as_secs_f64() / 1_000_000.0
+        );
+    }
+    for (err, count) in errors {
+        warn!("{} tunnels failed: {}", count, err);
+    }
+
+    Ok(())
+}
+
+/// Handshake latency and bytes received back
+async fn run_tunnel(
+    request_id: Uuid,
+    client_cfg: Arc<WsClientConfig>,
+    tunnel_cfg: LocalToRemote,
+    deadline: Instant,
+    echo: bool,
+    open_tunnels: Arc<OpenTunnels>,
+) -> anyhow::Result<(Duration, usize)> {
+    let started_at = Instant::now();
+    let (mut ws, response, ciphers, _server_tunnel) =
+        client::connect(request_id, &client_cfg, &tunnel_cfg, None).await?;
+    let stream_end = client::negotiated_stream_end(&response, None);
+    let handshake = started_at.elapsed();
+    ws.set_auto_apply_mask(client_cfg.websocket_mask_frame);
+
+    open_tunnels.open();
+    let _guard = scopeguard::guard((), |_| open_tunnels.close());
+    let (local, remote) = tokio::io::duplex(4 * CHUNK_LEN);
+    let relay = tokio::spawn(
+        async move {
+            client::relay(
+                &client_cfg,
+                &tunnel_cfg,
+                ws,
+                ciphers,
+                None,
+                stream_end,
+                tokio::io::split(remote),
+            )
+            .await
+        }
+        .in_current_span(),
+    );
+
+    let (mut local_rx, mut local_tx) = tokio::io::split(local);
+    let send = async {
+        let chunk = [0; CHUNK_LEN];
+        while echo && Instant::now() < deadline {
+            local_tx.write_all(&chunk).await?;
+        }
+        tokio::time::sleep_until(deadline).await;
+        local_tx.shutdown().await
+    };
+    let receive = async {
+        let mut buf = vec![0; CHUNK_LEN];
+        let mut received = 0;
+        loop {
+            match local_rx.read(&mut buf).await? {
+                0 => return Ok::<_, std::io::Error>(received),
+                len => received += len,
+            }
+        }
+    };
+    let (_, received) = tokio::try_join!(send, receive).context("tunnel closed before the end of the test")?;
+    let _ = relay.await;
+
+    Ok((handshake, received))
+}
+
+async fn run_echo_server(bind: SocketAddr) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("cannot start echo server on {}", bind))?;
+    let local_addr = listener.local_addr()?;
+    info!("Starting echo server on {}", local_addr);
+
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Error while accepting echo connection {:?}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let (mut rx, mut tx) = stream.split();
+                let _ = tokio::io::copy(&mut rx, &mut tx).await;
+            });
+        }
+    });
+
+    Ok(local_addr)
+}
+
+/// Nearest rank percentile of sorted durations
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let durations = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&durations, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&durations, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&durations, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&durations[..1], 50.0), Duration::from_millis(1));
+        assert_eq!(percentile(&durations[..3], 90.0), Duration::from_millis(3));
+    }
+}
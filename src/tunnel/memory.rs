@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// 0 when there is no limit
+static MAX_BUFFERED: AtomicUsize = AtomicUsize::new(0);
+static BUFFERED: AtomicUsize = AtomicUsize::new(0);
+static TUNNELS: AtomicUsize = AtomicUsize::new(0);
+
+/// Paused tunnels check this often if they can read again
+pub(super) const RECHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+pub fn set_max_buffered(bytes: usize) {
+    MAX_BUFFERED.store(bytes, Ordering::Relaxed);
+}
+
+/// Bytes buffered by a tunnel, counted in the total of the process until dropped
+pub(super) struct BufferUsage {
+    bytes: usize,
+}
+
+impl BufferUsage {
+    pub fn new() -> Self {
+        TUNNELS.fetch_add(1, Ordering::Relaxed);
+        Self { bytes: 0 }
+    }
+
+    pub fn set(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            BUFFERED.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            BUFFERED.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+    }
+
+    /// Past the limit, the tunnels buffering more than their share stop reading from the local side,
+    /// their peer is slowed down by tcp flow control until enough memory is released
+    pub fn should_pause(&self) -> bool {
+        let max = MAX_BUFFERED.load(Ordering::Relaxed);
+        if max == 0 || BUFFERED.load(Ordering::Relaxed) <= max {
+            return false;
+        }
+
+        self.bytes > max / TUNNELS.load(Ordering::Relaxed).max(1)
+    }
+
+    /// If the tunnel can buffer that many more bytes without going past the limit
+    pub fn can_grow(&self, bytes: usize) -> bool {
+        let max = MAX_BUFFERED.load(Ordering::Relaxed);
+        max == 0 || BUFFERED.load(Ordering::Relaxed) + bytes <= max
+    }
+}
+
+impl Drop for BufferUsage {
+    fn drop(&mut self) {
+        BUFFERED.fetch_sub(self.bytes, Ordering::Relaxed);
+        TUNNELS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_usage() {
+        let mut heavy = BufferUsage::new();
+        let mut light = BufferUsage::new();
+        heavy.set(900);
+        light.set(100);
+        assert!(!heavy.should_pause());
+
+        set_max_buffered(800);
+        // Only the tunnel above its share of the limit is paused
+        assert!(heavy.should_pause());
+        assert!(!light.should_pause());
+        assert!(!light.can_grow(100));
+
+        heavy.set(500);
+        assert!(!heavy.should_pause());
+        assert!(light.can_grow(200));
+        drop(heavy);
+        assert!(light.can_grow(700));
+        set_max_buffered(0);
+    }
+}
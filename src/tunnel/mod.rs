@@ -1,18 +1,56 @@
+mod accounting;
+mod admin;
+mod amplification;
 pub mod client;
+mod cluster;
+mod control_frame;
+mod decoy;
+mod destination_template;
+mod drain;
+mod egress;
+mod error_code;
+mod events;
+pub mod failover;
+mod hooks;
 mod io;
+mod jti;
+mod listeners;
+pub mod loadtest;
+mod memory;
+mod ocsp;
+mod protocol_version;
+mod qos;
+mod rate_limit;
+mod ready;
+mod registry;
+mod reliable;
+mod reverse_state;
+mod rewrite_map;
+mod router;
+mod rtt;
 pub mod server;
+mod session;
+mod spa;
+pub mod split_tunnel;
+mod time_limits;
 mod tls_reloader;
+mod upgrade_path;
+mod webhook;
 
-use crate::dns::DnsResolver;
-use crate::{tcp, tls, LocalProtocol, LocalToRemote, WsClientConfig};
+use crate::tunnel::session::JwtSessionResume;
+use crate::{tcp, tls, Compression, HttpRewrite, LocalProtocol, LocalToRemote, Qos, WsClientConfig};
+use anyhow::anyhow;
 use async_trait::async_trait;
 use bb8::ManageConnection;
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
 use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
 use std::io::{Error, IoSlice};
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
@@ -27,40 +65,238 @@ struct JwtTunnelConfig {
     pub p: LocalProtocol,
     pub r: String,
     pub rp: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s: Option<JwtSessionResume>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub c: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<Qos>,
+    /// Unique id of the token, to detect replayed upgrade requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Bytes are accounted, and quotas enforced, per subject for the clients without a certificate. Or per tunnel
+    /// without one. Only trusted when the tokens are signed with --jwt-secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /// Time window during which the token can open tunnels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tw: Option<String>,
+    /// Max duration of the tunnel, in seconds, the server closes it after that
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md: Option<u64>,
+    /// Rewriting of the http requests relayed to the destination of a tcp tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hr: Option<HttpRewrite>,
+    /// The server terminates the tls of the visitors of a reverse tcp tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tt: Option<bool>,
+    /// The client sends a ready frame once upgraded, so the visitor of a reverse listener is not lost on a dead
+    /// websocket. Only for the reverse listeners, the sni and http routes have no way to give the visitor back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ra: Option<bool>,
+    /// The client relays the half close of its connections, instead of closing the tunnel at the first end of stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hc: Option<bool>,
+    /// The client relays the resets of its connections, instead of closing the other side gracefully
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rs: Option<bool>,
+    /// Timeout of the connection to the destination, in seconds, instead of the one of the server. Up to its cap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ct: Option<u64>,
+    /// Peer of a socks5 BIND, as host:port. Its reverse tcp tunnel is upgraded once listening, and only this peer is
+    /// accepted on it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bp: Option<String>,
 }
 
 impl JwtTunnelConfig {
-    fn new(request_id: Uuid, tunnel: &LocalToRemote) -> Self {
+    fn new(request_id: Uuid, client_cfg: &WsClientConfig, tunnel: &LocalToRemote) -> Self {
+        let is_stream = !matches!(
+            tunnel.local_protocol,
+            LocalProtocol::Udp { .. }
+                | LocalProtocol::Dns { .. }
+                | LocalProtocol::TcpUdp { .. }
+                | LocalProtocol::TProxyUdp { .. }
+                | LocalProtocol::ReverseUdp { .. }
+                | LocalProtocol::Icmp
+        );
         Self {
             id: request_id.to_string(),
             p: match tunnel.local_protocol {
                 LocalProtocol::Tcp => LocalProtocol::Tcp,
-                LocalProtocol::Udp { .. } => tunnel.local_protocol,
+                LocalProtocol::Udp { .. } => tunnel.local_protocol.clone(),
                 LocalProtocol::Stdio => LocalProtocol::Tcp,
-                LocalProtocol::Socks5 => LocalProtocol::Tcp,
+                LocalProtocol::NamedPipe { .. } => LocalProtocol::Tcp,
+                LocalProtocol::Exec { .. } => LocalProtocol::ReverseTcp,
+                LocalProtocol::Icmp => LocalProtocol::Icmp,
+                LocalProtocol::Dns { timeout } => LocalProtocol::Udp {
+                    timeout,
+                    reliable: false,
+                },
+                LocalProtocol::TcpUdp { timeout, reliable } => LocalProtocol::Udp { timeout, reliable },
+                LocalProtocol::Socks5 { .. } => LocalProtocol::Tcp,
                 LocalProtocol::ReverseTcp => LocalProtocol::ReverseTcp,
-                LocalProtocol::ReverseUdp { .. } => tunnel.local_protocol,
+                LocalProtocol::ReverseUdp { .. } => tunnel.local_protocol.clone(),
                 LocalProtocol::ReverseSocks5 => LocalProtocol::ReverseSocks5,
+                LocalProtocol::Sni { .. } => tunnel.local_protocol.clone(),
+                LocalProtocol::ReverseSni { .. } => tunnel.local_protocol.clone(),
+                LocalProtocol::Http { .. } => tunnel.local_protocol.clone(),
+                LocalProtocol::ReverseHttp { .. } => tunnel.local_protocol.clone(),
                 LocalProtocol::TProxyTcp => LocalProtocol::Tcp,
-                LocalProtocol::TProxyUdp { timeout } => LocalProtocol::Udp { timeout },
+                LocalProtocol::TProxyUdp { timeout, reliable } => LocalProtocol::Udp { timeout, reliable },
             },
             r: tunnel.remote.0.to_string(),
             rp: tunnel.remote.1,
+            s: None,
+            c: tunnel.compression,
+            q: Some(tunnel.qos).filter(|qos| *qos != Qos::default()),
+            jti: Some(Uuid::new_v4().to_string()),
+            exp: Some(jsonwebtoken::get_current_timestamp() + jti::TOKEN_LIFETIME.as_secs()),
+            iss: client_cfg.jwt_issuer.clone(),
+            aud: client_cfg.jwt_audience.clone(),
+            sub: client_cfg.jwt_subject.clone(),
+            tw: client_cfg.jwt_time_window.map(|window| window.to_string()),
+            md: client_cfg.jwt_max_duration.map(|duration| duration.as_secs()),
+            hr: tunnel.http_rewrite.clone(),
+            tt: Some(true).filter(|_| tunnel.tls_termination),
+            // The listener of a socks5 BIND takes a single peer, there is no other tunnel to give it to. Neither is
+            // there for the sni and http routes: their connections cannot be put back on the route once taken, and a
+            // dead websocket loses them with or without the ready frame
+            ra: Some(true).filter(|_| {
+                tunnel.bind_peer.is_none()
+                    && matches!(
+                        tunnel.local_protocol,
+                        LocalProtocol::ReverseTcp
+                            | LocalProtocol::ReverseUdp { .. }
+                            | LocalProtocol::ReverseSocks5
+                            | LocalProtocol::Exec { .. }
+                    )
+            }),
+            hc: Some(true).filter(|_| is_stream),
+            rs: Some(true).filter(|_| is_stream),
+            ct: client_cfg.jwt_connect_timeout.map(|timeout| timeout.as_secs()),
+            bp: tunnel
+                .bind_peer
+                .as_ref()
+                .map(|(host, port)| format!("{}:{}", host, port)),
         }
     }
 }
 
+pub use accounting::{enable_accounting, ByteQuotas};
+pub use admin::{run_admin_server, Stats};
+pub use amplification::set_udp_anti_amplification;
+pub use cluster::ClusterConfig;
+pub use decoy::Decoy;
+pub use destination_template::DestinationTemplate;
+pub use egress::EgressRule;
+pub use hooks::{set_exec_hooks, ExecHooks};
+pub use io::set_relay_budget;
+pub use memory::set_max_buffered;
+pub use qos::set_max_bandwidth;
+pub use reverse_state::enable_reverse_state;
+pub use rewrite_map::enable_rewrite_map;
+pub use spa::{SpaKnocker, SpaSecret};
+pub use time_limits::TimeWindow;
+pub use upgrade_path::{PathPrefix, UpgradePathSecret};
+
+// Do not leak, through timing, how much of a secret is right
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 static JWT_HEADER_PREFIX: &str = "authorization.bearer.";
 static JWT_SECRET: &[u8; 15] = b"champignonfrais";
-static JWT_KEY: Lazy<(Header, EncodingKey)> =
-    Lazy::new(|| (Header::new(Algorithm::HS256), EncodingKey::from_secret(JWT_SECRET)));
+/// Correlation id of the tunnel, sent by the client and possibly replaced by a reverse proxy in front of the server
+static REQUEST_ID_HEADER: &str = "x-request-id";
 
-static JWT_DECODE: Lazy<(Validation, DecodingKey)> = Lazy::new(|| {
+static JWT_VALIDATION: Lazy<Validation> = Lazy::new(|| {
     let mut validation = Validation::new(Algorithm::HS256);
     validation.required_spec_claims = HashSet::with_capacity(0);
-    (validation, DecodingKey::from_secret(JWT_SECRET))
+    validation
 });
 
+/// Secret used to sign the tokens of the upgrade requests, identified by an optional key id (kid)
+#[derive(Clone)]
+pub struct JwtSecret {
+    pub kid: Option<String>,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+}
+
+impl JwtSecret {
+    pub fn new(kid: Option<String>, secret: &[u8]) -> Self {
+        Self {
+            kid,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+        }
+    }
+
+    /// Parse [KID:]SECRET, a leading ':' allows secrets containing a ':' without a kid
+    pub fn parse(arg: &str) -> anyhow::Result<Self> {
+        let (kid, secret) = match arg.split_once(':') {
+            Some(("", secret)) => (None, secret),
+            Some((kid, secret)) => (Some(kid.to_string()), secret),
+            None => (None, arg),
+        };
+        if secret.is_empty() {
+            return Err(anyhow!("jwt secret cannot be empty"));
+        }
+
+        Ok(Self::new(kid, secret.as_bytes()))
+    }
+
+    fn header(&self) -> Header {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = self.kid.clone();
+        header
+    }
+}
+
+impl Default for JwtSecret {
+    fn default() -> Self {
+        Self::new(None, JWT_SECRET)
+    }
+}
+
+// Never leak the secret in the logs
+impl Debug for JwtSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtSecret").field("kid", &self.kid).finish()
+    }
+}
+
+/// Secrets are tried in order, skipping the ones with a different kid than the token, to allow rotating them
+fn decode_jwt<T: DeserializeOwned>(
+    token: &str,
+    secrets: &[JwtSecret],
+    validation: &Validation,
+) -> jsonwebtoken::errors::Result<TokenData<T>> {
+    let kid = jsonwebtoken::decode_header(token)?.kid;
+    let mut last_err = None;
+    for secret in secrets
+        .iter()
+        .filter(|secret| kid.is_none() || secret.kid.is_none() || secret.kid == kid)
+    {
+        match jsonwebtoken::decode(token, &secret.decoding_key, validation) {
+            Ok(jwt) => return Ok(jwt),
+            // The signature is checked first, any other error means this is the right secret
+            Err(err) if *err.kind() == ErrorKind::InvalidSignature => last_err = Some(err),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| ErrorKind::InvalidSignature.into()))
+}
+
+#[allow(clippy::large_enum_variant)]
 pub enum TransportStream {
     Plain(TcpStream),
     Tls(TlsStream<TcpStream>),
@@ -126,11 +362,18 @@ impl ManageConnection for WsClientConfig {
         let so_mark = self.socket_so_mark;
         let timeout = self.timeout_connect;
 
+        if let Some(spa_knocker) = &self.spa_knocker {
+            spa_knocker.knock(host).await?;
+        }
         let tcp_stream = if let Some(http_proxy) = &self.http_proxy {
-            tcp::connect_with_http_proxy(http_proxy, host, *port, so_mark, timeout).await?
+            tcp::connect_with_http_proxy(http_proxy, host, *port, so_mark, timeout, self.mptcp).await?
         } else {
-            tcp::connect(host, *port, so_mark, timeout, &DnsResolver::System).await?
+            let addrs = self.server_addrs.lookup().await?;
+            tcp::connect_addrs(host, *port, addrs, &tcp::Egress::with_so_mark(so_mark), timeout, self.mptcp)
+                .await
+                .inspect_err(|_| self.server_addrs.invalidate())?
         };
+        self.socket_opts.apply(socket2::SockRef::from(&tcp_stream))?;
 
         match &self.tls {
             None => Ok(Some(TransportStream::Plain(tcp_stream))),
@@ -150,9 +393,83 @@ impl ManageConnection for WsClientConfig {
     }
 }
 
+/// Both sides of the reliability layer, if the udp tunnel asked for it
+fn reliable_udp(protocol: &LocalProtocol) -> (Option<reliable::ReliableTx>, Option<reliable::ReliableRx>) {
+    match protocol {
+        LocalProtocol::Udp { reliable: true, .. }
+        | LocalProtocol::ReverseUdp { reliable: true, .. }
+        | LocalProtocol::TProxyUdp { reliable: true, .. } => {
+            let (tx, rx) = reliable::new();
+            (Some(tx), Some(rx))
+        }
+        _ => (None, None),
+    }
+}
+
 pub fn to_host_port(addr: SocketAddr) -> (Host, u16) {
-    match addr.ip() {
-        IpAddr::V4(ip) => (Host::Ipv4(ip), addr.port()),
-        IpAddr::V6(ip) => (Host::Ipv6(ip), addr.port()),
+    match addr {
+        SocketAddr::V4(addr) => (Host::Ipv4(*addr.ip()), addr.port()),
+        // Scoped to a zone, see dns::parse_scoped_ipv6
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            (Host::Domain(format!("{}%{}", addr.ip(), addr.scope_id())), addr.port())
+        }
+        SocketAddr::V6(addr) => (Host::Ipv6(*addr.ip()), addr.port()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(secret: &JwtSecret) -> String {
+        let claims = JwtTunnelConfig {
+            id: "id".to_string(),
+            p: LocalProtocol::Tcp,
+            r: "localhost".to_string(),
+            rp: 22,
+            s: None,
+            c: None,
+            q: None,
+            jti: None,
+            exp: None,
+            iss: None,
+            aud: None,
+            sub: None,
+            tw: None,
+            md: None,
+            hr: None,
+            tt: None,
+            ra: None,
+            hc: None,
+            rs: None,
+            ct: None,
+            bp: None,
+        };
+        jsonwebtoken::encode(&secret.header(), &claims, &secret.encoding_key).unwrap()
+    }
+
+    #[test]
+    fn test_jwt_secret_rotation() {
+        let old = JwtSecret::parse("old-secret").unwrap();
+        let new = JwtSecret::parse("2024:new-secret").unwrap();
+        assert_eq!(new.kid.as_deref(), Some("2024"));
+        let with_colon = JwtSecret::parse(":new:secret").unwrap();
+        assert!(with_colon.kid.is_none());
+        assert!(JwtSecret::parse("2024:").is_err());
+
+        let secrets = [new.clone(), old.clone()];
+        assert!(decode_jwt::<JwtTunnelConfig>(&token(&old), &secrets, &JWT_VALIDATION).is_ok());
+        assert!(decode_jwt::<JwtTunnelConfig>(&token(&new), &secrets, &JWT_VALIDATION).is_ok());
+        assert!(decode_jwt::<JwtTunnelConfig>(&token(&with_colon), &secrets, &JWT_VALIDATION).is_err());
+
+        // A token with a kid is only checked against the secrets with the same kid, or without one
+        let other_kid = JwtSecret::parse("2023:new-secret").unwrap();
+        assert!(decode_jwt::<JwtTunnelConfig>(&token(&other_kid), &[new], &JWT_VALIDATION).is_err());
+        assert!(decode_jwt::<JwtTunnelConfig>(
+            &token(&other_kid),
+            &[old, JwtSecret::parse("new-secret").unwrap()],
+            &JWT_VALIDATION
+        )
+        .is_ok());
     }
 }
@@ -0,0 +1,284 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper_util::rt::TokioIo;
+use sha1::{Digest, Sha1};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tracing::{info, warn};
+use url::Url;
+
+use x509_cert::der::asn1::{AnyRef, Null, OctetString};
+use x509_cert::der::oid::db::rfc5912::ID_SHA_1;
+use x509_cert::der::oid::db::rfc6960::ID_PKIX_OCSP_BASIC;
+use x509_cert::der::{Decode, Encode};
+use x509_cert::serial_number::SerialNumber;
+use x509_cert::spki::AlgorithmIdentifierOwned;
+use x509_ocsp::{
+    BasicOcspResponse, CertId, CertStatus, OcspRequest, OcspResponse, OcspResponseStatus, Request, TbsRequest, Version,
+};
+
+use crate::x509::Certificate;
+use crate::TlsServerConfig;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+// Between two fetches, responders update their responses every few days at most
+const MIN_REFRESH: Duration = Duration::from_secs(5 * 60);
+const MAX_REFRESH: Duration = Duration::from_secs(24 * 3600);
+// Without a next update in the response
+const DEFAULT_REFRESH: Duration = Duration::from_secs(3600);
+
+/// Response of the responder of the certificate, to be stapled to the tls handshakes
+#[derive(Debug)]
+struct Staple {
+    response: Vec<u8>,
+    next_update: Option<SystemTime>,
+}
+
+fn cert_id(leaf: &Certificate, issuer: &Certificate) -> anyhow::Result<CertId> {
+    Ok(CertId {
+        hash_algorithm: AlgorithmIdentifierOwned {
+            oid: ID_SHA_1,
+            parameters: Some(AnyRef::from(Null).into()),
+        },
+        issuer_name_hash: OctetString::new(Sha1::digest(leaf.issuer).to_vec())?,
+        issuer_key_hash: OctetString::new(Sha1::digest(&issuer.public_key).to_vec())?,
+        serial_number: SerialNumber::new(leaf.serial)?,
+    })
+}
+
+fn request(cert_id: &CertId) -> anyhow::Result<Vec<u8>> {
+    let request = OcspRequest {
+        tbs_request: TbsRequest {
+            version: Version::V1,
+            requestor_name: None,
+            request_list: vec![Request {
+                req_cert: cert_id.clone(),
+                single_request_extensions: None,
+            }],
+            request_extensions: None,
+        },
+        optional_signature: None,
+    };
+    Ok(request.to_der()?)
+}
+
+/// The signature is left to the clients, only the status of the certificate and the validity are read
+fn parse_response(response: Vec<u8>, cert_id: &CertId) -> anyhow::Result<Staple> {
+    let ocsp_response = OcspResponse::from_der(&response).context("invalid ocsp response")?;
+    if ocsp_response.response_status != OcspResponseStatus::Successful {
+        return Err(anyhow!(
+            "the responder answered with the status {:?}",
+            ocsp_response.response_status
+        ));
+    }
+    let bytes = ocsp_response
+        .response_bytes
+        .ok_or_else(|| anyhow!("the response is empty"))?;
+    if bytes.response_type != ID_PKIX_OCSP_BASIC {
+        return Err(anyhow!("the response is not a basic ocsp response"));
+    }
+    let basic = BasicOcspResponse::from_der(bytes.response.as_bytes()).context("invalid basic ocsp response")?;
+
+    // Stapling the response of another certificate would get the handshakes rejected. The parameters of the hash
+    // algorithm are NULL or absent depending on the responders
+    let single = basic
+        .tbs_response_data
+        .responses
+        .into_iter()
+        .find(|single| {
+            single.cert_id.hash_algorithm.oid == cert_id.hash_algorithm.oid
+                && single.cert_id.issuer_name_hash == cert_id.issuer_name_hash
+                && single.cert_id.issuer_key_hash == cert_id.issuer_key_hash
+                && single.cert_id.serial_number == cert_id.serial_number
+        })
+        .ok_or_else(|| anyhow!("the response is not about the certificate"))?;
+    match single.cert_status {
+        CertStatus::Good(_) => {}
+        CertStatus::Revoked(_) => return Err(anyhow!("the certificate has been revoked")),
+        CertStatus::Unknown(_) => return Err(anyhow!("the certificate is unknown to the responder")),
+    }
+    let next_update = single.next_update.map(|next_update| next_update.0.to_system_time());
+
+    Ok(Staple { response, next_update })
+}
+
+async fn fetch(url: &Url, request: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if url.scheme() != "http" {
+        return Err(anyhow!("only http ocsp responders are supported, not {}", url));
+    }
+    let host = url.host_str().ok_or_else(|| anyhow!("no host in {}", url))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let stream = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port))
+        .await
+        .with_context(|| format!("cannot connect to {}:{}", host, port))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let req = hyper::Request::post(path)
+        .header(HOST, url.port().map_or(host.to_string(), |port| format!("{}:{}", host, port)))
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Full::new(Bytes::from(request)))?;
+    let response = sender.send_request(req).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("the responder answered with status {}", response.status()));
+    }
+    Ok(response.into_body().collect().await?.to_bytes().to_vec())
+}
+
+async fn staple(chain: &[CertificateDer<'static>]) -> anyhow::Result<Staple> {
+    let [leaf, issuer, ..] = chain else {
+        return Err(anyhow!(
+            "the certificate chain has no issuer certificate, needed to ask its responder"
+        ));
+    };
+    let (leaf, issuer) = (Certificate::parse(leaf)?, Certificate::parse(issuer)?);
+    let url = leaf
+        .ocsp_url
+        .as_deref()
+        .ok_or_else(|| anyhow!("the certificate has no ocsp responder"))?;
+    let url = Url::parse(url).with_context(|| format!("invalid ocsp responder {}", url))?;
+
+    let cert_id = cert_id(&leaf, &issuer)?;
+    let response = tokio::time::timeout(FETCH_TIMEOUT, fetch(&url, request(&cert_id)?))
+        .await
+        .map_err(|_| anyhow!("timeout while asking {}", url))??;
+    parse_response(response, &cert_id)
+}
+
+/// Keep an ocsp response for the certificate, fetched again before it expires or when the certificate changes.
+/// `reload` is called each time the stapled response changes, so the tls acceptor is rebuilt with it
+pub(super) async fn run_stapler(tls: &TlsServerConfig, changed: Arc<Notify>, reload: impl Fn()) {
+    let mut expires: Option<SystemTime> = None;
+    loop {
+        let chain = tls.tls_certificate.lock().clone();
+        let fetch_in = match staple(&chain).await {
+            Ok(staple) => {
+                let valid_for = staple
+                    .next_update
+                    .and_then(|next_update| next_update.duration_since(SystemTime::now()).ok());
+                match valid_for {
+                    Some(valid_for) => info!(
+                        "Stapling the ocsp response of the tls certificate, valid for {} minutes",
+                        valid_for.as_secs() / 60
+                    ),
+                    None => info!("Stapling the ocsp response of the tls certificate"),
+                }
+                *tls.tls_ocsp_response.lock() = staple.response;
+                expires = staple.next_update;
+                reload();
+
+                valid_for.map_or(DEFAULT_REFRESH, |valid_for| (valid_for / 2).clamp(MIN_REFRESH, MAX_REFRESH))
+            }
+            Err(err) => {
+                warn!("Cannot get the ocsp response of the tls certificate: {:#}", err);
+                // Better no response than an expired one, that browsers reject
+                if expires.is_some_and(|expires| expires <= SystemTime::now()) {
+                    warn!("The stapled ocsp response has expired, it is not stapled anymore");
+                    tls.tls_ocsp_response.lock().clear();
+                    expires = None;
+                    reload();
+                }
+                RETRY_DELAY
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(fetch_in) => {}
+            _ = changed.notified() => expires = None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_cert::der::asn1::{BitString, GeneralizedTime};
+    use x509_ocsp::{OcspGeneralizedTime, ResponderId, ResponseBytes, ResponseData, RevokedInfo, SingleResponse};
+
+    fn time(secs: u64) -> OcspGeneralizedTime {
+        OcspGeneralizedTime(GeneralizedTime::from_unix_duration(Duration::from_secs(secs)).unwrap())
+    }
+
+    fn cert_id(serial: u8) -> CertId {
+        CertId {
+            hash_algorithm: AlgorithmIdentifierOwned {
+                oid: ID_SHA_1,
+                parameters: None,
+            },
+            issuer_name_hash: OctetString::new([1; 20]).unwrap(),
+            issuer_key_hash: OctetString::new([2; 20]).unwrap(),
+            serial_number: SerialNumber::new(&[serial]).unwrap(),
+        }
+    }
+
+    fn response(cert_id: CertId, cert_status: CertStatus) -> Vec<u8> {
+        let basic = BasicOcspResponse {
+            tbs_response_data: ResponseData {
+                version: Version::V1,
+                responder_id: ResponderId::ByKey(OctetString::new([0; 20]).unwrap()),
+                produced_at: time(1704067200),
+                responses: vec![SingleResponse {
+                    cert_id,
+                    cert_status,
+                    this_update: time(1704067200),
+                    next_update: Some(time(1704672000)),
+                    single_extensions: None,
+                }],
+                response_extensions: None,
+            },
+            signature_algorithm: AlgorithmIdentifierOwned {
+                oid: ID_SHA_1,
+                parameters: None,
+            },
+            signature: BitString::from_bytes(&[0; 64]).unwrap(),
+            certs: None,
+        };
+        OcspResponse {
+            response_status: OcspResponseStatus::Successful,
+            response_bytes: Some(ResponseBytes {
+                response_type: ID_PKIX_OCSP_BASIC,
+                response: OctetString::new(basic.to_der().unwrap()).unwrap(),
+            }),
+        }
+        .to_der()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut expected = cert_id(1);
+        expected.hash_algorithm.parameters = Some(AnyRef::from(Null).into());
+        let staple = parse_response(response(cert_id(1), CertStatus::Good(Null)), &expected).unwrap();
+        assert_eq!(
+            staple.next_update,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1704672000))
+        );
+
+        // Of another certificate
+        assert!(parse_response(response(cert_id(2), CertStatus::Good(Null)), &expected).is_err());
+        let revoked = CertStatus::Revoked(RevokedInfo {
+            revocation_time: time(1701388800),
+            revocation_reason: None,
+        });
+        assert!(parse_response(response(cert_id(1), revoked), &expected).is_err());
+        let unauthorized = OcspResponse {
+            response_status: OcspResponseStatus::Unauthorized,
+            response_bytes: None,
+        };
+        assert!(parse_response(unauthorized.to_der().unwrap(), &expected).is_err());
+        assert!(request(&expected).is_ok());
+    }
+}
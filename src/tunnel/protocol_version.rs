@@ -0,0 +1,115 @@
+use std::fmt::{Display, Formatter};
+
+use anyhow::anyhow;
+use hyper::header::HeaderValue;
+
+/// Version of the tunnel protocol, i.e: the framing of the websocket messages. The client advertises the versions it
+/// supports in the Sec-WebSocket-Protocol header of the upgrade request, along with its token
+/// (i.e: `v2, v1, authorization.bearer.JWT`), and the server answers with the one it picked.
+/// A new version is added for each change of the framing that older peers would not understand:
+///  v1 data frames, and the eof and reset text frames once acknowledged by their response header
+///  v2 control frames too, see ControlMessage
+/// Which one the peers use:
+///  client \ server | v1  | v1,v2
+///  v1              | v1  | v1
+///  v1,v2           | v1  | v2
+///  none advertised | v1  | v1
+/// A client advertising no version is one that predates the negotiation, it speaks v1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ProtocolVersion(u16);
+
+impl ProtocolVersion {
+    pub const V1: Self = Self(1);
+    pub const V2: Self = Self(2);
+
+    fn parse(token: &str) -> Option<Self> {
+        let version = token.strip_prefix('v')?;
+        if version.starts_with('0') || !version.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        version.parse().ok().map(Self)
+    }
+
+    pub fn has_control_frames(self) -> bool {
+        self.0 >= Self::V2.0
+    }
+
+    pub fn header_value(self) -> HeaderValue {
+        HeaderValue::from_str(&self.to_string()).unwrap()
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// Versions supported by this build, from the preferred one
+const SUPPORTED: [ProtocolVersion; 2] = [ProtocolVersion::V2, ProtocolVersion::V1];
+
+fn advertised(header: &str) -> impl Iterator<Item = ProtocolVersion> + '_ {
+    header
+        .split(',')
+        .filter_map(|token| ProtocolVersion::parse(token.trim()))
+}
+
+/// Versions advertised by the client, to put before its token in the Sec-WebSocket-Protocol header
+pub(super) fn client_header() -> String {
+    SUPPORTED.iter().map(|version| format!("{}, ", version)).collect()
+}
+
+/// The preferred version of the server among the ones advertised by the client, none if they have none in common
+pub(super) fn negotiate(header: Option<&str>) -> Option<ProtocolVersion> {
+    let advertised: Vec<_> = advertised(header.unwrap_or_default()).collect();
+    if advertised.is_empty() {
+        return Some(ProtocolVersion::V1);
+    }
+    SUPPORTED.into_iter().find(|version| advertised.contains(version))
+}
+
+/// The version picked by the server, when it is one the client advertised. v1 without one, the header may have been
+/// dropped by a proxy in front of an older server
+pub(super) fn accepted(header: Option<&HeaderValue>) -> anyhow::Result<ProtocolVersion> {
+    let Some(header) = header else {
+        return Ok(ProtocolVersion::V1);
+    };
+    let header = header.to_str().unwrap_or_default();
+    let mut picked = advertised(header);
+    match (picked.next(), picked.next()) {
+        (Some(version), None) if SUPPORTED.contains(&version) => Ok(version),
+        _ => Err(anyhow!(
+            "the server answered with an unsupported protocol version {:?}, the client supports {}",
+            header,
+            client_header().trim_end_matches(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(client_header(), "v2, v1, ");
+        assert_eq!(negotiate(Some("v2, v1, authorization.bearer.xxx")), Some(ProtocolVersion::V2));
+        assert_eq!(negotiate(Some("v1, v2, authorization.bearer.xxx")), Some(ProtocolVersion::V2));
+        assert_eq!(negotiate(Some("v1, authorization.bearer.xxx")), Some(ProtocolVersion::V1));
+        assert_eq!(negotiate(Some("v9, v1, authorization.bearer.xxx")), Some(ProtocolVersion::V1));
+        assert_eq!(negotiate(Some("authorization.bearer.xxx")), Some(ProtocolVersion::V1));
+        assert_eq!(negotiate(None), Some(ProtocolVersion::V1));
+        assert_eq!(negotiate(Some("v9, authorization.bearer.xxx")), None);
+        assert_eq!(negotiate(Some("v01, v1a, v")), Some(ProtocolVersion::V1));
+
+        assert_eq!(accepted(Some(&HeaderValue::from_static("v1"))).unwrap(), ProtocolVersion::V1);
+        assert!(accepted(Some(&HeaderValue::from_static("v9"))).is_err());
+        assert!(accepted(Some(&HeaderValue::from_static("v1, v9"))).is_err());
+        assert_eq!(accepted(None).unwrap(), ProtocolVersion::V1);
+        assert!(accepted(Some(&HeaderValue::from_static("v2")))
+            .unwrap()
+            .has_control_frames());
+        assert!(!ProtocolVersion::V1.has_control_frames());
+        assert_eq!(ProtocolVersion::V1.header_value(), "v1");
+    }
+}
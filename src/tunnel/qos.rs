@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::Qos;
+
+// Bytes per second relayed by all the tunnels together, in both directions. 0 when there is no limit
+static MAX_BANDWIDTH: AtomicU64 = AtomicU64::new(0);
+static BUCKET: Lazy<Mutex<Bucket>> = Lazy::new(|| Mutex::new(Bucket::new(Instant::now())));
+
+// Bytes that can be relayed in a burst after an idle period, at least the size of a read of the tunnels
+const BURST: Duration = Duration::from_millis(50);
+const MIN_BURST: i128 = 64 * 1024;
+
+pub fn set_max_bandwidth(bytes_per_sec: u64) {
+    MAX_BANDWIDTH.store(bytes_per_sec, Ordering::Relaxed);
+}
+
+/// Token bucket shared by the tunnels. Interactive tunnels always take their bytes, and wait for the debt they
+/// leave. Bulk tunnels only take theirs when the bucket has enough, that interactive ones have not taken first
+struct Bucket {
+    tokens: i128,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: 0,
+            updated_at: now,
+        }
+    }
+
+    /// How long to wait after taking the bytes, or Err with how long to wait before trying again
+    fn take(&mut self, qos: Qos, len: usize, rate: u64, now: Instant) -> Result<Duration, Duration> {
+        let rate = rate as i128;
+        let capacity = (rate * BURST.as_nanos() as i128 / 1_000_000_000).max(MIN_BURST);
+        // Only the time turned into whole bytes is consumed, so frequent calls do not lose the rest
+        let refill = now.duration_since(self.updated_at).as_nanos() as i128 * rate / 1_000_000_000;
+        if self.tokens + refill >= capacity {
+            self.tokens = capacity;
+            self.updated_at = now;
+        } else {
+            self.tokens += refill;
+            self.updated_at += Duration::from_nanos((refill * 1_000_000_000 / rate) as u64);
+        }
+
+        let wait = |missing: i128| Duration::from_nanos((missing.max(0) * 1_000_000_000 / rate) as u64);
+        let len = len as i128;
+        if qos == Qos::Bulk && self.tokens < len.min(capacity) {
+            return Err(wait(len.min(capacity) - self.tokens));
+        }
+        self.tokens -= len;
+        Ok(wait(-self.tokens))
+    }
+}
+
+/// Wait for the bandwidth to relay len bytes
+pub(super) async fn throttle(qos: Qos, len: usize) {
+    let rate = MAX_BANDWIDTH.load(Ordering::Relaxed);
+    if rate == 0 {
+        return;
+    }
+
+    loop {
+        let ret = BUCKET.lock().take(qos, len, rate, Instant::now());
+        match ret {
+            Ok(wait) => {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                return;
+            }
+            Err(wait) => tokio::time::sleep(wait).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let rate = 1_000_000;
+        let mut bucket = Bucket::new(now);
+        let after = |ms| now + Duration::from_millis(ms);
+
+        // Full after an idle period, the burst is 64KB
+        assert_eq!(bucket.take(Qos::Bulk, 1000, rate, after(100)), Ok(Duration::ZERO));
+        assert_eq!(
+            bucket.take(Qos::Interactive, 100_000, rate, after(100)),
+            Ok(Duration::from_micros(35_464))
+        );
+        // Bulk waits for the debt of the interactive tunnel to be paid
+        assert_eq!(
+            bucket.take(Qos::Bulk, 1000, rate, after(100)),
+            Err(Duration::from_micros(36_464))
+        );
+        assert_eq!(
+            bucket.take(Qos::Interactive, 1000, rate, after(100)),
+            Ok(Duration::from_micros(36_464))
+        );
+        assert!(bucket.take(Qos::Bulk, 1000, rate, after(150)).is_ok());
+    }
+}
@@ -0,0 +1,159 @@
+use ahash::{HashMap, HashMapExt};
+use parking_lot::Mutex;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Avoid scanning the whole map on every connection
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct Buckets<K> {
+    by_key: HashMap<K, Bucket>,
+    pruned_at: Instant,
+}
+
+/// Token bucket per key, each event takes a token and tokens come back at a fixed rate
+pub(super) struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets<K>>,
+}
+
+/// Per source ip, each connection takes a token
+pub(super) type HandshakeLimiter = RateLimiter<IpAddr>;
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(rate_per_sec: u32, burst: u32) -> Self {
+        Self {
+            rate: rate_per_sec as f64,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::with_capacity(0),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn try_acquire(&self, key: K) -> bool {
+        let now = Instant::now();
+        let (rate, burst) = (self.rate, self.burst);
+        let refill =
+            |bucket: &Bucket| (bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate).min(burst);
+
+        let mut buckets = self.buckets.lock();
+        // Full buckets are the same as no bucket at all
+        if now.duration_since(buckets.pruned_at) >= PRUNE_INTERVAL {
+            buckets.by_key.retain(|_, bucket| refill(bucket) < burst);
+            buckets.pruned_at = now;
+        }
+
+        let bucket = buckets.by_key.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// Connections of each ip that are not yet upgraded to websocket
+pub(super) struct PendingUpgrades {
+    max: usize,
+    by_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Counted as pending until dropped
+pub(super) struct PendingUpgrade {
+    pending: Arc<PendingUpgrades>,
+    ip: IpAddr,
+}
+
+impl PendingUpgrades {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            by_ip: Mutex::new(HashMap::with_capacity(0)),
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<PendingUpgrade> {
+        let ip = ip.to_canonical();
+        let mut by_ip = self.by_ip.lock();
+        let count = by_ip.get(&ip).copied().unwrap_or(0);
+        if count >= self.max {
+            return None;
+        }
+
+        by_ip.insert(ip, count + 1);
+        Some(PendingUpgrade {
+            pending: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for PendingUpgrade {
+    fn drop(&mut self) {
+        let mut by_ip = self.pending.by_ip.lock();
+        if let Some(count) = by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_limiter() {
+        let limiter = HandshakeLimiter::new(2, 3);
+        let ip: IpAddr = "192.168.1.1".parse().unwrap();
+        let other_ip: IpAddr = "192.168.1.2".parse().unwrap();
+
+        assert!((0..3).all(|_| limiter.try_acquire(ip)));
+        assert!(!limiter.try_acquire(ip));
+        assert!(limiter.try_acquire(other_ip));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.try_acquire(ip));
+        assert!(!limiter.try_acquire(ip));
+
+        // Idle ips are forgotten once their bucket is full again
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(limiter.try_acquire(ip));
+        assert_eq!(limiter.buckets.lock().by_key.len(), 1);
+    }
+
+    #[test]
+    fn test_pending_upgrades() {
+        let pending = Arc::new(PendingUpgrades::new(2));
+        let ip: IpAddr = "192.168.1.1".parse().unwrap();
+
+        let first = pending.try_acquire(ip).unwrap();
+        let second = pending.try_acquire(ip).unwrap();
+        assert!(pending.try_acquire(ip).is_none());
+        assert!(pending.try_acquire("192.168.1.2".parse().unwrap()).is_some());
+
+        drop(first);
+        assert!(pending.try_acquire(ip).is_some());
+        drop(second);
+        assert!(pending.by_ip.lock().is_empty());
+    }
+}
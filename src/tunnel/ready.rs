@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use fastwebsockets::{Frame, OpCode, Payload, WebSocket};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Response header of the server waiting for the ready frame of the client, before relaying the visitor of a
+/// reverse listener. Older servers do not send it, and do not expect the frame
+pub(super) static READY_ACK_HEADER: &str = "x-wstunnel-ready-ack";
+const READY: &[u8] = b"ready";
+/// The visitor goes back to its listener past this, for the next tunnel
+pub(super) const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent by the client as soon as the websocket is upgraded, before anything else
+pub(super) async fn send_ready<S>(ws: &mut WebSocket<S>) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    ws.write_frame(Frame::text(Payload::Borrowed(READY))).await?;
+    Ok(())
+}
+
+/// The upgrade response has reached the client, and its websocket works both ways
+pub(super) async fn wait_ready<S>(ws: &mut WebSocket<S>, timeout: Duration) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let frame = tokio::time::timeout(timeout, ws.read_frame())
+        .await
+        .map_err(|_| anyhow!("no ready frame from the client after {:?}", timeout))??;
+    if frame.opcode != OpCode::Text || &frame.payload[..] != READY {
+        return Err(anyhow!(
+            "expected a ready frame from the client, got a {:?} frame",
+            frame.opcode
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastwebsockets::Role;
+
+    #[tokio::test]
+    async fn test_wait_ready() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WebSocket::after_handshake(client, Role::Client);
+        let mut server = WebSocket::after_handshake(server, Role::Server);
+        send_ready(&mut client).await.unwrap();
+        wait_ready(&mut server, READY_TIMEOUT).await.unwrap();
+
+        client
+            .write_frame(Frame::binary(Payload::Borrowed(READY)))
+            .await
+            .unwrap();
+        assert!(wait_ready(&mut server, READY_TIMEOUT).await.is_err());
+        assert!(wait_ready(&mut server, Duration::from_millis(10)).await.is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use slab::Slab;
+use tokio::time::Instant;
+use tracing::Span;
+
+use crate::metrics::OpenTunnel;
+
+/// What is known of an open tunnel
+#[derive(Debug, Clone)]
+pub(super) struct TunnelEntry {
+    pub destination: String,
+    pub opened_at: Instant,
+}
+
+static TUNNELS: Lazy<Mutex<Slab<TunnelEntry>>> = Lazy::new(|| Mutex::new(Slab::new()));
+
+/// Tunnel registered until dropped. Its id, shown as the tunnel field of its logs, does not change while it is open
+/// and is given to another tunnel once it is closed
+pub(super) struct RegisteredTunnel {
+    id: usize,
+    _open: OpenTunnel,
+}
+
+impl Drop for RegisteredTunnel {
+    fn drop(&mut self) {
+        TUNNELS.lock().remove(self.id);
+    }
+}
+
+/// None if there are already max tunnels open
+pub(super) fn register(destination: String, max: Option<usize>) -> Option<RegisteredTunnel> {
+    let mut tunnels = TUNNELS.lock();
+    if max.is_some_and(|max| tunnels.len() >= max) {
+        return None;
+    }
+
+    let id = tunnels.insert(TunnelEntry {
+        destination,
+        opened_at: Instant::now(),
+    });
+    Span::current().record("tunnel", id);
+    Some(RegisteredTunnel {
+        id,
+        _open: OpenTunnel::new(),
+    })
+}
+
+pub(super) fn count() -> usize {
+    TUNNELS.lock().len()
+}
+
+/// Tunnels currently open, by id
+pub(super) fn snapshot() -> Vec<(usize, TunnelEntry)> {
+    TUNNELS.lock().iter().map(|(id, entry)| (id, entry.clone())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let a = register("a:1".to_string(), None).unwrap();
+        let b = register("b:2".to_string(), None).unwrap();
+        assert_ne!(a.id, b.id);
+        assert!(register("c:3".to_string(), Some(count())).is_none());
+
+        let id = a.id;
+        drop(a);
+        assert!(!snapshot().iter().any(|(tunnel, _)| *tunnel == id));
+        let c = register("c:3".to_string(), None).unwrap();
+        assert_eq!(
+            snapshot()
+                .iter()
+                .find(|(tunnel, _)| *tunnel == c.id)
+                .unwrap()
+                .1
+                .destination,
+            "c:3"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::debug;
+
+// Each datagram sent over the websocket is prefixed with its kind and its sequence number
+pub(super) const HEADER_LEN: usize = 9;
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+
+pub(super) const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_RETRANSMIT: u8 = 3;
+// Datagrams not acknowledged when the window is full are considered lost
+const WINDOW_SIZE: usize = 1024;
+
+struct Unacked {
+    datagram: Vec<u8>,
+    sent_at: Instant,
+    retransmit: u8,
+}
+
+/// Sending half of the reliability layer, lives with the websocket tx
+pub(super) struct ReliableTx {
+    next_seq: u64,
+    unacked: Arc<Mutex<BTreeMap<u64, Unacked>>>,
+    acks: mpsc::UnboundedReceiver<u64>,
+}
+
+/// Receiving half of the reliability layer, lives with the websocket rx
+pub(super) struct ReliableRx {
+    unacked: Arc<Mutex<BTreeMap<u64, Unacked>>>,
+    acks: mpsc::UnboundedSender<u64>,
+    // Every sequence below floor has been received
+    floor: u64,
+    received: BTreeSet<u64>,
+}
+
+pub(super) fn new() -> (ReliableTx, ReliableRx) {
+    let unacked = Arc::new(Mutex::new(BTreeMap::new()));
+    let (acks_tx, acks_rx) = mpsc::unbounded_channel();
+    let tx = ReliableTx {
+        next_seq: 0,
+        unacked: unacked.clone(),
+        acks: acks_rx,
+    };
+    let rx = ReliableRx {
+        unacked,
+        acks: acks_tx,
+        floor: 0,
+        received: BTreeSet::new(),
+    };
+
+    (tx, rx)
+}
+
+fn header(kind: u8, seq: u64) -> [u8; HEADER_LEN] {
+    let mut header = [kind; HEADER_LEN];
+    header[1..].copy_from_slice(&seq.to_be_bytes());
+    header
+}
+
+pub(super) fn ack_frame(seq: u64) -> Vec<u8> {
+    header(KIND_ACK, seq).to_vec()
+}
+
+impl ReliableTx {
+    /// Write the header in front of the datagram, which must be located after HEADER_LEN bytes of buf,
+    /// and keep it until the peer acknowledges it
+    pub fn seal(&mut self, buf: &mut [u8]) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        buf[..HEADER_LEN].copy_from_slice(&header(KIND_DATA, seq));
+
+        let mut unacked = self.unacked.lock();
+        if unacked.len() >= WINDOW_SIZE {
+            unacked.pop_first();
+        }
+        unacked.insert(
+            seq,
+            Unacked {
+                datagram: buf.to_vec(),
+                sent_at: Instant::now(),
+                retransmit: 0,
+            },
+        );
+    }
+
+    /// Sequence number of the next datagram we must acknowledge to the peer
+    pub async fn next_ack(&mut self) -> Option<u64> {
+        self.acks.recv().await
+    }
+
+    /// Datagrams whose ack did not come back in time
+    pub fn retransmissions(&mut self) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut datagrams = vec![];
+        self.unacked.lock().retain(|seq, unacked| {
+            if now.duration_since(unacked.sent_at) < RETRANSMIT_TIMEOUT {
+                return true;
+            }
+            if unacked.retransmit >= MAX_RETRANSMIT {
+                debug!("giving up on udp datagram {}", seq);
+                return false;
+            }
+
+            unacked.retransmit += 1;
+            unacked.sent_at = now;
+            datagrams.push(unacked.datagram.clone());
+            true
+        });
+
+        datagrams
+    }
+}
+
+impl ReliableRx {
+    /// Handle a frame received from the peer, returns the datagram to forward locally if there is one
+    pub fn open<'a>(&mut self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+        let seq = u64::from_be_bytes(frame[1..HEADER_LEN].try_into().unwrap());
+
+        match frame[0] {
+            KIND_ACK => {
+                self.unacked.lock().remove(&seq);
+                None
+            }
+            KIND_DATA => {
+                // Always ack, as our previous ack may be the one that has been lost
+                let _ = self.acks.send(seq);
+                if seq < self.floor || !self.received.insert(seq) {
+                    return None;
+                }
+
+                while self.received.remove(&self.floor) {
+                    self.floor += 1;
+                }
+                // Lost datagrams will never come, do not keep waiting for them forever
+                while self.received.len() > WINDOW_SIZE {
+                    self.floor = self.received.pop_first().unwrap_or(self.floor) + 1;
+                }
+
+                Some(&frame[HEADER_LEN..])
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(tx: &mut ReliableTx, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN];
+        buf.extend_from_slice(payload);
+        tx.seal(&mut buf);
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_ack_and_dedup() {
+        let (mut client_tx, mut client_rx) = new();
+        let (mut server_tx, mut server_rx) = new();
+
+        let first = data(&mut client_tx, b"hello");
+        let second = data(&mut client_tx, b"world");
+        assert_eq!(server_rx.open(&second), Some(b"world".as_slice()));
+        assert_eq!(server_rx.open(&first), Some(b"hello".as_slice()));
+        assert_eq!(server_rx.open(&second), None);
+        assert_eq!(server_rx.floor, 2);
+
+        assert_eq!(server_tx.next_ack().await, Some(1));
+        assert_eq!(server_tx.next_ack().await, Some(0));
+        assert_eq!(client_rx.open(&ack_frame(1)), None);
+        assert_eq!(client_tx.unacked.lock().keys().copied().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retransmit() {
+        let (mut tx, _rx) = new();
+        let datagram = data(&mut tx, b"hello");
+        assert!(tx.retransmissions().is_empty());
+
+        for _ in 0..MAX_RETRANSMIT {
+            tokio::time::advance(RETRANSMIT_TIMEOUT).await;
+            assert_eq!(tx.retransmissions(), vec![datagram.clone()]);
+        }
+
+        tokio::time::advance(RETRANSMIT_TIMEOUT).await;
+        assert!(tx.retransmissions().is_empty());
+        assert!(tx.unacked.lock().is_empty());
+    }
+}
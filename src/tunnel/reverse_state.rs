@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// How long the bindings of the previous run stay reserved for their owner, waiting for it to reconnect
+pub(super) const RESERVATION: Duration = Duration::from_secs(5 * 60);
+const VERSION: u32 = 1;
+
+/// What is needed to bind the listener of a reverse tunnel again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
+pub(super) enum BindingKind {
+    Tcp { tls: bool },
+    Udp { timeout: Option<Duration> },
+    Socks5,
+}
+
+impl BindingKind {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            BindingKind::Tcp { .. } => "tcp",
+            BindingKind::Udp { .. } => "udp",
+            BindingKind::Socks5 => "socks5",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Binding {
+    #[serde(flatten)]
+    pub kind: BindingKind,
+    pub host: String,
+    pub port: u16,
+    /// Identity of the client certificate of the client. Anyone can take it without
+    pub owner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    bindings: Vec<Binding>,
+}
+
+struct State {
+    path: PathBuf,
+    bindings: Mutex<Vec<Binding>>,
+}
+
+static STATE: OnceCell<State> = OnceCell::new();
+
+/// Save the listeners of the reverse tunnels in this file, so they are bound again, for the same clients, after a
+/// restart of the server. It is loaded now if it exists
+pub fn enable_reverse_state(path: PathBuf) -> anyhow::Result<()> {
+    let bindings = match fs::read(&path) {
+        Ok(state) => parse(&state).with_context(|| format!("invalid reverse tunnel state file {:?}", path))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err).with_context(|| format!("cannot read reverse tunnel state file {:?}", path)),
+    };
+    let _ = STATE.set(State {
+        path,
+        bindings: Mutex::new(bindings),
+    });
+    Ok(())
+}
+
+fn parse(state: &[u8]) -> anyhow::Result<Vec<Binding>> {
+    let state: StateFile = serde_json::from_slice(state)?;
+    if state.version != VERSION {
+        return Err(anyhow!("unsupported version {}, expected {}", state.version, VERSION));
+    }
+    Ok(state.bindings)
+}
+
+pub(super) fn is_enabled() -> bool {
+    STATE.get().is_some()
+}
+
+/// Bindings of the previous run, to be bound again at startup
+pub(super) fn bindings() -> Vec<Binding> {
+    STATE
+        .get()
+        .map(|state| state.bindings.lock().clone())
+        .unwrap_or_default()
+}
+
+pub(super) fn add(binding: &Binding) {
+    let Some(state) = STATE.get() else {
+        return;
+    };
+    let mut bindings = state.bindings.lock();
+    if !bindings.contains(binding) {
+        bindings.push(binding.clone());
+        save(&state.path, &bindings);
+    }
+}
+
+pub(super) fn remove(binding: &Binding) {
+    let Some(state) = STATE.get() else {
+        return;
+    };
+    let mut bindings = state.bindings.lock();
+    let len = bindings.len();
+    bindings.retain(|b| b != binding);
+    if bindings.len() != len {
+        save(&state.path, &bindings);
+    }
+}
+
+fn save(path: &Path, bindings: &[Binding]) {
+    let state = StateFile {
+        version: VERSION,
+        bindings: bindings.to_vec(),
+    };
+    // Through a temporary file, to never leave a truncated state if the server is killed meanwhile
+    let tmp = path.with_extension("tmp");
+    let ret = serde_json::to_vec_pretty(&state)
+        .map_err(anyhow::Error::from)
+        .and_then(|state| Ok(fs::write(&tmp, state)?))
+        .and_then(|_| Ok(fs::rename(&tmp, path)?));
+    if let Err(err) = ret {
+        warn!("Cannot save the reverse tunnel state in {:?}: {:#}", path, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let state = br#"{"version":1,"bindings":[
+            {"protocol":"tcp","tls":true,"host":"0.0.0.0","port":8443,"owner":"alice@example.com"},
+            {"protocol":"udp","timeout":null,"host":"[::]","port":53,"owner":null},
+            {"protocol":"socks5","host":"127.0.0.1","port":1080,"owner":"bob"}
+        ]}"#;
+        let bindings = parse(state).unwrap();
+        assert_eq!(bindings.len(), 3);
+        assert_eq!(bindings[0].kind, BindingKind::Tcp { tls: true });
+        assert_eq!(bindings[1].kind.protocol(), "udp");
+        assert_eq!(bindings[2].owner.as_deref(), Some("bob"));
+
+        let saved = serde_json::to_vec(&StateFile {
+            version: VERSION,
+            bindings: bindings.clone(),
+        })
+        .unwrap();
+        assert_eq!(parse(&saved).unwrap(), bindings);
+        assert!(parse(br#"{"version":2,"bindings":[]}"#).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use arc_swap::ArcSwap;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use once_cell::sync::OnceCell;
+use tracing::{error, info, warn};
+
+use crate::dns;
+
+type Destinations = HashMap<String, (String, u16)>;
+
+struct RewriteMap {
+    // Swapped by the watcher, the tunnels only read the last snapshot instead of touching the file
+    destinations: Arc<ArcSwap<Destinations>>,
+    _watcher: RecommendedWatcher,
+}
+
+static REWRITE_MAP: OnceCell<RewriteMap> = OnceCell::new();
+
+/// Connect the tunnels to the destinations of this file instead of the ones asked by the clients. One
+/// `HOST:PORT -> HOST:PORT` per line, i.e: `db.prod:5432 -> 10.1.2.3:6432`. It is read again once modified
+pub fn enable_rewrite_map(path: PathBuf) -> anyhow::Result<()> {
+    let _ = REWRITE_MAP.set(RewriteMap::new(path)?);
+    Ok(())
+}
+
+impl RewriteMap {
+    fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let destinations = load(&path)?;
+        info!("Rewriting {} destinations from {:?}", destinations.len(), path);
+        let destinations = Arc::new(ArcSwap::from_pointee(destinations));
+
+        // The directory is watched, not the file, for the map to be reloaded when an editor replaces it
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut watcher = notify::recommended_watcher({
+            let destinations = destinations.clone();
+            move |event: notify::Result<notify::Event>| match event {
+                Ok(event) => Self::handle_fs_event(&path, &destinations, event),
+                Err(err) => error!("Error while watching the destination rewrite map for changes {:?}", err),
+            }
+        })
+        .with_context(|| "Cannot create destination rewrite map watcher")?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            destinations,
+            _watcher: watcher,
+        })
+    }
+
+    fn handle_fs_event(path: &Path, destinations: &ArcSwap<Destinations>, event: notify::Event) {
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            || !event.paths.iter().any(|p| p.file_name() == path.file_name())
+        {
+            return;
+        }
+
+        // The previous destinations are kept until the file is fixed
+        match load(path) {
+            Ok(loaded) => {
+                if **destinations.load() != loaded {
+                    info!("Rewriting {} destinations from {:?}", loaded.len(), path);
+                    destinations.store(Arc::new(loaded));
+                }
+            }
+            Err(err) => warn!("Cannot reload the destination rewrite map: {:#}", err),
+        }
+    }
+
+    fn get(&self, host: &str, port: u16) -> Option<(String, u16)> {
+        self.destinations
+            .load()
+            .get(&format!("{}:{}", host.to_ascii_lowercase(), port))
+            .cloned()
+    }
+}
+
+fn load(path: &Path) -> anyhow::Result<Destinations> {
+    let map = fs::read_to_string(path).with_context(|| format!("cannot read destination rewrite map {:?}", path))?;
+    parse(&map).with_context(|| format!("invalid destination rewrite map {:?}", path))
+}
+
+fn parse_destination(dest: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = dest
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("invalid destination {}, expected HOST:PORT", dest))?;
+    dns::parse_host(host).with_context(|| format!("invalid host of destination {}", dest))?;
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port of destination {}", dest))?;
+    Ok((host.to_string(), port))
+}
+
+fn parse(map: &str) -> anyhow::Result<Destinations> {
+    let mut destinations = HashMap::new();
+    for (nb, line) in map.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (requested, actual) = line
+            .split_once("->")
+            .ok_or_else(|| anyhow!("line {}: expected HOST:PORT -> HOST:PORT", nb + 1))?;
+        let (host, port) = parse_destination(requested.trim()).with_context(|| format!("line {}", nb + 1))?;
+        let actual = parse_destination(actual.trim()).with_context(|| format!("line {}", nb + 1))?;
+        if destinations
+            .insert(format!("{}:{}", host.to_ascii_lowercase(), port), actual)
+            .is_some()
+        {
+            return Err(anyhow!("line {}: {}:{} is already rewritten", nb + 1, host, port));
+        }
+    }
+    Ok(destinations)
+}
+
+/// The destination to connect to instead of this one, if any
+pub(super) fn rewrite(host: &str, port: u16) -> Option<(String, u16)> {
+    REWRITE_MAP.get()?.get(host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let map = "
+            # Moved to the new cluster
+            db.prod:5432 -> 10.1.2.3:6432
+            Cache.Prod:6379->[fd00::1]:6379 # ipv6 only
+        ";
+        let destinations = parse(map).unwrap();
+        assert_eq!(destinations.len(), 2);
+        assert_eq!(destinations["db.prod:5432"], ("10.1.2.3".to_string(), 6432));
+        assert_eq!(destinations["cache.prod:6379"], ("[fd00::1]".to_string(), 6379));
+
+        assert!(parse("db.prod:5432 10.1.2.3:6432").is_err());
+        assert!(parse("db.prod -> 10.1.2.3:6432").is_err());
+        assert!(parse("db.prod:5432 -> 10.1.2.3:http").is_err());
+        assert!(parse("db.prod:5432 -> 10.1.2.3:1\ndb.prod:5432 -> 10.1.2.3:2").is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-rewrite-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rewrite.map");
+        fs::write(&path, "db.prod:5432 -> 10.1.2.3:6432").unwrap();
+
+        let map = RewriteMap::new(path.clone()).unwrap();
+        assert_eq!(map.get("DB.prod", 5432), Some(("10.1.2.3".to_string(), 6432)));
+        assert_eq!(map.get("db.prod", 5433), None);
+
+        // Replaced like an editor does, then broken: the last valid destinations are kept
+        fs::write(dir.join("rewrite.map.tmp"), "db.prod:5432 -> 10.1.2.4:6432").unwrap();
+        fs::rename(dir.join("rewrite.map.tmp"), &path).unwrap();
+        let reloaded = || map.get("db.prod", 5432) == Some(("10.1.2.4".to_string(), 6432));
+        for _ in 0..50 {
+            if reloaded() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert!(reloaded());
+        fs::write(&path, "db.prod:5432 ->").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(reloaded());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,530 @@
+use crate::{sni, tcp, HttpRewrite};
+use ahash::{HashMap, HashMapExt};
+use anyhow::anyhow;
+use bytes::Bytes;
+use futures_util::{pin_mut, StreamExt};
+use http_body_util::{Either, Full};
+use hyper::body::Incoming;
+use hyper::client::conn::http1::SendRequest;
+use hyper::header::{HeaderValue, CONNECTION, HOST, LOCATION, UPGRADE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{http, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{error, info, span, warn, Instrument, Level, Span};
+use url::Url;
+
+/// Sender to the tunnel waiting on a route, its metadata and its owner
+type Route<T, M> = (mpsc::Sender<T>, M, Option<String>);
+
+/// Routing table between connections received on a public listener and the reverse tunnels
+/// that registered for their hostname. A route can be a wildcard (i.e: *.alice.example.com)
+/// A route belongs to the identity of the client certificate of the tunnels that registered it, if any
+pub struct HostnameRoutes<T, M> {
+    name: &'static str,
+    routes: Mutex<HashMap<String, Route<T, M>>>,
+    servers: Mutex<HashMap<String, mpsc::Receiver<T>>>,
+}
+
+impl<T, M: Clone> HostnameRoutes<T, M> {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            routes: Mutex::new(HashMap::with_capacity(0)),
+            servers: Mutex::new(HashMap::with_capacity(0)),
+        }
+    }
+
+    /// Register (or refresh) the route for this hostname, and wait for a connection to be routed to it
+    pub async fn wait_for_connection(
+        &self,
+        hostname: &str,
+        owner: Option<&str>,
+        route_metadata: M,
+    ) -> anyhow::Result<T> {
+        // Anyone could claim the hostnames no one else has a route for
+        if hostname.starts_with("*.") && owner.is_none() {
+            return Err(anyhow!(
+                "{} route for {} requires a client certificate, as a wildcard",
+                self.name,
+                hostname
+            ));
+        }
+
+        let listening_server = self.servers.lock().remove(hostname);
+        let mut listening_server = if let Some(listening_server) = listening_server {
+            let is_owner = match self.routes.lock().get_mut(hostname) {
+                Some(route) if route.2.as_deref() != owner => false,
+                Some(route) => {
+                    route.1 = route_metadata;
+                    true
+                }
+                None => true,
+            };
+            if !is_owner {
+                self.servers.lock().insert(hostname.to_string(), listening_server);
+                return Err(anyhow!("{} route for {} belongs to another client", self.name, hostname));
+            }
+            listening_server
+        } else {
+            let mut routes = self.routes.lock();
+            // A tunnel is waiting on the route, another client must not take over its connections
+            if routes.get(hostname).is_some_and(|(tx, _, _)| !tx.is_closed()) {
+                return Err(anyhow!("{} route for {} is already in use", self.name, hostname));
+            }
+            info!("Registering {} route for {}", self.name, hostname);
+            let (tx, rx) = mpsc::channel::<T>(1);
+            routes.insert(hostname.to_string(), (tx, route_metadata, owner.map(str::to_string)));
+            rx
+        };
+
+        let cnx = listening_server
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("{} route for {} has been removed", self.name, hostname))?;
+        self.servers.lock().insert(hostname.to_string(), listening_server);
+        Ok(cnx)
+    }
+
+    /// Hand over the connection to the most specific route matching the hostname
+    pub async fn dispatch(&self, hostname: &str, cnx: T) -> anyhow::Result<M> {
+        let route = {
+            let routes = self.routes.lock();
+            sni::hostname_route_candidates(hostname).find_map(|key| Some((routes.get(&key)?.clone(), key)))
+        };
+        let Some(((route, route_metadata, _), route_key)) = route else {
+            return Err(anyhow!("no reverse tunnel registered for {}", hostname));
+        };
+
+        if route.send_timeout(cnx, Duration::from_secs(30)).await.is_err() {
+            let mut routes = self.routes.lock();
+            if routes.get(&route_key).is_some_and(|(r, _, _)| r.same_channel(&route)) {
+                routes.remove(&route_key);
+            }
+            return Err(anyhow!("reverse tunnel for {} is not available", hostname));
+        }
+
+        Ok(route_metadata)
+    }
+}
+
+pub static SNI_ROUTES: Lazy<HostnameRoutes<TcpStream, ()>> = Lazy::new(|| HostnameRoutes::new("sni"));
+pub static HTTP_ROUTES: Lazy<HostnameRoutes<DuplexStream, HttpRewrite>> = Lazy::new(|| HostnameRoutes::new("http"));
+
+pub async fn run_sni_router(bind: SocketAddr) -> anyhow::Result<()> {
+    let listener = tcp::run_server(bind, false).await?;
+    pin_mut!(listener);
+
+    while let Some(cnx) = listener.next().await {
+        let stream = match cnx {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Error while accepting sni router connection {:?}", err);
+                continue;
+            }
+        };
+
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        let fut = async move {
+            let hostname = match sni::peek_sni(&stream, Duration::from_secs(10)).await {
+                Ok(hostname) => hostname,
+                Err(err) => {
+                    warn!("Rejecting sni router connection: {:?}", err);
+                    return;
+                }
+            };
+            Span::current().record("remote", &hostname);
+
+            if let Err(err) = SNI_ROUTES.dispatch(&hostname, stream).await {
+                warn!("Rejecting sni router connection, {}", err);
+            }
+        }
+        .instrument(span!(Level::INFO, "sni_router", peer = peer, remote = tracing::field::Empty));
+
+        tokio::spawn(fut);
+    }
+
+    Ok(())
+}
+
+pub async fn run_http_router(bind: SocketAddr) -> anyhow::Result<()> {
+    let listener = tcp::run_server(bind, false).await?;
+    pin_mut!(listener);
+
+    while let Some(cnx) = listener.next().await {
+        let stream = match cnx {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Error while accepting http router connection {:?}", err);
+                continue;
+            }
+        };
+
+        let Ok(peer) = stream.peer_addr() else {
+            continue;
+        };
+        let span = span!(
+            Level::INFO,
+            "http_router",
+            peer = peer.to_string(),
+            remote = tracing::field::Empty
+        );
+        let conn_fut = http1::Builder::new()
+            .serve_connection(
+                TokioIo::new(stream),
+                service_fn(move |req| proxy_http_request(req, peer).instrument(Span::current())),
+            )
+            .with_upgrades();
+
+        let fut = async move {
+            if let Err(err) = conn_fut.await {
+                warn!("Error while serving http router connection: {:?}", err);
+            }
+        }
+        .instrument(span);
+
+        tokio::spawn(fut);
+    }
+
+    Ok(())
+}
+
+type ProxyResponse = Response<Either<Incoming, Full<Bytes>>>;
+
+fn error_response(status: StatusCode) -> ProxyResponse {
+    http::Response::builder()
+        .status(status)
+        .body(Either::Right(Full::new(Bytes::from(
+            status.canonical_reason().unwrap_or_default(),
+        ))))
+        .unwrap()
+}
+
+fn request_hostname(req: &Request<Incoming>) -> Option<String> {
+    let host = req.headers().get(HOST)?.to_str().ok()?;
+    let host = Url::parse(&format!("http://{}", host)).ok()?;
+    Some(host.host_str()?.to_ascii_lowercase())
+}
+
+async fn proxy_http_request(req: Request<Incoming>, peer: SocketAddr) -> anyhow::Result<ProxyResponse> {
+    let Some(hostname) = request_hostname(&req) else {
+        warn!("Rejecting http router request without a valid host header");
+        return Ok(error_response(StatusCode::BAD_REQUEST));
+    };
+    Span::current().record("remote", &hostname);
+
+    // Each request gets its own reverse tunnel, the other end of the duplex is given to the tunnel
+    let (proxy_end, tunnel_end) = tokio::io::duplex(64 * 1024);
+    let rewrite = match HTTP_ROUTES.dispatch(&hostname, tunnel_end).await {
+        Ok(rewrite) => rewrite,
+        Err(err) => {
+            warn!("Rejecting http router request, {}", err);
+            return Ok(error_response(StatusCode::BAD_GATEWAY));
+        }
+    };
+
+    let (mut sender, conn) = match hyper::client::conn::http1::handshake(TokioIo::new(proxy_end)).await {
+        Ok(ret) => ret,
+        Err(err) => {
+            error!("Cannot start http connection through the reverse tunnel: {:?}", err);
+            return Ok(error_response(StatusCode::BAD_GATEWAY));
+        }
+    };
+    tokio::spawn(
+        async move {
+            if let Err(err) = conn.with_upgrades().await {
+                warn!("Error while proxying http request through the reverse tunnel: {:?}", err);
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(proxy_request(req, &mut sender, &rewrite, peer).await)
+}
+
+/// Send the rewritten request to the backend, and relay the connection as is once upgraded (i.e: websocket)
+async fn proxy_request(
+    mut req: Request<Incoming>,
+    sender: &mut SendRequest<Incoming>,
+    rewrite: &HttpRewrite,
+    peer: SocketAddr,
+) -> ProxyResponse {
+    let public_host = req.headers().get(HOST).cloned().unwrap_or(HeaderValue::from_static(""));
+    let is_upgrade = is_upgrade_request(&req);
+    let visitor_upgrade = is_upgrade.then(|| hyper::upgrade::on(&mut req));
+    rewrite_request(&mut req, rewrite, peer, &public_host);
+    let mut response = match sender.send_request(req).await {
+        Ok(response) => response,
+        Err(err) => {
+            warn!("Error while proxying http request to the backend: {:?}", err);
+            return error_response(StatusCode::BAD_GATEWAY);
+        }
+    };
+    rewrite_response(&mut response, rewrite, &public_host);
+
+    // Backend accepted the upgrade (i.e: websocket), from now on bytes are relayed as is in both directions
+    if let (Some(visitor_upgrade), StatusCode::SWITCHING_PROTOCOLS) = (visitor_upgrade, response.status()) {
+        let backend_upgrade = hyper::upgrade::on(&mut response);
+        tokio::spawn(
+            async move {
+                let (visitor, backend) = match tokio::try_join!(visitor_upgrade, backend_upgrade) {
+                    Ok(ret) => ret,
+                    Err(err) => {
+                        warn!("Error during http upgrade with the backend: {:?}", err);
+                        return;
+                    }
+                };
+
+                info!("Relaying upgraded http connection");
+                let mut visitor = TokioIo::new(visitor);
+                let mut backend = TokioIo::new(backend);
+                if let Err(err) = tokio::io::copy_bidirectional(&mut visitor, &mut backend).await {
+                    warn!("Error while relaying upgraded http connection: {:?}", err);
+                }
+            }
+            .instrument(Span::current()),
+        );
+    }
+
+    response.map(Either::Left)
+}
+
+/// Relay the http requests of a forward tcp tunnel to its destination, rewritten as for the reverse http tunnels.
+/// The public host is the one the requests are sent to on the client, i.e: localhost:8080
+pub(super) fn rewrite_http(destination: TcpStream, rewrite: HttpRewrite, peer: SocketAddr) -> DuplexStream {
+    let (tunnel_end, proxy_end) = tokio::io::duplex(64 * 1024);
+    let fut = async move {
+        let (sender, conn) = match hyper::client::conn::http1::handshake(TokioIo::new(destination)).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                error!("Cannot start http connection with the destination: {:?}", err);
+                return;
+            }
+        };
+        tokio::spawn(
+            async move {
+                if let Err(err) = conn.with_upgrades().await {
+                    warn!("Error while proxying http request to the destination: {:?}", err);
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        // Requests of a connection come one after the other, they all go to the same connection with the destination
+        let sender = Arc::new(tokio::sync::Mutex::new(sender));
+        let rewrite = Arc::new(rewrite);
+        let service = service_fn(move |req| {
+            let sender = sender.clone();
+            let rewrite = rewrite.clone();
+            async move { Ok::<_, hyper::Error>(proxy_request(req, &mut *sender.lock().await, &rewrite, peer).await) }
+        });
+        if let Err(err) = http1::Builder::new()
+            .serve_connection(TokioIo::new(proxy_end), service)
+            .with_upgrades()
+            .await
+        {
+            warn!("Error while rewriting http requests of the tunnel: {:?}", err);
+        }
+    };
+    tokio::spawn(fut.instrument(Span::current()));
+    tunnel_end
+}
+
+fn is_upgrade_request<B>(req: &Request<B>) -> bool {
+    let has_connection_upgrade = req
+        .headers()
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    has_connection_upgrade && req.headers().contains_key(UPGRADE)
+}
+
+fn rewrite_request<B>(req: &mut Request<B>, rewrite: &HttpRewrite, peer: SocketAddr, public_host: &HeaderValue) {
+    let is_upgrade = is_upgrade_request(req);
+    let headers = req.headers_mut();
+    // Hop by hop headers are not forwarded, except what is needed to perform the upgrade with the backend
+    headers.remove(CONNECTION);
+    if is_upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+    headers.remove("keep-alive");
+    headers.remove("proxy-connection");
+
+    if rewrite.forwarded_headers {
+        let forwarded_for = match headers.get("x-forwarded-for").and_then(|h| h.to_str().ok()) {
+            Some(forwarded_for) => format!("{}, {}", forwarded_for, peer.ip()),
+            None => peer.ip().to_string(),
+        };
+        if let Ok(forwarded_for) = HeaderValue::from_str(&forwarded_for) {
+            headers.insert("x-forwarded-for", forwarded_for);
+        }
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        headers.insert("x-forwarded-host", public_host.clone());
+    }
+
+    if let Some(host) = rewrite.host.as_ref().and_then(|h| HeaderValue::from_str(h).ok()) {
+        headers.insert(HOST, host);
+    }
+}
+
+fn rewrite_response<B>(response: &mut Response<B>, rewrite: &HttpRewrite, public_host: &HeaderValue) {
+    // Redirects issued by the backend for its own host must point to the public host instead
+    let Some(backend_host) = &rewrite.host else {
+        return;
+    };
+    let Some(location) = response.headers().get(LOCATION).and_then(|h| h.to_str().ok()) else {
+        return;
+    };
+    let Ok(mut location) = Url::parse(location) else {
+        return;
+    };
+
+    let location_host = match location.port() {
+        Some(port) => format!("{}:{}", location.host_str().unwrap_or_default(), port),
+        None => location.host_str().unwrap_or_default().to_string(),
+    };
+    if !location_host.eq_ignore_ascii_case(backend_host) {
+        return;
+    }
+
+    let Ok(public_url) = Url::parse(&format!("http://{}", public_host.to_str().unwrap_or_default())) else {
+        return;
+    };
+    let _ = location.set_scheme(public_url.scheme());
+    let _ = location.set_host(public_url.host_str());
+    let _ = location.set_port(public_url.port());
+    if let Ok(location) = HeaderValue::from_str(location.as_str()) {
+        response.headers_mut().insert(LOCATION, location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hostname_route_in_use() {
+        let routes = Arc::new(HostnameRoutes::<u32, ()>::new("test"));
+        let wait = |routes: Arc<HostnameRoutes<u32, ()>>| {
+            tokio::spawn(async move { routes.wait_for_connection("app.example.com", None, ()).await })
+        };
+        let (first, second) = (wait(routes.clone()), wait(routes.clone()));
+        while !routes.routes.lock().contains_key("app.example.com") {
+            tokio::task::yield_now().await;
+        }
+
+        routes.dispatch("app.example.com", 42).await.unwrap();
+        let (first, second) = (first.await.unwrap(), second.await.unwrap());
+        assert!(first.is_err() != second.is_err());
+        assert_eq!(first.or(second).unwrap(), 42);
+
+        // The route is given back once the connection is taken, for the next tunnel of the client
+        let next = wait(routes.clone());
+        routes.dispatch("app.example.com", 43).await.unwrap();
+        assert_eq!(next.await.unwrap().unwrap(), 43);
+    }
+
+    #[tokio::test]
+    async fn test_hostname_route_owner() {
+        let routes = Arc::new(HostnameRoutes::<u32, ()>::new("test"));
+        assert!(routes.wait_for_connection("*.example.com", None, ()).await.is_err());
+
+        let alice = tokio::spawn({
+            let routes = routes.clone();
+            async move { routes.wait_for_connection("*.example.com", Some("alice"), ()).await }
+        });
+        while !routes.routes.lock().contains_key("*.example.com") {
+            tokio::task::yield_now().await;
+        }
+        routes.dispatch("app.example.com", 42).await.unwrap();
+        assert_eq!(alice.await.unwrap().unwrap(), 42);
+
+        // The route is kept for alice between her tunnels
+        assert!(routes
+            .wait_for_connection("*.example.com", Some("bob"), ())
+            .await
+            .is_err());
+        let alice = tokio::spawn({
+            let routes = routes.clone();
+            async move { routes.wait_for_connection("*.example.com", Some("alice"), ()).await }
+        });
+        routes.dispatch("app.example.com", 43).await.unwrap();
+        assert_eq!(alice.await.unwrap().unwrap(), 43);
+    }
+
+    #[test]
+    fn test_rewrite_request() {
+        let rewrite = HttpRewrite {
+            host: Some("localhost:8080".to_string()),
+            forwarded_headers: true,
+        };
+        let mut req = Request::builder()
+            .header(HOST, "app.example.com")
+            .header("x-forwarded-for", "10.0.0.1")
+            .body(())
+            .unwrap();
+        let public_host = HeaderValue::from_static("app.example.com");
+        rewrite_request(&mut req, &rewrite, "1.2.3.4:5678".parse().unwrap(), &public_host);
+
+        assert_eq!(req.headers()[HOST], "localhost:8080");
+        assert_eq!(req.headers()["x-forwarded-for"], "10.0.0.1, 1.2.3.4");
+        assert_eq!(req.headers()["x-forwarded-proto"], "http");
+        assert_eq!(req.headers()["x-forwarded-host"], "app.example.com");
+    }
+
+    #[test]
+    fn test_rewrite_upgrade_request() {
+        let mut req = Request::builder()
+            .header(HOST, "app.example.com")
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .body(())
+            .unwrap();
+        assert!(is_upgrade_request(&req));
+        rewrite_request(
+            &mut req,
+            &HttpRewrite::default(),
+            "1.2.3.4:5678".parse().unwrap(),
+            &HeaderValue::from_static("app.example.com"),
+        );
+        assert_eq!(req.headers()[CONNECTION], "upgrade");
+        assert_eq!(req.headers()[UPGRADE], "websocket");
+
+        let req = Request::builder().header(CONNECTION, "keep-alive").body(()).unwrap();
+        assert!(!is_upgrade_request(&req));
+    }
+
+    #[test]
+    fn test_rewrite_location() {
+        let rewrite = HttpRewrite {
+            host: Some("localhost:8080".to_string()),
+            forwarded_headers: false,
+        };
+        let public_host = HeaderValue::from_static("app.example.com");
+
+        let mut response = Response::builder()
+            .header(LOCATION, "http://localhost:8080/login?next=/")
+            .body(())
+            .unwrap();
+        rewrite_response(&mut response, &rewrite, &public_host);
+        assert_eq!(response.headers()[LOCATION], "http://app.example.com/login?next=/");
+
+        let mut response = Response::builder()
+            .header(LOCATION, "https://other.example.com/")
+            .body(())
+            .unwrap();
+        rewrite_response(&mut response, &rewrite, &public_host);
+        assert_eq!(response.headers()[LOCATION], "https://other.example.com/");
+    }
+}
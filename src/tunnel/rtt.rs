@@ -0,0 +1,70 @@
+use once_cell::sync::Lazy;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+// Pings carry the time they were sent, relative to this, and the peer echoes it back in its pong
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+const PAYLOAD_LEN: usize = 8;
+// Pongs to send are dropped past this, the peer will ping again
+const PONG_QUEUE_LEN: usize = 4;
+
+/// The reading side of the websocket hands the pongs to send to the writing side
+pub(super) type PongTx = mpsc::Sender<Vec<u8>>;
+pub(super) type PongRx = mpsc::Receiver<Vec<u8>>;
+
+pub(super) fn pong_channel() -> (PongTx, PongRx) {
+    mpsc::channel(PONG_QUEUE_LEN)
+}
+
+pub(super) fn ping_payload() -> Vec<u8> {
+    let now = EPOCH.elapsed().as_micros() as u64;
+    now.to_be_bytes().to_vec()
+}
+
+/// Round trip time of the websocket, smoothed like the srtt of tcp
+#[derive(Debug, Default)]
+pub(super) struct RoundTrip {
+    smoothed: Option<Duration>,
+}
+
+impl RoundTrip {
+    /// Measured round trip of this pong, if it answers one of our pings
+    pub fn on_pong(&mut self, payload: &[u8]) -> Option<Duration> {
+        let sent_at = u64::from_be_bytes(<[u8; PAYLOAD_LEN]>::try_from(payload).ok()?);
+        let now = EPOCH.elapsed().as_micros() as u64;
+        let sample = Duration::from_micros(now.checked_sub(sent_at)?);
+
+        self.smoothed = Some(match self.smoothed {
+            None => sample,
+            Some(smoothed) => (smoothed * 7 + sample) / 8,
+        });
+        Some(sample)
+    }
+
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_trip() {
+        let mut rtt = RoundTrip::default();
+        assert_eq!(rtt.on_pong(&[]), None);
+        assert_eq!(rtt.on_pong(&[0; PAYLOAD_LEN + 1]), None);
+
+        let ping = ping_payload();
+        tokio::time::advance(Duration::from_millis(80)).await;
+        assert_eq!(rtt.on_pong(&ping), Some(Duration::from_millis(80)));
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(80)));
+
+        let ping = ping_payload();
+        tokio::time::advance(Duration::from_millis(160)).await;
+        assert_eq!(rtt.on_pong(&ping), Some(Duration::from_millis(160)));
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(90)));
+    }
+}
@@ -1,183 +1,840 @@
 use ahash::{HashMap, HashMapExt};
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use base64::Engine;
-use futures_util::{pin_mut, FutureExt, Stream, StreamExt};
+use fastwebsockets::upgrade::UpgradeFut;
+use futures_util::{future, pin_mut, stream, FutureExt, Stream, StreamExt};
 use std::cmp::min;
 use std::fmt::Debug;
 use std::future::Future;
-use std::ops::{Deref, Not};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Not;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use super::{JwtTunnelConfig, JWT_DECODE, JWT_HEADER_PREFIX};
-use crate::{socks5, tcp, tls, udp, LocalProtocol, TlsServerConfig, WsServerConfig};
+use super::error_code::{ErrorCode, ERROR_CODE_HEADER};
+use super::io::{StreamEnd, COMPRESSION_HEADER, HALF_CLOSE_HEADER, RESET_HEADER};
+use super::ready::{self, READY_ACK_HEADER};
+use super::rtt;
+use super::split_tunnel::AllowFrom;
+use super::{
+    constant_time_eq, decode_jwt, jti, reliable_udp, JwtTunnelConfig, PathPrefix, UpgradePathSecret, JWT_HEADER_PREFIX,
+    JWT_VALIDATION, REQUEST_ID_HEADER,
+};
+use crate::encryption::{self, PayloadCiphers, PayloadKey, ENCRYPTION_HEADER};
+use crate::geoip::GeoIp;
+use crate::health::{self, Check};
+use crate::log_sink::event_id;
+use crate::metrics::{self, FirstByte, Latency, METRICS};
+use crate::runtime;
+use crate::{
+    dns, icmp, named_pipe, proxy_protocol, sni, socks5, tcp, tls, udp, x509, Compression, LocalProtocol, Qos,
+    ServerBind, TlsServerConfig, WsServerConfig,
+};
+use http_body_util::Either;
 use hyper::body::Incoming;
-use hyper::header::{COOKIE, SEC_WEBSOCKET_PROTOCOL};
+use hyper::header::{AUTHORIZATION, COOKIE, LOCATION, SEC_WEBSOCKET_PROTOCOL, WWW_AUTHENTICATE};
 use hyper::http::HeaderValue;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{http, Request, Response, StatusCode};
+use hyper_util::rt::TokioTimer;
 use jsonwebtoken::TokenData;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 
+use crate::socks5::Socks5Request;
+use crate::tcp::{Abort, Egress, LocalWrite};
+use crate::tunnel::accounting;
+use crate::tunnel::admin;
+use crate::tunnel::amplification;
+use crate::tunnel::cluster;
+use crate::tunnel::control_frame::ControlMessage;
+use crate::tunnel::decoy::{self, DecoyBody};
+use crate::tunnel::destination_template::{DestinationTemplate, TemplateVars};
+use crate::tunnel::drain;
+use crate::tunnel::egress::EgressRule;
+use crate::tunnel::events::{self, Event, Peer};
+use crate::tunnel::listeners::{self, Listener};
+use crate::tunnel::protocol_version::{self, ProtocolVersion};
+use crate::tunnel::rate_limit::{HandshakeLimiter, PendingUpgrades};
+use crate::tunnel::registry::{self, RegisteredTunnel};
+use crate::tunnel::reverse_state::{self, Binding, BindingKind};
+use crate::tunnel::rewrite_map;
+use crate::tunnel::router;
+use crate::tunnel::session::{self, ResumableSession, SESSION_RX_HEADER};
+use crate::tunnel::spa::SpaGate;
+use crate::tunnel::time_limits::{self, TimeWindow};
 use crate::tunnel::tls_reloader::TlsReloader;
+use crate::tunnel::webhook;
 use crate::udp::UdpStream;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
+use tokio::sync::mpsc::error::SendTimeoutError;
 use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_rustls::rustls::pki_types::CertificateDer;
 use tokio_rustls::TlsAcceptor;
-use tracing::{error, info, span, warn, Instrument, Level, Span};
+use tracing::{debug, error, info, span, warn, Instrument, Level, Span};
 use url::Host;
 
-async fn run_tunnel(
+/// Addresses of the destination allowed by the geoip rules. Domains are resolved here, so that their addresses are checked
+async fn geoip_addrs(
     server_config: &WsServerConfig,
+    geoip: &GeoIp,
+    host: &Host,
+    port: u16,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs = match host {
+        Host::Domain(domain) => server_config.dns_resolver.lookup_host(domain, port).await?,
+        Host::Ipv4(ip) => vec![SocketAddr::new(IpAddr::V4(*ip), port)],
+        Host::Ipv6(ip) => vec![SocketAddr::new(IpAddr::V6(*ip), port)],
+    };
+
+    let addrs: Vec<_> = addrs.into_iter().filter(|addr| geoip.is_allowed(addr.ip())).collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("destination {}:{} is refused by the geoip rules", host, port));
+    }
+    Ok(addrs)
+}
+
+/// Egress of the rule of the destination, or else the one of the server
+fn egress(server_config: &WsServerConfig, claims: &JwtTunnelConfig) -> Egress {
+    match EgressRule::find(&server_config.egress_rules, &claims.r, claims.rp) {
+        Some(egress) => Egress {
+            so_mark: egress.so_mark.or(server_config.socket_so_mark),
+            ..egress.clone()
+        },
+        None => Egress::with_so_mark(server_config.socket_so_mark),
+    }
+}
+
+async fn run_tunnel(
+    server_config: &Arc<WsServerConfig>,
     jwt: TokenData<JwtTunnelConfig>,
-) -> anyhow::Result<(
-    LocalProtocol,
-    Host,
-    u16,
-    Pin<Box<dyn AsyncRead + Send>>,
-    Pin<Box<dyn AsyncWrite + Send>>,
-)> {
+    peer: SocketAddr,
+    correlation_id: &str,
+    owner: Option<&str>,
+    connect_timeout: Duration,
+) -> anyhow::Result<(LocalTunnel, Option<Requeue>)> {
     match jwt.claims.p {
-        LocalProtocol::Udp { timeout, .. } => {
-            let host = Host::parse(&jwt.claims.r)?;
-            let cnx = udp::connect(
-                &host,
-                jwt.claims.rp,
-                timeout.unwrap_or(Duration::from_secs(10)),
-                &server_config.dns_resolver,
-            )
-            .await?;
-            Ok((
-                LocalProtocol::Udp { timeout: None },
+        LocalProtocol::Udp { timeout, reliable } => {
+            let host = dns::parse_host(&jwt.claims.r)?;
+            let timeout = timeout.unwrap_or(Duration::from_secs(10));
+            let addrs = match &server_config.geoip {
+                Some(geoip) => geoip_addrs(server_config, geoip, &host, jwt.claims.rp).await?,
+                None => tcp::resolve(&host, jwt.claims.rp, &server_config.dns_resolver).await?,
+            };
+            let egress = egress(server_config, &jwt.claims);
+            let cnx = udp::connect_addrs(&host, jwt.claims.rp, addrs, &egress, timeout).await?;
+            let dest = cnx.peer_addr()?;
+            let (local_rx, local_tx) =
+                tokio::io::split(amplification::Guarded::new(cnx, dest, amplification::limits()));
+            let tunnel: LocalTunnel = (
+                LocalProtocol::Udp {
+                    timeout: None,
+                    reliable,
+                },
                 host,
                 jwt.claims.rp,
-                Box::pin(cnx.clone()),
-                Box::pin(cnx),
-            ))
+                Box::pin(local_rx),
+                Box::pin(local_tx),
+                None,
+            );
+            Ok((tunnel, None))
+        }
+        #[cfg(windows)]
+        LocalProtocol::Tcp if named_pipe::is_named_pipe(&jwt.claims.r) => {
+            let pipe = named_pipe::connect(&jwt.claims.r, connect_timeout).await?;
+            let (rx, tx) = tokio::io::split(pipe);
+
+            let tunnel: LocalTunnel = (
+                jwt.claims.p,
+                Host::Domain(jwt.claims.r),
+                jwt.claims.rp,
+                Box::pin(rx),
+                Box::pin(tx),
+                None,
+            );
+            Ok((tunnel, None))
+        }
+        #[cfg(not(windows))]
+        LocalProtocol::Tcp if named_pipe::is_named_pipe(&jwt.claims.r) => {
+            Err(anyhow!("Named pipe is not available for non Windows platform"))
         }
         LocalProtocol::Tcp => {
-            let host = Host::parse(&jwt.claims.r)?;
+            let host = dns::parse_host(&jwt.claims.r)?;
             let port = jwt.claims.rp;
-            let (rx, tx) = tcp::connect(
-                &host,
-                port,
-                server_config.socket_so_mark,
-                Duration::from_secs(10),
-                &server_config.dns_resolver,
-            )
-            .await?
-            .into_split();
+            let timeout = connect_timeout;
+            let addrs = match &server_config.geoip {
+                Some(geoip) => geoip_addrs(server_config, geoip, &host, port).await?,
+                None => tcp::resolve(&host, port, &server_config.dns_resolver).await?,
+            };
+            let egress = egress(server_config, &jwt.claims);
+            let mut stream = tcp::connect_addrs(&host, port, addrs, &egress, timeout, false).await?;
+            if server_config.destination_proxy_protocol {
+                let header = proxy_protocol::v2_header(peer, stream.peer_addr()?, correlation_id.as_bytes());
+                stream.write_all(&header).await?;
+            }
+            if let Some(rewrite) = jwt.claims.hr {
+                let (rx, tx) = tokio::io::split(router::rewrite_http(stream, rewrite, peer));
+                return Ok(((jwt.claims.p, host, port, Box::pin(rx), Box::pin(tx), None), None));
+            }
+            let (rx, tx) = tcp::abortable_split(stream);
+            let abort = tx.abort_handle();
+
+            Ok(((jwt.claims.p, host, port, Box::pin(rx), Box::pin(tx), abort), None))
+        }
+        LocalProtocol::Icmp => {
+            let host = dns::parse_host(&jwt.claims.r)?;
+            let addrs = match (&server_config.geoip, &host) {
+                (Some(geoip), _) => geoip_addrs(server_config, geoip, &host, 0).await?,
+                (None, Host::Domain(domain)) => server_config.dns_resolver.lookup_host(domain, 0).await?,
+                (None, Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(*ip), 0)],
+                (None, Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(*ip), 0)],
+            };
+            let dest = addrs.first().ok_or_else(|| anyhow!("cannot resolve {}", host))?.ip();
+            let (local_rx, local_tx) = tokio::io::split(icmp::ping(host.to_string(), dest)?);
 
-            Ok((jwt.claims.p, host, port, Box::pin(rx), Box::pin(tx)))
+            Ok(((jwt.claims.p, host, 0, Box::pin(local_rx), Box::pin(local_tx), None), None))
         }
         LocalProtocol::ReverseTcp => {
-            #[allow(clippy::type_complexity)]
-            static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<TcpStream>>>> =
-                Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+            let local_srv = (dns::parse_host(&jwt.claims.r)?, jwt.claims.rp);
+            let bind = dns::socket_addr(&local_srv.0, local_srv.1)?;
+            if jwt.claims.tt.unwrap_or(false) {
+                let listening_server = tls_listening_server(server_config.clone(), bind);
+                let kind = BindingKind::Tcp { tls: true };
+                let (tunnel, requeue) = run_listening_server(
+                    server_config,
+                    kind,
+                    &local_srv,
+                    &jwt.claims.id,
+                    owner,
+                    &TLS_SERVERS,
+                    listening_server,
+                    |tls| {
+                        let (local_rx, local_tx) = tokio::io::split(tls);
+                        Ok((
+                            jwt.claims.p.clone(),
+                            local_srv.0.clone(),
+                            local_srv.1,
+                            Box::pin(local_rx),
+                            Box::pin(local_tx),
+                            None,
+                        ))
+                    },
+                )
+                .await?;
 
-            let local_srv = (Host::parse(&jwt.claims.r)?, jwt.claims.rp);
-            let bind = format!("{}:{}", local_srv.0, local_srv.1);
-            let listening_server = tcp::run_server(bind.parse()?, false);
-            let tcp = run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
-            let (local_rx, local_tx) = tcp.into_split();
+                return Ok((tunnel, Some(requeue)));
+            }
 
-            Ok((jwt.claims.p, local_srv.0, local_srv.1, Box::pin(local_rx), Box::pin(local_tx)))
-        }
-        LocalProtocol::ReverseUdp { timeout } => {
-            #[allow(clippy::type_complexity)]
-            static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<UdpStream>>>> =
-                Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+            let listening_server = tcp::run_server(bind, false);
+            let kind = BindingKind::Tcp { tls: false };
+            let (tunnel, requeue) = run_listening_server(
+                server_config,
+                kind,
+                &local_srv,
+                &jwt.claims.id,
+                owner,
+                &TCP_SERVERS,
+                listening_server,
+                |tcp| {
+                    let (local_rx, local_tx) = tcp::abortable_split(tcp);
+                    let abort = local_tx.abort_handle();
+                    Ok((
+                        jwt.claims.p.clone(),
+                        local_srv.0.clone(),
+                        local_srv.1,
+                        Box::pin(local_rx),
+                        Box::pin(local_tx),
+                        abort,
+                    ))
+                },
+            )
+            .await?;
 
-            let local_srv = (Host::parse(&jwt.claims.r)?, jwt.claims.rp);
-            let bind = format!("{}:{}", local_srv.0, local_srv.1);
-            let listening_server =
-                udp::run_server(bind.parse()?, timeout, |_| Ok(()), |send_socket| Ok(send_socket.clone()));
-            let udp = run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
-            let (local_rx, local_tx) = tokio::io::split(udp);
+            Ok((tunnel, Some(requeue)))
+        }
+        LocalProtocol::ReverseUdp { timeout, .. } => {
+            let local_srv = (dns::parse_host(&jwt.claims.r)?, jwt.claims.rp);
+            let bind = dns::socket_addr(&local_srv.0, local_srv.1)?;
+            let listening_server = udp_listening_server(bind, timeout);
+            let kind = BindingKind::Udp { timeout };
+            let (tunnel, requeue) = run_listening_server(
+                server_config,
+                kind,
+                &local_srv,
+                &jwt.claims.id,
+                owner,
+                &UDP_SERVERS,
+                listening_server,
+                |udp| {
+                    let (local_rx, local_tx) = tokio::io::split(udp);
+                    Ok((
+                        jwt.claims.p.clone(),
+                        local_srv.0.clone(),
+                        local_srv.1,
+                        Box::pin(local_rx),
+                        Box::pin(local_tx),
+                        None,
+                    ))
+                },
+            )
+            .await?;
 
-            Ok((jwt.claims.p, local_srv.0, local_srv.1, Box::pin(local_rx), Box::pin(local_tx)))
+            Ok((tunnel, Some(requeue)))
         }
         LocalProtocol::ReverseSocks5 => {
-            #[allow(clippy::type_complexity)]
-            static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<(TcpStream, (Host, u16))>>>> =
-                Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+            let local_srv = (dns::parse_host(&jwt.claims.r)?, jwt.claims.rp);
+            let bind = dns::socket_addr(&local_srv.0, local_srv.1)?;
+            let listening_server = socks5::run_server(
+                bind,
+                false,
+                server_config.reverse_socks5_allow_from.clone(),
+                &server_config.reverse_socks5_credentials,
+            );
+            let (tunnel, requeue) = run_listening_server(
+                server_config,
+                BindingKind::Socks5,
+                &local_srv,
+                &jwt.claims.id,
+                owner,
+                &SOCKS5_SERVERS,
+                listening_server,
+                |(tcp, request)| {
+                    // BIND requests are refused by the listener
+                    let Socks5Request::Connect(dest) = request else {
+                        return Err(anyhow!("socks5 BIND is not supported by reverse tunnels"));
+                    };
+                    let (local_rx, local_tx) = tcp::abortable_split(tcp);
+                    let abort = local_tx.abort_handle();
+                    Ok((
+                        jwt.claims.p.clone(),
+                        dest.0,
+                        dest.1,
+                        Box::pin(local_rx),
+                        Box::pin(local_tx),
+                        abort,
+                    ))
+                },
+            )
+            .await?;
+
+            Ok((tunnel, Some(requeue)))
+        }
+        LocalProtocol::ReverseSni { .. } => {
+            if server_config.sni_router_bind.is_none() {
+                return Err(anyhow!("SNI routing is not enabled on this server"));
+            }
+
+            let hostname = jwt.claims.r.to_ascii_lowercase();
+            if !sni::is_valid_hostname_pattern(&hostname) {
+                return Err(anyhow!("Invalid hostname for sni routing: {}", hostname));
+            }
+            let tcp = router::SNI_ROUTES.wait_for_connection(&hostname, owner, ()).await?;
+            let (local_rx, local_tx) = tcp::abortable_split(tcp);
+            let abort = local_tx.abort_handle();
+
+            Ok((
+                (
+                    jwt.claims.p,
+                    Host::Domain(hostname),
+                    0,
+                    Box::pin(local_rx),
+                    Box::pin(local_tx),
+                    abort,
+                ),
+                None,
+            ))
+        }
+        LocalProtocol::ReverseHttp { ref rewrite, .. } => {
+            if server_config.http_router_bind.is_none() {
+                return Err(anyhow!("HTTP routing is not enabled on this server"));
+            }
 
-            let local_srv = (Host::parse(&jwt.claims.r)?, jwt.claims.rp);
-            let bind = format!("{}:{}", local_srv.0, local_srv.1);
-            let listening_server = socks5::run_server(bind.parse()?);
-            let (tcp, local_srv) = run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
-            let (local_rx, local_tx) = tokio::io::split(tcp);
+            let hostname = jwt.claims.r.to_ascii_lowercase();
+            if !sni::is_valid_hostname_pattern(&hostname) {
+                return Err(anyhow!("Invalid hostname for http routing: {}", hostname));
+            }
+            let cnx = router::HTTP_ROUTES
+                .wait_for_connection(&hostname, owner, rewrite.clone())
+                .await?;
+            let (local_rx, local_tx) = tokio::io::split(cnx);
 
-            Ok((jwt.claims.p, local_srv.0, local_srv.1, Box::pin(local_rx), Box::pin(local_tx)))
+            Ok((
+                (
+                    jwt.claims.p,
+                    Host::Domain(hostname),
+                    0,
+                    Box::pin(local_rx),
+                    Box::pin(local_tx),
+                    None,
+                ),
+                None,
+            ))
         }
         _ => Err(anyhow::anyhow!("Invalid upgrade request")),
     }
 }
 
-#[allow(clippy::type_complexity)]
+// The listener of a socks5 BIND is closed if its peer has not connected by then
+const BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Visitors of a reverse tcp tunnel doing their tls handshake at the same time, the others wait to be accepted
+const MAX_VISITOR_TLS_HANDSHAKES: usize = 64;
+
+/// Terminate the tls of a visitor of a reverse tcp tunnel. Those failing the handshake are dropped, without stopping
+/// the listener
+async fn accept_visitor_tls(
+    server_config: &WsServerConfig,
+    cnx: std::io::Result<TcpStream>,
+) -> Option<std::io::Result<tokio_rustls::server::TlsStream<TcpStream>>> {
+    let stream = match cnx {
+        Ok(stream) => stream,
+        Err(err) => return Some(Err(err)),
+    };
+    let peer = stream.peer_addr().ok();
+    let accept = server_config.reverse_tls.accept(server_config.tls.as_ref(), stream);
+    match tokio::time::timeout(server_config.tls_handshake_timeout, accept).await {
+        Ok(Ok(tls)) => Some(Ok(tls)),
+        Ok(Err(err)) => {
+            warn!(
+                "Tls handshake failed with the visitor {:?} of a reverse tunnel: {:?}",
+                peer, err
+            );
+            None
+        }
+        Err(_) => {
+            warn!(
+                "Timeout during the tls handshake with the visitor {:?} of a reverse tunnel",
+                peer
+            );
+            None
+        }
+    }
+}
+
+type LocalRx = Pin<Box<dyn AsyncRead + Send>>;
+type LocalTx = Pin<Box<dyn AsyncWrite + Send>>;
+/// Connection of a tunnel, on the server side, with its protocol and destination, and a handle to reset it for the
+/// tcp ones
+type LocalTunnel = (LocalProtocol, Host, u16, LocalRx, LocalTx, Option<Abort>);
+
+/// Listener of a reverse tunnel, with the connections it has accepted and those given back by the tunnels whose
+/// client never became ready
+struct ReverseListener<T> {
+    accepted: mpsc::Receiver<T>,
+    requeued: mpsc::UnboundedReceiver<LocalTunnel>,
+    requeue_tx: mpsc::UnboundedSender<LocalTunnel>,
+    listener: Arc<Listener>,
+}
+
+impl<T> ReverseListener<T> {
+    fn new(accepted: mpsc::Receiver<T>, listener: Arc<Listener>) -> Self {
+        let (requeue_tx, requeued) = mpsc::unbounded_channel();
+        Self {
+            accepted,
+            requeued,
+            requeue_tx,
+            listener,
+        }
+    }
+}
+
+/// Gives a visitor back to its listener, the next tunnel waiting on it gets it before the connections accepted since
+struct Requeue {
+    tx: mpsc::UnboundedSender<LocalTunnel>,
+    listener: Arc<Listener>,
+    protocol: LocalProtocol,
+    dest: Host,
+    port: u16,
+    abort: Option<Abort>,
+}
+
+impl Requeue {
+    fn requeue(self, local_rx: LocalRx, local_tx: LocalTx) {
+        self.listener.queue();
+        // The listener has stopped meanwhile
+        if self
+            .tx
+            .send((self.protocol, self.dest, self.port, local_rx, local_tx, self.abort))
+            .is_err()
+        {
+            self.listener.unqueue();
+        }
+    }
+}
+
+// Listeners of reverse tunnels by bind address, with the tunnels waiting on them
+type ReverseListeners<T> = Mutex<HashMap<(Host<String>, u16), ReverseListener<T>>>;
+
+static TCP_SERVERS: Lazy<ReverseListeners<TcpStream>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+static TLS_SERVERS: Lazy<ReverseListeners<tokio_rustls::server::TlsStream<TcpStream>>> =
+    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+static UDP_SERVERS: Lazy<ReverseListeners<UdpStream>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+static SOCKS5_SERVERS: Lazy<ReverseListeners<(TcpStream, Socks5Request)>> =
+    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+
+/// Listener of a reverse tcp tunnel terminating the tls of its visitors
+async fn tls_listening_server(
+    server_config: Arc<WsServerConfig>,
+    bind: SocketAddr,
+) -> anyhow::Result<impl Stream<Item = std::io::Result<tokio_rustls::server::TlsStream<TcpStream>>>> {
+    if server_config.tls.is_none() && server_config.reverse_tls.certificates.is_empty() {
+        return Err(anyhow!(
+            "No tls certificate on this server to terminate the tls of reverse tunnels"
+        ));
+    }
+    let visitors = tcp::run_server(bind, false).await?.map(move |cnx| {
+        let server_config = server_config.clone();
+        async move { accept_visitor_tls(&server_config, cnx).await }
+    });
+    Ok(visitors
+        .buffer_unordered(MAX_VISITOR_TLS_HANDSHAKES)
+        .filter_map(future::ready))
+}
+
+async fn udp_listening_server(
+    bind: SocketAddr,
+    timeout: Option<Duration>,
+) -> anyhow::Result<impl Stream<Item = std::io::Result<UdpStream>>> {
+    udp::run_server(
+        bind,
+        timeout,
+        AllowFrom::default(),
+        |_| Ok(()),
+        |send_socket| Ok(send_socket.clone()),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_listening_server<T, Fut, FutOut, E>(
+    server_config: &WsServerConfig,
+    kind: BindingKind,
     local_srv: &(Host, u16),
-    servers: &Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<T>>>,
+    client: &str,
+    owner: Option<&str>,
+    servers: &ReverseListeners<T>,
     gen_listening_server: Fut,
-) -> anyhow::Result<T>
+    into_tunnel: impl FnOnce(T) -> anyhow::Result<LocalTunnel>,
+) -> anyhow::Result<(LocalTunnel, Requeue)>
 where
     Fut: Future<Output = anyhow::Result<FutOut>>,
     FutOut: Stream<Item = Result<T, E>> + Send + 'static,
     E: Debug + Send,
     T: Send + 'static,
 {
-    let listening_server = servers.lock().remove(local_srv);
+    let listening_server = {
+        let mut servers = servers.lock();
+        if servers
+            .get(local_srv)
+            .is_some_and(|reverse_listener| !reverse_listener.listener.is_owned_by(owner))
+        {
+            return Err(anyhow!(
+                "{}://{}:{} is reserved for another client",
+                kind.protocol(),
+                local_srv.0,
+                local_srv.1
+            ));
+        }
+        servers.remove(local_srv)
+    };
     let mut listening_server = if let Some(listening_server) = listening_server {
         listening_server
     } else {
-        let listening_server = gen_listening_server.await?;
-        let (tx, rx) = mpsc::channel::<T>(1);
-        let fut = async move {
-            pin_mut!(listening_server);
-            loop {
-                select! {
-                    biased;
-                    cnx = listening_server.next() => {
-                       match cnx {
-                            None => break,
-                            Some(Err(err)) => {
-                                warn!("Error while listening for incoming connections {err:?}");
-                                break;
-                            }
-                            Some(Ok(cnx)) => {
-                                if tx.send_timeout(cnx, Duration::from_secs(30)).await.is_err() {
+        // The forwarder of another server may be listening on it, if the client comes from there
+        if server_config.cluster.is_some() {
+            cluster::hand_over(&format!("{}:{}", local_srv.0, local_srv.1)).await;
+        }
+        let listening_server = match gen_listening_server.await {
+            Ok(listening_server) => listening_server,
+            Err(err) => {
+                warn!(
+                    "Cannot listen on {}://{}:{}, reverse listeners of the server: {}",
+                    kind.protocol(),
+                    local_srv.0,
+                    local_srv.1,
+                    listeners::summary()
+                );
+                return Err(err);
+            }
+        };
+        // Listeners only belong to their client when they are persisted, to be reserved for it after a restart
+        let owner = owner.filter(|_| reverse_state::is_enabled()).map(str::to_string);
+        let (accepted, listener) = spawn_listening_server(server_config, kind, local_srv, owner, listening_server);
+        ReverseListener::new(accepted, listener)
+    };
+
+    let listener = listening_server.listener.clone();
+    listener.wait(client);
+    let cnx = select! {
+        biased;
+        Some(tunnel) = listening_server.requeued.recv() => Either::Left(tunnel),
+        cnx = listening_server.accepted.recv() => Either::Right(cnx.ok_or_else(|| anyhow!("listening server stopped"))?),
+    };
+    listener.take();
+    let tx = listening_server.requeue_tx.clone();
+    servers.lock().insert(local_srv.clone(), listening_server);
+
+    let tunnel = match cnx {
+        Either::Left(tunnel) => {
+            info!("Taking the visitor given back by a previous tunnel");
+            tunnel
+        }
+        Either::Right(cnx) => into_tunnel(cnx)?,
+    };
+    let requeue = Requeue {
+        tx,
+        listener,
+        protocol: tunnel.0.clone(),
+        dest: tunnel.1.clone(),
+        port: tunnel.2,
+        abort: tunnel.5.clone(),
+    };
+    Ok((tunnel, requeue))
+}
+
+/// Hand the connections accepted by the listener to the tunnels waiting on it. The listener is in the reverse tunnel
+/// state as long as it runs
+fn spawn_listening_server<T, S, E>(
+    server_config: &WsServerConfig,
+    kind: BindingKind,
+    local_srv: &(Host, u16),
+    owner: Option<String>,
+    listening_server: S,
+) -> (mpsc::Receiver<T>, Arc<Listener>)
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    E: Debug + Send,
+    T: Send + 'static,
+{
+    let binding = Binding {
+        kind,
+        host: local_srv.0.to_string(),
+        port: local_srv.1,
+        owner: owner.clone(),
+    };
+    let registered = listeners::register(binding.kind.protocol(), format!("{}:{}", local_srv.0, local_srv.1), owner);
+    let listener = registered.listener.clone();
+    reverse_state::add(&binding);
+    let (tx, rx) = mpsc::channel::<T>(server_config.reverse_queue_depth);
+    let accept_timeout = server_config.reverse_accept_timeout;
+    let fut = async move {
+        pin_mut!(listening_server);
+        loop {
+            select! {
+                biased;
+                cnx = listening_server.next() => {
+                   match cnx {
+                        None => break,
+                        Some(Err(err)) => {
+                            warn!("Error while listening for incoming connections {err:?}");
+                            break;
+                        }
+                        Some(Ok(cnx)) => {
+                            registered.listener.queue();
+                            match tx.send_timeout(cnx, accept_timeout).await {
+                                Ok(()) => {}
+                                // The client is too slow to open tunnels for the queued connections
+                                Err(SendTimeoutError::Timeout(_)) => {
+                                    registered.listener.unqueue();
+                                    metrics::incr(&METRICS.reverse_connection_drops, 1);
+                                    warn!(
+                                        "Dropping a connection to the reverse listener {}:{}, its queue is still full after {:?}",
+                                        binding.host, binding.port, accept_timeout
+                                    );
+                                }
+                                Err(SendTimeoutError::Closed(_)) => {
+                                    registered.listener.unqueue();
                                     break;
                                 }
                             }
                         }
-                    },
-
-                    _ = tx.closed() => {
-                        break;
                     }
+                },
+
+                _ = tx.closed() => {
+                    break;
                 }
             }
-            info!("Stopping listening server");
-        };
-
-        tokio::spawn(fut.instrument(Span::current()));
-        rx
+        }
+        reverse_state::remove(&binding);
+        info!("Stopping listening server");
     };
 
-    let cnx = listening_server
-        .recv()
-        .await
-        .ok_or_else(|| anyhow!("listening server stopped"))?;
-    servers.lock().insert(local_srv.clone(), listening_server);
-    Ok(cnx)
+    tokio::spawn(fut.instrument(Span::current()));
+    (rx, listener)
+}
+
+/// Bind again the listeners of the reverse tunnels of the previous run, so no one else takes their port while their
+/// clients are reconnecting
+async fn restore_reverse_listeners(server_config: &Arc<WsServerConfig>) {
+    for binding in reverse_state::bindings() {
+        if let Err(err) = restore_reverse_listener(server_config, &binding).await {
+            warn!(
+                "Cannot bind again {}://{}:{} of the previous run: {:#}",
+                binding.kind.protocol(),
+                binding.host,
+                binding.port,
+                err
+            );
+            reverse_state::remove(&binding);
+        }
+    }
+}
+
+async fn restore_reverse_listener(server_config: &Arc<WsServerConfig>, binding: &Binding) -> anyhow::Result<()> {
+    let local_srv = (dns::parse_host(&binding.host)?, binding.port);
+    let bind = dns::socket_addr(&local_srv.0, local_srv.1)?;
+    match binding.kind {
+        BindingKind::Tcp { tls: false } => reserve(
+            server_config,
+            binding,
+            local_srv,
+            &TCP_SERVERS,
+            tcp::run_server(bind, false).await?,
+        ),
+        BindingKind::Tcp { tls: true } => reserve(
+            server_config,
+            binding,
+            local_srv,
+            &TLS_SERVERS,
+            tls_listening_server(server_config.clone(), bind).await?,
+        ),
+        BindingKind::Udp { timeout } => reserve(
+            server_config,
+            binding,
+            local_srv,
+            &UDP_SERVERS,
+            udp_listening_server(bind, timeout).await?,
+        ),
+        BindingKind::Socks5 => reserve(
+            server_config,
+            binding,
+            local_srv,
+            &SOCKS5_SERVERS,
+            socks5::run_server(
+                bind,
+                false,
+                server_config.reverse_socks5_allow_from.clone(),
+                &server_config.reverse_socks5_credentials,
+            )
+            .await?,
+        ),
+    }
+    Ok(())
+}
+
+/// Keep the listener for its owner, until it waits on it again or the reservation expires
+fn reserve<T, S, E>(
+    server_config: &WsServerConfig,
+    binding: &Binding,
+    local_srv: (Host, u16),
+    servers: &'static Lazy<ReverseListeners<T>>,
+    listening_server: S,
+) where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    E: Debug + Send,
+    T: Send + 'static,
+{
+    info!(
+        "Reserving {}://{}:{} of the previous run for {}",
+        binding.kind.protocol(),
+        binding.host,
+        binding.port,
+        binding.owner.as_deref().unwrap_or("any client")
+    );
+    let (rx, listener) = spawn_listening_server(
+        server_config,
+        binding.kind.clone(),
+        &local_srv,
+        binding.owner.clone(),
+        listening_server,
+    );
+    servers
+        .lock()
+        .insert(local_srv.clone(), ReverseListener::new(rx, listener.clone()));
+
+    tokio::spawn(async move {
+        tokio::time::sleep(reverse_state::RESERVATION).await;
+        let mut servers = servers.lock();
+        if servers
+            .get(&local_srv)
+            .is_some_and(|reserved| Arc::ptr_eq(&reserved.listener, &listener) && !reserved.listener.has_waited())
+        {
+            info!("Releasing {}:{}, its client has not reconnected", local_srv.0, local_srv.1);
+            // Stops the listener
+            servers.remove(&local_srv);
+        }
+    });
+}
+
+/// Identity of the client certificate with mTLS, added to the extensions of the requests of the connection
+#[derive(Debug, Clone)]
+struct ClientIdentity(String);
+
+fn client_identity(certificates: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    let certificate = certificates?.first()?;
+    match x509::Certificate::parse(certificate) {
+        Ok(certificate) => certificate.identity().map(str::to_string),
+        Err(err) => {
+            warn!("Cannot read the identity of the client certificate: {:#}", err);
+            None
+        }
+    }
+}
+
+/// Response given to rejected upgrade requests. A fixed answer is easy to probe for, so it can be customized
+/// to look like the one of any other website (i.e: a 404 or a redirect)
+#[derive(Debug, Clone)]
+pub struct UpgradeRejection {
+    pub status: StatusCode,
+    pub body: String,
+    pub location: Option<HeaderValue>,
+    /// Tell the client why in a response header, at the cost of being recognizable as a wstunnel server
+    pub error_codes: bool,
+}
+
+impl Default for UpgradeRejection {
+    fn default() -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            body: "Invalid upgrade request".to_string(),
+            location: None,
+            error_codes: false,
+        }
+    }
+}
+
+impl UpgradeRejection {
+    fn response(&self) -> Response<String> {
+        let mut response = http::Response::builder().status(self.status);
+        if let Some(location) = &self.location {
+            response = response.header(LOCATION, location);
+        }
+        response.body(self.body.clone()).unwrap()
+    }
+
+    fn rejected(&self, code: ErrorCode) -> Response<String> {
+        self.with_code(self.response(), code)
+    }
+
+    fn with_code(&self, mut response: Response<String>, code: ErrorCode) -> Response<String> {
+        if self.error_codes {
+            response
+                .headers_mut()
+                .insert(ERROR_CODE_HEADER, HeaderValue::from_static(code.as_str()));
+        }
+        response
+    }
 }
 
 #[inline]
+#[allow(clippy::result_large_err)]
 fn extract_x_forwarded_for(req: &Request<Incoming>) -> Result<Option<&str>, Response<String>> {
     let Some(x_forward_for) = req.headers().get("X-Forwarded-For") else {
         return Ok(None);
@@ -187,34 +844,32 @@ fn extract_x_forwarded_for(req: &Request<Incoming>) -> Result<Option<&str>, Resp
 }
 
 #[inline]
+#[allow(clippy::result_large_err)]
 fn validate_url(
     req: &Request<Incoming>,
-    path_restriction_prefix: &Option<Vec<String>>,
+    path_restriction_prefix: &Option<Vec<PathPrefix>>,
+    path_secret: &Option<UpgradePathSecret>,
+    rejection: &UpgradeRejection,
 ) -> Result<(), Response<String>> {
-    if !req.uri().path().ends_with("/events") {
+    let (_, last_segment) = req.uri().path().rsplit_once('/').unwrap_or_default();
+    let is_valid_path = match path_secret {
+        None => last_segment == "events",
+        Some(secret) => secret.is_valid_segment(last_segment, jsonwebtoken::get_current_timestamp()),
+    };
+    if !is_valid_path {
         warn!("Rejecting connection with bad upgrade request: {}", req.uri());
-        return Err(http::Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Invalid upgrade request".into())
-            .unwrap());
+        return Err(rejection.rejected(ErrorCode::AuthFailed));
     }
 
     if let Some(paths_prefix) = &path_restriction_prefix {
         let path = req.uri().path();
-        let min_len = min(path.len(), 1);
-        let mut max_len = 0;
-        if &path[0..min_len] != "/"
-            || !paths_prefix.iter().any(|p| {
-                max_len = min(path.len(), p.len() + 1);
-                p == &path[min_len..max_len]
-            })
-            || !path[max_len..].starts_with('/')
-        {
-            warn!("Rejecting connection with bad path prefix in upgrade request: {}", req.uri());
-            return Err(http::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("Invalid upgrade request".to_string())
-                .unwrap());
+        if !paths_prefix.iter().any(|prefix| prefix.matches_path(path)) {
+            warn!(
+                event_id = event_id::AUTH_FAILURE,
+                "Rejecting connection with bad path prefix in upgrade request: {}",
+                req.uri()
+            );
+            return Err(rejection.rejected(ErrorCode::AuthFailed));
         }
     }
 
@@ -222,7 +877,11 @@ fn validate_url(
 }
 
 #[inline]
-fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<JwtTunnelConfig>, Response<String>> {
+#[allow(clippy::result_large_err)]
+fn extract_tunnel_info(
+    req: &Request<Incoming>,
+    server_config: &WsServerConfig,
+) -> Result<TokenData<JwtTunnelConfig>, Response<String>> {
     let jwt = req
         .headers()
         .get(SEC_WEBSOCKET_PROTOCOL)
@@ -231,56 +890,203 @@ fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<JwtTunnelCon
         .map(|(_prefix, jwt)| jwt)
         .unwrap_or_default();
 
-    let (validation, decode_key) = JWT_DECODE.deref();
-    let jwt = match jsonwebtoken::decode(jwt, decode_key, validation) {
+    let mut validation = JWT_VALIDATION.clone();
+    if let Some(issuer) = &server_config.jwt_issuer {
+        validation.set_issuer(&[issuer]);
+        validation.required_spec_claims.insert("iss".to_string());
+    }
+    if let Some(audience) = &server_config.jwt_audience {
+        validation.set_audience(&[audience]);
+        validation.required_spec_claims.insert("aud".to_string());
+    }
+    let jwt: TokenData<JwtTunnelConfig> = match decode_jwt(jwt, &server_config.jwt_secrets, &validation) {
         Ok(jwt) => jwt,
         err => {
             warn!(
+                event_id = event_id::AUTH_FAILURE,
                 "error while decoding jwt for tunnel info {:?} header {:?}",
                 err,
                 req.headers().get(SEC_WEBSOCKET_PROTOCOL)
             );
-            return Err(http::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("Invalid upgrade request".to_string())
-                .unwrap());
+            return Err(server_config.upgrade_rejection.rejected(ErrorCode::AuthFailed));
         }
     };
 
+    // Tokens of older clients do not have a jti, they cannot be protected against replay
+    if let Some(jti) = &jwt.claims.jti {
+        if let Err(err) = jti::check_not_replayed(jti, jwt.claims.exp) {
+            warn!(event_id = event_id::AUTH_FAILURE, "Rejecting upgrade request: {}", err);
+            return Err(server_config.upgrade_rejection.rejected(ErrorCode::AuthFailed));
+        }
+    }
+
+    if let Some(window) = &jwt.claims.tw {
+        let now = jsonwebtoken::get_current_timestamp();
+        match window.parse::<TimeWindow>() {
+            Ok(window) if window.contains(now) => {}
+            Ok(window) => {
+                warn!(
+                    event_id = event_id::AUTH_FAILURE,
+                    "Rejecting upgrade request outside of the time window of the token {}", window
+                );
+                return Err(server_config.upgrade_rejection.rejected(ErrorCode::AuthFailed));
+            }
+            Err(err) => {
+                warn!(
+                    event_id = event_id::AUTH_FAILURE,
+                    "Rejecting upgrade request with bad time window: {}", err
+                );
+                return Err(server_config.upgrade_rejection.rejected(ErrorCode::AuthFailed));
+            }
+        }
+    }
+
     Ok(jwt)
 }
 
+/// The timeout of the destination, unless the client asks for another one, up to the cap of the server
+fn connect_timeout(server_config: &WsServerConfig, claims: &JwtTunnelConfig) -> Duration {
+    let timeout = server_config
+        .destination_connect_timeouts
+        .get(&format!("{}:{}", claims.r, claims.rp))
+        .copied()
+        .unwrap_or(server_config.timeout_connect);
+    match claims.ct.filter(|secs| *secs > 0) {
+        Some(secs) => min(
+            Duration::from_secs(secs),
+            server_config.max_client_connect_timeout.unwrap_or(timeout),
+        ),
+        None => timeout,
+    }
+}
+
 #[inline]
+#[allow(clippy::result_large_err)]
 fn validate_destination(
-    _req: &Request<Incoming>,
+    req: &Request<Incoming>,
     jwt: &TokenData<JwtTunnelConfig>,
     destination_restriction: &Option<Vec<String>>,
+    identity_restrictions: &std::collections::HashMap<String, Vec<String>>,
+    rejection: &UpgradeRejection,
 ) -> Result<(), Response<String>> {
-    let Some(allowed_dests) = &destination_restriction else {
+    // The rules of the identity of the client certificate replace the global ones
+    let identity_restriction = req
+        .extensions()
+        .get::<ClientIdentity>()
+        .and_then(|ClientIdentity(identity)| identity_restrictions.get(identity));
+    let Some(allowed_dests) = identity_restriction.or(destination_restriction.as_ref()) else {
         return Ok(());
     };
 
     let requested_dest = format!("{}:{}", jwt.claims.r, jwt.claims.rp);
     if allowed_dests.iter().any(|dest| dest == &requested_dest).not() {
         warn!("Rejecting connection with not allowed destination: {}", requested_dest);
+        return Err(rejection.rejected(ErrorCode::DestForbidden));
+    }
+
+    Ok(())
+}
+
+/// Both sides must agree on payload encryption, returns the ciphers and the salt of the server
+#[inline]
+#[allow(clippy::result_large_err)]
+fn validate_payload_encryption(
+    req: &Request<Incoming>,
+    key: &Option<PayloadKey>,
+    rejection: &UpgradeRejection,
+) -> Result<Option<(PayloadCiphers, HeaderValue)>, Response<String>> {
+    match (key, req.headers().get(ENCRYPTION_HEADER)) {
+        (None, None) => Ok(None),
+        (Some(key), Some(client_salt)) => {
+            let Ok(client_salt) = encryption::decode_salt(client_salt.as_bytes()) else {
+                warn!("Rejecting connection with invalid payload encryption salt");
+                return Err(rejection.response());
+            };
+            let server_salt = encryption::new_salt();
+            let ciphers = encryption::ciphers(key, &client_salt, &server_salt, false);
+            let header = HeaderValue::from_str(&encryption::encode_salt(&server_salt)).unwrap();
+            Ok(Some((ciphers, header)))
+        }
+        (Some(_), None) => {
+            warn!("Rejecting connection without payload encryption");
+            Err(rejection.rejected(ErrorCode::AuthFailed))
+        }
+        (None, Some(_)) => {
+            warn!("Rejecting connection requesting payload encryption, no payload encryption key is configured");
+            Err(rejection.response())
+        }
+    }
+}
+
+#[inline]
+#[allow(clippy::result_large_err)]
+fn validate_credentials(
+    req: &Request<Incoming>,
+    allowed_credentials: &Option<Vec<HeaderValue>>,
+) -> Result<(), Response<String>> {
+    let Some(allowed_credentials) = allowed_credentials else {
+        return Ok(());
+    };
+
+    let authorized = req.headers().get(AUTHORIZATION).is_some_and(|auth| {
+        allowed_credentials
+            .iter()
+            .any(|credentials| constant_time_eq(credentials.as_bytes(), auth.as_bytes()))
+    });
+    if !authorized {
+        warn!(
+            event_id = event_id::AUTH_FAILURE,
+            "Rejecting connection with missing or bad credentials: {}",
+            req.uri()
+        );
         return Err(http::Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Invalid upgrade request".to_string())
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Basic realm=\"Restricted\"")
+            .body("Unauthorized".to_string())
             .unwrap());
     }
 
     Ok(())
 }
 
-async fn server_upgrade(server_config: Arc<WsServerConfig>, mut req: Request<Incoming>) -> Response<String> {
+async fn server_upgrade(
+    server_config: Arc<WsServerConfig>,
+    peer_addr: SocketAddr,
+    mut req: Request<Incoming>,
+) -> Response<String> {
+    let peer = Peer::new(peer_addr, &req);
+    if let Err(err) = validate_credentials(&req, &server_config.restrict_http_upgrade_credentials) {
+        events::notify(
+            Some(&peer),
+            Event::AuthFailure {
+                reason: "bad credentials",
+            },
+        );
+        return server_config.upgrade_rejection.with_code(err, ErrorCode::AuthFailed);
+    }
+
+    let rejection = &server_config.upgrade_rejection;
     if !fastwebsockets::upgrade::is_upgrade_request(&req) {
         warn!("Rejecting connection with bad upgrade request: {}", req.uri());
-        return http::Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Invalid upgrade request".to_string())
-            .unwrap();
+        return rejection.response();
     }
 
+    let protocols = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|header| header.to_str().ok());
+    let Some(version) = protocol_version::negotiate(protocols) else {
+        // Without the token of the client
+        let versions = protocols
+            .and_then(|header| header.split(JWT_HEADER_PREFIX).next())
+            .unwrap_or_default();
+        warn!(
+            "Rejecting upgrade request, no supported protocol version in {:?}",
+            versions.trim_end_matches([',', ' '])
+        );
+        return rejection.rejected(ErrorCode::VersionUnsupported);
+    };
+
     match extract_x_forwarded_for(&req) {
         Ok(Some(x_forward_for)) => {
             info!("Request X-Forwarded-For: {:?}", x_forward_for);
@@ -290,84 +1096,587 @@ async fn server_upgrade(server_config: Arc<WsServerConfig>, mut req: Request<Inc
         Err(err) => return err,
     }
 
-    if let Err(err) = validate_url(&req, &server_config.restrict_http_upgrade_path_prefix) {
+    if let Err(err) = validate_url(
+        &req,
+        &server_config.restrict_http_upgrade_path_prefix,
+        &server_config.http_upgrade_path_secret,
+        rejection,
+    ) {
+        events::notify(
+            Some(&peer),
+            Event::AuthFailure {
+                reason: "bad upgrade path",
+            },
+        );
         return err;
     }
 
-    let jwt = match extract_tunnel_info(&req) {
+    let mut jwt = match extract_tunnel_info(&req, &server_config) {
         Ok(jwt) => jwt,
-        Err(err) => return err,
+        Err(err) => {
+            events::notify(Some(&peer), Event::AuthFailure { reason: "bad token" });
+            return err;
+        }
     };
 
     Span::current().record("id", &jwt.claims.id);
     Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
+    let correlation_id = correlation_id(&req).unwrap_or(&jwt.claims.id).to_string();
+    if correlation_id != jwt.claims.id {
+        Span::current().record("correlation_id", &correlation_id);
+    }
+    let Ok(correlation_header) = HeaderValue::from_str(&correlation_id) else {
+        return rejection.response();
+    };
 
-    if let Err(err) = validate_destination(&req, &jwt, &server_config.restrict_to) {
+    if let Err(err) = validate_destination(
+        &req,
+        &jwt,
+        &server_config.restrict_to,
+        &server_config.restrict_to_identity,
+        rejection,
+    ) {
         return err;
     }
 
-    let tunnel = match run_tunnel(&server_config, jwt).await {
+    // Reverse listeners are reserved for the same client after a restart. Only by the identity of its certificate,
+    // the claims of the token are chosen by the client
+    let owner = req
+        .extensions()
+        .get::<ClientIdentity>()
+        .map(|ClientIdentity(identity)| identity.clone());
+    let is_reverse = matches!(
+        jwt.claims.p,
+        LocalProtocol::ReverseTcp
+            | LocalProtocol::ReverseUdp { .. }
+            | LocalProtocol::ReverseSocks5
+            | LocalProtocol::ReverseSni { .. }
+            | LocalProtocol::ReverseHttp { .. }
+    );
+    if !is_reverse {
+        let vars = TemplateVars {
+            user: owner.as_deref(),
+            host: &jwt.claims.r,
+            port: jwt.claims.rp,
+        };
+        match DestinationTemplate::expand(&server_config.destination_templates, &vars) {
+            None => {}
+            Some(Ok((host, port))) => {
+                info!("Destination {}:{} expanded to {}:{}", jwt.claims.r, jwt.claims.rp, host, port);
+                (jwt.claims.r, jwt.claims.rp) = (host, port);
+            }
+            Some(Err(err)) => {
+                warn!("Rejecting connection: {:#}", err);
+                return rejection.rejected(ErrorCode::DestForbidden);
+            }
+        }
+        if let Some((host, port)) = rewrite_map::rewrite(&jwt.claims.r, jwt.claims.rp) {
+            info!("Destination {}:{} rewritten to {}:{}", jwt.claims.r, jwt.claims.rp, host, port);
+            (jwt.claims.r, jwt.claims.rp) = (host, port);
+        }
+    }
+
+    let (ciphers, encryption_salt) =
+        match validate_payload_encryption(&req, &server_config.payload_encryption_key, rejection) {
+            Ok(encryption) => encryption.unzip(),
+            Err(err) => return err,
+        };
+
+    // Client reconnecting to its session, the tunnel is already established
+    let session_id = jwt.claims.id.clone();
+    let session_tunnel = (jwt.claims.p.clone(), jwt.claims.r.clone(), jwt.claims.rp);
+    let session_resume = jwt.claims.s.clone();
+    let compression = jwt.claims.c;
+    let ready_ack = jwt.claims.ra.unwrap_or(false);
+    let half_close = jwt.claims.hc.unwrap_or(false);
+    let reset = jwt.claims.rs.unwrap_or(false);
+    let max_duration = jwt.claims.md.map(Duration::from_secs);
+    // Destinations the server classifies as bulk cannot be made interactive by the claim of the client
+    let qos = match server_config
+        .bulk_destinations
+        .contains(&format!("{}:{}", jwt.claims.r, jwt.claims.rp))
+    {
+        true => Qos::Bulk,
+        false => jwt.claims.q.unwrap_or_default(),
+    };
+    if let Some(client_rx) = session_resume.as_ref().and_then(|s| s.rx) {
+        return resume_session(
+            &session_id,
+            &session_tunnel,
+            client_rx,
+            req,
+            ciphers,
+            encryption_salt,
+            version,
+            rejection,
+        )
+        .await;
+    }
+
+    let account = match accounting::open(owner.as_deref(), jwt.claims.sub.as_deref().unwrap_or(&jwt.claims.id)) {
+        Ok(account) => account,
+        Err(err) => {
+            warn!("Rejecting connection: {}", err);
+            return rejection.rejected(ErrorCode::LimitExceeded);
+        }
+    };
+
+    let timeout = connect_timeout(&server_config, &jwt.claims);
+    // Reverse tunnels wait for their visitors as long as needed instead
+    let connect_timeout = Some(timeout).filter(|_| !is_reverse);
+    if jwt.claims.bp.is_some() && jwt.claims.p == LocalProtocol::ReverseTcp {
+        return bind_tunnel(
+            server_config.clone(),
+            req,
+            peer,
+            jwt.claims,
+            account,
+            (ciphers, encryption_salt),
+            version,
+            (max_duration, qos),
+            correlation_header,
+            rejection,
+        )
+        .await;
+    }
+
+    let tunnel_destination = format!("{}:{}", jwt.claims.r, jwt.claims.rp);
+    let connect_started_at = Instant::now();
+    let tunnel = run_tunnel(&server_config, jwt, peer_addr, &correlation_id, owner.as_deref(), timeout);
+    let tunnel = match connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, tunnel)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("destination not connected after {:?}", timeout))),
+        None => tunnel.await,
+    };
+    let tunnel = match tunnel {
         Ok(ret) => ret,
         Err(err) => {
+            metrics::incr(&METRICS.connect_errors, 1);
             warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
-            return http::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("Invalid upgrade request".to_string())
-                .unwrap();
+            return rejection.rejected(ErrorCode::DestUnreachable);
         }
     };
 
-    let (protocol, dest, port, local_rx, local_tx) = tunnel;
+    let ((protocol, dest, port, local_rx, local_tx, abort), requeue) = tunnel;
+    // Only once connected, a reverse tunnel waiting for its visitor is not open yet
+    let Some(registered) = registry::register(tunnel_destination, server_config.max_tunnels) else {
+        warn!("Rejecting connection, too many tunnels open");
+        metrics::incr(&METRICS.upgrade_rejections, 1);
+        // The visitor goes to the next client of the listener
+        if let Some(requeue) = requeue {
+            requeue.requeue(local_rx, local_tx);
+        }
+        let response = http::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body("Service Unavailable".to_string())
+            .unwrap();
+        return rejection.with_code(response, ErrorCode::LimitExceeded);
+    };
     info!("connected to {:?} {:?} {:?}", protocol, dest, port);
+    let destination = format!("{}:{}", dest, port);
+    // Reverse tunnels wait for an incoming connection instead of connecting to their destination
+    let is_forward = matches!(protocol, LocalProtocol::Tcp | LocalProtocol::Udp { .. });
+    if is_forward {
+        metrics::record_latency(Latency::Connect, Some(&destination), connect_started_at.elapsed());
+    }
+    // Only once the client is ready, a visitor given back to its listener is not part of this tunnel
+    let watch = {
+        let session_id = session_id.clone();
+        move |local_rx: LocalRx, local_tx: LocalTx| -> (LocalRx, LocalTx) {
+            let (local_rx, local_tx) =
+                events::watch_tunnel(Some(&peer), &session_id, destination.clone(), local_rx, local_tx);
+            let (local_rx, local_tx) = accounting::meter(account, local_rx, local_tx);
+            let local_rx: LocalRx = match is_forward {
+                true => Box::pin(FirstByte::new(local_rx, destination)),
+                false => Box::pin(local_rx),
+            };
+            (local_rx, Box::pin(local_tx))
+        }
+    };
+
+    // Udp datagrams cannot be replayed from the middle
+    let session_resume =
+        session_resume.filter(|_| !matches!(protocol, LocalProtocol::Udp { .. } | LocalProtocol::ReverseUdp { .. }));
+    // A resumable session carries the visitor over the next websocket instead
+    let ready = requeue.filter(|_| ready_ack && session_resume.is_none());
+    let wait_ready = ready.is_some();
+    // Datagrams have no end of stream, and sessions carry the tunnel over websockets of their own
+    let is_stream = session_resume.is_none()
+        && !matches!(
+            protocol,
+            LocalProtocol::Udp { .. } | LocalProtocol::ReverseUdp { .. } | LocalProtocol::Icmp
+        );
+    let (half_close, reset) = (half_close && is_stream, reset && is_stream);
     let (mut response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
         Ok(ret) => ret,
         Err(err) => {
             warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
-            return http::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(format!("Invalid upgrade request: {:?}", err))
-                .unwrap();
+            return rejection.response();
         }
     };
+    // Older clients get it in the cookie header of the response
+    let first_frame = (protocol == LocalProtocol::ReverseSocks5 && version.has_control_frames()).then(|| {
+        ControlMessage::Destination {
+            host: dest.to_string(),
+            port,
+        }
+    });
+
+    match session_resume {
+        Some(resume) => {
+            let (local_rx, local_tx) = watch(local_rx, local_tx);
+            session::spawn_server_session(
+                session_id,
+                session_tunnel,
+                resume.timeout(),
+                ResumableSession::new(local_rx, local_tx, compression, qos),
+                fut,
+                server_config.websocket_upgrade_timeout,
+                ciphers,
+                server_config.websocket_mask_frame,
+                registered,
+                max_duration,
+                first_frame,
+            );
+        }
+        None => spawn_tunnel(
+            server_config.clone(),
+            &protocol,
+            local_rx,
+            local_tx,
+            watch,
+            ready,
+            StreamEnd::new(half_close, reset, version.has_control_frames(), abort),
+            fut,
+            compression,
+            ciphers,
+            registered,
+            max_duration,
+            qos,
+            first_frame,
+        ),
+    }
+
+    if protocol == LocalProtocol::ReverseSocks5 && !version.has_control_frames() {
+        let Ok(header_val) = HeaderValue::from_str(
+            &base64::engine::general_purpose::STANDARD.encode(format!("https://{}:{}", dest, port)),
+        ) else {
+            error!("Bad headervalue for reverse socks5: {} {}", dest, port);
+            return rejection.response();
+        };
+        response.headers_mut().insert(COOKIE, header_val);
+    }
+    if let Some(compression) = compression {
+        response
+            .headers_mut()
+            .insert(COMPRESSION_HEADER, HeaderValue::from_static(compression.as_str()));
+    }
+    if let Some(salt) = encryption_salt {
+        response.headers_mut().insert(ENCRYPTION_HEADER, salt);
+    }
+    if wait_ready {
+        response
+            .headers_mut()
+            .insert(READY_ACK_HEADER, HeaderValue::from_static("true"));
+    }
+    if half_close {
+        response
+            .headers_mut()
+            .insert(HALF_CLOSE_HEADER, HeaderValue::from_static("true"));
+    }
+    if reset {
+        response
+            .headers_mut()
+            .insert(RESET_HEADER, HeaderValue::from_static("true"));
+    }
+    response.headers_mut().insert(REQUEST_ID_HEADER, correlation_header);
+    response
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, version.header_value());
+
+    Response::from_parts(response.into_parts().0, "".to_string())
+}
+
+/// X-Request-Id of the upgrade request, if it fits in the unique id TLV of the PROXY protocol
+fn correlation_id(req: &Request<Incoming>) -> Option<&str> {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+}
 
-    tokio::spawn(
+#[allow(clippy::too_many_arguments)]
+fn spawn_tunnel(
+    server_config: Arc<WsServerConfig>,
+    protocol: &LocalProtocol,
+    local_rx: LocalRx,
+    local_tx: LocalTx,
+    watch: impl FnOnce(LocalRx, LocalTx) -> (LocalRx, LocalTx) + Send + 'static,
+    ready: Option<Requeue>,
+    stream_end: Arc<StreamEnd>,
+    fut: UpgradeFut,
+    compression: Option<Compression>,
+    ciphers: Option<PayloadCiphers>,
+    registered: RegisteredTunnel,
+    max_duration: Option<Duration>,
+    qos: Qos,
+    first_frame: Option<ControlMessage>,
+) {
+    let (reliable_tx, reliable_rx) = reliable_udp(protocol);
+    let (tx_cipher, rx_cipher) = ciphers.unzip();
+    runtime::spawn_relay(
         async move {
-            let (ws_rx, mut ws_tx) = match fut.await {
-                Ok(ws) => ws.split(tokio::io::split),
-                Err(err) => {
+            let _registered = registered;
+            let upgraded = tokio::time::timeout(server_config.websocket_upgrade_timeout, fut).await;
+            let ws = match upgraded {
+                Ok(Ok(ws)) => Some(ws),
+                Ok(Err(err)) => {
                     error!("Error during http upgrade request: {:?}", err);
-                    return;
+                    None
+                }
+                Err(_) => {
+                    count_handshake_timeout("websocket upgrade");
+                    None
                 }
             };
+            let Some(mut ws) = ws else {
+                if let Some(requeue) = ready {
+                    requeue.requeue(local_rx, local_tx);
+                }
+                return;
+            };
+            if let Some(requeue) = ready {
+                if let Err(err) = ready::wait_ready(&mut ws, ready::READY_TIMEOUT).await {
+                    warn!("Giving the visitor back to its reverse listener: {:#}", err);
+                    requeue.requeue(local_rx, local_tx);
+                    return;
+                }
+            }
+            if let Some(frame) = first_frame {
+                if let Err(err) = ws.write_frame(frame.frame()).await {
+                    warn!("Cannot send the first control frame to the client: {}", err);
+                    return;
+                }
+            }
+            let (local_rx, local_tx) = watch(local_rx, local_tx);
+            let (ws_rx, mut ws_tx) = ws.split(tokio::io::split);
             let (close_tx, close_rx) = oneshot::channel::<()>();
+            let (pong_tx, pong_rx) = rtt::pong_channel();
             ws_tx.set_auto_apply_mask(server_config.websocket_mask_frame);
 
-            tokio::task::spawn(super::io::propagate_write(local_tx, ws_rx, close_rx).instrument(Span::current()));
+            tokio::task::spawn(
+                super::io::propagate_write(
+                    local_tx,
+                    ws_rx,
+                    close_rx,
+                    pong_tx,
+                    reliable_rx,
+                    compression,
+                    rx_cipher,
+                    qos,
+                    stream_end.clone(),
+                )
+                .instrument(Span::current()),
+            );
 
-            let _ = super::io::propagate_read(local_rx, ws_tx, close_tx, None).await;
+            let propagate_read = super::io::propagate_read(
+                local_rx,
+                ws_tx,
+                close_tx,
+                pong_rx,
+                None,
+                reliable_tx,
+                compression,
+                tx_cipher,
+                qos,
+                stream_end,
+            );
+            time_limits::with_max_duration(max_duration, propagate_read).await;
         }
         .instrument(Span::current()),
     );
+}
 
-    if protocol == LocalProtocol::ReverseSocks5 {
-        let Ok(header_val) = HeaderValue::from_str(
-            &base64::engine::general_purpose::STANDARD.encode(format!("https://{}:{}", dest, port)),
-        ) else {
-            error!("Bad headervalue for reverse socks5: {} {}", dest, port);
-            return http::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("Invalid upgrade request".to_string())
-                .unwrap();
+#[allow(clippy::too_many_arguments)]
+async fn resume_session(
+    session_id: &str,
+    session_tunnel: &(LocalProtocol, String, u16),
+    client_rx: u64,
+    mut req: Request<Incoming>,
+    ciphers: Option<PayloadCiphers>,
+    encryption_salt: Option<HeaderValue>,
+    version: ProtocolVersion,
+    rejection: &UpgradeRejection,
+) -> Response<String> {
+    let (mut response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
+            return rejection.response();
+        }
+    };
+
+    let server_rx = match session::resume_server_session(session_id, session_tunnel, client_rx, fut, ciphers).await {
+        Ok(server_rx) => server_rx,
+        Err(err) => {
+            warn!("Rejecting session resumption: {}", err);
+            return rejection.response();
+        }
+    };
+
+    response
+        .headers_mut()
+        .insert(SESSION_RX_HEADER, HeaderValue::from(server_rx));
+    if let Some(salt) = encryption_salt {
+        response.headers_mut().insert(ENCRYPTION_HEADER, salt);
+    }
+    response
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, version.header_value());
+
+    Response::from_parts(response.into_parts().0, "".to_string())
+}
+
+/// Socks5 BIND of a client, a reverse tcp tunnel whose listener takes a single connection, of the peer expected by
+/// the client. The upgrade acknowledges the listener, and the peer is given in the first frame once connected
+#[allow(clippy::too_many_arguments)]
+async fn bind_tunnel(
+    server_config: Arc<WsServerConfig>,
+    mut req: Request<Incoming>,
+    peer: Peer,
+    claims: JwtTunnelConfig,
+    account: Option<Arc<accounting::Account>>,
+    (ciphers, encryption_salt): (Option<PayloadCiphers>, Option<HeaderValue>),
+    version: ProtocolVersion,
+    (max_duration, qos): (Option<Duration>, Qos),
+    correlation_header: HeaderValue,
+    rejection: &UpgradeRejection,
+) -> Response<String> {
+    if !version.has_control_frames() {
+        warn!("Rejecting socks5 BIND, the client cannot be told its peer without control frames");
+        return rejection.rejected(ErrorCode::VersionUnsupported);
+    }
+    let listener = match BindListener::bind(&server_config, &claims).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            metrics::incr(&METRICS.connect_errors, 1);
+            warn!("Rejecting socks5 BIND: {:#}", err);
+            return rejection.rejected(ErrorCode::DestUnreachable);
+        }
+    };
+    let (mut response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
+            return rejection.response();
+        }
+    };
+
+    let compression = claims.c;
+    let destination = format!("{}:{}", claims.r, claims.rp);
+    let tunnel = async move {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(cnx) => cnx,
+            Err(err) => {
+                warn!("Closing the socks5 BIND on {}: {:#}", destination, err);
+                return;
+            }
         };
-        response.headers_mut().insert(COOKIE, header_val);
+        info!("Peer {} connected to the socks5 BIND on {}", peer_addr, destination);
+        let Some(registered) = registry::register(destination.clone(), server_config.max_tunnels) else {
+            warn!("Closing the socks5 BIND on {}, too many tunnels open", destination);
+            return;
+        };
+
+        let (local_rx, local_tx) = tcp::abortable_split(stream);
+        let abort = local_tx.abort_handle();
+        let watch = move |local_rx: LocalRx, local_tx: LocalTx| -> (LocalRx, LocalTx) {
+            let (local_rx, local_tx) = events::watch_tunnel(Some(&peer), &claims.id, destination, local_rx, local_tx);
+            let (local_rx, local_tx) = accounting::meter(account, local_rx, local_tx);
+            (Box::pin(local_rx), Box::pin(local_tx))
+        };
+        let first_frame = ControlMessage::Destination {
+            host: peer_addr.ip().to_string(),
+            port: peer_addr.port(),
+        };
+        spawn_tunnel(
+            server_config.clone(),
+            &LocalProtocol::ReverseTcp,
+            Box::pin(local_rx),
+            Box::pin(local_tx),
+            watch,
+            None,
+            StreamEnd::new(false, false, true, abort),
+            fut,
+            compression,
+            ciphers,
+            registered,
+            max_duration,
+            qos,
+            Some(first_frame),
+        );
+    };
+    tokio::spawn(tunnel.instrument(Span::current()));
+
+    if let Some(compression) = compression {
+        response
+            .headers_mut()
+            .insert(COMPRESSION_HEADER, HeaderValue::from_static(compression.as_str()));
     }
+    if let Some(salt) = encryption_salt {
+        response.headers_mut().insert(ENCRYPTION_HEADER, salt);
+    }
+    response.headers_mut().insert(REQUEST_ID_HEADER, correlation_header);
     response
         .headers_mut()
-        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
+        .insert(SEC_WEBSOCKET_PROTOCOL, version.header_value());
 
     Response::from_parts(response.into_parts().0, "".to_string())
 }
 
+/// Listener of a socks5 BIND, only the peer given by the client is accepted on it. Not its port, clients give the one
+/// of their own connection to the peer
+struct BindListener {
+    listener: TcpListener,
+    peers: Vec<IpAddr>,
+}
+
+impl BindListener {
+    async fn bind(server_config: &WsServerConfig, claims: &JwtTunnelConfig) -> anyhow::Result<Self> {
+        let bind_peer = claims.bp.as_deref().unwrap_or_default();
+        let (host, port) = bind_peer
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("bad peer {} for the bind", bind_peer))?;
+        let peers = tcp::resolve(&dns::parse_host(host)?, port.parse()?, &server_config.dns_resolver)
+            .await?
+            .into_iter()
+            .map(|addr| addr.ip().to_canonical())
+            .collect();
+        let bind = dns::socket_addr(&dns::parse_host(&claims.r)?, claims.rp)?;
+        let listener = TcpListener::bind(bind)
+            .await
+            .with_context(|| format!("cannot listen on {}", bind))?;
+        Ok(Self { listener, peers })
+    }
+
+    /// The first connection of the peer, the others are refused
+    async fn accept(self) -> anyhow::Result<(TcpStream, SocketAddr)> {
+        let accept = async {
+            loop {
+                let (stream, addr) = self.listener.accept().await?;
+                if self.peers.contains(&addr.ip().to_canonical()) {
+                    return anyhow::Ok((stream, addr));
+                }
+                warn!("Refusing {} on the listener of a socks5 BIND, expecting {:?}", addr, self.peers);
+            }
+        };
+        tokio::time::timeout(BIND_TIMEOUT, accept)
+            .await
+            .map_err(|_| anyhow!("the peer did not connect after {:?}", BIND_TIMEOUT))?
+    }
+}
+
 struct TlsContext<'a> {
     tls_acceptor: Arc<TlsAcceptor>,
     tls_reloader: TlsReloader,
@@ -378,8 +1687,14 @@ impl TlsContext<'_> {
     pub fn tls_acceptor(&mut self) -> &Arc<TlsAcceptor> {
         if self.tls_reloader.should_reload_certificate() {
             match tls::tls_acceptor(self.tls_config, Some(vec![b"http/1.1".to_vec()])) {
-                Ok(acceptor) => self.tls_acceptor = Arc::new(acceptor),
-                Err(err) => error!("Cannot reload TLS certificate {:?}", err),
+                Ok(acceptor) => {
+                    self.tls_acceptor = Arc::new(acceptor);
+                    health::recover(Check::TlsCertificate);
+                }
+                Err(err) => {
+                    error!(event_id = event_id::TLS_ERROR, "Cannot reload TLS certificate {:?}", err);
+                    health::fail(Check::TlsCertificate, format!("cannot reload: {}", err));
+                }
             };
         }
 
@@ -387,12 +1702,139 @@ impl TlsContext<'_> {
     }
 }
 
-pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()> {
-    info!("Starting wstunnel server listening on {}", server_config.bind);
+async fn counted_upgrade(
+    server_config: Arc<WsServerConfig>,
+    peer: SocketAddr,
+    req: Request<Incoming>,
+) -> Response<String> {
+    let response = server_upgrade(server_config, peer, req).await;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        metrics::incr(&METRICS.upgrade_rejections, 1);
+    }
+    response
+}
+
+/// Requests that are not websocket upgrades get the decoy website, if there is one
+async fn server_request(
+    server_config: Arc<WsServerConfig>,
+    req: Request<Incoming>,
+    peer: SocketAddr,
+    throttled: bool,
+) -> Response<Either<String, DecoyBody>> {
+    if throttled {
+        warn!("Rejecting request, too many handshakes");
+        metrics::incr(&METRICS.upgrade_rejections, 1);
+        let response = http::Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body("Too Many Requests".to_string())
+            .unwrap();
+        return server_config
+            .upgrade_rejection
+            .with_code(response, ErrorCode::LimitExceeded)
+            .map(Either::Left);
+    }
+
+    let Some(decoy) = &server_config.decoy else {
+        return counted_upgrade(server_config, peer, req).await.map(Either::Left);
+    };
+    if fastwebsockets::upgrade::is_upgrade_request(&req) {
+        return counted_upgrade(server_config.clone(), peer, req)
+            .await
+            .map(Either::Left);
+    }
+
+    if let Err(err) = validate_credentials(&req, &server_config.restrict_http_upgrade_credentials) {
+        return err.map(Either::Left);
+    }
+    decoy::serve(decoy, req).await.map(Either::Right)
+}
+
+fn count_handshake_timeout(phase: &str) {
+    let count = metrics::incr(&METRICS.handshake_timeouts, 1);
+    warn!(
+        "Dropping connection, {} not done in time ({} connections dropped so far)",
+        phase, count
+    );
+}
+
+fn on_connection_error(err: hyper::Error) {
+    if err.is_timeout() {
+        count_handshake_timeout("http upgrade");
+    } else {
+        error!("Error while upgrading cnx to websocket: {:?}", err);
+    }
+}
+
+// Connections accepted by the tcp or the unix socket listener
+trait ServerStream: AsyncRead + AsyncWrite + Unpin + Send {
+    fn as_tcp(&self) -> Option<&TcpStream>;
+}
+
+impl ServerStream for TcpStream {
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl ServerStream for tokio::net::UnixStream {
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+/// If the client starts with a TLS handshake, its first byte is peeked, not consumed
+async fn starts_with_tls_handshake(stream: Option<&TcpStream>) -> bool {
+    let Some(stream) = stream else {
+        return false;
+    };
+    let mut buf = [0; 1];
+    // Content type of the handshake records
+    matches!(stream.peek(&mut buf).await, Ok(1)) && buf[0] == 0x16
+}
+
+enum ServerListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl ServerListener {
+    fn bind(bind: &ServerBind, reuse_port: bool, mptcp: bool) -> anyhow::Result<Self> {
+        match bind {
+            ServerBind::Tcp { addr, .. } => Ok(Self::Tcp(tcp::bind_server(*addr, reuse_port, mptcp)?)),
+            #[cfg(unix)]
+            ServerBind::Unix { path, mode, owner } => {
+                Ok(Self::Unix(crate::unix_socket::bind(path, *mode, owner.as_deref())?))
+            }
+            #[cfg(not(unix))]
+            ServerBind::Unix { .. } => Err(anyhow!("Unix socket is not available for non unix platform")),
+        }
+    }
+
+    /// Peers of the unix socket are local, they are all seen as 127.0.0.1 by the limits per ip
+    async fn accept(&self) -> std::io::Result<(Box<dyn ServerStream>, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                let _ = stream.set_nodelay(true);
+                Ok((Box::new(stream), peer_addr))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0))))
+            }
+        }
+    }
+}
 
+pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()> {
     // setup upgrade request handler
     let config = server_config.clone();
-    let upgrade_fn = move |req: Request<Incoming>| server_upgrade(config.clone(), req).map::<anyhow::Result<_>, _>(Ok);
+    let upgrade_fn = move |req: Request<Incoming>, peer: SocketAddr, throttled: bool| {
+        server_request(config.clone(), req, peer, throttled).map::<anyhow::Result<_>, _>(Ok)
+    };
 
     // Init TLS if needed
     let mut tls_context = if let Some(tls_config) = &server_config.tls {
@@ -406,50 +1848,216 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
         None
     };
 
-    // Bind server and run forever to serve incoming connections.
-    let listener = TcpListener::bind(&server_config.bind).await?;
+    if let Some(sni_router_bind) = server_config.sni_router_bind {
+        info!("Starting sni router listening on {}", sni_router_bind);
+        tokio::spawn(async move {
+            if let Err(err) = router::run_sni_router(sni_router_bind).await {
+                error!("Sni router stopped: {:?}", err);
+            }
+        });
+    }
+
+    if let Some(http_router_bind) = server_config.http_router_bind {
+        info!("Starting http router listening on {}", http_router_bind);
+        tokio::spawn(async move {
+            if let Err(err) = router::run_http_router(http_router_bind).await {
+                error!("Http router stopped: {:?}", err);
+            }
+        });
+    }
+
+    if let Some(admin_bind) = server_config.admin_bind {
+        info!("Starting admin api listening on {}", admin_bind);
+        tokio::spawn(async move {
+            if let Err(err) = admin::run_admin_server(admin_bind).await {
+                error!("Admin api stopped: {:?}", err);
+            }
+        });
+    }
+
+    if let Some(cluster) = &server_config.cluster {
+        info!("Sharing the reverse tunnels with the cluster as {}", cluster.advertise);
+        tokio::spawn(cluster::run(cluster.clone(), server_config.jwt_secrets.clone()).instrument(Span::none()));
+    }
+
+    if !server_config.webhooks.is_empty() {
+        let tls_crypto_provider = server_config
+            .tls
+            .as_ref()
+            .map(|tls| tls.tls_crypto_provider)
+            .unwrap_or_default();
+        webhook::start(server_config.webhooks.clone(), tls_crypto_provider)?;
+    }
+
+    let spa_gate = match (server_config.spa_bind, &server_config.spa_secret) {
+        (Some(spa_bind), Some(spa_secret)) => {
+            let gate = Arc::new(SpaGate::new(spa_secret.clone(), server_config.spa_window));
+            tokio::spawn(gate.clone().run(spa_bind).map(|ret| {
+                if let Err(err) = ret {
+                    error!("Single packet authorization stopped: {:?}", err);
+                }
+            }));
+            Some(gate)
+        }
+        _ => None,
+    };
+    let handshake_limiter = server_config
+        .handshake_rate_limit
+        .map(|rate| Arc::new(HandshakeLimiter::new(rate, server_config.handshake_rate_burst)));
+    let pending_upgrades = server_config
+        .max_pending_upgrades_per_ip
+        .map(|max| Arc::new(PendingUpgrades::new(max)));
+    // Do not let slow clients hold connections forever in the middle of their handshake
+    let tls_handshake_timeout = server_config.tls_handshake_timeout;
+    let mut http_builder = http1::Builder::new();
+    http_builder
+        .timer(TokioTimer::new())
+        .header_read_timeout(server_config.http_upgrade_timeout);
+
+    // Bind server and run forever to serve incoming connections. All the listeners feed the same loop
+    let mut listeners = Vec::with_capacity(server_config.binds.len());
+    for bind in &server_config.binds {
+        info!("Starting wstunnel server listening on {}", bind);
+        let listener = ServerListener::bind(bind, server_config.reuse_port, server_config.mptcp)
+            .with_context(|| format!("cannot listen on {}", bind))?;
+        let with_tls = matches!(bind, ServerBind::Tcp { tls: true, .. });
+        listeners.push(
+            stream::unfold(listener, |listener| async { Some((listener.accept().await, listener)) })
+                .map(move |accepted| (accepted, with_tls))
+                .boxed(),
+        );
+    }
+    let mut listeners = stream::select_all(listeners);
+    restore_reverse_listeners(&server_config).await;
+    health::set_max_tunnels(server_config.max_tunnels);
+    health::set_listening(true);
+    let drain_requested = drain::drain_requested();
+    pin_mut!(drain_requested);
     loop {
-        let (stream, peer_addr) = match listener.accept().await {
+        let (accepted, with_tls) = select! {
+            Some(accepted) = listeners.next() => accepted,
+            ret = &mut drain_requested => {
+                ret?;
+                break;
+            }
+        };
+        let (stream, peer_addr) = match accepted {
             Ok(ret) => ret,
             Err(err) => {
                 warn!("Error while accepting connection {:?}", err);
                 continue;
             }
         };
-        let _ = stream.set_nodelay(true);
+        // Stay silent, the server must not be discovered by scans
+        if spa_gate.as_ref().is_some_and(|gate| !gate.is_allowed(peer_addr.ip())) {
+            debug!("Dropping connection from {} without a valid knock", peer_addr);
+            continue;
+        }
+        if handshake_limiter
+            .as_ref()
+            .is_some_and(|limiter| !limiter.try_acquire(peer_addr.ip().to_canonical()))
+        {
+            debug!("Dropping connection from {}, too many handshakes", peer_addr);
+            continue;
+        }
+        // Released once the connection is upgraded to websocket, or closed
+        let pending_upgrade = match pending_upgrades
+            .as_ref()
+            .map(|pending| pending.try_acquire(peer_addr.ip()))
+        {
+            Some(None) => {
+                debug!("Dropping connection from {}, too many pending upgrades", peer_addr);
+                continue;
+            }
+            Some(Some(pending_upgrade)) => Some(pending_upgrade),
+            None => None,
+        };
 
         let span = span!(
             Level::INFO,
             "tunnel",
             id = tracing::field::Empty,
+            correlation_id = tracing::field::Empty,
+            tunnel = tracing::field::Empty,
             remote = tracing::field::Empty,
             peer = peer_addr.to_string(),
-            forwarded_for = tracing::field::Empty
+            forwarded_for = tracing::field::Empty,
+            identity = tracing::field::Empty
         );
 
         info!("Accepting connection");
         let upgrade_fn = upgrade_fn.clone();
+        let handshake_limiter = handshake_limiter.clone();
+        let requests = AtomicUsize::new(0);
+        let upgrade_fn = move |req| {
+            // The first request is counted with the connection, others must not go through the limiter for free
+            let throttled = requests.fetch_add(1, Ordering::Relaxed) > 0
+                && handshake_limiter
+                    .as_ref()
+                    .is_some_and(|limiter| !limiter.try_acquire(peer_addr.ip().to_canonical()));
+            upgrade_fn(req, peer_addr, throttled)
+        };
+        let http_builder = http_builder.clone();
         // TLS
-        if let Some(tls) = tls_context.as_mut() {
+        if let Some(tls) = tls_context.as_mut().filter(|_| with_tls) {
             // Reload TLS certificate if needed
             let tls_acceptor = tls.tls_acceptor().clone();
+            let tls_auto_detect = tls.tls_config.tls_auto_detect;
             let fut = async move {
+                if tls_auto_detect {
+                    match tokio::time::timeout(tls_handshake_timeout, starts_with_tls_handshake(stream.as_tcp())).await
+                    {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            debug!("Serving plain http, the connection does not start with a TLS handshake");
+                            let conn_fut = http_builder
+                                .serve_connection(hyper_util::rt::TokioIo::new(stream), service_fn(upgrade_fn))
+                                .with_upgrades();
+                            if let Err(e) = conn_fut.await {
+                                on_connection_error(e);
+                            }
+                            drop(pending_upgrade);
+                            return;
+                        }
+                        Err(_) => {
+                            count_handshake_timeout("TLS handshake");
+                            return;
+                        }
+                    }
+                }
+
                 info!("Doing TLS handshake");
-                let tls_stream = match tls_acceptor.accept(stream).await {
-                    Ok(tls_stream) => hyper_util::rt::TokioIo::new(tls_stream),
-                    Err(err) => {
-                        error!("error while accepting TLS connection {}", err);
+                let tls_stream = match tokio::time::timeout(tls_handshake_timeout, tls_acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => tls_stream,
+                    Ok(Err(err)) => {
+                        error!(event_id = event_id::TLS_ERROR, "error while accepting TLS connection {}", err);
                         return;
                     }
+                    Err(_) => {
+                        count_handshake_timeout("TLS handshake");
+                        return;
+                    }
+                };
+
+                let identity = client_identity(tls_stream.get_ref().1.peer_certificates());
+                if let Some(identity) = &identity {
+                    Span::current().record("identity", identity);
+                }
+                let upgrade_fn = move |mut req: Request<Incoming>| {
+                    if let Some(identity) = &identity {
+                        req.extensions_mut().insert(ClientIdentity(identity.clone()));
+                    }
+                    upgrade_fn(req)
                 };
 
-                let conn_fut = http1::Builder::new()
-                    .serve_connection(tls_stream, service_fn(upgrade_fn))
+                let conn_fut = http_builder
+                    .serve_connection(hyper_util::rt::TokioIo::new(tls_stream), service_fn(upgrade_fn))
                     .with_upgrades();
 
                 if let Err(e) = conn_fut.await {
-                    error!("Error while upgrading cnx to websocket: {:?}", e);
+                    on_connection_error(e);
                 }
+                drop(pending_upgrade);
             }
             .instrument(span);
 
@@ -457,18 +2065,30 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
             // Normal
         } else {
             let stream = hyper_util::rt::TokioIo::new(stream);
-            let conn_fut = http1::Builder::new()
+            let conn_fut = http_builder
                 .serve_connection(stream, service_fn(upgrade_fn))
                 .with_upgrades();
 
             let fut = async move {
                 if let Err(e) = conn_fut.await {
-                    error!("Error while upgrading cnx to websocket: {:?}", e);
+                    on_connection_error(e);
                 }
+                drop(pending_upgrade);
             }
             .instrument(span);
 
             tokio::spawn(fut);
         };
     }
+
+    // A new server has taken over the listening address, let the tunnels of this one end before exiting
+    info!(
+        "Stop accepting connections, draining tunnels for at most {:?}",
+        server_config.drain_timeout
+    );
+    health::set_listening(false);
+    drop(listeners);
+    drain::wait_tunnels_closed(server_config.drain_timeout).await;
+
+    Ok(())
 }
@@ -0,0 +1,469 @@
+use ahash::{HashMap, HashMapExt};
+use anyhow::anyhow;
+use fastwebsockets::upgrade::UpgradeFut;
+use fastwebsockets::{Frame, OpCode, Payload, WebSocket};
+use futures_util::future::ready;
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, info, warn, Instrument, Span};
+
+use super::control_frame::ControlMessage;
+use super::io::{compress, decompress, RelayBudget};
+use super::memory::{self, BufferUsage};
+use super::registry::RegisteredTunnel;
+use super::rtt::{self, RoundTrip};
+use super::time_limits;
+use crate::encryption::PayloadCiphers;
+use crate::metrics::{self, Latency, METRICS};
+use crate::runtime;
+use crate::{Compression, LocalProtocol, Qos};
+
+/// Response header used by the server to tell, when resuming a session, how many bytes it has received from the client
+pub(super) static SESSION_RX_HEADER: &str = "x-wstunnel-session-rx";
+
+/// Bytes sent over the websocket are kept around to be replayed in case the websocket is lost before the peer
+/// received them. The peer does not acknowledge what it receives, so only the most recent bytes are kept.
+const REPLAY_BUFFER_CAPACITY: usize = 1024 * 1024;
+const MAX_RESUME_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwtSessionResume {
+    /// How long, in seconds, the tunnel should be kept waiting for the client to reconnect
+    pub timeout: u64,
+    /// Only set when reconnecting, number of bytes already received by the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rx: Option<u64>,
+}
+
+impl JwtSessionResume {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout).min(MAX_RESUME_TIMEOUT)
+    }
+}
+
+struct ReplayBuffer {
+    buf: VecDeque<u8>,
+    tx_offset: u64,
+}
+
+impl ReplayBuffer {
+    fn push(&mut self, data: &[u8]) {
+        self.tx_offset += data.len() as u64;
+        if data.len() >= REPLAY_BUFFER_CAPACITY {
+            self.buf.clear();
+            self.buf.extend(&data[data.len() - REPLAY_BUFFER_CAPACITY..]);
+            return;
+        }
+
+        let overflow = (self.buf.len() + data.len()).saturating_sub(REPLAY_BUFFER_CAPACITY);
+        self.buf.drain(..overflow);
+        self.buf.extend(data);
+    }
+
+    /// Bytes sent after the given offset, if we still have them
+    fn since(&mut self, offset: u64) -> Option<&[u8]> {
+        let start_offset = self.tx_offset - self.buf.len() as u64;
+        if offset < start_offset || offset > self.tx_offset {
+            return None;
+        }
+
+        Some(&self.buf.make_contiguous()[(offset - start_offset) as usize..])
+    }
+}
+
+pub(super) enum PumpEnd<T> {
+    /// One side of the tunnel is gone, the session is over
+    Closed,
+    /// The websocket has been lost, the session can be resumed over a new one
+    Disconnected,
+    Interrupted(T),
+}
+
+/// Local side of a tunnel, that can be carried over successive websockets
+pub(super) struct ResumableSession {
+    local_rx: Pin<Box<dyn AsyncRead + Send>>,
+    local_tx: Pin<Box<dyn AsyncWrite + Send>>,
+    replay: ReplayBuffer,
+    rx_offset: u64,
+    compression: Option<Compression>,
+    qos: Qos,
+    usage: BufferUsage,
+}
+
+impl ResumableSession {
+    pub fn new(
+        local_rx: Pin<Box<dyn AsyncRead + Send>>,
+        local_tx: Pin<Box<dyn AsyncWrite + Send>>,
+        compression: Option<Compression>,
+        qos: Qos,
+    ) -> Self {
+        Self {
+            local_rx,
+            local_tx,
+            replay: ReplayBuffer {
+                buf: VecDeque::new(),
+                tx_offset: 0,
+            },
+            rx_offset: 0,
+            compression,
+            qos,
+            usage: BufferUsage::new(),
+        }
+    }
+
+    /// Number of bytes received from the peer so far
+    pub fn rx_offset(&self) -> u64 {
+        self.rx_offset
+    }
+
+    pub fn can_resume_from(&mut self, peer_rx: u64) -> bool {
+        self.replay.since(peer_rx).is_some()
+    }
+
+    /// Forward traffic between the local side and the websocket, until one of them is closed or interrupt resolves.
+    /// The bytes the peer has not received (it got peer_rx bytes so far) are sent first
+    pub async fn pump<I: Future>(
+        &mut self,
+        ws: WebSocket<TokioIo<Upgraded>>,
+        ciphers: Option<PayloadCiphers>,
+        peer_rx: u64,
+        ping_frequency: Option<Duration>,
+        interrupt: I,
+    ) -> PumpEnd<I::Output> {
+        let (mut ws_rx, mut ws_tx) = ws.split(tokio::io::split);
+        let (mut tx_cipher, mut rx_cipher) = ciphers.unzip();
+        let Some(replay) = self.replay.since(peer_rx) else {
+            warn!("Cannot resume session, peer is too far behind");
+            return PumpEnd::Closed;
+        };
+        if !replay.is_empty() {
+            info!("Replaying {} bytes not received by the peer", replay.len());
+            let replay = match self.compression {
+                None => Ok(replay.to_vec()),
+                Some(compression) => compress(compression, replay),
+            };
+            let Ok(mut replay) = replay else {
+                return PumpEnd::Closed;
+            };
+            if let Some(cipher) = tx_cipher.as_mut() {
+                replay = cipher.seal(&replay);
+            }
+            if ws_tx.write_frame(Frame::binary(Payload::Owned(replay))).await.is_err() {
+                return PumpEnd::Disconnected;
+            }
+        }
+
+        let Self {
+            local_rx,
+            local_tx,
+            replay,
+            rx_offset,
+            compression,
+            qos,
+            usage,
+        } = self;
+        let compression = *compression;
+        let qos = *qos;
+
+        let ws_tx_ref = &mut ws_tx;
+        let (pong_tx, mut pong_rx) = rtt::pong_channel();
+        let local_to_ws = async move {
+            let mut buffer = vec![0u8; 64 * 1024];
+            let mut budget = RelayBudget::new(qos);
+            let frequency = ping_frequency.unwrap_or(Duration::from_secs(3600 * 24));
+            let start_at = Instant::now().checked_add(frequency).unwrap_or(Instant::now());
+            let mut timeout = tokio::time::interval_at(start_at, frequency);
+
+            loop {
+                usage.set(buffer.capacity() + replay.buf.len());
+                let paused = usage.should_pause();
+                let read_len = select! {
+                    biased;
+
+                    read_len = local_rx.read(&mut buffer), if !paused => read_len,
+
+                    Some(payload) = pong_rx.recv() => {
+                        if ws_tx_ref.write_frame(Frame::pong(Payload::Owned(payload))).await.is_err() {
+                            return PumpEnd::Disconnected;
+                        }
+                        continue;
+                    }
+
+                    _ = timeout.tick(), if ping_frequency.is_some() => {
+                        let ping = Frame::new(true, OpCode::Ping, None, Payload::Owned(rtt::ping_payload()));
+                        if ws_tx_ref.write_frame(ping).await.is_err() {
+                            return PumpEnd::Disconnected;
+                        }
+                        continue;
+                    }
+
+                    _ = tokio::time::sleep(memory::RECHECK_INTERVAL), if paused => continue,
+                };
+
+                let read_len = match read_len {
+                    Ok(0) => return PumpEnd::Closed,
+                    Ok(read_len) => read_len,
+                    Err(err) => {
+                        warn!("error while reading incoming bytes from local tx tunnel: {}", err);
+                        return PumpEnd::Closed;
+                    }
+                };
+                metrics::incr(&METRICS.bytes_sent, read_len as u64);
+
+                // Must be done before writing the frame, as masking is applied in place
+                replay.push(&buffer[..read_len]);
+                let payload = match compression {
+                    None => Payload::BorrowedMut(&mut buffer[..read_len]),
+                    Some(compression) => match compress(compression, &buffer[..read_len]) {
+                        Ok(compressed) => Payload::Owned(compressed),
+                        Err(err) => {
+                            warn!("error while compressing tunnel payload {}", err);
+                            return PumpEnd::Closed;
+                        }
+                    },
+                };
+                let payload = match tx_cipher.as_mut() {
+                    None => payload,
+                    Some(cipher) => Payload::Owned(cipher.seal(&payload)),
+                };
+                if let Err(err) = ws_tx_ref.write_frame(Frame::binary(payload)).await {
+                    warn!("error while writing to websocket tx tunnel {}", err);
+                    return PumpEnd::Disconnected;
+                }
+                budget.consume(read_len).await;
+            }
+        };
+
+        let ws_to_local = async move {
+            let mut budget = RelayBudget::new(qos);
+            let mut round_trip = RoundTrip::default();
+            let mut send_fn = |frame: Frame<'_>| {
+                if frame.opcode == OpCode::Pong {
+                    let _ = pong_tx.try_send(frame.payload.to_vec());
+                }
+                ready(anyhow::Ok(()))
+            };
+            loop {
+                let msg = match ws_rx.read_frame(&mut send_fn).await {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        warn!("error while reading from websocket rx {}", err);
+                        return PumpEnd::Disconnected;
+                    }
+                };
+
+                match msg.opcode {
+                    OpCode::Continuation | OpCode::Text | OpCode::Binary => {}
+                    OpCode::Close => return PumpEnd::Closed,
+                    OpCode::Ping => continue,
+                    OpCode::Pong => {
+                        if let Some(sample) = round_trip.on_pong(&msg.payload) {
+                            debug!("Websocket round trip time {:?}, smoothed {:?}", sample, round_trip.smoothed());
+                            metrics::record_latency(Latency::RoundTrip, None, sample);
+                        }
+                        continue;
+                    }
+                }
+
+                let decrypted = match rx_cipher.as_mut() {
+                    None => None,
+                    Some(cipher) => match cipher.open(msg.payload.as_ref()) {
+                        Ok(data) => Some(data),
+                        Err(err) => {
+                            warn!("{}", err);
+                            return PumpEnd::Closed;
+                        }
+                    },
+                };
+                let frame = decrypted.as_deref().unwrap_or(msg.payload.as_ref());
+                let decompressed = match compression {
+                    None => None,
+                    Some(compression) => match decompress(compression, frame) {
+                        Ok(data) => Some(data),
+                        Err(err) => {
+                            warn!("error while decompressing tunnel payload {}", err);
+                            return PumpEnd::Closed;
+                        }
+                    },
+                };
+
+                // Account bytes as soon as they are written, so we never ask the peer to replay more or less than
+                // what the local side actually received, even if we are cancelled in the middle
+                let mut data = decompressed.as_deref().unwrap_or(frame);
+                let data_len = data.len();
+                while !data.is_empty() {
+                    match local_tx.write(data).await {
+                        Ok(0) | Err(_) => return PumpEnd::Closed,
+                        Ok(len) => {
+                            *rx_offset += len as u64;
+                            metrics::incr(&METRICS.bytes_received, len as u64);
+                            data = &data[len..];
+                        }
+                    }
+                }
+                budget.consume(data_len).await;
+            }
+        };
+
+        let end = select! {
+            biased;
+            ret = interrupt => PumpEnd::Interrupted(ret),
+            end = local_to_ws => end,
+            end = ws_to_local => end,
+        };
+
+        if let PumpEnd::Closed = end {
+            let _ = ws_tx.write_frame(Frame::close(1000, &[])).await;
+        }
+
+        end
+    }
+}
+
+/// A client reconnecting to its session, with the number of bytes it has received and a channel to get
+/// the number of bytes the server has received back
+type SessionAttach = (u64, UpgradeFut, Option<PayloadCiphers>, oneshot::Sender<u64>);
+
+#[allow(clippy::type_complexity)]
+static SESSIONS: Lazy<Mutex<HashMap<String, ((LocalProtocol, String, u16), mpsc::Sender<SessionAttach>)>>> =
+    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+
+/// Run a new resumable session on the server side, waiting for the client to reconnect when the websocket is lost
+#[allow(clippy::too_many_arguments)]
+pub(super) fn spawn_server_session(
+    id: String,
+    tunnel: (LocalProtocol, String, u16),
+    timeout: Duration,
+    mut session: ResumableSession,
+    upgrade: UpgradeFut,
+    upgrade_timeout: Duration,
+    ciphers: Option<PayloadCiphers>,
+    websocket_mask_frame: bool,
+    registered: RegisteredTunnel,
+    max_duration: Option<Duration>,
+    first_frame: Option<ControlMessage>,
+) {
+    let (attach_tx, mut attach_rx) = mpsc::channel::<SessionAttach>(1);
+    SESSIONS.lock().insert(id.clone(), (tunnel, attach_tx));
+
+    let fut = async move {
+        let _registered = registered;
+        let _guard = scopeguard::guard((), |_| {
+            SESSIONS.lock().remove(&id);
+            info!("Closing resumable session");
+        });
+
+        let mut upgrade = upgrade;
+        let mut ciphers = ciphers;
+        let mut peer_rx = 0;
+        // Only on the first websocket, the client has it already when it resumes the session
+        let mut first_frame = first_frame;
+        loop {
+            let mut ws = match tokio::time::timeout(upgrade_timeout, upgrade).await {
+                Ok(Ok(ws)) => ws,
+                Ok(Err(err)) => {
+                    warn!("Error during http upgrade request: {:?}", err);
+                    return;
+                }
+                Err(_) => {
+                    warn!("Websocket still not upgraded after {:?}", upgrade_timeout);
+                    return;
+                }
+            };
+            ws.set_auto_apply_mask(websocket_mask_frame);
+            if let Some(frame) = first_frame.take() {
+                if let Err(err) = ws.write_frame(frame.frame()).await {
+                    warn!("Cannot send the first control frame to the client: {}", err);
+                    return;
+                }
+            }
+
+            let attach = match session.pump(ws, ciphers, peer_rx, None, attach_rx.recv()).await {
+                PumpEnd::Closed => return,
+                PumpEnd::Interrupted(attach) => attach,
+                PumpEnd::Disconnected => {
+                    info!("Websocket lost, waiting {:?} for the client to resume the session", timeout);
+                    tokio::time::timeout(timeout, attach_rx.recv()).await.ok().flatten()
+                }
+            };
+
+            let Some((client_rx, next_upgrade, next_ciphers, rx_tx)) = attach else {
+                return;
+            };
+            if !session.can_resume_from(client_rx) {
+                warn!("Cannot resume session, client is too far behind");
+                return;
+            }
+
+            info!("Resuming session");
+            let _ = rx_tx.send(session.rx_offset());
+            peer_rx = client_rx;
+            upgrade = next_upgrade;
+            ciphers = next_ciphers;
+        }
+    };
+
+    // The duration counts from the opening of the session, resuming it does not extend it
+    runtime::spawn_relay(time_limits::with_max_duration(max_duration, fut).instrument(Span::current()));
+}
+
+/// Hand over a new websocket to an existing session, returns the number of bytes the server has received
+pub(super) async fn resume_server_session(
+    id: &str,
+    tunnel: &(LocalProtocol, String, u16),
+    client_rx: u64,
+    upgrade: UpgradeFut,
+    ciphers: Option<PayloadCiphers>,
+) -> anyhow::Result<u64> {
+    let attach_tx = match SESSIONS.lock().get(id) {
+        Some((session_tunnel, attach_tx)) if session_tunnel == tunnel => attach_tx.clone(),
+        Some(_) => return Err(anyhow!("session {} does not belong to this tunnel", id)),
+        None => return Err(anyhow!("unknown or expired session {}", id)),
+    };
+
+    let (rx_tx, rx_rx) = oneshot::channel();
+    attach_tx
+        .send((client_rx, upgrade, ciphers, rx_tx))
+        .await
+        .map_err(|_| anyhow!("session {} is closed", id))?;
+
+    rx_rx.await.map_err(|_| anyhow!("session {} cannot be resumed", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_buffer() {
+        let mut replay = ReplayBuffer {
+            buf: VecDeque::new(),
+            tx_offset: 0,
+        };
+        replay.push(b"hello");
+        replay.push(b" world");
+        assert_eq!(replay.since(0), Some(b"hello world".as_slice()));
+        assert_eq!(replay.since(6), Some(b"world".as_slice()));
+        assert_eq!(replay.since(11), Some(b"".as_slice()));
+        assert_eq!(replay.since(12), None);
+
+        replay.push(&vec![b'a'; REPLAY_BUFFER_CAPACITY]);
+        assert_eq!(replay.since(10), None);
+        assert_eq!(replay.since(11).map(|b| b.len()), Some(REPLAY_BUFFER_CAPACITY));
+
+        replay.push(b"b");
+        assert_eq!(replay.buf.len(), REPLAY_BUFFER_CAPACITY);
+        assert_eq!(replay.since(replay.tx_offset - 2), Some(b"ab".as_slice()));
+    }
+}
@@ -0,0 +1,225 @@
+use ahash::{HashMap, HashMapExt};
+use anyhow::anyhow;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use url::Host;
+
+use super::constant_time_eq;
+
+const TIMESTAMP_LEN: usize = 8;
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+const KNOCK_LEN: usize = TIMESTAMP_LEN + NONCE_LEN + TAG_LEN;
+// Knocks are only valid this long around the clock of the server
+const MAX_CLOCK_SKEW: u64 = 60;
+/// Clients knock again when their previous knock is older than this, the window of the server must be longer
+pub const KNOCK_REFRESH: Duration = Duration::from_secs(10);
+// Give the server a moment to process the knock before connecting
+const KNOCK_DELAY: Duration = Duration::from_millis(50);
+
+/// Secret shared by the client and the server to authenticate the knock sent before connecting
+#[derive(Clone)]
+pub struct SpaSecret(Arc<[u8]>);
+
+impl SpaSecret {
+    pub fn new(secret: &str) -> anyhow::Result<Self> {
+        if secret.is_empty() {
+            return Err(anyhow!("single packet authorization secret cannot be empty"));
+        }
+
+        Ok(Self(secret.as_bytes().into()))
+    }
+
+    fn tag(&self, signed: &[u8]) -> [u8; TAG_LEN] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("hmac accepts keys of any size");
+        mac.update(b"wstunnel knock");
+        mac.update(signed);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn knock(&self, now: u64) -> [u8; KNOCK_LEN] {
+        let mut knock = [0u8; KNOCK_LEN];
+        knock[..TIMESTAMP_LEN].copy_from_slice(&now.to_be_bytes());
+        OsRng.fill_bytes(&mut knock[TIMESTAMP_LEN..TIMESTAMP_LEN + NONCE_LEN]);
+        let tag = self.tag(&knock[..TIMESTAMP_LEN + NONCE_LEN]);
+        knock[TIMESTAMP_LEN + NONCE_LEN..].copy_from_slice(&tag);
+        knock
+    }
+
+    /// Timestamp and nonce of a valid knock
+    fn verify(&self, knock: &[u8], now: u64) -> Option<(u64, [u8; NONCE_LEN])> {
+        if knock.len() != KNOCK_LEN {
+            return None;
+        }
+
+        let (signed, tag) = knock.split_at(TIMESTAMP_LEN + NONCE_LEN);
+        if !constant_time_eq(&self.tag(signed), tag) {
+            return None;
+        }
+        let timestamp = u64::from_be_bytes(signed[..TIMESTAMP_LEN].try_into().unwrap());
+        if timestamp.abs_diff(now) > MAX_CLOCK_SKEW {
+            return None;
+        }
+
+        Some((timestamp, signed[TIMESTAMP_LEN..].try_into().unwrap()))
+    }
+}
+
+// Never leak the secret in the logs
+impl Debug for SpaSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SpaSecret(****)")
+    }
+}
+
+/// Client side, sends a knock to the server before opening connections to it
+#[derive(Clone, Debug)]
+pub struct SpaKnocker {
+    secret: SpaSecret,
+    port: u16,
+    knocked_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl SpaKnocker {
+    pub fn new(secret: SpaSecret, port: u16) -> Self {
+        Self {
+            secret,
+            port,
+            knocked_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub async fn knock(&self, host: &Host<String>) -> anyhow::Result<()> {
+        if self.knocked_at.lock().is_some_and(|at| at.elapsed() < KNOCK_REFRESH) {
+            return Ok(());
+        }
+
+        let addr = match host {
+            Host::Domain(domain) => tokio::net::lookup_host((domain.as_str(), self.port))
+                .await?
+                .next()
+                .ok_or_else(|| anyhow!("cannot resolve {} to knock", domain))?,
+            Host::Ipv4(ip) => SocketAddr::new(IpAddr::V4(*ip), self.port),
+            Host::Ipv6(ip) => SocketAddr::new(IpAddr::V6(*ip), self.port),
+        };
+        let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+
+        debug!("Knocking on {}", addr);
+        let socket = UdpSocket::bind(bind).await?;
+        socket
+            .send_to(&self.secret.knock(jsonwebtoken::get_current_timestamp()), addr)
+            .await?;
+        *self.knocked_at.lock() = Some(Instant::now());
+        tokio::time::sleep(KNOCK_DELAY).await;
+
+        Ok(())
+    }
+}
+
+/// Server side, only ips having recently sent a valid knock can connect
+pub(super) struct SpaGate {
+    secret: SpaSecret,
+    window: Duration,
+    allowed_until: Mutex<HashMap<IpAddr, Instant>>,
+    seen_nonces: Mutex<HashMap<[u8; NONCE_LEN], u64>>,
+}
+
+impl SpaGate {
+    pub fn new(secret: SpaSecret, window: Duration) -> Self {
+        Self {
+            secret,
+            window,
+            allowed_until: Mutex::new(HashMap::with_capacity(0)),
+            seen_nonces: Mutex::new(HashMap::with_capacity(0)),
+        }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed_until
+            .lock()
+            .get(&ip.to_canonical())
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    fn on_knock(&self, knock: &[u8], ip: IpAddr, now: u64) -> bool {
+        let Some((timestamp, nonce)) = self.secret.verify(knock, now) else {
+            return false;
+        };
+
+        // A captured knock must not open the gate for someone else
+        let mut seen_nonces = self.seen_nonces.lock();
+        seen_nonces.retain(|_, expire_at| *expire_at >= now);
+        if seen_nonces.insert(nonce, timestamp + MAX_CLOCK_SKEW).is_some() {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut allowed_until = self.allowed_until.lock();
+        allowed_until.retain(|_, until| *until > now);
+        allowed_until.insert(ip.to_canonical(), now + self.window);
+        true
+    }
+
+    pub async fn run(self: Arc<Self>, bind: SocketAddr) -> anyhow::Result<()> {
+        let socket = UdpSocket::bind(bind).await?;
+        info!("Waiting for knocks on {}", bind);
+
+        let mut buf = [0u8; KNOCK_LEN + 1];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(ret) => ret,
+                Err(err) => {
+                    warn!("Error while receiving knock {:?}", err);
+                    continue;
+                }
+            };
+
+            if self.on_knock(&buf[..len], peer.ip(), jsonwebtoken::get_current_timestamp()) {
+                debug!("Valid knock from {}, allowing it to connect", peer.ip());
+            } else {
+                debug!("Ignoring invalid knock from {}", peer.ip());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_knock() {
+        let secret = SpaSecret::new("secret").unwrap();
+        let gate = SpaGate::new(secret.clone(), Duration::from_secs(30));
+        let ip: IpAddr = "192.168.1.1".parse().unwrap();
+        let now = 1_700_000_000;
+
+        assert!(!gate.is_allowed(ip));
+        let knock = secret.knock(now);
+        assert!(gate.on_knock(&knock, ip, now));
+        assert!(gate.is_allowed(ip));
+        assert!(gate.is_allowed("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!gate.is_allowed("192.168.1.2".parse().unwrap()));
+
+        // Replayed, too old, tampered or with another secret
+        let other_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(!gate.on_knock(&knock, other_ip, now));
+        assert!(!gate.on_knock(&secret.knock(now - MAX_CLOCK_SKEW - 1), other_ip, now));
+        let mut tampered = secret.knock(now);
+        tampered[0] ^= 1;
+        assert!(!gate.on_knock(&tampered, other_ip, now));
+        let other_secret = SpaSecret::new("other").unwrap();
+        assert!(!gate.on_knock(&other_secret.knock(now), other_ip, now));
+        assert!(!gate.is_allowed(other_ip));
+    }
+}
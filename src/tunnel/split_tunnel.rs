@@ -0,0 +1,153 @@
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+use url::Host;
+
+use crate::dns;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Tunnel,
+    Direct,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostMatch {
+    Any,
+    /// The domain and its subdomains
+    Domain(String),
+    Cidr(IpAddr, u8),
+}
+
+/// Networks allowed to connect to a local listener, any if there is none
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowFrom(pub Vec<(IpAddr, u8)>);
+
+impl AllowFrom {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.0.is_empty() || self.0.iter().any(|(net, prefix_len)| in_cidr(ip, *net, *prefix_len))
+    }
+}
+
+/// Destinations matching one of the hosts and one of the ports (any if there is none) take this route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRule {
+    pub route: Route,
+    pub hosts: Vec<HostMatch>,
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl HostMatch {
+    fn matches(&self, host: &Host) -> bool {
+        // Hosts of tunnels are parsed as opaque, an ip can be a Host::Domain
+        let ip = match host {
+            Host::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            Host::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+            Host::Domain(domain) => match dns::split_scoped_ipv6(domain) {
+                Some((ip, _)) => Some(IpAddr::V6(ip)),
+                None => domain.parse::<IpAddr>().ok(),
+            },
+        };
+
+        match (self, host, ip) {
+            (HostMatch::Any, _, _) => true,
+            (HostMatch::Cidr(net, prefix_len), _, Some(ip)) => in_cidr(ip, *net, *prefix_len),
+            (HostMatch::Domain(suffix), Host::Domain(domain), None) => {
+                let domain = domain.trim_end_matches('.');
+                domain.eq_ignore_ascii_case(suffix)
+                    || (domain.len() > suffix.len()
+                        && domain.as_bytes()[domain.len() - suffix.len() - 1] == b'.'
+                        && domain[domain.len() - suffix.len()..].eq_ignore_ascii_case(suffix))
+            }
+            _ => false,
+        }
+    }
+}
+
+fn in_cidr(ip: IpAddr, net: IpAddr, prefix_len: u8) -> bool {
+    let (ip, net, bits) = match (ip.to_canonical(), net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => (u32::from(ip) as u128, u32::from(net) as u128, 32),
+        (IpAddr::V6(ip), IpAddr::V6(net)) => (u128::from(ip), u128::from(net), 128),
+        _ => return false,
+    };
+    let mask = match prefix_len {
+        0 => 0,
+        len => u128::MAX << (bits - len as u32) & (u128::MAX >> (128 - bits)),
+    };
+    ip & mask == net & mask
+}
+
+impl RouteRule {
+    fn matches(&self, (host, port): &(Host, u16)) -> bool {
+        self.hosts.iter().any(|h| h.matches(host))
+            && (self.ports.is_empty() || self.ports.iter().any(|p| p.contains(port)))
+    }
+}
+
+/// The first matching rule decides, connections go through the tunnel if none does
+pub fn route(rules: &[RouteRule], target: &(Host, u16)) -> Route {
+    rules
+        .iter()
+        .find(|rule| rule.matches(target))
+        .map_or(Route::Tunnel, |rule| rule.route)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_from() {
+        assert!(AllowFrom::default().allows("203.0.113.7".parse().unwrap()));
+
+        let allow_from = AllowFrom(vec![("10.0.0.0".parse().unwrap(), 8), ("fd00::".parse().unwrap(), 8)]);
+        assert!(allow_from.allows("10.1.2.3".parse().unwrap()));
+        assert!(allow_from.allows("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(allow_from.allows("fd12::1".parse().unwrap()));
+        assert!(!allow_from.allows("11.1.2.3".parse().unwrap()));
+        assert!(!allow_from.allows("fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_route() {
+        let rules = [
+            RouteRule {
+                route: Route::Tunnel,
+                hosts: vec![
+                    HostMatch::Domain("corp.example.com".to_string()),
+                    HostMatch::Cidr("10.0.0.0".parse().unwrap(), 8),
+                    HostMatch::Cidr("fe80::".parse().unwrap(), 10),
+                ],
+                ports: vec![],
+            },
+            RouteRule {
+                route: Route::Tunnel,
+                hosts: vec![HostMatch::Any],
+                ports: vec![22..=22, 8000..=8100],
+            },
+            RouteRule {
+                route: Route::Direct,
+                hosts: vec![HostMatch::Any],
+                ports: vec![],
+            },
+        ];
+        let route = |host: &str, port| route(&rules, &(Host::parse(host).unwrap(), port));
+
+        assert_eq!(route("corp.example.com", 443), Route::Tunnel);
+        assert_eq!(route("git.CORP.example.com", 443), Route::Tunnel);
+        assert_eq!(route("notcorp.example.com", 443), Route::Direct);
+        assert_eq!(route("10.1.2.3", 443), Route::Tunnel);
+        assert_eq!(route("11.1.2.3", 443), Route::Direct);
+        assert_eq!(route("[::ffff:10.1.2.3]", 443), Route::Tunnel);
+        let scoped = (dns::parse_host("[fe80::1%eth0]").unwrap(), 443);
+        assert_eq!(super::route(&rules, &scoped), Route::Tunnel);
+        assert_eq!(
+            super::route(&rules, &(Host::Domain("fe80::1%".to_string()), 443)),
+            Route::Direct
+        );
+        assert_eq!(route("example.org", 22), Route::Tunnel);
+        assert_eq!(route("example.org", 8080), Route::Tunnel);
+        assert_eq!(route("example.org", 80), Route::Direct);
+        assert_eq!(super::route(&[], &(Host::parse("example.org").unwrap(), 80)), Route::Tunnel);
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use tracing::info;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Daily window, in UTC, during which a token can open tunnels, i.e: `mon-fri 09:00-18:00`.
+/// Without days it applies every day, and a window ending before it starts goes past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    // Bit 0 is monday
+    days: u8,
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    /// If a tunnel can be opened at this unix timestamp
+    pub fn contains(&self, timestamp: u64) -> bool {
+        let day = ((timestamp / 86400 + 3) % 7) as u8;
+        let minute = (timestamp % 86400 / 60) as u32;
+        let previous_day = (day + 6) % 7;
+        let has_day = |day: u8| self.days & (1 << day) != 0;
+
+        match self.start < self.end {
+            true => has_day(day) && (self.start..self.end).contains(&minute),
+            false => (has_day(day) && minute >= self.start) || (has_day(previous_day) && minute < self.end),
+        }
+    }
+}
+
+fn parse_day(day: &str) -> anyhow::Result<u8> {
+    DAYS.iter()
+        .position(|d| d.eq_ignore_ascii_case(day))
+        .map(|day| day as u8)
+        .ok_or_else(|| anyhow!("invalid day {}, expected one of {:?}", day, DAYS))
+}
+
+// A day or a range of days, i.e: mon-fri
+fn parse_days(range: &str) -> anyhow::Result<u8> {
+    let Some((first, last)) = range.split_once('-') else {
+        return Ok(1 << parse_day(range)?);
+    };
+    let (first, last) = (parse_day(first)?, parse_day(last)?);
+    Ok((0..=(last + 7 - first) % 7).fold(0, |days, i| days | (1 << ((first + i) % 7))))
+}
+
+fn parse_time(time: &str) -> anyhow::Result<u32> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid time {}, expected HH:MM", time))?;
+    let hours: u32 = hours.parse().with_context(|| format!("invalid hours in {}", time))?;
+    let minutes: u32 = minutes
+        .parse()
+        .with_context(|| format!("invalid minutes in {}", time))?;
+    if minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return Err(anyhow!("invalid time {}", time));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for TimeWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days_str, hours) = match s.trim().split_once(' ') {
+            Some((days, hours)) => (Some(days), hours.trim()),
+            None => (None, s.trim()),
+        };
+
+        let days = match days_str {
+            Some(days) => days
+                .split(',')
+                .try_fold(0, |acc, range| Ok::<_, anyhow::Error>(acc | parse_days(range)?))?,
+            None => 0x7f,
+        };
+
+        let (start, end) = hours
+            .split_once('-')
+            .ok_or_else(|| anyhow!("invalid time window {}, expected [DAYS ]HH:MM-HH:MM", s))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end || start == MINUTES_PER_DAY {
+            return Err(anyhow!("empty time window {}", s));
+        }
+
+        Ok(Self {
+            days,
+            start,
+            end: end % MINUTES_PER_DAY,
+        })
+    }
+}
+
+impl Display for TimeWindow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let days: Vec<_> = (0..7)
+            .filter(|day| self.days & (1 << day) != 0)
+            .map(|day| DAYS[day])
+            .collect();
+        let end = match self.end {
+            0 => MINUTES_PER_DAY,
+            end => end,
+        };
+        write!(
+            f,
+            "{} {:02}:{:02}-{:02}:{:02}",
+            days.join(","),
+            self.start / 60,
+            self.start % 60,
+            end / 60,
+            end % 60
+        )
+    }
+}
+
+/// Run the relay of the tunnel, dropping it and so closing the tunnel once it lived longer than max_duration
+pub(super) async fn with_max_duration<F: Future>(max_duration: Option<Duration>, fut: F) {
+    let Some(max_duration) = max_duration else {
+        fut.await;
+        return;
+    };
+    if tokio::time::timeout(max_duration, fut).await.is_err() {
+        info!("Closing tunnel, it reached its max duration of {:?}", max_duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_window() {
+        // Monday 2024-01-01 00:00 UTC
+        let monday = 1704067200;
+        let at = |day: u64, hours: u64, minutes: u64| monday + day * 86400 + hours * 3600 + minutes * 60;
+
+        let business_hours: TimeWindow = "mon-fri 09:00-18:00".parse().unwrap();
+        assert!(business_hours.contains(at(0, 9, 0)));
+        assert!(business_hours.contains(at(4, 17, 59)));
+        assert!(!business_hours.contains(at(0, 18, 0)));
+        assert!(!business_hours.contains(at(2, 8, 59)));
+        assert!(!business_hours.contains(at(5, 12, 0)));
+        assert_eq!(business_hours.to_string(), "mon,tue,wed,thu,fri 09:00-18:00");
+        assert_eq!(business_hours.to_string().parse::<TimeWindow>().unwrap(), business_hours);
+
+        let nights: TimeWindow = "fri-mon 22:00-06:00".parse().unwrap();
+        assert!(nights.contains(at(4, 23, 0)));
+        assert!(nights.contains(at(5, 5, 59)));
+        assert!(nights.contains(at(1, 5, 0)));
+        assert!(!nights.contains(at(1, 22, 0)));
+        assert!(!nights.contains(at(4, 5, 0)));
+
+        let every_day: TimeWindow = "00:00-24:00".parse().unwrap();
+        assert!(every_day.contains(at(6, 23, 59)));
+        assert_eq!(every_day.to_string(), "mon,tue,wed,thu,fri,sat,sun 00:00-24:00");
+
+        assert!("09:00-09:00".parse::<TimeWindow>().is_err());
+        assert!("mon-fry 09:00-18:00".parse::<TimeWindow>().is_err());
+        assert!("09:60-18:00".parse::<TimeWindow>().is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use crate::{tls, WsServerConfig};
+use super::ocsp;
+use crate::health::{self, Check};
+use crate::{tls, TlsServerConfig, WsServerConfig};
 use anyhow::Context;
 use log::trace;
 use notify::{EventKind, RecommendedWatcher, Watcher};
@@ -9,6 +11,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
 use tracing::{error, info, warn};
 
 struct TlsReloaderState {
@@ -17,6 +20,8 @@ struct TlsReloaderState {
     server_config: Arc<WsServerConfig>,
     cert_path: PathBuf,
     key_path: PathBuf,
+    // The ocsp response of the previous certificate cannot be stapled anymore
+    ocsp_changed: Arc<Notify>,
 }
 pub struct TlsReloader {
     state: Option<Arc<TlsReloaderState>>,
@@ -39,8 +44,18 @@ impl TlsReloader {
             cert_path: cert_path.to_path_buf(),
             key_path: key_path.to_path_buf(),
             server_config,
+            ocsp_changed: Arc::new(Notify::new()),
         });
 
+        if this.server_config.tls.as_ref().is_some_and(|tls| tls.tls_ocsp_stapling) {
+            let this = this.clone();
+            tokio::spawn(async move {
+                let tls = this.server_config.tls.as_ref().unwrap();
+                let reload = || this.tls_reload_certificate.store(true, Ordering::Relaxed);
+                ocsp::run_stapler(tls, this.ocsp_changed.clone(), reload).await;
+            });
+        }
+
         info!("Starting to watch tls certificate and private key for changes to reload them");
         let mut watcher = notify::recommended_watcher({
             let this = this.clone();
@@ -93,6 +108,14 @@ impl TlsReloader {
         });
     }
 
+    // The acceptor is only rebuilt on the next connection, the server should not wait for one to be ready again
+    fn check_certificate(tls: &TlsServerConfig) {
+        match tls::tls_acceptor(tls, None) {
+            Ok(_) => health::recover(Check::TlsCertificate),
+            Err(err) => health::fail(Check::TlsCertificate, format!("cannot reload: {}", err)),
+        }
+    }
+
     fn handle_fs_event(this: &Arc<TlsReloaderState>, event: notify::Result<notify::Event>) {
         let event = match event {
             Ok(event) => event,
@@ -111,11 +134,18 @@ impl TlsReloader {
             match event.kind {
                 EventKind::Create(_) | EventKind::Modify(_) => match tls::load_certificates_from_pem(&this.cert_path) {
                     Ok(tls_certs) => {
+                        tls::check_certificate_chain(&tls_certs, tls.tls_crypto_provider);
                         *tls.tls_certificate.lock() = tls_certs;
+                        if tls.tls_ocsp_stapling {
+                            tls.tls_ocsp_response.lock().clear();
+                            this.ocsp_changed.notify_one();
+                        }
                         this.tls_reload_certificate.store(true, Ordering::Relaxed);
+                        Self::check_certificate(tls);
                     }
                     Err(err) => {
                         warn!("Error while loading TLS certificate {:?}", err);
+                        health::fail(Check::TlsCertificate, format!("cannot load {:?}: {}", this.cert_path, err));
                     }
                 },
                 EventKind::Remove(_) => {
@@ -134,9 +164,11 @@ impl TlsReloader {
                     Ok(tls_key) => {
                         *tls.tls_key.lock() = tls_key;
                         this.tls_reload_certificate.store(true, Ordering::Relaxed);
+                        Self::check_certificate(tls);
                     }
                     Err(err) => {
                         warn!("Error while loading TLS private key {:?}", err);
+                        health::fail(Check::TlsCertificate, format!("cannot load {:?}: {}", this.key_path, err));
                     }
                 },
                 EventKind::Remove(_) => {
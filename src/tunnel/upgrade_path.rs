@@ -0,0 +1,232 @@
+use anyhow::anyhow;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use sha2::Sha256;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::Arc;
+
+use super::constant_time_eq;
+
+const NONCE_LEN: usize = 9;
+const TAG_LEN: usize = 15;
+// Paths are only valid for the current hour, give peers with a bit of clock drift an hour to spare
+const PERIOD_SECS: u64 = 3600;
+
+/// Secret shared by the client and the server to generate and check the last segment of the upgrade path,
+/// which is otherwise the constant /events
+#[derive(Clone)]
+pub struct UpgradePathSecret(Arc<[u8]>);
+
+impl UpgradePathSecret {
+    pub fn new(secret: &str) -> anyhow::Result<Self> {
+        if secret.is_empty() {
+            return Err(anyhow!("upgrade path secret cannot be empty"));
+        }
+
+        Ok(Self(secret.as_bytes().into()))
+    }
+
+    fn tag(&self, period: u64, nonce: &[u8]) -> [u8; TAG_LEN] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("hmac accepts keys of any size");
+        mac.update(b"wstunnel upgrade path");
+        mac.update(&period.to_be_bytes());
+        mac.update(nonce);
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+        tag
+    }
+
+    /// A new random segment for each request, so the tunnel cannot be fingerprinted by its url
+    pub fn generate_segment(&self, now: u64) -> String {
+        let mut segment = [0u8; NONCE_LEN + TAG_LEN];
+        OsRng.fill_bytes(&mut segment[..NONCE_LEN]);
+        let tag = self.tag(now / PERIOD_SECS, &segment[..NONCE_LEN]);
+        segment[NONCE_LEN..].copy_from_slice(&tag);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(segment)
+    }
+
+    pub fn is_valid_segment(&self, segment: &str, now: u64) -> bool {
+        let Ok(segment) = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(segment) else {
+            return false;
+        };
+        if segment.len() != NONCE_LEN + TAG_LEN {
+            return false;
+        }
+
+        let (nonce, tag) = segment.split_at(NONCE_LEN);
+        let period = now / PERIOD_SECS;
+        [period.saturating_sub(1), period, period + 1]
+            .into_iter()
+            .any(|period| constant_time_eq(&self.tag(period, nonce), tag))
+    }
+}
+
+// Never leak the secret in the logs
+impl Debug for UpgradePathSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UpgradePathSecret(****)")
+    }
+}
+
+/// Allowed prefix of the upgrade path, i.e: everything before its last segment
+#[derive(Debug, Clone)]
+pub enum PathPrefix {
+    /// Matches the first segments of the prefix. A `*` stands for any characters within a segment, a `**` segment
+    /// for any number of segments
+    Glob(Vec<String>),
+    /// Must match the whole prefix, without its leading slash
+    Regex(Regex),
+}
+
+impl PathPrefix {
+    pub fn new(pattern: &str) -> anyhow::Result<Self> {
+        if pattern.starts_with('^') {
+            let regex = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|err| anyhow!("invalid path prefix regex {}: {}", pattern, err))?;
+            return Ok(Self::Regex(regex));
+        }
+
+        let segments: Vec<String> = pattern.trim_matches('/').split('/').map(str::to_string).collect();
+        if let Some(segment) = segments.iter().find(|s| s.is_empty() || *s == "." || *s == "..") {
+            return Err(anyhow!("invalid path prefix {}, segment {:?} is not allowed", pattern, segment));
+        }
+        Ok(Self::Glob(segments))
+    }
+
+    /// The path of an upgrade request, with its last segment
+    pub fn matches_path(&self, path: &str) -> bool {
+        let Some(segments) = prefix_segments(path) else {
+            return false;
+        };
+        match self {
+            Self::Glob(pattern) => glob_prefix(pattern, &segments),
+            Self::Regex(regex) => regex.is_match(&segments.join("/")),
+        }
+    }
+}
+
+impl Display for PathPrefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Glob(segments) => f.write_str(&segments.join("/")),
+            Self::Regex(regex) => f.write_str(regex.as_str()),
+        }
+    }
+}
+
+/// Decoded segments before the last one. Paths that a proxy could normalize into another one are never valid
+fn prefix_segments(path: &str) -> Option<Vec<String>> {
+    let (prefix, _last_segment) = path.strip_prefix('/')?.rsplit_once('/')?;
+    prefix
+        .split('/')
+        .map(|segment| {
+            let segment = urlencoding::decode(segment).ok()?;
+            match segment.as_ref() {
+                "" | "." | ".." => None,
+                s if s.contains('/') => None,
+                _ => Some(segment.into_owned()),
+            }
+        })
+        .collect()
+}
+
+fn glob_prefix(pattern: &[String], segments: &[String]) -> bool {
+    match pattern.split_first() {
+        None => true,
+        Some((p, rest)) if p == "**" => (0..=segments.len()).any(|skip| glob_prefix(rest, &segments[skip..])),
+        Some((p, rest)) => match segments.split_first() {
+            Some((segment, segments)) => glob_segment(p, segment) && glob_prefix(rest, segments),
+            None => false,
+        },
+    }
+}
+
+fn glob_segment(pattern: &str, segment: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut segment) = segment.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // Without wildcard
+        return segment.is_empty();
+    };
+    for part in middle {
+        match segment.find(part) {
+            Some(ix) => segment = &segment[ix + part.len()..],
+            None => return false,
+        }
+    }
+    segment.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_path_segment() {
+        let secret = UpgradePathSecret::new("secret").unwrap();
+        let now = 1_700_000_000;
+        let segment = secret.generate_segment(now);
+        assert_ne!(segment, secret.generate_segment(now));
+        assert!(secret.is_valid_segment(&segment, now));
+        assert!(secret.is_valid_segment(&segment, now + PERIOD_SECS));
+        assert!(!secret.is_valid_segment(&segment, now + 3 * PERIOD_SECS));
+
+        assert!(!UpgradePathSecret::new("other").unwrap().is_valid_segment(&segment, now));
+        assert!(!secret.is_valid_segment("events", now));
+        assert!(!secret.is_valid_segment(&segment[1..], now));
+    }
+
+    #[test]
+    fn test_path_prefix() {
+        let prefix = PathPrefix::new("v1").unwrap();
+        assert!(prefix.matches_path("/v1/events"));
+        assert!(prefix.matches_path("/v1/more/events"));
+        assert!(prefix.matches_path("/%76%31/events"));
+        assert!(!prefix.matches_path("/v1events"));
+        assert!(!prefix.matches_path("/v10/events"));
+        assert!(!prefix.matches_path("/v1"));
+        assert!(!prefix.matches_path("v1/events"));
+        assert!(!prefix.matches_path("//v1/events"));
+        assert!(!prefix.matches_path("/v1//events"));
+        assert!(!prefix.matches_path("/./v1/events"));
+        assert!(!prefix.matches_path("/x/../v1/events"));
+        assert!(!prefix.matches_path(""));
+        assert!(!prefix.matches_path("/"));
+
+        let prefix = PathPrefix::new("/api/v*/").unwrap();
+        assert_eq!(prefix.to_string(), "api/v*");
+        assert!(prefix.matches_path("/api/v/events"));
+        assert!(prefix.matches_path("/api/v2/events"));
+        assert!(!prefix.matches_path("/api/x2/events"));
+        assert!(!prefix.matches_path("/api%2Fv2/events"));
+
+        let prefix = PathPrefix::new("*/**/t*n*l").unwrap();
+        assert!(prefix.matches_path("/a/tunnel/events"));
+        assert!(prefix.matches_path("/a/b/c/tnl/events"));
+        assert!(!prefix.matches_path("/tunnel/events"));
+        assert!(!prefix.matches_path("/a/tunnels/events"));
+
+        let prefix = PathPrefix::new("caf\u{e9}").unwrap();
+        assert!(prefix.matches_path("/caf%C3%A9/events"));
+        assert!(!prefix.matches_path("/cafe/events"));
+
+        let prefix = PathPrefix::new("^v[0-9]+|beta").unwrap();
+        assert!(prefix.matches_path("/v12/events"));
+        assert!(prefix.matches_path("/beta/events"));
+        assert!(!prefix.matches_path("/v12/x/events"));
+        assert!(!prefix.matches_path("/xbeta/events"));
+
+        assert!(PathPrefix::new("").is_err());
+        assert!(PathPrefix::new("/").is_err());
+        assert!(PathPrefix::new("a//b").is_err());
+        assert!(PathPrefix::new("a/../b").is_err());
+        assert!(PathPrefix::new("^(").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use once_cell::sync::OnceCell;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tracing::{warn, Instrument, Span};
+use url::Url;
+
+use super::events::Notification;
+use crate::tls::{self, TlsCryptoProvider};
+
+// Events waiting to be delivered, past it new ones are dropped
+const QUEUE_LEN: usize = 1024;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+static WEBHOOKS: OnceCell<mpsc::Sender<Notification>> = OnceCell::new();
+
+/// Events are posted as json to each of the urls in turn, in the background. Delivery is best effort,
+/// events are not sent again if a webhook cannot be reached
+pub(super) fn start(urls: Vec<Url>, tls_crypto_provider: TlsCryptoProvider) -> anyhow::Result<()> {
+    let tls_connector = match urls.iter().any(|url| url.scheme() == "https") {
+        true => Some(tls::https_connector(tls_crypto_provider)?),
+        false => None,
+    };
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    WEBHOOKS.set(tx).map_err(|_| anyhow!("webhooks are already started"))?;
+    tokio::spawn(deliver(urls, tls_connector, rx).instrument(Span::none()));
+    Ok(())
+}
+
+pub(super) fn is_enabled() -> bool {
+    WEBHOOKS.get().is_some()
+}
+
+pub(super) fn send(notification: Notification) {
+    let Some(tx) = WEBHOOKS.get() else {
+        return;
+    };
+    if tx.try_send(notification).is_err() {
+        warn!("Dropping webhook event, too many are waiting to be delivered");
+    }
+}
+
+async fn deliver(urls: Vec<Url>, tls_connector: Option<TlsConnector>, mut rx: mpsc::Receiver<Notification>) {
+    while let Some(notification) = rx.recv().await {
+        let body = match serde_json::to_string(&notification) {
+            Ok(body) => Bytes::from(body),
+            Err(err) => {
+                warn!("Cannot serialize webhook event {:?}: {:?}", notification, err);
+                continue;
+            }
+        };
+
+        for url in &urls {
+            match tokio::time::timeout(DELIVERY_TIMEOUT, post(url, tls_connector.as_ref(), body.clone())).await {
+                Ok(Ok(status)) if status.is_success() => {}
+                Ok(Ok(status)) => warn!("Webhook {} answered with status {}", url, status),
+                Ok(Err(err)) => warn!("Cannot send event to webhook {}: {:?}", url, err),
+                Err(_) => warn!("Timeout while sending event to webhook {}", url),
+            }
+        }
+    }
+}
+
+async fn post(url: &Url, tls_connector: Option<&TlsConnector>, body: Bytes) -> anyhow::Result<StatusCode> {
+    // Without the brackets of ipv6 addresses
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let stream = TcpStream::connect((host, port))
+        .await
+        .with_context(|| format!("cannot connect to {}:{}", host, port))?;
+
+    match (url.scheme(), tls_connector) {
+        ("https", Some(tls_connector)) => {
+            let server_name = ServerName::try_from(host.to_string())?;
+            let stream = tls_connector.connect(server_name, stream).await?;
+            send_request(stream, url, body).await
+        }
+        _ => send_request(stream, url, body).await,
+    }
+}
+
+async fn send_request<S>(stream: S, url: &Url, body: Bytes) -> anyhow::Result<StatusCode>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let req = Request::post(path)
+        .header(HOST, host)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(body))?;
+    Ok(sender.send_request(req).await?.status())
+}
@@ -1,38 +1,115 @@
 use anyhow::{anyhow, Context};
+use bytes::Bytes;
 use futures_util::{stream, Stream};
 
-use parking_lot::RwLock;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
 use pin_project::{pin_project, pinned_drop};
+use serde::Serialize;
 use std::collections::HashMap;
-use std::future::Future;
+use std::future::poll_fn;
 use std::io;
 use std::io::{Error, ErrorKind};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use log::warn;
-use std::pin::{pin, Pin};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
 use std::task::{ready, Poll};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::UdpSocket;
-use tokio::sync::futures::Notified;
+use tokio::sync::mpsc;
 
 use crate::dns::DnsResolver;
-use tokio::sync::Notify;
-use tokio::time::{timeout, Interval};
+use crate::metrics::{self, METRICS};
+use crate::tcp::{Egress, LocalStream};
+use crate::tunnel::split_tunnel::AllowFrom;
+use crate::udp_offload::{self, GroBuffer, GsoSender};
+use tokio::time::{timeout, Instant, Interval};
 use tracing::{debug, error, info};
 use url::Host;
 
+// Flows of each udp server, 0 when there is no limit. Past it, the least recently active flow is evicted
+static MAX_FLOWS: AtomicUsize = AtomicUsize::new(0);
+static FLOW_TABLES: Lazy<Mutex<Vec<FlowTable>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn set_max_flows(max_flows: usize) {
+    MAX_FLOWS.store(max_flows, Ordering::Relaxed);
+}
+
+// Datagrams received for a flow and not read yet by its tunnel. Past it, they are dropped like by a full socket
+// buffer, so a flow that is slow, or still waiting for a tunnel, does not hold back the others
+const FLOW_QUEUE_LEN: usize = 1024;
+
+type Peers = HashMap<SocketAddr, Flow, ahash::RandomState>;
+// Bind address of the udp server, and its peers
+type FlowTable = (SocketAddr, Weak<Mutex<Peers>>);
+
 struct IoInner {
-    has_data_to_read: Notify,
-    has_read_data: Notify,
+    created_at: Instant,
+    last_active: Mutex<Instant>,
+    evicted: AtomicBool,
+}
+
+impl IoInner {
+    fn new() -> Self {
+        Self {
+            created_at: Instant::now(),
+            last_active: Mutex::new(Instant::now()),
+            evicted: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Way to the stream of a peer, for the server. The stream sees the end of its datagrams once it is dropped
+#[derive(Clone)]
+struct Flow {
+    datagrams: mpsc::Sender<Bytes>,
+    io: Arc<IoInner>,
+}
+
+/// Flow of a peer of an udp server, for the admin api
+#[derive(Debug, Serialize)]
+pub struct UdpFlowInfo {
+    pub bind: SocketAddr,
+    pub peer: SocketAddr,
+    pub age_sec: u64,
+    pub idle_sec: u64,
 }
+
+pub fn flows_snapshot() -> Vec<UdpFlowInfo> {
+    let mut tables = FLOW_TABLES.lock();
+    tables.retain(|(_, peers)| peers.strong_count() > 0);
+
+    let mut flows: Vec<_> = tables
+        .iter()
+        .filter_map(|(bind, peers)| Some((*bind, peers.upgrade()?)))
+        .flat_map(|(bind, peers)| {
+            peers
+                .lock()
+                .iter()
+                .map(|(peer, flow)| UdpFlowInfo {
+                    bind,
+                    peer: *peer,
+                    age_sec: flow.io.created_at.elapsed().as_secs(),
+                    idle_sec: flow.io.last_active.lock().elapsed().as_secs(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    flows.sort_by_key(|flow| (flow.bind, flow.peer));
+    flows
+}
+
 struct UdpServer {
     listener: Arc<UdpSocket>,
-    peers: HashMap<SocketAddr, Pin<Arc<IoInner>>, ahash::RandomState>,
+    peers: Arc<Mutex<Peers>>,
     keys_to_delete: Arc<RwLock<Vec<SocketAddr>>>,
     cnx_timeout: Option<Duration>,
+    gro: Option<GroBuffer>,
+    buf: Box<[u8]>,
 }
 
 impl UdpServer {
@@ -48,11 +125,18 @@ impl UdpServer {
             warn!("Cannot set UDP server recv buffer: {}", err);
         }
 
+        let gro = udp_offload::enable_gro(&listener).then(GroBuffer::default);
+        let peers = Arc::new(Mutex::new(HashMap::with_hasher(ahash::RandomState::new())));
+        if let Ok(bind) = listener.local_addr() {
+            FLOW_TABLES.lock().push((bind, Arc::downgrade(&peers)));
+        }
         Self {
             listener: Arc::new(listener),
-            peers: HashMap::with_hasher(ahash::RandomState::new()),
+            peers,
             keys_to_delete: Default::default(),
             cnx_timeout: timeout,
+            gro,
+            buf: vec![0; 64 * 1024].into_boxed_slice(),
         }
     }
     #[inline]
@@ -64,77 +148,123 @@ impl UdpServer {
 
         debug!("Cleaning {} dead udp peers", nb_key_to_delete);
         let mut keys_to_delete = self.keys_to_delete.write();
+        let mut peers = self.peers.lock();
         for key in keys_to_delete.iter() {
-            self.peers.remove(key);
+            peers.remove(key);
         }
         keys_to_delete.clear();
     }
-    pub fn clone_socket(&self) -> Arc<UdpSocket> {
-        self.listener.clone()
+
+    /// Make room for a new flow, by closing the one that has been inactive for the longest time
+    fn evict_least_recently_active(&mut self, max_flows: usize) {
+        let mut peers = self.peers.lock();
+        if max_flows == 0 || peers.len() < max_flows {
+            return;
+        }
+
+        let Some(peer) = peers
+            .iter()
+            .min_by_key(|(_, flow)| *flow.io.last_active.lock())
+            .map(|(peer, _)| *peer)
+        else {
+            return;
+        };
+        // Dropping its sender wakes up the stream, that sees it has been evicted
+        if let Some(flow) = peers.remove(&peer) {
+            info!(
+                "Too many UDP flows, evicting the one of {} inactive for {:?}",
+                peer,
+                flow.io.last_active.lock().elapsed()
+            );
+            metrics::incr(&METRICS.udp_flow_evictions, 1);
+            flow.io.evicted.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Receive the datagrams waiting on the socket, and hand them to the flow of their peer
+    async fn dispatch(&mut self) -> io::Result<()> {
+        let (len, peer) = match self.gro.as_mut() {
+            Some(gro) => (0, poll_fn(|cx| gro.poll_recv(&self.listener, cx)).await?),
+            None => self.listener.recv_from(&mut self.buf).await?,
+        };
+        let Some(flow) = self.peers.lock().get(&peer).cloned() else {
+            debug!("Dropping UDP datagram of {}, its flow is closed", peer);
+            return Ok(());
+        };
+        *flow.io.last_active.lock() = Instant::now();
+
+        let queue = |datagram: &[u8]| {
+            if flow.datagrams.try_send(Bytes::copy_from_slice(datagram)).is_err() {
+                metrics::incr(&METRICS.udp_datagram_drops, 1);
+            }
+        };
+        match self.gro.as_mut() {
+            // All the segments of a read come from the same peer
+            Some(gro) => loop {
+                let mut obuf = ReadBuf::new(&mut self.buf);
+                if !gro.read_segment(&mut obuf) {
+                    break;
+                }
+                queue(obuf.filled());
+            },
+            None => queue(&self.buf[..len]),
+        }
+        Ok(())
     }
 }
 
 #[pin_project(PinnedDrop)]
 pub struct UdpStream {
-    recv_socket: Arc<UdpSocket>,
+    datagrams: mpsc::Receiver<Bytes>,
     send_socket: Arc<UdpSocket>,
     peer: SocketAddr,
     #[pin]
     watchdog_deadline: Option<Interval>,
     data_read_before_deadline: bool,
-    has_been_notified: bool,
-    #[pin]
-    pending_notification: Option<Notified<'static>>,
-    io: Pin<Arc<IoInner>>,
+    io: Arc<IoInner>,
     keys_to_delete: Weak<RwLock<Vec<SocketAddr>>>,
+    gso: Option<GsoSender>,
 }
 
 #[pinned_drop]
 impl PinnedDrop for UdpStream {
     fn drop(self: Pin<&mut Self>) {
-        if let Some(keys_to_delete) = self.keys_to_delete.upgrade() {
+        metrics::incr(&METRICS.udp_flows_closed, 1);
+        // An evicted flow is already gone, and its peer may have a new one
+        if let Some(keys_to_delete) = self
+            .keys_to_delete
+            .upgrade()
+            .filter(|_| !self.io.evicted.load(Ordering::Relaxed))
+        {
             keys_to_delete.write().push(self.peer);
         }
-
-        // safety: we are dropping the notification as we extend its lifetime to 'static unsafely
-        // So it must be gone before we drop its parent. It should never happen but in case
-        let mut project = self.project();
-        project.pending_notification.as_mut().set(None);
-        project.io.has_read_data.notify_one();
     }
 }
 
 impl UdpStream {
     fn new(
-        recv_socket: Arc<UdpSocket>,
         send_socket: Arc<UdpSocket>,
         peer: SocketAddr,
         watchdog_deadline: Option<Duration>,
         keys_to_delete: Weak<RwLock<Vec<SocketAddr>>>,
-    ) -> (Self, Pin<Arc<IoInner>>) {
-        let has_data_to_read = Notify::new();
-        let has_read_data = Notify::new();
-        let io = Arc::pin(IoInner {
-            has_data_to_read,
-            has_read_data,
-        });
-        let mut s = Self {
-            recv_socket,
+    ) -> (Self, Flow) {
+        let (tx, datagrams) = mpsc::channel(FLOW_QUEUE_LEN);
+        let io = Arc::new(IoInner::new());
+        metrics::incr(&METRICS.udp_flows_opened, 1);
+        let gso = GsoSender::new(send_socket.clone(), Some(peer));
+        let s = Self {
+            datagrams,
             send_socket,
             peer,
             watchdog_deadline: watchdog_deadline
                 .map(|timeout| tokio::time::interval_at(tokio::time::Instant::now() + timeout, timeout)),
             data_read_before_deadline: false,
-            has_been_notified: false,
-            pending_notification: None,
             io: io.clone(),
             keys_to_delete,
+            gso,
         };
 
-        let pending_notification = unsafe { std::mem::transmute(s.io.has_data_to_read.notified()) };
-        s.pending_notification = Some(pending_notification);
-
-        (s, io)
+        (s, Flow { datagrams: tx, io })
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -148,7 +278,13 @@ impl AsyncRead for UdpStream {
         cx: &mut std::task::Context<'_>,
         obuf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        let mut project = self.project();
+        let project = self.project();
+        let evicted =
+            |peer: &SocketAddr| Error::new(ErrorKind::ConnectionAborted, format!("UDP flow with {} evicted", peer));
+        if project.io.evicted.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(evicted(project.peer)));
+        }
+
         // Look that the timeout for client has not elapsed
         if let Some(mut deadline) = project.watchdog_deadline.as_pin_mut() {
             if deadline.poll_tick(cx).is_ready() {
@@ -164,34 +300,35 @@ impl AsyncRead for UdpStream {
             }
         }
 
-        if let Some(notified) = project.pending_notification.as_mut().as_pin_mut() {
-            ready!(notified.poll(cx));
-            project.pending_notification.as_mut().set(None);
-        }
-
-        let peer = ready!(project.recv_socket.poll_recv_from(cx, obuf))?;
-        debug_assert_eq!(peer, *project.peer);
+        let Some(datagram) = ready!(project.datagrams.poll_recv(cx)) else {
+            // The server is gone, or has given up on this flow
+            return match project.io.evicted.load(Ordering::Relaxed) {
+                true => Poll::Ready(Err(evicted(project.peer))),
+                false => Poll::Ready(Ok(())),
+            };
+        };
+        // Like for a plain read, what does not fit in the buffer is lost
+        obuf.put_slice(&datagram[..datagram.len().min(obuf.remaining())]);
         *project.data_read_before_deadline = true;
 
-        // re-arm notification
-        let notified: Notified<'static> = unsafe { std::mem::transmute(project.io.has_data_to_read.notified()) };
-        project.pending_notification.as_mut().set(Some(notified));
-        project.pending_notification.as_pin_mut().unwrap().enable();
-
-        // Let know server that we have read data
-        project.io.has_read_data.notify_one();
-
         Poll::Ready(Ok(()))
     }
 }
 
 impl AsyncWrite for UdpStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
-        self.send_socket.poll_send_to(cx, buf, self.peer)
+        *self.io.last_active.lock() = Instant::now();
+        match &self.gso {
+            Some(gso) => gso.poll_write(cx, buf),
+            None => self.send_socket.poll_send_to(cx, buf, self.peer),
+        }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
-        self.send_socket.poll_send_ready(cx)
+        match &self.gso {
+            Some(gso) => gso.poll_flush(cx),
+            None => self.send_socket.poll_send_ready(cx),
+        }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
@@ -199,9 +336,12 @@ impl AsyncWrite for UdpStream {
     }
 }
 
+impl LocalStream for MyUdpSocket {}
+
 pub async fn run_server(
     bind: SocketAddr,
     timeout: Option<Duration>,
+    allow_from: AllowFrom,
     configure_listener: impl Fn(&UdpSocket) -> anyhow::Result<()>,
     mk_send_socket: impl Fn(&Arc<UdpSocket>) -> anyhow::Result<Arc<UdpSocket>>,
 ) -> Result<impl Stream<Item = io::Result<UdpStream>>, anyhow::Error> {
@@ -217,18 +357,11 @@ pub async fn run_server(
     configure_listener(&listener)?;
 
     let udp_server = UdpServer::new(listener, timeout);
-    let stream = stream::unfold(
-        (udp_server, None, mk_send_socket),
-        |(mut server, peer_with_data, mk_send_socket)| async move {
-            // New returned peer hasn't read its data yet, await for it.
-            if let Some(await_peer) = peer_with_data {
-                if let Some(peer) = server.peers.get(&await_peer) {
-                    peer.has_read_data.notified().await;
-                }
-            };
-
+    let allow_from = Arc::new(allow_from);
+    let stream = stream::unfold((udp_server, mk_send_socket), move |(mut server, mk_send_socket)| {
+        let allow_from = allow_from.clone();
+        async move {
             loop {
-                server.clean_dead_keys();
                 let peer_addr = match server.listener.peek_sender().await {
                     Ok(ret) => ret,
                     Err(err) => {
@@ -236,59 +369,92 @@ pub async fn run_server(
                         return None;
                     }
                 };
+                server.clean_dead_keys();
 
-                match server.peers.get(&peer_addr) {
-                    Some(io) => {
-                        io.has_data_to_read.notify_one();
-                        io.has_read_data.notified().await;
+                // The return path of the flow is made while its first datagram is still on the socket, for tproxy
+                let known_peer = server.peers.lock().contains_key(&peer_addr);
+                let udp_client = match known_peer {
+                    true => None,
+                    // No flow is made for it, for its datagrams not to evict the ones of the allowed peers
+                    false if !allow_from.allows(peer_addr.ip()) => {
+                        debug!("Dropping UDP datagram of {}, not in allow_from", peer_addr);
+                        None
                     }
-                    None => {
+                    false => {
                         info!("New UDP connection from {}", peer_addr);
-                        let (udp_client, io) = UdpStream::new(
-                            server.clone_socket(),
+                        server.evict_least_recently_active(MAX_FLOWS.load(Ordering::Relaxed));
+                        let (udp_client, flow) = UdpStream::new(
                             mk_send_socket(&server.listener).ok()?,
                             peer_addr,
                             server.cnx_timeout,
                             Arc::downgrade(&server.keys_to_delete),
                         );
-                        io.has_data_to_read.notify_waiters();
-                        server.peers.insert(peer_addr, io);
-                        return Some((Ok(udp_client), (server, Some(peer_addr), mk_send_socket)));
+                        server.peers.lock().insert(peer_addr, flow);
+                        Some(udp_client)
                     }
+                };
+
+                if let Err(err) = server.dispatch().await {
+                    error!("Cannot read from UDP server. Closing server: {}", err);
+                    return None;
+                }
+                if let Some(udp_client) = udp_client {
+                    return Some((Ok(udp_client), (server, mk_send_socket)));
                 }
             }
-        },
-    );
+        }
+    });
 
     Ok(stream)
 }
 
-#[derive(Clone)]
 pub struct MyUdpSocket {
     socket: Arc<UdpSocket>,
+    gro: Option<GroBuffer>,
+    gso: Option<GsoSender>,
 }
 
 impl MyUdpSocket {
     pub fn new(socket: Arc<UdpSocket>) -> Self {
-        Self { socket }
+        let gro = udp_offload::enable_gro(&socket).then(GroBuffer::default);
+        let gso = GsoSender::new(socket.clone(), None);
+        Self { socket, gro, gso }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
     }
 }
 
 impl AsyncRead for MyUdpSocket {
     fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        unsafe { self.map_unchecked_mut(|x| &mut x.socket) }
-            .poll_recv_from(cx, buf)
-            .map(|x| x.map(|_| ()))
+        let this = self.get_mut();
+        match this.gro.as_mut() {
+            Some(gro) => {
+                if !gro.read_segment(buf) {
+                    ready!(gro.poll_recv(&this.socket, cx))?;
+                    gro.read_segment(buf);
+                }
+                Poll::Ready(Ok(()))
+            }
+            None => this.socket.poll_recv_from(cx, buf).map(|x| x.map(|_| ())),
+        }
     }
 }
 
 impl AsyncWrite for MyUdpSocket {
     fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
-        unsafe { self.map_unchecked_mut(|x| &mut x.socket) }.poll_send(cx, buf)
+        match &self.gso {
+            Some(gso) => gso.poll_write(cx, buf),
+            None => self.socket.poll_send(cx, buf),
+        }
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
+        match &self.gso {
+            Some(gso) => gso.poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
@@ -302,8 +468,6 @@ pub async fn connect(
     connect_timeout: Duration,
     dns_resolver: &DnsResolver,
 ) -> anyhow::Result<MyUdpSocket> {
-    info!("Opening UDP connection to {}:{}", host, port);
-
     let socket_addrs: Vec<SocketAddr> = match host {
         Host::Ipv4(ip) => vec![SocketAddr::V4(SocketAddrV4::new(*ip, port))],
         Host::Ipv6(ip) => vec![SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, 0))],
@@ -313,17 +477,39 @@ pub async fn connect(
             .with_context(|| format!("cannot resolve domain: {}", domain))?,
     };
 
+    connect_addrs(host, port, socket_addrs, &Egress::default(), connect_timeout).await
+}
+
+async fn bind_socket(addr: &SocketAddr, egress: &Egress) -> anyhow::Result<UdpSocket> {
+    if *egress == Egress::default() {
+        return Ok(match addr {
+            SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?,
+            SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await?,
+        });
+    }
+
+    let socket = socket2::Socket::new(socket2::Domain::for_address(*addr), socket2::Type::DGRAM, None)?;
+    egress.apply(socket2::SockRef::from(&socket))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Connect to the first of the addresses of the host that is reachable
+pub async fn connect_addrs(
+    host: &Host<String>,
+    port: u16,
+    socket_addrs: Vec<SocketAddr>,
+    egress: &Egress,
+    connect_timeout: Duration,
+) -> anyhow::Result<MyUdpSocket> {
+    info!("Opening UDP connection to {}:{}", host, port);
+
     let mut cnx = None;
     let mut last_err = None;
-    for addr in socket_addrs {
+    for addr in socket_addrs.into_iter().filter(|addr| egress.reaches(addr)) {
         debug!("connecting to {}", addr);
 
-        let socket = match &addr {
-            SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
-            SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await,
-        };
-
-        let socket = match socket {
+        let socket = match bind_socket(&addr, egress).await {
             Ok(socket) => socket,
             Err(err) => {
                 warn!("cannot bind udp socket {:?}", err);
@@ -429,14 +615,14 @@ pub fn mk_send_socket_tproxy(listener: &Arc<UdpSocket>) -> anyhow::Result<Arc<Ud
 mod tests {
     use super::*;
     use futures_util::{pin_mut, StreamExt};
-    use tokio::io::AsyncReadExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::time::error::Elapsed;
     use tokio::time::timeout;
 
     #[tokio::test]
     async fn test_udp_server() {
         let server_addr: SocketAddr = "[::1]:1234".parse().unwrap();
-        let server = run_server(server_addr, None, |_| Ok(()), |l| Ok(l.clone()))
+        let server = run_server(server_addr, None, AllowFrom::default(), |_| Ok(()), |l| Ok(l.clone()))
             .await
             .unwrap();
         pin_mut!(server);
@@ -481,11 +667,27 @@ mod tests {
         assert_eq!(&buf[..16], b"helloworld test\0");
     }
 
+    #[tokio::test]
+    async fn test_udp_server_allow_from() {
+        let server_addr: SocketAddr = "[::1]:1239".parse().unwrap();
+        let allow_from = AllowFrom(vec![("fd00::".parse().unwrap(), 8)]);
+        let server = run_server(server_addr, None, allow_from, |_| Ok(()), |l| Ok(l.clone()))
+            .await
+            .unwrap();
+        pin_mut!(server);
+
+        // Not allowed, no flow is made for it
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        client.send_to(b"hello".as_ref(), server_addr).await.unwrap();
+        let fut = timeout(Duration::from_millis(100), server.next()).await;
+        assert!(matches!(fut, Err(Elapsed { .. })));
+    }
+
     #[tokio::test]
     async fn test_multiple_client() {
         let server_addr: SocketAddr = "[::1]:1235".parse().unwrap();
         let mut server = Box::pin(
-            run_server(server_addr, None, |_| Ok(()), |l| Ok(l.clone()))
+            run_server(server_addr, None, AllowFrom::default(), |_| Ok(()), |l| Ok(l.clone()))
                 .await
                 .unwrap(),
         );
@@ -550,13 +752,51 @@ mod tests {
         assert_eq!(&buf[..6], b"fffff\0");
     }
 
+    #[tokio::test]
+    async fn test_unread_flow() {
+        let server_addr: SocketAddr = "[::1]:1238".parse().unwrap();
+        let server = run_server(server_addr, None, AllowFrom::default(), |_| Ok(()), |l| Ok(l.clone()))
+            .await
+            .unwrap();
+        pin_mut!(server);
+
+        // The flow of the first peer is not read, like a reverse tunnel waiting for its client
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        client.send_to(b"aaaaa".as_ref(), server_addr).await.unwrap();
+        let _unread = timeout(Duration::from_millis(100), server.next()).await.unwrap();
+        client.send_to(b"bbbbb".as_ref(), server_addr).await.unwrap();
+
+        let client2 = UdpSocket::bind("[::1]:0").await.unwrap();
+        client2.send_to(b"ccccc".as_ref(), server_addr).await.unwrap();
+        let stream2 = timeout(Duration::from_millis(100), server.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        pin_mut!(stream2);
+        let mut buf = [0u8; 25];
+        assert!(matches!(stream2.read(&mut buf).await, Ok(5)));
+        assert_eq!(&buf[..5], b"ccccc");
+
+        // Replies go to the peer of the flow
+        stream2.write_all(b"ddddd").await.unwrap();
+        let (len, from) = client2.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (b"ddddd".as_ref(), server_addr));
+    }
+
     #[tokio::test]
     async fn test_udp_should_timeout() {
         let server_addr: SocketAddr = "[::1]:1237".parse().unwrap();
         let socket_timeout = Duration::from_secs(1);
-        let server = run_server(server_addr, Some(socket_timeout), |_| Ok(()), |l| Ok(l.clone()))
-            .await
-            .unwrap();
+        let server = run_server(
+            server_addr,
+            Some(socket_timeout),
+            AllowFrom::default(),
+            |_| Ok(()),
+            |l| Ok(l.clone()),
+        )
+        .await
+        .unwrap();
         pin_mut!(server);
 
         // Send some data to the server
@@ -586,4 +826,31 @@ mod tests {
         let ret = stream.read(&mut buf[5..]).await;
         assert!(ret.is_err());
     }
+
+    #[tokio::test]
+    async fn test_evict_least_recently_active() {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut server = UdpServer::new(listener, None);
+        let now = Instant::now();
+        let flows: Vec<(SocketAddr, Flow)> = (1..=3)
+            .map(|i| {
+                let io = Arc::new(IoInner::new());
+                *io.last_active.lock() = now - Duration::from_secs(10 * i as u64);
+                let (datagrams, _) = mpsc::channel(1);
+                (SocketAddr::from(([127, 0, 0, i], 4242)), Flow { datagrams, io })
+            })
+            .collect();
+        server.peers.lock().extend(flows.clone());
+
+        server.evict_least_recently_active(0);
+        server.evict_least_recently_active(4);
+        assert_eq!(server.peers.lock().len(), 3);
+
+        server.evict_least_recently_active(3);
+        assert_eq!(server.peers.lock().len(), 2);
+        assert!(!server.peers.lock().contains_key(&flows[2].0));
+        assert!(flows[2].1.io.evicted.load(Ordering::Relaxed));
+        assert!(!flows[0].1.io.evicted.load(Ordering::Relaxed));
+        assert!(flows_snapshot().iter().any(|flow| flow.peer == flows[0].0));
+    }
 }
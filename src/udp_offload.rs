@@ -0,0 +1,350 @@
+//! UDP segmentation offloads of linux. With GRO the kernel hands several datagrams of a flow in a single read,
+//! and with GSO a single write sends several datagrams of the same size, saving a syscall per datagram.
+//! The tunnel still sees one datagram per read and per write.
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::*;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use log::warn;
+    use nix::sys::socket::{
+        getsockopt, recvmsg, sendmsg, setsockopt, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+        SockaddrStorage,
+    };
+    use parking_lot::Mutex;
+    use std::io::{self, ErrorKind, IoSlice, IoSliceMut};
+    use std::net::SocketAddr;
+    use std::os::fd::{AsFd, AsRawFd};
+    use std::sync::Arc;
+    use std::task::{ready, Context, Poll, Waker};
+    use tokio::io::{Interest, ReadBuf};
+    use tokio::net::UdpSocket;
+    use tokio::sync::Notify;
+
+    /// Biggest payload of a udp packet, so of a GRO read or a GSO write too
+    pub(super) const MAX_UDP_PAYLOAD: usize = 65507;
+    // Segments in a single GSO write, the kernel refuses more
+    pub(super) const MAX_GSO_SEGMENTS: usize = 64;
+
+    /// Datagrams sent together with segmentation offload, as (segment size, number of datagrams).
+    /// All of them have the segment size except the last one, that can be smaller
+    pub(super) fn gso_groups(lens: &[usize]) -> Vec<(usize, usize)> {
+        let mut groups: Vec<(usize, usize, usize)> = vec![];
+        let mut last_is_smaller = false;
+        for &len in lens {
+            match groups.last_mut() {
+                Some((segment, count, total))
+                    if !last_is_smaller
+                        && len <= *segment
+                        && *count < MAX_GSO_SEGMENTS
+                        && *total + len <= MAX_UDP_PAYLOAD =>
+                {
+                    last_is_smaller = len < *segment;
+                    *count += 1;
+                    *total += len;
+                }
+                _ => {
+                    last_is_smaller = false;
+                    groups.push((len, 1, len));
+                }
+            }
+        }
+        groups.into_iter().map(|(segment, count, _)| (segment, count)).collect()
+    }
+
+    // The tunnel is paused past this, until the pending datagrams are sent
+    const MAX_PENDING_LEN: usize = 4 * MAX_UDP_PAYLOAD;
+
+    /// Enable GRO on the socket, false if the kernel does not support it
+    pub fn enable_gro(socket: &UdpSocket) -> bool {
+        setsockopt(&socket.as_fd(), sockopt::UdpGroSegment, &true).is_ok()
+    }
+
+    /// Datagrams of the last GRO read, handed one at a time to the tunnel
+    pub struct GroBuffer {
+        data: Box<[u8]>,
+        len: usize,
+        pos: usize,
+        segment: usize,
+    }
+
+    impl Default for GroBuffer {
+        fn default() -> Self {
+            Self {
+                data: vec![0; MAX_UDP_PAYLOAD].into_boxed_slice(),
+                len: 0,
+                pos: 0,
+                segment: 0,
+            }
+        }
+    }
+
+    impl GroBuffer {
+        /// Copy the next datagram of the last read, false once they have all been read.
+        /// Like for a plain read, what does not fit in the buffer is lost
+        pub fn read_segment(&mut self, obuf: &mut ReadBuf<'_>) -> bool {
+            if self.pos >= self.len {
+                return false;
+            }
+
+            let end = (self.pos + self.segment).min(self.len);
+            let datagram = &self.data[self.pos..end];
+            obuf.put_slice(&datagram[..datagram.len().min(obuf.remaining())]);
+            self.pos = end;
+            true
+        }
+
+        fn recv(&mut self, socket: &UdpSocket) -> io::Result<SocketAddr> {
+            let mut cmsg = nix::cmsg_space!(nix::libc::c_int);
+            let mut iov = [IoSliceMut::new(&mut self.data)];
+            let msg = recvmsg::<SockaddrStorage>(socket.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+            let segment = msg.cmsgs().find_map(|cmsg| match cmsg {
+                ControlMessageOwned::UdpGroSegments(segment) => Some(segment as usize),
+                _ => None,
+            });
+            let peer = msg.address.as_ref().and_then(|addr| {
+                addr.as_sockaddr_in()
+                    .map(|addr| SocketAddr::V4((*addr).into()))
+                    .or_else(|| addr.as_sockaddr_in6().map(|addr| SocketAddr::V6((*addr).into())))
+            });
+
+            self.len = msg.bytes;
+            self.pos = 0;
+            // Not coalesced, a single datagram
+            self.segment = segment.filter(|segment| *segment > 0).unwrap_or(msg.bytes);
+            peer.ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "udp datagram without source address"))
+        }
+
+        pub fn poll_recv(&mut self, socket: &UdpSocket, cx: &mut Context<'_>) -> Poll<io::Result<SocketAddr>> {
+            loop {
+                ready!(socket.poll_recv_ready(cx))?;
+                match socket.try_io(Interest::READABLE, || self.recv(socket)) {
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                    ret => return Poll::Ready(ret),
+                }
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Batch {
+        data: Vec<u8>,
+        lens: Vec<usize>,
+        error: Option<io::Error>,
+        writer: Option<Waker>,
+        closed: bool,
+    }
+
+    struct Shared {
+        batch: Mutex<Batch>,
+        has_data: Notify,
+    }
+
+    /// Datagrams written by the tunnel are sent by a task of their own, with GSO.
+    /// As the tunnel writes one datagram at a time, those written until the task gets to run are sent together
+    pub struct GsoSender {
+        shared: Arc<Shared>,
+    }
+
+    impl GsoSender {
+        /// None if the kernel does not support GSO
+        pub fn new(socket: Arc<UdpSocket>, peer: Option<SocketAddr>) -> Option<Self> {
+            getsockopt(&socket.as_fd(), sockopt::UdpGsoSegment).ok()?;
+            let shared = Arc::new(Shared {
+                batch: Mutex::new(Batch::default()),
+                has_data: Notify::new(),
+            });
+            tokio::spawn(send_batches(socket, peer.map(SockaddrStorage::from), shared.clone()));
+            Some(Self { shared })
+        }
+
+        pub fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let mut batch = self.shared.batch.lock();
+            if let Some(err) = batch.error.take() {
+                return Poll::Ready(Err(err));
+            }
+            if !batch.lens.is_empty() && batch.data.len() + buf.len() > MAX_PENDING_LEN {
+                batch.writer = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            batch.data.extend_from_slice(buf);
+            batch.lens.push(buf.len());
+            drop(batch);
+            self.shared.has_data.notify_one();
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        pub fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let mut batch = self.shared.batch.lock();
+            if let Some(err) = batch.error.take() {
+                return Poll::Ready(Err(err));
+            }
+            if batch.lens.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+
+            batch.writer = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    impl Drop for GsoSender {
+        fn drop(&mut self) {
+            // What is pending is still sent
+            self.shared.batch.lock().closed = true;
+            self.shared.has_data.notify_one();
+        }
+    }
+
+    async fn send(
+        socket: &UdpSocket,
+        peer: Option<&SockaddrStorage>,
+        data: &[u8],
+        segment: Option<u16>,
+    ) -> io::Result<()> {
+        let segment = segment.as_ref().map(ControlMessage::UdpGsoSegments);
+        let cmsgs = segment.as_slice();
+        loop {
+            socket.writable().await?;
+            let ret = socket.try_io(Interest::WRITABLE, || {
+                Ok(sendmsg(
+                    socket.as_raw_fd(),
+                    &[IoSlice::new(data)],
+                    cmsgs,
+                    MsgFlags::empty(),
+                    peer,
+                )?)
+            });
+            match ret {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                ret => return ret.map(|_| ()),
+            }
+        }
+    }
+
+    async fn send_batches(socket: Arc<UdpSocket>, peer: Option<SockaddrStorage>, shared: Arc<Shared>) {
+        let mut gso = true;
+        loop {
+            let (data, lens) = {
+                let mut batch = shared.batch.lock();
+                if let Some(writer) = batch.writer.take() {
+                    writer.wake();
+                }
+                (std::mem::take(&mut batch.data), std::mem::take(&mut batch.lens))
+            };
+            if lens.is_empty() {
+                if shared.batch.lock().closed {
+                    return;
+                }
+                shared.has_data.notified().await;
+                continue;
+            }
+
+            let mut offset = 0;
+            let mut lens = lens.as_slice();
+            for (segment, count) in gso_groups(lens) {
+                let len = lens[..count].iter().sum::<usize>();
+                let group = &data[offset..offset + len];
+                let offloaded = match count {
+                    1 => None,
+                    _ if gso => Some(send(&socket, peer.as_ref(), group, Some(segment as u16)).await),
+                    _ => None,
+                };
+                let ret = match offloaded {
+                    // The network device cannot offload the checksums
+                    Some(Err(err)) if err.raw_os_error() == Some(nix::libc::EIO) => {
+                        warn!("UDP segmentation offload is not supported by the network device, disabling it");
+                        gso = false;
+                        None
+                    }
+                    ret => ret,
+                };
+                let ret = match ret {
+                    Some(ret) => ret,
+                    None => {
+                        let mut ret = Ok(());
+                        let mut datagram_offset = 0;
+                        for len in &lens[..count] {
+                            let datagram = &group[datagram_offset..datagram_offset + len];
+                            ret = ret.and(send(&socket, peer.as_ref(), datagram, None).await);
+                            datagram_offset += len;
+                        }
+                        ret
+                    }
+                };
+
+                if let Err(err) = ret {
+                    shared.batch.lock().error = Some(err);
+                }
+                offset += len;
+                lens = &lens[count..];
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+    use tokio::net::UdpSocket;
+
+    /// Segmentation offloads are only available on linux
+    pub fn enable_gro(_socket: &UdpSocket) -> bool {
+        false
+    }
+
+    #[derive(Default)]
+    pub struct GroBuffer(());
+
+    impl GroBuffer {
+        pub fn read_segment(&mut self, _obuf: &mut ReadBuf<'_>) -> bool {
+            false
+        }
+
+        pub fn poll_recv(&mut self, _socket: &UdpSocket, _cx: &mut Context<'_>) -> Poll<io::Result<SocketAddr>> {
+            Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+        }
+    }
+
+    pub enum GsoSender {}
+
+    impl GsoSender {
+        pub fn new(_socket: Arc<UdpSocket>, _peer: Option<SocketAddr>) -> Option<Self> {
+            None
+        }
+
+        pub fn poll_write(&self, _cx: &mut Context<'_>, _buf: &[u8]) -> Poll<io::Result<usize>> {
+            match *self {}
+        }
+
+        pub fn poll_flush(&self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::linux::{gso_groups, MAX_GSO_SEGMENTS};
+
+    #[test]
+    fn test_gso_groups() {
+        assert_eq!(gso_groups(&[]), vec![]);
+        assert_eq!(gso_groups(&[1200, 1200, 1200, 500]), vec![(1200, 4)]);
+        // Only the last datagram can be smaller, a bigger one starts a new group
+        assert_eq!(gso_groups(&[1200, 500, 500, 1300, 1300]), vec![(1200, 2), (500, 1), (1300, 2)]);
+        assert_eq!(
+            gso_groups(&[100; 100]),
+            vec![(100, MAX_GSO_SEGMENTS), (100, 100 - MAX_GSO_SEGMENTS)]
+        );
+        assert_eq!(gso_groups(&[40000, 40000]), vec![(40000, 1), (40000, 1)]);
+    }
+}
@@ -0,0 +1,118 @@
+#[cfg(unix)]
+mod unix {
+    use std::fs;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::path::Path;
+
+    use anyhow::{anyhow, Context};
+    use nix::unistd::{Group, User};
+    use tokio::net::UnixListener;
+    use tracing::info;
+
+    /// Uid and gid of USER[:GROUP], by name or by id
+    fn resolve_owner(owner: &str) -> anyhow::Result<(Option<u32>, Option<u32>)> {
+        let (user, group) = match owner.split_once(':') {
+            Some((user, group)) => (Some(user).filter(|u| !u.is_empty()), Some(group)),
+            None => (Some(owner), None),
+        };
+
+        let uid = match user {
+            None => None,
+            Some(user) => match user.parse() {
+                Ok(uid) => Some(uid),
+                Err(_) => Some(
+                    User::from_name(user)?
+                        .ok_or_else(|| anyhow!("unknown user {}", user))?
+                        .uid
+                        .as_raw(),
+                ),
+            },
+        };
+        let gid = match group {
+            None => None,
+            Some(group) => match group.parse() {
+                Ok(gid) => Some(gid),
+                Err(_) => Some(
+                    Group::from_name(group)?
+                        .ok_or_else(|| anyhow!("unknown group {}", group))?
+                        .gid
+                        .as_raw(),
+                ),
+            },
+        };
+        Ok((uid, gid))
+    }
+
+    /// Listen on the unix socket, replacing the one left by a previous server. The socket is created in a private
+    /// directory and moved to its path once its mode and owner are set, so that only the allowed users (i.e: the one
+    /// of the frontend) can ever connect
+    pub fn bind(path: &Path, mode: Option<u32>, owner: Option<&str>) -> anyhow::Result<UnixListener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!("cannot listen on {:?}, it exists and is not a unix socket", path));
+            }
+            info!("Removing the stale unix socket {:?}", path);
+            fs::remove_file(path).with_context(|| format!("cannot remove the unix socket {:?}", path))?;
+        }
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("cannot listen on {:?}, it is not a file", path))?;
+        let dir = path.with_file_name(format!(".wstunnel-{}", std::process::id()));
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("cannot create the directory {:?} of the unix socket", dir))?;
+        let _dir = scopeguard::guard(&dir, |dir| {
+            let _ = fs::remove_dir_all(dir);
+        });
+
+        let tmp_path = dir.join(file_name);
+        let listener =
+            UnixListener::bind(&tmp_path).with_context(|| format!("cannot listen on unix socket {:?}", path))?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))
+                .with_context(|| format!("cannot set the mode of {:?} to {:o}", path, mode))?;
+        }
+        if let Some(owner) = owner {
+            let (uid, gid) = resolve_owner(owner)?;
+            std::os::unix::fs::chown(&tmp_path, uid, gid)
+                .with_context(|| format!("cannot set the owner of {:?} to {}", path, owner))?;
+        }
+        fs::rename(&tmp_path, path).with_context(|| format!("cannot move the unix socket to {:?}", path))?;
+
+        Ok(listener)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_bind() {
+            let path = std::env::temp_dir().join(format!("wstunnel-test-{}.sock", std::process::id()));
+            let listener = bind(&path, Some(0o660), None).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+            assert!(!path
+                .with_file_name(format!(".wstunnel-{}", std::process::id()))
+                .exists());
+
+            // The socket of a previous server is replaced, other files are not
+            drop(listener);
+            let listener = bind(&path, None, None).unwrap();
+            drop(listener);
+            fs::remove_file(&path).unwrap();
+            fs::write(&path, "").unwrap();
+            assert!(bind(&path, None, None).is_err());
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(resolve_owner("0:0").unwrap(), (Some(0), Some(0)));
+            assert_eq!(resolve_owner(":12").unwrap(), (None, Some(12)));
+            assert_eq!(resolve_owner("root").unwrap(), (Some(0), None));
+            assert!(resolve_owner("wstunnel-no-such-user").is_err());
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::bind;
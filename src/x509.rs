@@ -0,0 +1,153 @@
+use std::borrow::Cow;
+
+use anyhow::anyhow;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP;
+
+/// Der encoding of a tag and its content
+pub fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match content.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len => {
+            let len = (len as u64).to_be_bytes();
+            let len = &len[len.iter().position(|b| *b != 0).unwrap_or(7)..];
+            out.push(0x80 | len.len() as u8);
+            out.extend_from_slice(len);
+        }
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+pub fn sequence(parts: &[&[u8]]) -> Vec<u8> {
+    der(0x30, &parts.concat())
+}
+
+/// What OCSP stapling, the checks of the chain and mTLS need from a certificate
+#[derive(Debug)]
+pub struct Certificate<'a> {
+    pub serial: &'a [u8],
+    /// Whole encoding of the names, to be compared
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    /// Without the count of unused bits
+    pub public_key: Cow<'a, [u8]>,
+    pub ocsp_url: Option<String>,
+    pub common_name: Option<String>,
+    /// The dns names, emails and uris
+    pub subject_alt_names: Vec<String>,
+}
+
+impl<'a> Certificate<'a> {
+    pub fn parse(der: &'a [u8]) -> anyhow::Result<Self> {
+        let (_, certificate) =
+            x509_parser::parse_x509_certificate(der).map_err(|err| anyhow!("invalid certificate: {}", err))?;
+
+        let mut ocsp_url = None;
+        let mut subject_alt_names = Vec::new();
+        for extension in certificate.extensions() {
+            match extension.parsed_extension() {
+                ParsedExtension::AuthorityInfoAccess(access) => {
+                    ocsp_url = access.iter().find_map(|description| match description.access_location {
+                        GeneralName::URI(uri) if description.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP => {
+                            Some(uri.to_string())
+                        }
+                        _ => None,
+                    });
+                }
+                ParsedExtension::SubjectAlternativeName(names) => {
+                    subject_alt_names = names
+                        .general_names
+                        .iter()
+                        .filter_map(|name| match name {
+                            // The other ones are not strings
+                            GeneralName::RFC822Name(name) | GeneralName::DNSName(name) | GeneralName::URI(name) => {
+                                Some(name.to_string())
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        let common_name = certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .map(|name| match name.as_str() {
+                Ok(name) => name.to_string(),
+                // Whatever the string type, only utf8 and ascii ones are used in practice
+                Err(_) => String::from_utf8_lossy(name.as_slice()).to_string(),
+            });
+
+        Ok(Self {
+            serial: certificate.raw_serial(),
+            issuer: certificate.tbs_certificate.issuer.as_raw(),
+            subject: certificate.tbs_certificate.subject.as_raw(),
+            public_key: certificate.tbs_certificate.subject_pki.subject_public_key.data.clone(),
+            ocsp_url,
+            common_name,
+            subject_alt_names,
+        })
+    }
+
+    /// Who the certificate of a client is issued to, its first alternative name or else its common name
+    pub fn identity(&self) -> Option<&str> {
+        self.subject_alt_names
+            .first()
+            .or(self.common_name.as_ref())
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let name = |cn: &str| {
+            sequence(&[&der(
+                0x31,
+                &sequence(&[&der(0x06, &[0x55, 0x04, 0x03]), &der(0x0c, cn.as_bytes())]),
+            )])
+        };
+        let certificate = |extensions: &[u8]| {
+            let ed25519 = sequence(&[&der(0x06, &[0x2b, 0x65, 0x70])]);
+            let validity = sequence(&[&der(0x17, b"240101000000Z"), &der(0x17, b"340101000000Z")]);
+            let tbs = sequence(&[
+                &der(0xa0, &der(0x02, &[2])),
+                &der(0x02, &[1]),
+                &ed25519,
+                &name("ca"),
+                &validity,
+                &name("client"),
+                &sequence(&[&ed25519, &der(0x03, &[0; 33])]),
+                extensions,
+            ]);
+            sequence(&[&tbs, &ed25519, &der(0x03, &[0; 65])])
+        };
+
+        let without_san = certificate(&[]);
+        assert_eq!(Certificate::parse(&without_san).unwrap().identity(), Some("client"));
+
+        let san = sequence(&[&der(0x87, &[127, 0, 0, 1]), &der(0x81, b"alice@example.com")]);
+        let extension = sequence(&[&der(0x06, &[0x55, 0x1d, 0x11]), &der(0x04, &san)]);
+        let ocsp = sequence(&[
+            &der(0x06, &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01]),
+            &der(0x86, b"http://ocsp.example.com"),
+        ]);
+        let authority_info_access = sequence(&[
+            &der(0x06, &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01]),
+            &der(0x04, &sequence(&[&ocsp])),
+        ]);
+        let with_san = certificate(&der(0xa3, &sequence(&[&extension, &authority_info_access])));
+        let with_san = Certificate::parse(&with_san).unwrap();
+        assert_eq!(with_san.identity(), Some("alice@example.com"));
+        assert_eq!(with_san.common_name.as_deref(), Some("client"));
+        assert_eq!(with_san.ocsp_url.as_deref(), Some("http://ocsp.example.com"));
+        assert!(Certificate::parse(&without_san[..without_san.len() - 1]).is_err());
+    }
+}